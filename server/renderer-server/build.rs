@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// Sets HACKLILY_GIT_HASH, the commit `get_status` reports under
+// `build`. From a checkout that's `git rev-parse`; `install.sh` builds
+// from the registry, where the only record of the commit is the
+// `.cargo_vcs_info.json` `cargo publish` writes into the package.
+// HACKLILY_GIT_HASH in the environment wins over both.
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=HACKLILY_GIT_HASH");
+    println!("cargo:rerun-if-changed=../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../.git/index");
+
+    let hash = std::env::var("HACKLILY_GIT_HASH")
+        .ok()
+        .or_else(from_git)
+        .or_else(from_vcs_info)
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=HACKLILY_GIT_HASH={}", hash);
+}
+
+fn from_git() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+    let hash = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    Some(hash).filter(|hash| output.status.success() && !hash.is_empty())
+}
+
+/// `{"git": {"sha1": "<40 hex digits>", ...}, ...}`, without pulling in
+/// a JSON parser as a build dependency.
+fn from_vcs_info() -> Option<String> {
+    let info = std::fs::read_to_string(".cargo_vcs_info.json").ok()?;
+    let rest = &info[info.find("\"sha1\"")? + "\"sha1\"".len()..];
+    let rest = &rest[rest.find('"')? + 1..];
+    let sha1 = &rest[..rest.find('"')?];
+    Some(sha1.chars().take(12).collect())
+}
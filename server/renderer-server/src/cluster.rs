@@ -0,0 +1,617 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// Coordinators that share their work. Coordinators pointed at the same
+// `SharedState` form a cluster, in which every coordinator is active:
+// frontends and workers may use any of them, and move to another when
+// one goes away (see `ws-worker`'s failover addresses). Each
+// coordinator heartbeats its spare capacity into the shared state. A
+// render a coordinator can't serve — it has no renderer for it at all,
+// or it is saturated while another coordinator has idle renderers — is
+// offered to the cluster (`Cluster::offload`) rather than failed or
+// left waiting. A coordinator with room claims it (`Cluster::claim`),
+// renders it on its own pool or workers, and posts the answer back for
+// the coordinator the frontend is waiting on.
+//
+// Connections are not shared: a frontend whose coordinator dies
+// reconnects and asks again, and a worker registers afresh with the
+// coordinator it fails over to. What a coordinator knows about its own
+// workers reaches the others only as the capacity in its heartbeat.
+//
+// `SharedState` is the pluggable part. `InProcessState` shares state
+// between coordinators in one process, for tests; `FileState` keeps it
+// in a directory, claiming jobs by atomic rename, so coordinators on
+// one host or a shared filesystem can form a cluster.
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::command_source::ResponseCallback;
+use crate::request::{Request, Response};
+use crate::status::StatusHandle;
+
+/// A coordinator's heartbeat.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub node: String,
+    /// Free local containers and idle remote slots.
+    pub idle: u64,
+    pub backlog: u64,
+    pub workers: u64,
+    pub seen_unix_msec: u64,
+}
+
+/// A render offered to the cluster by the coordinator `origin`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SharedJob {
+    pub id: String,
+    pub origin: String,
+    pub offered_unix_msec: u64,
+    pub request: Request,
+}
+
+/// Which offered jobs a node will claim.
+pub type JobFilter<'f> = dyn for<'a> Fn(&'a SharedJob) -> bool + Send + Sync + 'f;
+
+/// Where a cluster keeps its shared state.
+#[async_trait]
+pub trait SharedState: Send + Sync {
+    /// Records `node`'s latest heartbeat, replacing its previous one.
+    async fn heartbeat(&self, node: &NodeStatus) -> io::Result<()>;
+
+    /// The latest heartbeat of every node that has sent one.
+    async fn nodes(&self) -> io::Result<Vec<NodeStatus>>;
+
+    async fn offer(&self, job: &SharedJob) -> io::Result<()>;
+
+    /// Claims the oldest offered job `accepts` lets through, so that no
+    /// other node can.
+    async fn claim(&self, accepts: &JobFilter<'_>) -> io::Result<Option<SharedJob>>;
+
+    /// Answers a claimed job. Dropped if its origin has abandoned it.
+    async fn answer(&self, job_id: &str, response: &Response) -> io::Result<()>;
+
+    /// Removes and returns the answer to a job, if it has one yet.
+    async fn take_answer(&self, job_id: &str) -> io::Result<Option<Response>>;
+
+    /// Gives up on a job, claimed or not.
+    async fn abandon(&self, job_id: &str) -> io::Result<()>;
+}
+
+fn unix_msec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// ---------------------------------------------------------------------------
+// In-process backend
+// ---------------------------------------------------------------------------
+
+/// Shared state for coordinators in the same process. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct InProcessState {
+    inner: Arc<Mutex<InProcessInner>>,
+}
+
+#[derive(Default)]
+struct InProcessInner {
+    nodes: BTreeMap<String, NodeStatus>,
+    queue: Vec<SharedJob>,
+    claimed: Vec<String>,
+    answers: HashMap<String, Response>,
+}
+
+impl InProcessState {
+    fn lock(&self) -> std::sync::MutexGuard<'_, InProcessInner> {
+        self.inner.lock().expect("shared state lock poisoned")
+    }
+}
+
+#[async_trait]
+impl SharedState for InProcessState {
+    async fn heartbeat(&self, node: &NodeStatus) -> io::Result<()> {
+        self.lock().nodes.insert(node.node.clone(), node.clone());
+        Ok(())
+    }
+
+    async fn nodes(&self) -> io::Result<Vec<NodeStatus>> {
+        Ok(self.lock().nodes.values().cloned().collect())
+    }
+
+    async fn offer(&self, job: &SharedJob) -> io::Result<()> {
+        self.lock().queue.push(job.clone());
+        Ok(())
+    }
+
+    async fn claim(&self, accepts: &JobFilter<'_>) -> io::Result<Option<SharedJob>> {
+        let mut inner = self.lock();
+        let oldest = inner
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, job)| accepts(job))
+            .min_by_key(|(_, job)| job.offered_unix_msec);
+        let Some((i, _)) = oldest else {
+            return Ok(None);
+        };
+        let job = inner.queue.remove(i);
+        inner.claimed.push(job.id.clone());
+        Ok(Some(job))
+    }
+
+    async fn answer(&self, job_id: &str, response: &Response) -> io::Result<()> {
+        let mut inner = self.lock();
+        if let Some(i) = inner.claimed.iter().position(|id| id == job_id) {
+            inner.claimed.remove(i);
+            inner.answers.insert(job_id.to_owned(), response.clone());
+        }
+        Ok(())
+    }
+
+    async fn take_answer(&self, job_id: &str) -> io::Result<Option<Response>> {
+        Ok(self.lock().answers.remove(job_id))
+    }
+
+    async fn abandon(&self, job_id: &str) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.queue.retain(|job| job.id != job_id);
+        inner.claimed.retain(|id| id != job_id);
+        inner.answers.remove(job_id);
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Directory backend
+// ---------------------------------------------------------------------------
+
+/// Shared state in a directory: `nodes/<node>.json` heartbeats, offered
+/// jobs in `queue/` (named so they sort oldest first), claimed ones in
+/// `claimed/`, and answers in `answers/`. Files are written to a
+/// temporary name and renamed into place, so readers never see half a
+/// file, and a claim is a rename out of `queue/` that only one node can
+/// win.
+pub struct FileState {
+    dir: PathBuf,
+}
+
+impl FileState {
+    /// Uses `dir`, creating its subdirectories.
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        for sub in ["nodes", "queue", "claimed", "answers"] {
+            std::fs::create_dir_all(dir.join(sub))?;
+        }
+        Ok(FileState { dir })
+    }
+
+    fn path(&self, sub: &str, name: &str) -> PathBuf {
+        self.dir.join(sub).join(name)
+    }
+
+    async fn write(&self, sub: &str, name: &str, contents: Vec<u8>) -> io::Result<()> {
+        let tmp = self.path(sub, &format!(".{}.{}", name, Uuid::new_v4()));
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(&tmp, self.path(sub, name)).await
+    }
+
+    /// Names of the JSON files in `sub`, sorted.
+    async fn list(&self, sub: &str) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        let mut dir = tokio::fs::read_dir(self.dir.join(sub)).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                if !name.starts_with('.') && name.ends_with(".json") {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+fn queue_name(job: &SharedJob) -> String {
+    format!("{:016}-{}.json", job.offered_unix_msec, job.id)
+}
+
+/// Treats a file another node removed first as absent.
+fn absent_ok<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn json_err(err: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[async_trait]
+impl SharedState for FileState {
+    async fn heartbeat(&self, node: &NodeStatus) -> io::Result<()> {
+        let json = serde_json::to_vec(node).map_err(json_err)?;
+        self.write("nodes", &format!("{}.json", node.node), json)
+            .await
+    }
+
+    async fn nodes(&self) -> io::Result<Vec<NodeStatus>> {
+        let mut nodes = vec![];
+        for name in self.list("nodes").await? {
+            if let Some(json) = absent_ok(tokio::fs::read(self.path("nodes", &name)).await)? {
+                match serde_json::from_slice(&json) {
+                    Ok(node) => nodes.push(node),
+                    Err(err) => warn!("cluster: ignoring heartbeat {}: {}", name, err),
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    async fn offer(&self, job: &SharedJob) -> io::Result<()> {
+        let json = serde_json::to_vec(job).map_err(json_err)?;
+        self.write("queue", &queue_name(job), json).await
+    }
+
+    async fn claim(&self, accepts: &JobFilter<'_>) -> io::Result<Option<SharedJob>> {
+        for name in self.list("queue").await? {
+            let Some(json) = absent_ok(tokio::fs::read(self.path("queue", &name)).await)? else {
+                continue;
+            };
+            let job: SharedJob = match serde_json::from_slice(&json) {
+                Ok(job) => job,
+                Err(err) => {
+                    warn!("cluster: ignoring job {}: {}", name, err);
+                    continue;
+                }
+            };
+            if !accepts(&job) {
+                continue;
+            }
+            let claimed = tokio::fs::rename(
+                self.path("queue", &name),
+                self.path("claimed", &format!("{}.json", job.id)),
+            )
+            .await;
+            if absent_ok(claimed)?.is_some() {
+                return Ok(Some(job));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn answer(&self, job_id: &str, response: &Response) -> io::Result<()> {
+        let name = format!("{}.json", job_id);
+        let claimed = tokio::fs::remove_file(self.path("claimed", &name)).await;
+        if absent_ok(claimed)?.is_none() {
+            return Ok(());
+        }
+        let json = serde_json::to_vec(response).map_err(json_err)?;
+        self.write("answers", &name, json).await
+    }
+
+    async fn take_answer(&self, job_id: &str) -> io::Result<Option<Response>> {
+        let path = self.path("answers", &format!("{}.json", job_id));
+        let Some(json) = absent_ok(tokio::fs::read(&path).await)? else {
+            return Ok(None);
+        };
+        absent_ok(tokio::fs::remove_file(&path).await)?;
+        serde_json::from_slice(&json).map(Some).map_err(json_err)
+    }
+
+    async fn abandon(&self, job_id: &str) -> io::Result<()> {
+        let name = format!("{}.json", job_id);
+        let suffix = format!("-{}", name);
+        for queued in self.list("queue").await? {
+            if queued.ends_with(&suffix) {
+                absent_ok(tokio::fs::remove_file(self.path("queue", &queued)).await)?;
+            }
+        }
+        for sub in ["claimed", "answers"] {
+            absent_ok(tokio::fs::remove_file(self.path(sub, &name)).await)?;
+        }
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// A coordinator's view of the cluster
+// ---------------------------------------------------------------------------
+
+/// One coordinator's membership in a cluster. Cheap to clone.
+#[derive(Clone)]
+pub struct Cluster {
+    node: String,
+    backend: Arc<dyn SharedState>,
+    tick: Duration,
+    /// Idle capacity of the other live nodes, as of the last heartbeat.
+    spare: Arc<AtomicU64>,
+}
+
+/// Heartbeats older than this many ticks are from nodes that are gone.
+const LIVE_TICKS: u32 = 20;
+
+impl Cluster {
+    pub fn new(node: String, backend: Arc<dyn SharedState>) -> Self {
+        Cluster {
+            node,
+            backend,
+            tick: Duration::from_millis(250),
+            spare: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// How often to heartbeat, look for work, and check for answers.
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    /// Idle capacity of the other live nodes, as of the last heartbeat.
+    pub fn spare(&self) -> u64 {
+        self.spare.load(Ordering::Relaxed)
+    }
+
+    /// Publishes this node's capacity from `status`, and the live nodes
+    /// back into `status`.
+    pub async fn heartbeat(&self, status: &StatusHandle) {
+        let snap = status.snapshot();
+        let me = NodeStatus {
+            node: self.node.clone(),
+            idle: snap.local_free.load(Ordering::Relaxed)
+                + snap.remote_free.load(Ordering::Relaxed),
+            backlog: snap.backlog.load(Ordering::Relaxed),
+            workers: snap.remote_total.load(Ordering::Relaxed),
+            seen_unix_msec: unix_msec(),
+        };
+        if let Err(err) = self.backend.heartbeat(&me).await {
+            warn!("cluster: could not heartbeat: {}", err);
+        }
+        let nodes = match self.backend.nodes().await {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                warn!("cluster: could not read heartbeats: {}", err);
+                return;
+            }
+        };
+        let oldest = me
+            .seen_unix_msec
+            .saturating_sub((self.tick * LIVE_TICKS).as_millis() as u64);
+        let live: Vec<NodeStatus> = nodes
+            .into_iter()
+            .filter(|node| node.seen_unix_msec >= oldest)
+            .collect();
+        self.spare.store(
+            live.iter()
+                .filter(|node| node.node != self.node)
+                .map(|node| node.idle)
+                .sum(),
+            Ordering::Relaxed,
+        );
+        *snap.cluster_nodes.lock().expect("status lock poisoned") = live;
+    }
+
+    /// Offers `request` to the other coordinators. `callback` gets the
+    /// answer of whichever claims it, or an error if none has answered
+    /// within `timeout`.
+    pub fn offload(&self, request: Request, callback: ResponseCallback, timeout: Duration) {
+        let job = SharedJob {
+            id: Uuid::new_v4().to_string(),
+            origin: self.node.clone(),
+            offered_unix_msec: unix_msec(),
+            request,
+        };
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            info!("cluster: offering {} as job {}", job.request.id, job.id);
+            let answer = match cluster.backend.offer(&job).await {
+                Ok(()) => cluster.await_answer(&job.id, timeout).await,
+                Err(err) => Err(format!("could not offer it to the cluster: {}", err)),
+            };
+            callback(answer.unwrap_or_else(|reason| {
+                warn!("cluster: job {}: {}", job.id, reason);
+                Response::failure(format!("Internal error: {}", reason))
+            }));
+        });
+    }
+
+    async fn await_answer(&self, job_id: &str, timeout: Duration) -> Result<Response, String> {
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            tokio::time::sleep(self.tick).await;
+            match self.backend.take_answer(job_id).await {
+                Ok(Some(answer)) => return Ok(answer),
+                Ok(None) => {}
+                Err(err) => warn!("cluster: could not check job {}: {}", job_id, err),
+            }
+        }
+        if let Err(err) = self.backend.abandon(job_id).await {
+            warn!("cluster: could not abandon job {}: {}", job_id, err);
+        }
+        Err(format!(
+            "no coordinator in the cluster answered within {} ms",
+            timeout.as_millis()
+        ))
+    }
+
+    /// Claims up to `n` jobs offered by other coordinators that
+    /// `accepts` lets through. Each comes with the callback that posts
+    /// its answer back.
+    pub async fn claim(
+        &self,
+        n: usize,
+        accepts: impl Fn(&Request) -> bool + Send + Sync,
+    ) -> Vec<(Request, ResponseCallback)> {
+        let mut claimed = vec![];
+        let accepts = |job: &SharedJob| job.origin != self.node && accepts(&job.request);
+        while claimed.len() < n {
+            let job = match self.backend.claim(&accepts).await {
+                Ok(Some(job)) => job,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("cluster: could not claim a job: {}", err);
+                    break;
+                }
+            };
+            info!("cluster: claimed job {} from {}", job.id, job.origin);
+            let backend = self.backend.clone();
+            let job_id = job.id;
+            let callback: ResponseCallback = Box::new(move |response| {
+                let backend = backend.clone();
+                let job_id = job_id.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = backend.answer(&job_id, &response).await {
+                        warn!("cluster: could not answer job {}: {}", job_id, err);
+                    }
+                });
+            });
+            claimed.push((job.request, callback));
+        }
+        claimed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Backend, RenderOptions, Version};
+
+    fn job(id: &str, origin: &str, offered_unix_msec: u64) -> SharedJob {
+        SharedJob {
+            id: id.to_owned(),
+            origin: origin.to_owned(),
+            offered_unix_msec,
+            request: Request {
+                id: format!("request-{}", id),
+                backend: Backend::Svg,
+                src: "c4".to_owned(),
+                version: Version::Stable,
+                options: RenderOptions::default(),
+                files: BTreeMap::new(),
+            },
+        }
+    }
+
+    /// The contract every backend keeps.
+    async fn exercise(backend: &dyn SharedState) {
+        backend.offer(&job("b", "n1", 2)).await.unwrap();
+        backend.offer(&job("a", "n1", 1)).await.unwrap();
+        backend.offer(&job("c", "n1", 3)).await.unwrap();
+
+        // Oldest first, each claimed once.
+        let first = backend.claim(&|_| true).await.unwrap().unwrap();
+        assert_eq!(first.id, "a");
+        let skip_b = backend.claim(&|job| job.id != "b").await.unwrap().unwrap();
+        assert_eq!(skip_b.id, "c");
+        assert!(backend.claim(&|job| job.id != "b").await.unwrap().is_none());
+
+        backend.answer("a", &Response::ok("a done")).await.unwrap();
+        assert_eq!(
+            backend.take_answer("a").await.unwrap(),
+            Some(Response::ok("a done"))
+        );
+        assert_eq!(backend.take_answer("a").await.unwrap(), None);
+
+        // An abandoned job can't be claimed, and its answer is dropped.
+        backend.abandon("b").await.unwrap();
+        assert!(backend.claim(&|_| true).await.unwrap().is_none());
+        backend.abandon("c").await.unwrap();
+        backend
+            .answer("c", &Response::ok("too late"))
+            .await
+            .unwrap();
+        assert_eq!(backend.take_answer("c").await.unwrap(), None);
+
+        let node = NodeStatus {
+            node: "n1".to_owned(),
+            idle: 2,
+            backlog: 0,
+            workers: 1,
+            seen_unix_msec: 5,
+        };
+        backend.heartbeat(&node).await.unwrap();
+        backend
+            .heartbeat(&NodeStatus {
+                idle: 3,
+                ..node.clone()
+            })
+            .await
+            .unwrap();
+        let nodes = backend.nodes().await.unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].idle, 3);
+    }
+
+    #[tokio::test]
+    async fn in_process_state_keeps_the_contract() {
+        exercise(&InProcessState::default()).await;
+    }
+
+    #[tokio::test]
+    async fn file_state_keeps_the_contract() {
+        let dir = std::env::temp_dir().join(format!("cluster-{}", Uuid::new_v4()));
+        exercise(&FileState::new(dir.clone()).unwrap()).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn offloaded_renders_are_answered_by_another_node() {
+        let shared = Arc::new(InProcessState::default());
+        let tick = Duration::from_millis(10);
+        let a = Cluster::new("a".to_owned(), shared.clone()).with_tick(tick);
+        let b = Cluster::new("b".to_owned(), shared).with_tick(tick);
+
+        let (answered, mut answer) = tokio::sync::mpsc::unbounded_channel();
+        a.offload(
+            job("x", "a", 0).request,
+            Box::new(move |response| answered.send(response.logs).unwrap()),
+            Duration::from_secs(2),
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // A node never claims its own offers.
+        assert!(a.claim(1, |_| true).await.is_empty());
+        let mut claimed = b.claim(5, |_| true).await;
+        assert_eq!(claimed.len(), 1);
+        let (request, callback) = claimed.pop().unwrap();
+        assert_eq!(request.id, "request-x");
+        callback(Response::ok("rendered by b"));
+        assert_eq!(answer.recv().await.unwrap(), "rendered by b");
+    }
+
+    #[tokio::test]
+    async fn unclaimed_offers_time_out() {
+        let shared = Arc::new(InProcessState::default());
+        let a = Cluster::new("a".to_owned(), shared.clone()).with_tick(Duration::from_millis(10));
+        let (answered, mut answer) = tokio::sync::mpsc::unbounded_channel();
+        a.offload(
+            job("x", "a", 0).request,
+            Box::new(move |response| answered.send(response.logs).unwrap()),
+            Duration::from_millis(50),
+        );
+        let logs = answer.recv().await.unwrap();
+        assert!(
+            logs.starts_with("Internal error: no coordinator"),
+            "{}",
+            logs
+        );
+        assert!(shared.claim(&|_| true).await.unwrap().is_none());
+    }
+}
@@ -4,13 +4,20 @@
 // The coordinator command source: a WebSocket server that replaces the
 // legacy Qt `HacklilyServer` (now retired; formerly `server/ws-server/`).
 //
-// It listens on one port shared by frontend clients and remote renderer
-// workers (distinguished by the first JSON-RPC message: workers send
-// `i_haz_computes`, frontends send `render`/`signIn`/etc.). This module
+// Frontend clients and remote renderer workers share one port by
+// default, told apart by the first JSON-RPC message (workers send
+// `i_haz_computes`, frontends send `render`/`signIn`/etc.). With
+// `worker_listener` workers get a port of their own and the main one
+// serves frontends only; with `worker_path` only connections to that
+// URL path may be workers (`role_for`). This module
 // produces a `RequestStream` of render requests that feed the existing
 // `event_loop` / `RendererManager` local pool, exactly like the batch
 // and ws-worker command sources do. Non-render RPCs (ping, signIn,
 // signOut, notifySaved, get_status) are handled inline per connection.
+// `submitRender` feeds the same stream but answers with a job id at
+// once; `getJob` and `fetchResult` then read the job from `job_log`, so
+// the client needn't stay connected while it renders. With `http_listener`,
+// `http` serves `GET /status` and `POST /render` alongside.
 //
 // Remote-worker dispatch (forwarding renders to connected workers
 // instead of the local pool) is the router's job, added in the next
@@ -21,8 +28,11 @@ use futures::SinkExt;
 use log::{debug, error, info, warn};
 use serde::Deserialize;
 use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
@@ -36,12 +46,18 @@ pub type SharedSink = Arc<Mutex<Box<dyn WsSink + Send>>>;
 
 /// Trait abstracting the write half of a WebSocket connection, so the
 /// worker registry can be tested without a real socket. The production
-/// impl wraps `SplitSink<WebSocketStream<TcpStream>, Message>`.
+/// impl wraps `SplitSink<WebSocketStream<ServerStream>, Message>`.
 pub trait WsSink: Send {
     /// Send a text message. The implementation must be async-safe.
     fn send_text(&mut self, text: String) -> SendFut<'_>;
+    /// Send a binary message (see `wire::encode_render_result`).
+    fn send_binary(&mut self, data: Vec<u8>) -> SendFut<'_>;
     /// Send a pong (reply to a ping).
     fn send_pong(&mut self, payload: Vec<u8>) -> SendFut<'_>;
+    /// Send a ping (the worker heartbeat).
+    fn send_ping(&mut self, payload: Vec<u8>) -> SendFut<'_>;
+    /// Send a close frame (hanging up on a drained worker).
+    fn send_close(&mut self) -> SendFut<'_>;
 }
 
 /// Future returned by `WsSink::send_*`. Using a concrete boxed future
@@ -49,38 +65,92 @@ pub trait WsSink: Send {
 pub type SendFut<'a> =
     std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), String>> + Send + 'a>>;
 
-/// Production `WsSink` wrapping a `SplitSink`.
-struct TungsteniteSink(SplitSink<WebSocketStream<tokio::net::TcpStream>, WsMessage>);
+/// Production `WsSink` wrapping a `SplitSink`. Applies the
+/// connection's compression envelope and counts traffic.
+struct TungsteniteSink {
+    sink: SplitSink<WebSocketStream<ServerStream>, WsMessage>,
+    compression: Compression,
+    status: StatusHandle,
+}
+
+impl TungsteniteSink {
+    fn send(&mut self, message: WsMessage) -> SendFut<'_> {
+        let message = prepare(message, self.compression, &self.status);
+        Box::pin(async move { self.sink.send(message).await.map_err(|e| e.to_string()) })
+    }
+}
 
 impl WsSink for TungsteniteSink {
     fn send_text(&mut self, text: String) -> SendFut<'_> {
-        Box::pin(async move {
-            self.0
-                .send(WsMessage::Text(text))
-                .await
-                .map_err(|e| e.to_string())
-        })
+        self.send(WsMessage::Text(text))
+    }
+    fn send_binary(&mut self, data: Vec<u8>) -> SendFut<'_> {
+        self.send(WsMessage::Binary(data))
     }
     fn send_pong(&mut self, payload: Vec<u8>) -> SendFut<'_> {
-        Box::pin(async move {
-            self.0
-                .send(WsMessage::Pong(payload))
-                .await
-                .map_err(|e| e.to_string())
-        })
+        self.send(WsMessage::Pong(payload))
+    }
+    fn send_ping(&mut self, payload: Vec<u8>) -> SendFut<'_> {
+        self.send(WsMessage::Ping(payload))
+    }
+    fn send_close(&mut self) -> SendFut<'_> {
+        self.send(WsMessage::Close(None))
     }
 }
 
+/// Put an outgoing message in the connection's compression envelope,
+/// counting it and its size before and after.
+fn prepare(message: WsMessage, compression: Compression, status: &StatusHandle) -> WsMessage {
+    let snap = status.snapshot();
+    snap.ws_payload_bytes_out
+        .fetch_add(message.len() as u64, Ordering::Relaxed);
+    snap.ws_messages_out.fetch_add(1, Ordering::Relaxed);
+    let message = wire::seal(message, compression);
+    snap.ws_bytes_out
+        .fetch_add(message.len() as u64, Ordering::Relaxed);
+    message
+}
+
+/// Take an incoming message out of the connection's compression
+/// envelope, counting it and its size before and after.
+fn receive(
+    message: Result<WsMessage, tokio_tungstenite::tungstenite::Error>,
+    compression: Compression,
+    status: &StatusHandle,
+) -> Result<WsMessage, String> {
+    let message = message.map_err(|e| e.to_string())?;
+    let snap = status.snapshot();
+    snap.ws_bytes_in
+        .fetch_add(message.len() as u64, Ordering::Relaxed);
+    snap.ws_messages_in.fetch_add(1, Ordering::Relaxed);
+    let message = wire::open(message, compression)?;
+    snap.ws_payload_bytes_in
+        .fetch_add(message.len() as u64, Ordering::Relaxed);
+    Ok(message)
+}
+
 use crate::auth::{self, AuthError, GitHub};
-use crate::command_source::{QuitSignal, QuitSink, RequestStream, ResponseCallback};
+use crate::command_source::{http, QuitSignal, QuitSink, RequestStream, ResponseCallback};
 use crate::error::HacklilyError;
+use crate::job_log::{self, Job, JobLog};
 use crate::jsonrpc::{self, method, Request, Response};
-use crate::request::{Backend, Request as RenderRequest, Response as RenderResponse, Version};
+use crate::request::{
+    Backend, RenderOptions, Request as RenderRequest, Response as RenderResponse, Version,
+};
 use crate::status::StatusHandle;
+use crate::tls::{self, ClientAuth, ServerStream, TlsAcceptorHandle};
+use crate::wire::{self, Compression, Encoding, Transport};
+use crate::worker_auth::{self, AdminToken, WorkerKeys};
+use crate::worker_registry::WorkerCapabilities;
 use std::sync::atomic::Ordering;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request as HandshakeRequest, Response as HandshakeResponse,
+};
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 
 /// Parameters for the `render` method, mirroring `RenderParams` in
-/// `src/RPCClient.tsx`. `version` is optional there and defaults to
+/// `src/lib/RPCClient.tsx`. `version` is optional there and defaults to
 /// stable, matching the legacy Qt `requestObj["version"].toString("stable")`.
 #[derive(Debug, Deserialize)]
 struct RenderParams {
@@ -88,6 +158,10 @@ struct RenderParams {
     src: String,
     #[serde(default = "default_version")]
     version: Version,
+    #[serde(default)]
+    options: RenderOptions,
+    #[serde(default)]
+    files: BTreeMap<String, String>,
 }
 
 fn default_version() -> Version {
@@ -98,6 +172,40 @@ fn default_version() -> Version {
 #[derive(Debug, Deserialize)]
 struct IHazComputesParams {
     max_jobs: u64,
+    /// Containers per version (`{"stable": 2, "unstable": 1}`). Older
+    /// workers don't send it, and get `max_jobs` any-version slots.
+    /// Versions and backends this coordinator doesn't know are skipped.
+    #[serde(default)]
+    slots: Option<HashMap<String, u64>>,
+    #[serde(default)]
+    backends: Option<Vec<Value>>,
+    /// Credentials, checked against `CoordinatorConfig::worker_keys`.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+impl IHazComputesParams {
+    fn capabilities(&self) -> WorkerCapabilities {
+        let slots = match &self.slots {
+            Some(slots) => slots
+                .iter()
+                .filter_map(|(version, n)| {
+                    let version = serde_json::from_value::<Version>(json!(version)).ok()?;
+                    Some((Some(version), *n))
+                })
+                .collect(),
+            None => HashMap::from([(None, self.max_jobs)]),
+        };
+        let backends = self.backends.as_ref().map(|backends| {
+            backends
+                .iter()
+                .filter_map(|b| serde_json::from_value::<Backend>(b.clone()).ok())
+                .collect()
+        });
+        WorkerCapabilities { slots, backends }
+    }
 }
 
 /// Shared coordinator state visible to all connections. Analytics
@@ -109,6 +217,9 @@ struct IHazComputesParams {
 #[derive(Clone)]
 struct ConnState {
     status: StatusHandle,
+    /// Jobs submitted on one frontend connection that haven't finished;
+    /// each connection starts its own.
+    submitted: Arc<AtomicUsize>,
 }
 
 impl ConnState {
@@ -130,6 +241,25 @@ pub struct CoordinatorConfig {
     pub github_secret: String,
     pub workers: crate::worker_registry::WorkerRegistryHandle,
     pub status: StatusHandle,
+    /// Accept peers asking for the `wire::Compression::Deflate` envelope.
+    pub ws_compression: bool,
+    /// If set, only workers on this allow-list may register.
+    pub worker_keys: Option<Arc<WorkerKeys>>,
+    /// If set, workers connect to this separate listener, and the main
+    /// one only serves frontends.
+    pub worker_listener: Option<SocketAddr>,
+    /// If set, workers must connect with this URL path (e.g. `/worker`).
+    /// On a shared listener any other path is frontend-only.
+    pub worker_path: Option<String>,
+    /// If set, terminate TLS on both listeners; see `tls`.
+    pub tls: Option<TlsAcceptorHandle>,
+    /// If set, frontends presenting it may use admin methods.
+    pub admin_token: Option<Arc<AdminToken>>,
+    /// Where `submitRender` records jobs for `getJob`/`fetchResult`.
+    pub jobs: JobLog,
+    /// If set, serve `GET /status` and `POST /render` over HTTP on this
+    /// address; see `http`.
+    pub http_listener: Option<SocketAddr>,
 }
 
 /// Build the coordinator command source. Binds the WebSocket listener
@@ -149,9 +279,20 @@ pub async fn coordinator(
         "coordinator listening on {}:{}",
         cfg.bind_address, cfg.ws_port
     );
+    let worker_listener = match cfg.worker_listener {
+        Some(addr) => {
+            let l = TcpListener::bind(addr).await.map_err(|e| {
+                HacklilyError::CommandSourceError(format!("worker bind failed: {}", e))
+            })?;
+            info!("coordinator listening for workers on {}", addr);
+            Some(l)
+        }
+        None => None,
+    };
 
     let conn = ConnState {
         status: cfg.status.clone(),
+        submitted: Arc::default(),
     };
     let github: Arc<dyn GitHub> = Arc::new(auth::ReqwestGitHub::new().map_err(|e| {
         HacklilyError::CommandSourceError(format!("could not build GitHub client: {}", e.message))
@@ -160,6 +301,24 @@ pub async fn coordinator(
     let (req_tx, req_rx) =
         tokio::sync::mpsc::channel::<Result<(RenderRequest, ResponseCallback), HacklilyError>>(100);
 
+    let http = match cfg.http_listener {
+        Some(addr) => {
+            let l = TcpListener::bind(addr).await.map_err(|e| {
+                HacklilyError::CommandSourceError(format!("HTTP bind failed: {}", e))
+            })?;
+            Some(tokio::spawn(http::serve(
+                l,
+                http::HttpState {
+                    status: cfg.status.clone(),
+                    tls: cfg.tls.clone(),
+                    req_tx: req_tx.clone(),
+                },
+            )))
+        }
+        None => None,
+    };
+    let tls_watch = cfg.tls.as_ref().map(|tls| tls.watch(tls::RELOAD_INTERVAL));
+
     // Spawn the accept loop. It owns the listener and spawns one task
     // per connection. Cancellation is via the quit stream: when the
     // quit sink fires, the select below exits and the listener drops.
@@ -172,17 +331,20 @@ pub async fn coordinator(
     tokio::spawn(async move {
         loop {
             // Accept a new connection or quit, whichever comes first.
-            let next = futures::future::select(Box::pin(listener.accept()), quit.next());
+            let accept = accept_any(&listener, worker_listener.as_ref());
+            let next = futures::future::select(Box::pin(accept), quit.next());
             match next.await {
-                futures::future::Either::Left((Ok((stream, addr)), _quit)) => {
-                    debug!("coordinator: new connection from {}", addr);
+                futures::future::Either::Left(((Ok((stream, addr)), ingress), _quit)) => {
+                    debug!("coordinator: new connection from {} ({:?})", addr, ingress);
                     let conn = conn_acc_loop.clone();
                     let github = github_acc_loop.clone();
                     let cfg = cfg_acc_loop.clone();
                     let req_tx = req_tx_acc_loop.clone();
-                    tokio::spawn(handle_connection(stream, conn, github, cfg, req_tx));
+                    tokio::spawn(handle_connection(
+                        stream, ingress, conn, github, cfg, req_tx,
+                    ));
                 }
-                futures::future::Either::Left((Err(e), _quit)) => {
+                futures::future::Either::Left(((Err(e), _), _quit)) => {
                     error!("coordinator: accept failed: {}", e);
                     break;
                 }
@@ -192,6 +354,12 @@ pub async fn coordinator(
                 }
             }
         }
+        if let Some(http) = http {
+            http.abort();
+        }
+        if let Some(tls_watch) = tls_watch {
+            tls_watch.abort();
+        }
         info!("coordinator: accept loop exiting");
     });
 
@@ -203,17 +371,145 @@ pub async fn coordinator(
     Ok((request_stream, parent_quit_sink))
 }
 
+/// Which listener a connection came in on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ingress {
+    Main,
+    Workers,
+}
+
+/// What a connection may turn out to be. With neither a separate
+/// worker listener nor a worker path, the first message decides.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Either,
+    Frontend,
+    Worker,
+}
+
+/// Accept the next connection on either listener.
+async fn accept_any(
+    main: &TcpListener,
+    workers: Option<&TcpListener>,
+) -> (std::io::Result<(TcpStream, SocketAddr)>, Ingress) {
+    match workers {
+        None => (main.accept().await, Ingress::Main),
+        Some(workers) => tokio::select! {
+            r = main.accept() => (r, Ingress::Main),
+            r = workers.accept() => (r, Ingress::Workers),
+        },
+    }
+}
+
+/// The role a connection to `path` on `ingress` may take, or `None` if
+/// the handshake should be refused (a wrong path on the worker listener).
+fn role_for(
+    ingress: Ingress,
+    path: &str,
+    separate_listener: bool,
+    worker_path: Option<&str>,
+) -> Option<Role> {
+    let is_worker_path = |p: &str| path.trim_end_matches('/') == p.trim_end_matches('/');
+    match (ingress, worker_path) {
+        (Ingress::Workers, None) => Some(Role::Worker),
+        (Ingress::Workers, Some(p)) => Some(Role::Worker).filter(|_| is_worker_path(p)),
+        (Ingress::Main, _) if separate_listener => Some(Role::Frontend),
+        (Ingress::Main, None) => Some(Role::Either),
+        (Ingress::Main, Some(p)) if is_worker_path(p) => Some(Role::Worker),
+        (Ingress::Main, Some(_)) => Some(Role::Frontend),
+    }
+}
+
+/// Handshake callback recording the `wire::Transport` the peer asked
+/// for, and echoing the matching subprotocol back. Also decides the
+/// connection's `Role` from the URL path.
+struct NegotiateTransport<'a> {
+    transport: &'a mut Transport,
+    allow_compression: bool,
+    role: &'a mut Role,
+    ingress: Ingress,
+    cfg: &'a CoordinatorConfig,
+}
+
+impl Callback for NegotiateTransport<'_> {
+    fn on_request(
+        self,
+        request: &HandshakeRequest,
+        mut response: HandshakeResponse,
+    ) -> Result<HandshakeResponse, ErrorResponse> {
+        *self.role = match role_for(
+            self.ingress,
+            request.uri().path(),
+            self.cfg.worker_listener.is_some(),
+            self.cfg.worker_path.as_deref(),
+        ) {
+            Some(role) => role,
+            None => {
+                let mut err = ErrorResponse::new(None);
+                *err.status_mut() = StatusCode::NOT_FOUND;
+                return Err(err);
+            }
+        };
+        let offered = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok());
+        let (transport, subprotocol) = Transport::negotiate(offered, self.allow_compression);
+        *self.transport = transport;
+        if let Some(subprotocol) = subprotocol {
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(subprotocol),
+            );
+        }
+        Ok(response)
+    }
+}
+
 /// Handle a single WebSocket connection. Determines whether it is a
 /// frontend client or a remote worker by the first JSON-RPC message,
-/// then dispatches accordingly.
+/// then dispatches accordingly. Binary results and compression are
+/// negotiated during the handshake; see `wire::Transport`.
 async fn handle_connection(
     raw_stream: tokio::net::TcpStream,
+    ingress: Ingress,
     conn: ConnState,
     github: Arc<dyn GitHub>,
     cfg: CoordinatorConfig,
     req_tx: tokio::sync::mpsc::Sender<Result<(RenderRequest, ResponseCallback), HacklilyError>>,
 ) {
-    let mut ws = match tokio_tungstenite::accept_async(raw_stream).await {
+    let raw_stream = match &cfg.tls {
+        Some(tls) => {
+            let auth = match ingress {
+                Ingress::Workers => ClientAuth::Required,
+                Ingress::Main if cfg.worker_listener.is_some() => ClientAuth::None,
+                Ingress::Main => ClientAuth::Optional,
+            };
+            let handshake = tls.accept(raw_stream, auth);
+            match tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => {
+                    debug!("coordinator: TLS handshake failed: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("coordinator: TLS handshake timed out");
+                    return;
+                }
+            }
+        }
+        None => ServerStream::Plain(raw_stream),
+    };
+    let mut transport = Transport::default();
+    let mut role = Role::Either;
+    let negotiate = NegotiateTransport {
+        transport: &mut transport,
+        allow_compression: cfg.ws_compression,
+        role: &mut role,
+        ingress,
+        cfg: &cfg,
+    };
+    let mut ws = match tokio_tungstenite::accept_hdr_async(raw_stream, negotiate).await {
         Ok(ws) => ws,
         Err(e) => {
             warn!("coordinator: ws handshake failed: {}", e);
@@ -222,7 +518,12 @@ async fn handle_connection(
     };
 
     // First message determines the role.
-    let first = match ws.next().await {
+    let compression = transport.compression;
+    let first = match ws
+        .next()
+        .await
+        .map(|m| receive(m, compression, &cfg.status))
+    {
         Some(Ok(tokio_tungstenite::tungstenite::Message::Text(t))) => t,
         Some(Ok(_)) => {
             debug!("coordinator: ignoring non-text first message");
@@ -240,18 +541,46 @@ async fn handle_connection(
         Err(err) => {
             let resp = *err;
             let _ = ws
-                .send(tokio_tungstenite::tungstenite::Message::Text(
-                    resp.serialize(),
+                .send(prepare(
+                    tokio_tungstenite::tungstenite::Message::Text(resp.serialize()),
+                    compression,
+                    &cfg.status,
                 ))
                 .await;
             return;
         }
     };
 
-    if req.method == method::I_HAZ_COMPUTES {
-        handle_worker(ws, req, cfg.workers.clone()).await;
+    let is_worker = req.method == method::I_HAZ_COMPUTES;
+    let refusal = match role {
+        Role::Frontend if is_worker => {
+            warn!("coordinator: refused i_haz_computes on a frontend listener or path");
+            StatusHandle::bump(&cfg.status.snapshot().workers_rejected);
+            Some((jsonrpc::STDERR_METHOD_NOT_FOUND, "Method not found"))
+        }
+        Role::Worker if !is_worker => Some((
+            jsonrpc::STDERR_INVALID_REQUEST,
+            "Expected i_haz_computes on the worker listener",
+        )),
+        _ => None,
+    };
+    if let Some((code, message)) = refusal {
+        let resp = Response::error(req.id, code, message);
+        let _ = ws
+            .send(prepare(
+                tokio_tungstenite::tungstenite::Message::Text(resp.serialize()),
+                compression,
+                &cfg.status,
+            ))
+            .await;
+        let _ = ws.close(None).await;
+        return;
+    }
+
+    if is_worker {
+        handle_worker(ws, req, compression, cfg).await;
     } else {
-        handle_frontend_first(ws, req, conn, github, cfg, req_tx).await;
+        handle_frontend_first(ws, req, transport, conn, github, cfg, req_tx).await;
     }
 }
 
@@ -261,55 +590,145 @@ async fn handle_connection(
 /// responses keyed by request id and are routed back through the
 /// registry to the originating frontend callback.
 async fn handle_worker(
-    mut ws: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    mut ws: tokio_tungstenite::WebSocketStream<ServerStream>,
     req: Request,
-    workers: crate::worker_registry::WorkerRegistryHandle,
+    compression: Compression,
+    cfg: CoordinatorConfig,
 ) {
+    let CoordinatorConfig {
+        workers,
+        status,
+        worker_keys,
+        tls,
+        ..
+    } = cfg;
     let params: IHazComputesParams = match serde_json::from_value(req.params.clone()) {
         Ok(p) => p,
         Err(e) => {
             let resp = Response::error(req.id, jsonrpc::STDERR_INVALID_PARAMS, &e.to_string());
             let _ = ws
-                .send(tokio_tungstenite::tungstenite::Message::Text(
-                    resp.serialize(),
+                .send(prepare(
+                    tokio_tungstenite::tungstenite::Message::Text(resp.serialize()),
+                    compression,
+                    &status,
                 ))
                 .await;
             return;
         }
     };
+    let name = params.name.as_deref();
+    let verdict = match (&tls, &worker_keys) {
+        (Some(tls), _) if tls.verifies_clients() && !ws.get_ref().client_verified() => {
+            Err("no client certificate".to_owned())
+        }
+        (_, Some(keys)) => keys.verify(name, params.token.as_deref(), worker_auth::unix_now()),
+        _ => Ok(()),
+    };
+    if let Err(e) = verdict {
+        let peer = ws
+            .get_ref()
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_else(|_| "unknown peer".to_owned());
+        warn!(
+            "coordinator: rejected worker {:?} from {}: {}",
+            name.unwrap_or(""),
+            peer,
+            e
+        );
+        StatusHandle::bump(&status.snapshot().workers_rejected);
+        let resp = Response::error(req.id, jsonrpc::ERROR_UNAUTHORIZED, "Unauthorized");
+        let _ = ws
+            .send(prepare(
+                tokio_tungstenite::tungstenite::Message::Text(resp.serialize()),
+                compression,
+                &status,
+            ))
+            .await;
+        let _ = ws.close(None).await;
+        return;
+    }
+    let peer = ws.get_ref().peer_addr().ok();
     info!(
-        "coordinator: worker registered (max_jobs={})",
+        "coordinator: worker {} registered (max_jobs={})",
+        params.name.as_deref().unwrap_or("(anonymous)"),
         params.max_jobs
     );
+    let capabilities = params.capabilities();
 
     // Split so the sink can be shared with the registry for dispatch.
     let (sink, mut stream) = ws.split();
-    let sink: SharedSink = Arc::new(tokio::sync::Mutex::new(Box::new(TungsteniteSink(sink))));
+    let sink: SharedSink = Arc::new(tokio::sync::Mutex::new(Box::new(TungsteniteSink {
+        sink,
+        compression,
+        status: status.clone(),
+    })));
     let worker_id = Uuid::new_v4().to_string();
     workers
-        .register_worker(worker_id.clone(), params.max_jobs, sink.clone())
+        .register_worker(worker_id.clone(), capabilities, sink.clone())
         .await;
+    if let Some(peer) = peer {
+        workers.set_address(&worker_id, peer.to_string()).await;
+    }
 
     // No handshake ack is sent: the `ws-worker` client does not wait for
     // one (it starts processing the inbound stream immediately and only
     // acts on `render` requests). Sending a JSON-RPC response here would
     // just surface as a warn-level parse failure on the worker.
 
-    // Drain render responses from the worker until it disconnects.
-    while let Some(msg) = stream.next().await {
-        match msg {
+    // Drain render responses from the worker until it disconnects, goes
+    // quiet for too long while pinged in between, or has drained.
+    let drained = workers
+        .drained(&worker_id)
+        .await
+        .expect("registered just above");
+    let heartbeat = workers.heartbeat();
+    let mut pings = tokio::time::interval(heartbeat.interval);
+    pings.tick().await;
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = pings.tick() => {
+                if workers.is_silent(&worker_id).await {
+                    warn!(
+                        "coordinator: worker {} silent for over {:?}, evicting it",
+                        worker_id, heartbeat.silence
+                    );
+                    StatusHandle::bump(&status.snapshot().workers_evicted);
+                    break;
+                }
+                // A half-open socket may never take the ping; don't let
+                // that hold up the next check.
+                let mut sink = sink.lock().await;
+                let _ = tokio::time::timeout(heartbeat.interval, sink.send_ping(vec![])).await;
+                continue;
+            }
+            _ = drained.notified() => {
+                info!("coordinator: worker {} drained, disconnecting", worker_id);
+                let mut sink = sink.lock().await;
+                let _ = tokio::time::timeout(heartbeat.interval, sink.send_close()).await;
+                break;
+            }
+        };
+        workers.saw_worker(&worker_id).await;
+        match receive(msg, compression, &status) {
             Ok(tokio_tungstenite::tungstenite::Message::Text(t)) => {
                 // Workers send JSON-RPC responses (result/error) keyed
-                // by the request id. Parse and deliver to the registry.
+                // by the dispatch id the registry sent the render under.
+                // Parse and deliver to the registry.
                 match serde_json::from_str::<serde_json::Value>(&t) {
+                    Ok(v) if v.get("method").and_then(|m| m.as_str()) == Some(method::DRAIN) => {
+                        workers.drain_worker(&worker_id).await;
+                    }
                     Ok(v) => {
                         if let Some(id) = v.get("id").and_then(|i| i.as_str()) {
                             let result = match v.get("result") {
                                 Some(r) => serde_json::from_value::<RenderResponse>(r.clone())
-                                    .unwrap_or_else(|_| RenderResponse {
-                                        files: vec![],
-                                        logs: "Could not parse worker response".to_owned(),
-                                        midi: String::new(),
+                                    .map_err(|_| {
+                                        RenderResponse::failure("Could not parse worker response")
                                     }),
                                 None => {
                                     let message = v
@@ -318,14 +737,13 @@ async fn handle_worker(
                                         .and_then(|m| m.as_str())
                                         .unwrap_or("worker error")
                                         .to_owned();
-                                    RenderResponse {
-                                        files: vec![],
-                                        logs: message,
-                                        midi: String::new(),
-                                    }
+                                    Err(RenderResponse::failure(message))
                                 }
                             };
-                            workers.handle_response(id, result).await;
+                            match result {
+                                Ok(result) => workers.handle_response(&worker_id, id, result).await,
+                                Err(error) => workers.handle_error(&worker_id, id, error).await,
+                            }
                         } else {
                             warn!("coordinator: worker message with no id: {}", t);
                         }
@@ -335,6 +753,20 @@ async fn handle_worker(
                     }
                 }
             }
+            Ok(tokio_tungstenite::tungstenite::Message::Binary(b)) => {
+                // A successful render result from a worker that
+                // negotiated `wire::BINARY_SUBPROTOCOL`. Errors always
+                // come as text, above.
+                match wire::decode_render_result(&b) {
+                    Ok((id, result)) => match id.as_str() {
+                        Some(id) => workers.handle_response(&worker_id, id, result).await,
+                        None => warn!("coordinator: binary worker message with no id"),
+                    },
+                    Err(e) => {
+                        warn!("coordinator: could not decode worker message: {}", e);
+                    }
+                }
+            }
             Ok(tokio_tungstenite::tungstenite::Message::Ping(p)) => {
                 let _ = {
                     let mut g = sink.lock().await;
@@ -357,16 +789,26 @@ async fn handle_worker(
 /// already read by `handle_connection` to determine the role), then
 /// continue reading subsequent messages from the same socket.
 async fn handle_frontend_first(
-    ws: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    ws: tokio_tungstenite::WebSocketStream<ServerStream>,
     first_req: Request,
+    transport: Transport,
     conn: ConnState,
     github: Arc<dyn GitHub>,
     cfg: CoordinatorConfig,
     req_tx: tokio::sync::mpsc::Sender<Result<(RenderRequest, ResponseCallback), HacklilyError>>,
 ) {
+    let conn = ConnState {
+        submitted: Arc::default(),
+        ..conn
+    };
     // Split the stream so we can clone the sink for response callbacks.
     let (sink, stream) = ws.split();
-    let sink: SharedSink = Arc::new(tokio::sync::Mutex::new(Box::new(TungsteniteSink(sink))));
+    let sink: SharedSink = Arc::new(tokio::sync::Mutex::new(Box::new(TungsteniteSink {
+        sink,
+        compression: transport.compression,
+        status: conn.status.clone(),
+    })));
+    let encoding = transport.encoding;
 
     // Track this frontend connection in the live active-user count.
     // Workers don't go through this path, so only frontend clients
@@ -378,6 +820,7 @@ async fn handle_frontend_first(
     if let Err(e) = dispatch_frontend_message(
         first_req,
         sink.clone(),
+        encoding,
         &conn,
         github.clone(),
         &cfg,
@@ -392,7 +835,7 @@ async fn handle_frontend_first(
 
     let mut stream = stream;
     while let Some(msg) = stream.next().await {
-        match msg {
+        match receive(msg, transport.compression, &conn.status) {
             Ok(tokio_tungstenite::tungstenite::Message::Text(t)) => {
                 let req = match t.parse::<Request>() {
                     Ok(r) => r,
@@ -405,6 +848,7 @@ async fn handle_frontend_first(
                 if let Err(e) = dispatch_frontend_message(
                     req,
                     sink.clone(),
+                    encoding,
                     &conn,
                     github.clone(),
                     &cfg,
@@ -437,10 +881,12 @@ async fn handle_frontend_first(
 
 /// Dispatch one frontend JSON-RPC message. Non-render methods are
 /// handled inline; `render` is forwarded to the render-request
-/// channel as a `(RenderRequest, ResponseCallback)` pair.
+/// channel as a `(RenderRequest, ResponseCallback)` pair, whose
+/// callback replies in the connection's negotiated `encoding`.
 async fn dispatch_frontend_message(
     req: Request,
     sink: SharedSink,
+    encoding: Encoding,
     conn: &ConnState,
     github: Arc<dyn GitHub>,
     cfg: &CoordinatorConfig,
@@ -457,41 +903,22 @@ async fn dispatch_frontend_message(
             let _ = send_text(sink, resp.serialize()).await;
         }
         method::RENDER => {
-            let params: RenderParams = match serde_json::from_value(req.params.clone()) {
-                Ok(p) => p,
-                Err(e) => {
-                    let resp =
-                        Response::error(req.id, jsonrpc::STDERR_INVALID_PARAMS, &e.to_string());
+            let request = match parse_render(&req, req.id.to_string()) {
+                Ok(request) => request,
+                Err(resp) => {
                     let _ = send_text(sink, resp.serialize()).await;
                     return Ok(());
                 }
             };
-            if params.src.is_empty() {
-                let resp = Response::error(
-                    req.id,
-                    jsonrpc::STDERR_INVALID_PARAMS,
-                    "src must not be empty",
-                );
-                let _ = send_text(sink, resp.serialize()).await;
-                return Ok(());
-            }
             ConnState::bump(&conn.status.snapshot().analytics_renders);
             let rpc_id = req.id;
-            let id = rpc_id.to_string();
-            let request = RenderRequest {
-                id,
-                backend: params.backend,
-                src: params.src,
-                version: params.version,
-            };
+            let backend = request.backend;
             let sink_cb = sink.clone();
             let cb: ResponseCallback = Box::new(move |response: RenderResponse| {
                 let sink = sink_cb.clone();
                 let rpc_id = rpc_id.clone();
                 tokio::spawn(async move {
-                    let result = serde_json::to_value(&response).unwrap_or_else(|_| json!({}));
-                    let resp = Response::success(rpc_id, result);
-                    let _ = send_text(sink, resp.serialize()).await;
+                    let _ = send_render_result(sink, encoding, rpc_id, &response, backend).await;
                 });
             });
             if req_tx.send(Ok((request, cb))).await.is_err() {
@@ -500,6 +927,84 @@ async fn dispatch_frontend_message(
                 let _ = send_text(sink, resp.serialize()).await;
             }
         }
+        method::SUBMIT_RENDER => {
+            if conn.submitted.load(Ordering::Relaxed) >= job_log::MAX_OUTSTANDING_PER_CONNECTION {
+                let resp = Response::error(
+                    req.id,
+                    jsonrpc::ERROR_TOO_MANY_JOBS,
+                    "too many unfinished jobs on this connection; wait for one to finish",
+                );
+                let _ = send_text(sink, resp.serialize()).await;
+                return Ok(());
+            }
+            // The job id doubles as the render's request id, which is
+            // how `State` tells the job log it started.
+            let job = Uuid::new_v4().to_string();
+            let request = match parse_render(&req, job.clone()) {
+                Ok(request) => request,
+                Err(resp) => {
+                    let _ = send_text(sink, resp.serialize()).await;
+                    return Ok(());
+                }
+            };
+            ConnState::bump(&conn.status.snapshot().analytics_renders);
+            cfg.jobs.queued(&request);
+            conn.submitted.fetch_add(1, Ordering::Relaxed);
+            let jobs = cfg.jobs.clone();
+            let job_cb = job.clone();
+            let submitted = conn.submitted.clone();
+            let cb: ResponseCallback = Box::new(move |response| {
+                submitted.fetch_sub(1, Ordering::Relaxed);
+                jobs.done(&job_cb, &response)
+            });
+            let resp = if req_tx.send(Ok((request, cb))).await.is_err() {
+                conn.submitted.fetch_sub(1, Ordering::Relaxed);
+                cfg.jobs
+                    .done(&job, &RenderResponse::failure("render queue closed"));
+                Response::error(req.id, jsonrpc::ERROR_INTERNAL, "render queue closed")
+            } else {
+                Response::success(req.id, json!({ "job": job }))
+            };
+            let _ = send_text(sink, resp.serialize()).await;
+        }
+        method::GET_JOB => {
+            let resp = match find_job(&req, &cfg.jobs) {
+                Ok(job) => Response::success(
+                    req.id,
+                    json!({
+                        "job": job.id,
+                        "state": job.state,
+                        "position": job.position,
+                        "queued_unix_msec": job.queued_unix_msec,
+                        "started_unix_msec": job.started_unix_msec,
+                        "done_unix_msec": job.done_unix_msec,
+                    }),
+                ),
+                Err(resp) => *resp,
+            };
+            let _ = send_text(sink, resp.serialize()).await;
+        }
+        method::FETCH_RESULT => match find_job(&req, &cfg.jobs) {
+            Ok(Job {
+                response: Some(response),
+                request,
+                ..
+            }) => {
+                let _ =
+                    send_render_result(sink, encoding, req.id, &response, request.backend).await;
+            }
+            Ok(_) => {
+                let resp = Response::error(
+                    req.id,
+                    jsonrpc::ERROR_JOB_NOT_DONE,
+                    "job has not finished; poll it with getJob",
+                );
+                let _ = send_text(sink, resp.serialize()).await;
+            }
+            Err(resp) => {
+                let _ = send_text(sink, resp.serialize()).await;
+            }
+        },
         method::SIGN_IN => {
             let params = req.params.clone();
             let state = serde_json::from_value::<auth_sign_in_params::Params>(params);
@@ -557,38 +1062,53 @@ async fn dispatch_frontend_message(
                 }
             }
         }
+        method::DRAIN_WORKER => {
+            let params = &req.params;
+            let token = params.get("token").and_then(|v| v.as_str());
+            let resp = match &cfg.admin_token {
+                Some(admin) if admin.verify(token) => {
+                    match params.get("worker").and_then(|v| v.as_str()) {
+                        Some(worker) if cfg.workers.drain_worker(worker).await => {
+                            Response::success(req.id, json!("OK"))
+                        }
+                        Some(_) => Response::error(
+                            req.id,
+                            jsonrpc::STDERR_INVALID_PARAMS,
+                            "no such worker",
+                        ),
+                        None => Response::error(
+                            req.id,
+                            jsonrpc::STDERR_INVALID_PARAMS,
+                            "worker must be a worker id",
+                        ),
+                    }
+                }
+                _ => {
+                    warn!("coordinator: refused drain_worker without the admin token");
+                    Response::error(req.id, jsonrpc::ERROR_UNAUTHORIZED, "Unauthorized")
+                }
+            };
+            let _ = send_text(sink, resp.serialize()).await;
+        }
+        method::GET_STATUS_DETAIL => {
+            let token = req.params.get("token").and_then(|v| v.as_str());
+            let resp = match &cfg.admin_token {
+                Some(admin) if admin.verify(token) => Response::success(
+                    req.id,
+                    json!({
+                        "local_containers": conn.status.snapshot().local_containers_detail(),
+                        "remote_workers": cfg.workers.detail().await,
+                    }),
+                ),
+                _ => {
+                    warn!("coordinator: refused get_status_detail without the admin token");
+                    Response::error(req.id, jsonrpc::ERROR_UNAUTHORIZED, "Unauthorized")
+                }
+            };
+            let _ = send_text(sink, resp.serialize()).await;
+        }
         method::GET_STATUS => {
-            let snap = conn.status.snapshot();
-            let local_total = snap.local_total.load(Ordering::Relaxed);
-            let local_busy = snap.local_busy.load(Ordering::Relaxed);
-            let local_free = snap.local_free.load(Ordering::Relaxed);
-            let remote_total = snap.remote_total.load(Ordering::Relaxed);
-            let remote_busy = snap.remote_busy.load(Ordering::Relaxed);
-            let remote_free = snap.remote_free.load(Ordering::Relaxed);
-            let backlog = snap.backlog.load(Ordering::Relaxed);
-            let active_users = snap.active_users.load(Ordering::Relaxed);
-            let renders = snap.analytics_renders.load(Ordering::Relaxed);
-            let saves = snap.analytics_saves.load(Ordering::Relaxed);
-            let sign_in = snap.analytics_sign_in.load(Ordering::Relaxed);
-            let total = local_total + remote_total;
-            let busy = local_busy + remote_busy;
-            let free = local_free + remote_free;
-            let result = json!({
-                "alive": total > 0,
-                "total_worker_count": total,
-                "local_worker_count": local_total,
-                "remote_worker_count": remote_total,
-                "busy_worker_count": busy,
-                "free_worker_count": free,
-                "backlog": backlog,
-                "startup_time": conn.status.startup_time(),
-                "uptime_secs": conn.status.uptime_secs(),
-                "current_active_users": active_users,
-                "analytics_renders": renders,
-                "analytics_saves": saves,
-                "analytics_sign_in": sign_in,
-            });
-            let resp = Response::success(req.id, result);
+            let resp = Response::success(req.id, conn.status.get_status());
             let _ = send_text(sink, resp.serialize()).await;
         }
         other => {
@@ -603,6 +1123,76 @@ async fn dispatch_frontend_message(
     Ok(())
 }
 
+/// Parse and check the params of `render` or `submitRender` into a
+/// render request with id `id`, or return the error to answer with.
+fn parse_render(req: &Request, id: String) -> Result<RenderRequest, Box<Response>> {
+    render_request(req.params.clone(), id).map_err(|reason| {
+        Box::new(Response::error(
+            req.id.clone(),
+            jsonrpc::STDERR_INVALID_PARAMS,
+            &reason,
+        ))
+    })
+}
+
+/// Parse and check render params (a `RenderParams` object) into a
+/// render request with id `id`. Shared with `POST /render`.
+pub(super) fn render_request(params: Value, id: String) -> Result<RenderRequest, String> {
+    let params: RenderParams = serde_json::from_value(params).map_err(|e| e.to_string())?;
+    if params.src.is_empty() {
+        return Err("src must not be empty".to_owned());
+    }
+    let request = RenderRequest {
+        id,
+        backend: params.backend,
+        src: params.src,
+        version: params.version,
+        options: params.options,
+        files: params.files,
+    };
+    request.validate()?;
+    Ok(request)
+}
+
+/// Look up the job named by `params.job`, or return the error to answer
+/// with.
+fn find_job(req: &Request, jobs: &JobLog) -> Result<Job, Box<Response>> {
+    match req.params.get("job").and_then(|v| v.as_str()) {
+        Some(id) => jobs.get(id).ok_or_else(|| {
+            Box::new(Response::error(
+                req.id.clone(),
+                jsonrpc::ERROR_NO_SUCH_JOB,
+                "no such job (or its result has expired)",
+            ))
+        }),
+        None => Err(Box::new(Response::error(
+            req.id.clone(),
+            jsonrpc::STDERR_INVALID_PARAMS,
+            "job must be a job id",
+        ))),
+    }
+}
+
+/// Answer request `rpc_id` with a render result, as a binary frame if
+/// the connection negotiated them.
+async fn send_render_result(
+    sink: SharedSink,
+    encoding: Encoding,
+    rpc_id: Value,
+    response: &RenderResponse,
+    backend: Backend,
+) -> Result<(), HacklilyError> {
+    if encoding == Encoding::Binary {
+        match wire::encode_render_result(&rpc_id, response, backend) {
+            Ok(frame) => return send_binary(sink, frame).await,
+            Err(e) => warn!("coordinator: sending render as JSON: {}", e),
+        }
+    }
+    let result = serde_json::to_value(response).unwrap_or_else(|_| json!({}));
+    let resp = Response::success(rpc_id, result);
+    send_text(sink, resp.serialize()).await
+}
+
 /// Send a text message on a shared sink. The `Mutex` serializes sends
 /// because `SinkExt::send` takes `&mut self`.
 async fn send_text(sink: SharedSink, text: String) -> Result<(), HacklilyError> {
@@ -613,6 +1203,15 @@ async fn send_text(sink: SharedSink, text: String) -> Result<(), HacklilyError>
         .map_err(|e| HacklilyError::CommandSourceError(format!("ws send failed: {}", e)))
 }
 
+/// Send a binary message on a shared sink. See `send_text`.
+async fn send_binary(sink: SharedSink, data: Vec<u8>) -> Result<(), HacklilyError> {
+    let mut guard = sink.lock().await;
+    guard
+        .send_binary(data)
+        .await
+        .map_err(|e| HacklilyError::CommandSourceError(format!("ws send failed: {}", e)))
+}
+
 // Helper module to deserialize signIn params without adding a public
 // struct. Kept private to this module.
 mod auth_sign_in_params {
@@ -652,6 +1251,25 @@ mod tests {
     async fn i_haz_computes_params_parses() {
         let p: IHazComputesParams = serde_json::from_str(r#"{"max_jobs":4}"#).expect("parse");
         assert_eq!(p.max_jobs, 4);
+        assert_eq!(p.capabilities(), WorkerCapabilities::any_version(4));
+    }
+
+    #[test]
+    fn i_haz_computes_capabilities_skip_unknown_entries() {
+        let p: IHazComputesParams = serde_json::from_str(
+            r#"{"max_jobs":3,"slots":{"stable":2,"unstable":1,"nightly":5},
+                "backends":["svg","musicxml2ly","midi2wav"]}"#,
+        )
+        .expect("parse");
+        let caps = p.capabilities();
+        assert_eq!(
+            caps.slots,
+            HashMap::from([(Some(Version::Stable), 2), (Some(Version::Unstable), 1)])
+        );
+        assert_eq!(
+            caps.backends,
+            Some(vec![Backend::Svg, Backend::MusicXml2Ly])
+        );
     }
 
     #[tokio::test]
@@ -671,6 +1289,48 @@ mod tests {
         assert_eq!(p.state, "csrf");
     }
 
+    #[test]
+    fn role_depends_on_listener_and_path() {
+        use super::{role_for, Ingress, Role};
+        // The default: one listener, any path, the first message decides.
+        assert_eq!(
+            role_for(Ingress::Main, "/rpc", false, None),
+            Some(Role::Either)
+        );
+        // A worker path on the shared listener.
+        let path = Some("/worker");
+        assert_eq!(
+            role_for(Ingress::Main, "/worker", false, path),
+            Some(Role::Worker)
+        );
+        assert_eq!(
+            role_for(Ingress::Main, "/worker/", false, path),
+            Some(Role::Worker)
+        );
+        assert_eq!(
+            role_for(Ingress::Main, "/rpc", false, path),
+            Some(Role::Frontend)
+        );
+        assert_eq!(
+            role_for(Ingress::Main, "/", false, path),
+            Some(Role::Frontend)
+        );
+        // A separate worker listener, with and without a path.
+        assert_eq!(
+            role_for(Ingress::Main, "/worker", true, path),
+            Some(Role::Frontend)
+        );
+        assert_eq!(
+            role_for(Ingress::Workers, "/", true, None),
+            Some(Role::Worker)
+        );
+        assert_eq!(
+            role_for(Ingress::Workers, "/worker", true, path),
+            Some(Role::Worker)
+        );
+        assert_eq!(role_for(Ingress::Workers, "/rpc", true, path), None);
+    }
+
     #[tokio::test]
     async fn coordinator_binds_and_quits_cleanly() {
         // Bind on an ephemeral port and immediately fire the quit sink.
@@ -684,6 +1344,14 @@ mod tests {
             github_secret: String::new(),
             workers: crate::worker_registry::WorkerRegistryHandle::new(),
             status: StatusHandle::new(),
+            ws_compression: false,
+            worker_keys: None,
+            worker_listener: None,
+            worker_path: None,
+            tls: None,
+            admin_token: None,
+            jobs: JobLog::default(),
+            http_listener: None,
         };
         let (stream, quit_sink) = coordinator(cfg).await.expect("coordinator starts");
 
@@ -709,4 +1377,3 @@ mod tests {
         listener.local_addr().expect("addr").port()
     }
 }
-
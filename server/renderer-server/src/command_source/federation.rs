@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2018-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// A ws-worker donating its containers to several coordinators at once.
+// Each link is offered a share of the containers in proportion to its
+// weight (`split_slots`), so between them the coordinators never send
+// more renders than the worker has containers for. Every link keeps its
+// own connection and reconnects on its own, with its own copy of the
+// worker's backoff: losing one coordinator leaves the others alone,
+// which is why the event loop only ever sees one command source that
+// never dies. Renders from all links share the one local pool, and each
+// render's callback answers through the `Outbox` of the link it came
+// from.
+use futures::stream::StreamExt;
+use log::{info, warn};
+use std::collections::HashMap;
+use tokio::sync::{mpsc, watch};
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+use url::Url;
+
+use crate::backoff::Backoff;
+use crate::command_source::ws_worker_client::{ws_worker_client, Outbox};
+use crate::command_source::{QuitSignal, QuitSink, RequestStream, ResponseCallback};
+use crate::config::CoordinatorLink;
+use crate::error::HacklilyError;
+use crate::request::{Request, Version};
+use crate::tls::TlsClientFiles;
+use crate::worker_auth::WorkerCredentials;
+
+/// Deals `slots` out to links with the given weights, one container at
+/// a time, each to the link furthest below its share (ties to the
+/// earlier link). Versions are dealt in the order given.
+pub fn split_slots(slots: &[(Version, u64)], weights: &[u32]) -> Vec<HashMap<Version, u64>> {
+    let mut shares = vec![HashMap::new(); weights.len()];
+    let mut dealt = vec![0u64; weights.len()];
+    for (version, n) in slots {
+        for _ in 0..*n {
+            // Highest weight / (dealt + 1), compared without dividing.
+            let Some(link) = (0..weights.len())
+                .filter(|&i| weights[i] > 0)
+                .reduce(|best, i| {
+                    let here = u64::from(weights[i]) * (dealt[best] + 1);
+                    let there = u64::from(weights[best]) * (dealt[i] + 1);
+                    if here > there {
+                        i
+                    } else {
+                        best
+                    }
+                })
+            else {
+                return shares;
+            };
+            dealt[link] += 1;
+            *shares[link].entry(*version).or_insert(0) += 1;
+        }
+    }
+    shares
+}
+
+/// Everything one link needs to (re)connect.
+struct Link {
+    coordinators: Vec<Url>,
+    slots: HashMap<Version, u64>,
+    outbox: Outbox,
+    binary_transport: bool,
+    ws_compression: bool,
+    credentials: Option<WorkerCredentials>,
+    tls: TlsClientFiles,
+    reconnect: Backoff,
+}
+
+impl Link {
+    /// Serves the link until `quitting`, reconnecting whenever it drops.
+    async fn run(
+        mut self,
+        requests: mpsc::Sender<(Request, ResponseCallback)>,
+        mut quitting: watch::Receiver<bool>,
+    ) {
+        let name = self.coordinators[0].to_string();
+        while !*quitting.borrow_and_update() {
+            match ws_worker_client(
+                self.coordinators.clone(),
+                self.slots.clone(),
+                self.binary_transport,
+                self.ws_compression,
+                self.credentials.clone(),
+                self.tls.clone(),
+                self.outbox.clone(),
+            )
+            .await
+            {
+                Ok((mut stream, quit_sink)) => {
+                    info!("Serving coordinator {} with {:?}", name, self.slots);
+                    self.reconnect.reset();
+                    let mut quit_sent = false;
+                    loop {
+                        tokio::select! {
+                            request = stream.next() => match request {
+                                Some(Ok(request)) => {
+                                    if requests.send(request).await.is_err() {
+                                        return;
+                                    }
+                                }
+                                Some(Err(err)) => {
+                                    warn!("Lost coordinator {}: {}", name, err);
+                                    break;
+                                }
+                                None => break,
+                            },
+                            changed = quitting.changed(), if !quit_sent => {
+                                // The stream ends once the link has
+                                // asked its coordinator to drain.
+                                quit_sent = true;
+                                if changed.is_ok() {
+                                    quit_sink.send(QuitSignal {}).await.unwrap_or(());
+                                }
+                            }
+                        }
+                    }
+                    if quit_sent {
+                        return;
+                    }
+                }
+                Err(err) => warn!("Could not reach coordinator {}: {}", name, err),
+            }
+
+            let delay = self.reconnect.next_delay();
+            info!("Reconnecting to {} in {:?}", name, delay);
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = quitting.changed() => {}
+            }
+        }
+    }
+}
+
+/// Serves every link in `links`, each with its share of `slots`.
+pub async fn federation(
+    links: Vec<(CoordinatorLink, HashMap<Version, u64>)>,
+    binary_transport: bool,
+    ws_compression: bool,
+    credentials: Option<WorkerCredentials>,
+    tls: TlsClientFiles,
+    reconnect: Backoff,
+) -> Result<(RequestStream, QuitSink), HacklilyError> {
+    let (request_sink, request_stream) = mpsc::channel(50);
+    let (quit_sink, mut quit_stream) = mpsc::channel::<QuitSignal>(50);
+    let (quitting_sink, quitting) = watch::channel(false);
+
+    let mut serving = 0;
+    for (link, slots) in links {
+        if slots.values().sum::<u64>() == 0 {
+            warn!(
+                "Not serving coordinator {}: its weight earns it no containers",
+                link.coordinators[0]
+            );
+            continue;
+        }
+        serving += 1;
+        tokio::spawn(
+            Link {
+                coordinators: link.coordinators,
+                slots,
+                outbox: link.outbox,
+                binary_transport,
+                ws_compression,
+                credentials: credentials.clone(),
+                tls: tls.clone(),
+                reconnect,
+            }
+            .run(request_sink.clone(), quitting.clone()),
+        );
+    }
+    if serving == 0 {
+        return Err(HacklilyError::CommandSourceError(
+            "No coordinator to serve".to_owned(),
+        ));
+    }
+
+    tokio::spawn(async move {
+        if quit_stream.recv().await.is_some() {
+            quitting_sink.send(true).unwrap_or(());
+        }
+        // Keep `quitting` open for as long as the links watch it.
+        quitting_sink.closed().await;
+    });
+
+    let request_stream: RequestStream = Box::new(ReceiverStream::new(request_stream).map(Ok));
+    Ok((request_stream, quit_sink))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Response;
+    use futures::SinkExt;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    type Coordinator = WebSocketStream<TcpStream>;
+
+    /// A coordinator that accepts one connection.
+    async fn fake_coordinator() -> (Url, tokio::task::JoinHandle<Coordinator>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let accepted = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+        (url.parse().unwrap(), accepted)
+    }
+
+    async fn next_text(ws: &mut Coordinator) -> Option<serde_json::Value> {
+        let next = async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Text(text) = msg {
+                    return serde_json::from_str(&text).ok();
+                }
+            }
+            None
+        };
+        tokio::time::timeout(Duration::from_millis(500), next)
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn each_link_gets_its_share_and_its_own_answers() {
+        let (url_a, a) = fake_coordinator().await;
+        let (url_b, b) = fake_coordinator().await;
+        let links = [url_a, url_b]
+            .into_iter()
+            .map(|url| CoordinatorLink {
+                coordinators: vec![url],
+                weight: 1,
+                outbox: Outbox::default(),
+            })
+            .zip(split_slots(&[(Version::Stable, 2)], &[1, 1]))
+            .collect();
+        let (mut requests, _quit) = federation(
+            links,
+            false,
+            false,
+            None,
+            TlsClientFiles::default(),
+            Backoff::default(),
+        )
+        .await
+        .unwrap();
+        let (mut a, mut b) = (a.await.unwrap(), b.await.unwrap());
+        for ws in [&mut a, &mut b] {
+            let hello = next_text(ws).await.expect("handshake");
+            assert_eq!(hello["params"]["slots"], serde_json::json!({ "stable": 1 }));
+        }
+
+        let render = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "from-b",
+            "method": "render",
+            "params": { "backend": "svg", "src": "c4", "version": "stable" },
+        });
+        b.send(Message::Text(render.to_string())).await.unwrap();
+        let (request, cb) = requests.next().await.unwrap().unwrap();
+        assert_eq!(request.id, "from-b");
+        cb(Response::ok("done"));
+
+        let answer = next_text(&mut b).await.expect("answered on b");
+        assert_eq!(answer["id"], serde_json::json!("from-b"));
+        assert!(next_text(&mut a).await.is_none());
+    }
+
+    #[test]
+    fn split_slots_follows_weights() {
+        let slots = [(Version::Stable, 3), (Version::Unstable, 1)];
+        let shares = split_slots(&slots, &[3, 1]);
+        assert_eq!(shares[0], HashMap::from([(Version::Stable, 3)]));
+        assert_eq!(shares[1], HashMap::from([(Version::Unstable, 1)]));
+
+        let shares = split_slots(&[(Version::Stable, 1), (Version::Unstable, 1)], &[1, 1]);
+        assert_eq!(shares[0], HashMap::from([(Version::Stable, 1)]));
+        assert_eq!(shares[1], HashMap::from([(Version::Unstable, 1)]));
+    }
+
+    #[test]
+    fn split_slots_never_oversubscribes() {
+        let slots = [(Version::Stable, 7), (Version::Unstable, 2)];
+        let shares = split_slots(&slots, &[5, 2, 0, 1]);
+        let total: u64 = shares.iter().flat_map(|s| s.values()).sum();
+        assert_eq!(total, 9);
+        assert!(shares[2].is_empty());
+        let per_link: Vec<u64> = shares.iter().map(|s| s.values().sum()).collect();
+        assert_eq!(per_link, [6, 2, 0, 1]);
+    }
+}
@@ -0,0 +1,491 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// The coordinator's HTTP endpoint, for monitoring and for scripts that
+// would rather not speak JSON-RPC over a WebSocket:
+//
+// - `GET /status` answers with the `get_status` result, so the nginx
+//   reverse proxy and health checks needn't open a WebSocket. Browsers
+//   on any origin may read it, for the status page.
+// - `GET /metrics` answers with `metrics::exposition`, for Prometheus.
+// - `POST /render` takes the params of the `render` RPC as a JSON body
+//   and answers when the render finishes: with the render result as
+//   JSON, or, depending on `Accept`, with the raw SVG (first page),
+//   PDF or MIDI.
+//
+// Renders go into the coordinator's request stream like the WebSocket
+// ones, so they share its backlog, its workers and its analytics. The
+// listener is started (and stopped) by `coordinator`, and serves HTTPS
+// when the coordinator terminates TLS. hyper takes care of HTTP/1.1
+// itself (keep-alive, pipelining, requests split across reads, `HEAD`
+// answered without a body); `Endpoint` is the routing table, so a new
+// endpoint is a variant plus an arm in `handle`.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ALLOW, CONTENT_TYPE,
+};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{debug, error, info};
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+use super::coordinator::render_request;
+use crate::backoff::Backoff;
+use crate::command_source::ResponseCallback;
+use crate::error::HacklilyError;
+use crate::metrics;
+use crate::request::{Backend, Request as RenderRequest, Response as RenderResponse};
+use crate::status::StatusHandle;
+use crate::tls::{self, ClientAuth, ServerStream, TlsAcceptorHandle};
+use crate::wire;
+
+/// Largest `POST /render` body we read. Sources and their `files` are
+/// capped well below this by `Request::validate`.
+pub const MAX_BODY_BYTES: usize = 16 << 20;
+
+/// How long a client gets to send its request headers.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again after `accept` fails, at
+/// first and at most.
+const ACCEPT_RETRY_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// Header carrying the page count when `POST /render` answers with the
+/// first page of an SVG render only.
+const PAGE_COUNT: &str = "x-page-count";
+
+/// What the HTTP handlers need from the coordinator.
+#[derive(Clone)]
+pub(super) struct HttpState {
+    pub status: StatusHandle,
+    pub tls: Option<TlsAcceptorHandle>,
+    pub req_tx: mpsc::Sender<Result<(RenderRequest, ResponseCallback), HacklilyError>>,
+}
+
+/// Serve HTTP on `listener` until the task is aborted, one task per
+/// connection.
+pub(super) async fn serve(listener: TcpListener, state: HttpState) {
+    if let Ok(addr) = listener.local_addr() {
+        let scheme = if state.tls.is_some() { "https" } else { "http" };
+        info!("HTTP endpoint listening on {}://{}", scheme, addr);
+    }
+    // Accept errors are usually out of file descriptors, which retrying
+    // at once won't fix.
+    let mut backoff = Backoff::new(ACCEPT_RETRY_MIN, ACCEPT_RETRY_MAX);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                error!("HTTP: accept error: {}; retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
+        backoff.reset();
+        debug!("HTTP: connection from {}", peer);
+        let state = state.clone();
+        tokio::spawn(async move {
+            let stream = match &state.tls {
+                Some(tls) => {
+                    let handshake = tls.accept(stream, ClientAuth::None);
+                    match tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            debug!("HTTP: TLS handshake failed: {}", e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("HTTP: TLS handshake from {} timed out", peer);
+                            return;
+                        }
+                    }
+                }
+                None => ServerStream::Plain(stream),
+            };
+            let service = service_fn(move |req| handle(req, state.clone()));
+            let served = Http::new()
+                .http1_header_read_timeout(HEADER_READ_TIMEOUT)
+                .serve_connection(stream, service)
+                .await;
+            if let Err(e) = served {
+                debug!("HTTP: connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// The paths we serve. Query strings are ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Endpoint {
+    Status,
+    Metrics,
+    Render,
+}
+
+impl Endpoint {
+    fn for_path(path: &str) -> Option<Endpoint> {
+        match path {
+            "/status" => Some(Endpoint::Status),
+            "/metrics" => Some(Endpoint::Metrics),
+            "/render" => Some(Endpoint::Render),
+            _ => None,
+        }
+    }
+
+    /// The method it serves. `GET` endpoints take `HEAD` too, and all
+    /// of them take `OPTIONS`.
+    fn method(self) -> Method {
+        match self {
+            Endpoint::Status | Endpoint::Metrics => Method::GET,
+            Endpoint::Render => Method::POST,
+        }
+    }
+
+    /// The `Allow` header for it.
+    fn allow(self) -> &'static str {
+        match self {
+            Endpoint::Status | Endpoint::Metrics => "GET, HEAD, OPTIONS",
+            Endpoint::Render => "POST, OPTIONS",
+        }
+    }
+
+    /// Whether pages on other origins may read it.
+    fn cross_origin(self) -> bool {
+        self == Endpoint::Status
+    }
+}
+
+async fn handle(req: Request<Body>, state: HttpState) -> Result<Response<Body>, Infallible> {
+    let endpoint = match Endpoint::for_path(req.uri().path()) {
+        Some(endpoint) => endpoint,
+        None => return Ok(text(StatusCode::NOT_FOUND, "not found".to_owned())),
+    };
+    let method = req.method().clone();
+    let mut resp = if method == endpoint.method()
+        || (method == Method::HEAD && endpoint.method() == Method::GET)
+    {
+        match endpoint {
+            Endpoint::Status => json(StatusCode::OK, &state.status.get_status()),
+            Endpoint::Metrics => Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")
+                .body(Body::from(metrics::exposition(&state.status)))
+                .expect("valid response"),
+            Endpoint::Render => render(req, &state).await,
+        }
+    } else if method == Method::OPTIONS {
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .expect("valid response")
+    } else {
+        text(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed".to_owned(),
+        )
+    };
+
+    let status = resp.status();
+    let headers = resp.headers_mut();
+    let allow = HeaderValue::from_static(endpoint.allow());
+    if status == StatusCode::NO_CONTENT || status == StatusCode::METHOD_NOT_ALLOWED {
+        headers.insert(ALLOW, allow.clone());
+    }
+    if endpoint.cross_origin() {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        if method == Method::OPTIONS {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, allow);
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static("accept"),
+            );
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
+        }
+    }
+    Ok(resp)
+}
+
+/// What `POST /render` answers with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// The render result, as `render` would return it.
+    Json,
+    Svg,
+    Pdf,
+    Midi,
+}
+
+impl Format {
+    /// Pick the first type in `Accept` that `backend` can produce, with
+    /// JSON for no `Accept` at all. Quality values are ignored; list
+    /// types in order of preference.
+    fn negotiate(headers: &HeaderMap, backend: Backend) -> Option<Format> {
+        let accept = match headers.get(ACCEPT).map(HeaderValue::to_str) {
+            None => return Some(Format::Json),
+            Some(Ok(accept)) => accept,
+            Some(Err(_)) => return None,
+        };
+        accept.split(',').find_map(|range| {
+            let media = range.split(';').next().unwrap_or("").trim();
+            match (media.to_ascii_lowercase().as_str(), backend) {
+                ("application/json" | "application/*" | "*/*", _) => Some(Format::Json),
+                ("image/svg+xml", Backend::Svg) => Some(Format::Svg),
+                ("application/pdf", Backend::Pdf) => Some(Format::Pdf),
+                ("audio/midi", Backend::Svg | Backend::Pdf) => Some(Format::Midi),
+                _ => None,
+            }
+        })
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Svg => "image/svg+xml",
+            Format::Pdf => "application/pdf",
+            Format::Midi => "audio/midi",
+        }
+    }
+}
+
+async fn render(req: Request<Body>, state: &HttpState) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let body = match read_body(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+    let params: Value = match serde_json::from_slice(&body) {
+        Ok(params) => params,
+        Err(e) => return text(StatusCode::BAD_REQUEST, format!("invalid JSON: {}", e)),
+    };
+    let request = match render_request(params, Uuid::new_v4().to_string()) {
+        Ok(request) => request,
+        Err(reason) => return text(StatusCode::BAD_REQUEST, reason),
+    };
+    let format = match Format::negotiate(&parts.headers, request.backend) {
+        Some(format) => format,
+        None => {
+            return text(
+                StatusCode::NOT_ACCEPTABLE,
+                format!(
+                    "the {:?} backend can answer with application/json{}",
+                    request.backend,
+                    match request.backend {
+                        Backend::Svg => ", image/svg+xml or audio/midi",
+                        Backend::Pdf => ", application/pdf or audio/midi",
+                        Backend::MusicXml2Ly => " only",
+                    }
+                ),
+            )
+        }
+    };
+
+    StatusHandle::bump(&state.status.snapshot().analytics_renders);
+    // The callback is `Fn`, but the answer only goes out once.
+    let (tx, rx) = oneshot::channel();
+    let tx = std::sync::Mutex::new(Some(tx));
+    let cb: ResponseCallback = Box::new(move |response| {
+        if let Some(tx) = tx.lock().expect("callback lock poisoned").take() {
+            let _ = tx.send(response);
+        }
+    });
+    if state.req_tx.send(Ok((request, cb))).await.is_err() {
+        return text(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "render queue closed".to_owned(),
+        );
+    }
+    match rx.await {
+        Ok(response) => answer(format, response),
+        Err(_) => text(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the coordinator dropped the render".to_owned(),
+        ),
+    }
+}
+
+/// Turn a finished render into the response `format` asked for. A
+/// raw format the render didn't produce (say, a PDF that failed to
+/// compile) is a 422 with the LilyPond logs as the body.
+fn answer(format: Format, response: RenderResponse) -> Response<Body> {
+    let payload = match format {
+        Format::Json => {
+            return json(
+                StatusCode::OK,
+                &serde_json::to_value(&response).unwrap_or_default(),
+            )
+        }
+        Format::Svg => response
+            .files
+            .first()
+            .map(|svg| Ok(svg.clone().into_bytes())),
+        Format::Pdf => response.files.first().map(|pdf| wire::decode_base64(pdf)),
+        Format::Midi => {
+            Some(wire::decode_base64(&response.midi)).filter(|_| !response.midi.is_empty())
+        }
+    };
+    match payload {
+        Some(Ok(bytes)) => {
+            let mut builder = Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, format.content_type());
+            if format == Format::Svg {
+                builder = builder.header(PAGE_COUNT, response.files.len());
+            }
+            builder.body(Body::from(bytes)).expect("valid response")
+        }
+        Some(Err(e)) => text(StatusCode::INTERNAL_SERVER_ERROR, e),
+        None => text(StatusCode::UNPROCESSABLE_ENTITY, response.logs),
+    }
+}
+
+/// Read a request body of at most `limit` bytes, or return the error
+/// to answer with.
+async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, Response<Body>> {
+    let too_large = || {
+        text(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("body must be at most {} bytes", limit),
+        )
+    };
+    if body.size_hint().lower() > limit as u64 {
+        return Err(too_large());
+    }
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| text(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn json(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
+
+fn text(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .expect("valid response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn negotiate_picks_first_type_the_backend_produces() {
+        let none = HeaderMap::new();
+        assert_eq!(Format::negotiate(&none, Backend::Pdf), Some(Format::Json));
+        assert_eq!(
+            Format::negotiate(&accept("image/svg+xml, */*;q=0.1"), Backend::Svg),
+            Some(Format::Svg)
+        );
+        assert_eq!(
+            Format::negotiate(&accept("image/svg+xml, */*;q=0.1"), Backend::Pdf),
+            Some(Format::Json)
+        );
+        assert_eq!(
+            Format::negotiate(&accept("Audio/MIDI"), Backend::Pdf),
+            Some(Format::Midi)
+        );
+        assert_eq!(
+            Format::negotiate(&accept("application/pdf"), Backend::Svg),
+            None
+        );
+    }
+
+    #[test]
+    fn answer_decodes_raw_output_and_reports_failures() {
+        let pdf = RenderResponse {
+            files: vec![base64::encode(b"%PDF-1.5")],
+            logs: String::new(),
+            midi: String::new(),
+            audio: String::new(),
+        };
+        let resp = answer(Format::Pdf, pdf);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/pdf");
+
+        let failed = RenderResponse::failure("error: syntax error");
+        assert_eq!(
+            answer(Format::Midi, failed).status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    /// Serve on an ephemeral port. Renders would go nowhere.
+    async fn start() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (req_tx, _req_rx) = mpsc::channel(1);
+        let state = HttpState {
+            status: StatusHandle::new(),
+            tls: None,
+            req_tx,
+        };
+        tokio::spawn(serve(listener, state));
+        addr
+    }
+
+    #[tokio::test]
+    async fn serves_split_pipelined_and_head_requests_on_one_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(start().await).await.unwrap();
+        stream.write_all(b"GET /status?from=page HT").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stream
+            .write_all(
+                b"TP/1.1\r\nHost: x\r\nOrigin: https://hacklily.org\r\n\r\n\
+                  HEAD /status HTTP/1.1\r\nHost: x\r\n\r\n\
+                  OPTIONS /status HTTP/1.1\r\nHost: x\r\n\r\n\
+                  DELETE /render HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /nope HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut answers = String::new();
+        stream.read_to_string(&mut answers).await.unwrap();
+
+        let statuses: Vec<&str> = answers
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|answer| &answer[..3])
+            .collect();
+        assert_eq!(statuses, ["200", "200", "204", "405", "404"], "{}", answers);
+        let answers: Vec<&str> = answers.split("HTTP/1.1 ").skip(1).collect();
+        assert!(answers[0].contains("access-control-allow-origin: *"));
+        assert!(answers[0].contains("\"alive\":false"));
+        // HEAD gets the headers only, so the next answer follows them.
+        assert!(answers[1].ends_with("\r\n\r\n"), "{}", answers[1]);
+        assert!(answers[2].contains("access-control-allow-methods: GET, HEAD, OPTIONS"));
+        assert!(answers[3].contains("allow: POST, OPTIONS"));
+        assert!(!answers[3].contains("access-control-allow-origin"));
+    }
+}
@@ -7,19 +7,23 @@ use tokio_stream::Stream;
 
 use crate::config::{CommandSourceConfig, Config};
 use crate::error::HacklilyError;
-use crate::request::{Request, Response};
+use crate::request::{Request, Response, Version};
 
 #[allow(unused_imports)]
 pub use self::coordinator::{coordinator, CoordinatorConfig, SendFut, SharedSink, WsSink};
 
 mod batch;
 mod coordinator;
+mod federation;
+mod http;
 mod test_runner;
 mod ws_worker_client;
 
 use self::batch::batch;
+use self::federation::{federation, split_slots};
 use self::test_runner::test_runner;
 use self::ws_worker_client::ws_worker_client;
+pub use self::ws_worker_client::Outbox;
 
 #[derive(Debug)]
 pub struct QuitSignal {}
@@ -36,9 +40,47 @@ pub type FutureCommandSource =
 
 pub fn new(config: &Config) -> FutureCommandSource {
     match &config.command_source {
-        CommandSourceConfig::Worker { coordinator } => {
-            let worker_count = config.stable_worker_count + config.unstable_worker_count;
-            Box::pin(ws_worker_client(coordinator.clone(), worker_count))
+        CommandSourceConfig::Worker {
+            links,
+            reconnect,
+            binary_transport,
+            ws_compression,
+            credentials,
+            tls,
+        } => {
+            let slots: Vec<(Version, u64)> = [
+                (Version::Stable, config.stable_worker_count),
+                (Version::Unstable, config.unstable_worker_count),
+            ]
+            .into_iter()
+            .filter(|(_, n)| *n > 0)
+            .collect();
+            match &links[..] {
+                [link] => Box::pin(ws_worker_client(
+                    link.coordinators.clone(),
+                    slots.into_iter().collect(),
+                    *binary_transport,
+                    *ws_compression,
+                    credentials.clone(),
+                    tls.clone(),
+                    link.outbox.clone(),
+                )),
+                _ => {
+                    let weights: Vec<u32> = links.iter().map(|link| link.weight).collect();
+                    Box::pin(federation(
+                        links
+                            .iter()
+                            .cloned()
+                            .zip(split_slots(&slots, &weights))
+                            .collect(),
+                        *binary_transport,
+                        *ws_compression,
+                        credentials.clone(),
+                        tls.clone(),
+                        *reconnect,
+                    ))
+                }
+            }
         }
         CommandSourceConfig::Batch { path } => Box::pin(batch(path.clone())),
         CommandSourceConfig::TestRunner { input, output } => {
@@ -55,6 +97,14 @@ pub fn new(config: &Config) -> FutureCommandSource {
                     github_secret,
                     workers,
                     status,
+                    ws_compression,
+                    worker_keys,
+                    worker_listener,
+                    worker_path,
+                    tls,
+                    admin_token,
+                    cluster: _,
+                    http_listener,
                 } => Box::pin(coordinator(CoordinatorConfig {
                     bind_address: *bind_address,
                     ws_port: *ws_port,
@@ -62,6 +112,14 @@ pub fn new(config: &Config) -> FutureCommandSource {
                     github_secret: github_secret.clone(),
                     workers: workers.clone(),
                     status: status.clone(),
+                    ws_compression: *ws_compression,
+                    worker_keys: worker_keys.clone(),
+                    worker_listener: *worker_listener,
+                    worker_path: worker_path.clone(),
+                    tls: tls.clone(),
+                    admin_token: admin_token.clone(),
+                    jobs: config.job_log.clone(),
+                    http_listener: *http_listener,
                 })),
                 _ => unreachable!(),
             }
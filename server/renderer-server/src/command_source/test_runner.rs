@@ -32,7 +32,7 @@ pub async fn test_runner(
     let parent_quit_sink = quit_sink.clone();
     let input_len = input.len();
 
-    let request_stream = stream::iter(input.into_iter())
+    let request_stream = stream::iter(input)
         .filter_map(move |request| -> Option<(Request, ResponseCallback)> {
             let output = output.clone();
             let id = request.id.clone();
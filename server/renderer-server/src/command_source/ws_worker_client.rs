@@ -5,23 +5,38 @@ use futures::sink::SinkExt;
 use futures::stream::{self, StreamExt, TryStreamExt};
 use log::{debug, error, info, trace, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 use tokio_stream::wrappers::{IntervalStream, ReceiverStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::Connector;
 use url::Url;
 use uuid::Uuid;
 
 use crate::command_source::{QuitSignal, QuitSink, RequestStream, ResponseCallback};
 use crate::error::HacklilyError;
 use crate::jsonrpc;
-use crate::request::{Backend, Request, Response, Version};
+use crate::request::{Backend, RenderOptions, Request, Response, Version};
+use crate::tls::TlsClientFiles;
+use crate::wire::{self, Encoding, Transport};
+use crate::worker_auth::WorkerCredentials;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct IHazComputesParams {
+    /// Total of `slots`, for coordinators that predate per-version slots.
     max_jobs: u64,
+    slots: HashMap<Version, u64>,
+    backends: Vec<Backend>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,6 +44,10 @@ struct RenderRequest {
     backend: Backend,
     src: String,
     version: Version,
+    #[serde(default)]
+    options: RenderOptions,
+    #[serde(default)]
+    files: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,6 +64,98 @@ struct RenderResponse {
     result: Response,
 }
 
+/// Where a ws-worker sends the answers to its renders. It outlives any
+/// one connection: answers to renders that finish while the coordinator
+/// is unreachable are held, and sent once `attach` hands it the next
+/// connection, under the id each render arrived with. The coordinator
+/// accepts them from the new connection as long as that id is the one
+/// it dispatched the render under and the render's deadline hasn't
+/// passed.
+#[derive(Clone, Default)]
+pub struct Outbox {
+    inner: Arc<Mutex<OutboxState>>,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    connection: Option<(mpsc::Sender<Message>, Encoding)>,
+    held: Vec<(String, Response, Backend)>,
+}
+
+impl Outbox {
+    /// Sends through `sink` from now on, starting with anything held.
+    fn attach(&self, sink: mpsc::Sender<Message>, encoding: Encoding) {
+        let held = {
+            let mut state = self.inner.lock().expect("outbox lock poisoned");
+            state.connection = Some((sink, encoding));
+            std::mem::take(&mut state.held)
+        };
+        if held.is_empty() {
+            return;
+        }
+        info!(
+            "Delivering {} responses held while disconnected",
+            held.len()
+        );
+        let outbox = self.clone();
+        tokio::spawn(async move {
+            for (id, response, backend) in held {
+                outbox.deliver(id, response, backend).await;
+            }
+        });
+    }
+
+    async fn deliver(&self, id: String, response: Response, backend: Backend) {
+        loop {
+            let connection = self
+                .inner
+                .lock()
+                .expect("outbox lock poisoned")
+                .connection
+                .clone();
+            if let Some((sink, encoding)) = connection {
+                if sink
+                    .send(encode_response(&id, &response, backend, encoding))
+                    .await
+                    .is_ok()
+                {
+                    return;
+                }
+            }
+
+            let mut state = self.inner.lock().expect("outbox lock poisoned");
+            // A new connection may have been attached while we tried
+            // the old one.
+            if let Some((sink, _)) = &state.connection {
+                if !sink.is_closed() {
+                    continue;
+                }
+            }
+            warn!("Coordinator unreachable, holding response {}", id);
+            state.connection = None;
+            state.held.push((id, response, backend));
+            return;
+        }
+    }
+}
+
+fn encode_response(id: &str, response: &Response, backend: Backend, encoding: Encoding) -> Message {
+    if encoding == Encoding::Binary {
+        match wire::encode_render_result(&serde_json::json!(id), response, backend) {
+            Ok(frame) => return Message::Binary(frame),
+            Err(err) => warn!("Sending response as JSON: {}", err),
+        }
+    }
+    let response = RenderResponse {
+        jsonrpc: "2.0".to_owned(),
+        id: id.to_owned(),
+        result: response.clone(),
+    };
+    let response = serde_json::to_string(&response).expect("Could not JSONify reply");
+    debug!("Response {:?}", response);
+    Message::Text(response)
+}
+
 enum Event {
     WsWorkerMethod(WsWorkerMethod),
     PingNeeded,
@@ -53,33 +164,77 @@ enum Event {
 
 /// Tries connecting to a coordinator, then returns a stream of events.
 ///
-/// This does not time out (use ws_worker_client_impl for the timeout)
+/// This does not time out (use ws_worker_client for the timeout)
 async fn ws_worker_client_impl(
     coordinator: Url,
-    max_jobs: u64,
+    slots: HashMap<Version, u64>,
+    binary_transport: bool,
+    ws_compression: bool,
+    credentials: Option<WorkerCredentials>,
+    tls: TlsClientFiles,
+    outbox: Outbox,
 ) -> Result<(RequestStream, QuitSink), HacklilyError> {
     let (quit_sink, quit_stream) = mpsc::channel::<QuitSignal>(50);
     let quit_stream = ReceiverStream::new(quit_stream).map(|x| Ok(Event::QuitSignal(x)));
 
     debug!("Connecting to coordinator {}", &coordinator);
 
-    match tokio_tungstenite::connect_async(coordinator).await {
-        Ok((duplex, _handshake_response)) => {
+    let mut handshake_request = coordinator.into_client_request().map_err(|err| {
+        HacklilyError::CommandSourceError("Invalid coordinator URL: ".to_owned() + &err.to_string())
+    })?;
+    if let Some(offer) = Transport::offer(binary_transport, ws_compression) {
+        handshake_request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(&offer).expect("subprotocol names are valid header values"),
+        );
+    }
+
+    // Without a private CA or client certificate, tungstenite's default
+    // (system roots) does.
+    let connector = if tls.is_empty() {
+        None
+    } else {
+        Some(Connector::Rustls(tls.client_config().map_err(|err| {
+            HacklilyError::CommandSourceError("Invalid TLS configuration: ".to_owned() + &err)
+        })?))
+    };
+
+    match tokio_tungstenite::connect_async_tls_with_config(handshake_request, None, connector).await
+    {
+        Ok((duplex, handshake_response)) => {
             let (mut sink, stream) = duplex.split();
-            debug!("Connected to server");
+            // Coordinators that predate binary frames and compression
+            // ignore the offer and don't echo a subprotocol; keep
+            // speaking plain JSON to them.
+            let transport = Transport::accepted(
+                handshake_response
+                    .headers()
+                    .get(SEC_WEBSOCKET_PROTOCOL)
+                    .and_then(|v| v.to_str().ok()),
+            );
+            let encoding = transport.encoding;
+            let compression = transport.compression;
+            debug!("Connected to server ({:?})", transport);
 
             // Send the handshake as a proper JSON-RPC 2.0 request so the
             // coordinator's `Request::from_str` (which requires
             // `jsonrpc == "2.0"`) accepts it. The id is a fresh Uuid;
             // the coordinator acks with a JSON-RPC success response,
             // which this worker silently drops (it only acts on
-            // `render` requests).
+            // `render` requests). Credentials, if any, are a token signed
+            // just now, never the secret itself.
             let handshake = jsonrpc::Request {
                 jsonrpc: jsonrpc::JSONRPC_VERSION.to_owned(),
                 id: serde_json::json!(Uuid::new_v4()),
                 method: jsonrpc::method::I_HAZ_COMPUTES.to_owned(),
-                params: serde_json::to_value(IHazComputesParams { max_jobs })
-                    .unwrap_or_else(|_| serde_json::json!({})),
+                params: serde_json::to_value(IHazComputesParams {
+                    max_jobs: slots.values().sum(),
+                    slots: slots.clone(),
+                    backends: Backend::ALL.to_vec(),
+                    name: credentials.as_ref().map(|c| c.name.clone()),
+                    token: credentials.as_ref().map(|c| c.token()),
+                })
+                .unwrap_or_else(|_| serde_json::json!({})),
             };
             let cmd = serde_json::to_string(&handshake).map_err(|err| {
                 HacklilyError::CommandSourceError(
@@ -87,7 +242,7 @@ async fn ws_worker_client_impl(
                 )
             })?;
 
-            let cmd = Message::Text(cmd);
+            let cmd = wire::seal(Message::Text(cmd), compression);
 
             sink.send(cmd).await.map_err(|err| {
                 HacklilyError::CommandSourceError(
@@ -95,11 +250,13 @@ async fn ws_worker_client_impl(
                 )
             })?;
 
-            // Create a cloneable sink that forwards to the sink.
+            // Create a cloneable sink that forwards to the sink, sealing
+            // each message in the negotiated compression envelope.
             let sink = {
                 let (multi_sink, ab_stream) = mpsc::channel::<Message>(50);
                 tokio::spawn(
                     ReceiverStream::new(ab_stream)
+                        .map(move |m| wire::seal(m, compression))
                         .map(Ok)
                         .forward(sink)
                         .map(|_| ()),
@@ -107,6 +264,7 @@ async fn ws_worker_client_impl(
 
                 multi_sink
             };
+            outbox.attach(sink.clone(), encoding);
 
             let parent_quit_sink = quit_sink.clone();
 
@@ -115,12 +273,16 @@ async fn ws_worker_client_impl(
                     .map(|_| Ok(Event::PingNeeded {}));
 
             let request_stream = stream
-                .try_filter_map(|req| {
-                    future::ok(match req {
-                        Message::Text(req) => serde_json::from_str(&req).unwrap_or_else(|e| {
+                .try_filter_map(move |req| {
+                    future::ok(match wire::open(req, compression) {
+                        Ok(Message::Text(req)) => serde_json::from_str(&req).unwrap_or_else(|e| {
                             warn!("Got invalid request: {}", e);
                             None
                         }),
+                        Err(e) => {
+                            warn!("Got invalid message: {}", e);
+                            None
+                        }
                         _ => None,
                     })
                 })
@@ -129,40 +291,45 @@ async fn ws_worker_client_impl(
                 })
                 .map_ok(Event::WsWorkerMethod);
 
+            // On the way out (SIGTERM), ask the coordinator to stop
+            // sending renders. The socket stays open until it hangs up,
+            // so the renders already here are still answered.
+            let drain_sink = sink.clone();
             let request_stream = stream::select(request_stream, quit_stream);
             let request_stream = stream::select(request_stream, ping_interval)
+                .inspect(move |ev| {
+                    if let Ok(Event::QuitSignal(_)) = ev {
+                        let drain = jsonrpc::Request {
+                            jsonrpc: jsonrpc::JSONRPC_VERSION.to_owned(),
+                            id: serde_json::Value::Null,
+                            method: jsonrpc::method::DRAIN.to_owned(),
+                            params: serde_json::json!({}),
+                        };
+                        let drain = serde_json::to_string(&drain).expect("drain is serializable");
+                        if let Err(err) = drain_sink.try_send(Message::Text(drain)) {
+                            warn!("Could not ask the coordinator to drain: {}", err);
+                        }
+                    }
+                })
                 .take_while(|ev| future::ready(!matches!(ev, Ok(Event::QuitSignal(_)))))
                 .try_filter_map(move |req| {
                     future::ok(match req {
                         Event::WsWorkerMethod(WsWorkerMethod::Render { id, params }) => {
-                            let sink = sink.clone();
+                            let outbox = outbox.clone();
                             info!("Received request {}", id);
                             let quit_sink = quit_sink.clone();
 
                             let id_copy = id.clone();
+                            let backend = params.backend;
                             let cb: ResponseCallback = Box::new(move |response: Response| {
                                 let id = id_copy.clone();
-                                let sink = sink.clone();
+                                let outbox = outbox.clone();
                                 let quit_sink = quit_sink.clone();
 
                                 tokio::spawn(async move {
                                     let f = async move {
                                         info!("Sending response {}", id);
-                                        let response = RenderResponse {
-                                            jsonrpc: "2.0".to_owned(),
-                                            id,
-                                            result: response,
-                                        };
-
-                                        let response = serde_json::to_string(&response)
-                                            .expect("Could not JSONify reply");
-
-                                        debug!("Response {:?}", response);
-
-                                        let response = Message::Text(response);
-                                        if let Err(err) = sink.send(response).await {
-                                            error!("Could not talk to coordinator: {}", err);
-                                        }
+                                        outbox.deliver(id, response, backend).await;
                                     };
 
                                     if AssertUnwindSafe(f).catch_unwind().await.is_err() {
@@ -183,6 +350,8 @@ async fn ws_worker_client_impl(
                                     backend: params.backend,
                                     src: params.src,
                                     version: params.version,
+                                    options: params.options,
+                                    files: params.files,
                                 },
                                 cb,
                             ))
@@ -215,17 +384,71 @@ async fn ws_worker_client_impl(
     }
 }
 
+/// Connects to the first of `coordinators` that answers, giving each
+/// 2.5s.
 pub async fn ws_worker_client(
-    coordinator: Url,
-    max_jobs: u64,
+    coordinators: Vec<Url>,
+    slots: HashMap<Version, u64>,
+    binary_transport: bool,
+    ws_compression: bool,
+    credentials: Option<WorkerCredentials>,
+    tls: TlsClientFiles,
+    outbox: Outbox,
 ) -> Result<(RequestStream, QuitSink), HacklilyError> {
-    let client = Box::pin(ws_worker_client_impl(coordinator, max_jobs));
-    let timeout = Box::pin(async { sleep(Duration::from_millis(2500)).await });
-
-    match future::select(client, timeout).await {
-        Either::Left((client, _)) => client,
-        Either::Right((_, _)) => Err(HacklilyError::CommandSourceError(
-            "Timeout: could not connect to coordinator".to_owned(),
-        )),
+    let mut last_err = HacklilyError::CommandSourceError("No coordinator to connect to".to_owned());
+    for coordinator in coordinators {
+        let client = Box::pin(ws_worker_client_impl(
+            coordinator.clone(),
+            slots.clone(),
+            binary_transport,
+            ws_compression,
+            credentials.clone(),
+            tls.clone(),
+            outbox.clone(),
+        ));
+        let timeout = Box::pin(async { sleep(Duration::from_millis(2500)).await });
+
+        last_err = match future::select(client, timeout).await {
+            Either::Left((Ok(client), _)) => return Ok(client),
+            Either::Left((Err(err), _)) => err,
+            Either::Right((_, _)) => HacklilyError::CommandSourceError(
+                "Timeout: could not connect to coordinator".to_owned(),
+            ),
+        };
+        warn!("{} ({})", last_err, coordinator);
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn outbox_holds_responses_until_reconnected() {
+        let outbox = Outbox::default();
+        let (first, first_rx) = mpsc::channel(4);
+        outbox.attach(first, Encoding::Json);
+        drop(first_rx);
+
+        outbox
+            .deliver("r1".to_owned(), Response::ok("one"), Backend::Svg)
+            .await;
+        outbox
+            .deliver("r2".to_owned(), Response::ok("two"), Backend::Svg)
+            .await;
+
+        let (second, mut second_rx) = mpsc::channel(4);
+        outbox.attach(second, Encoding::Json);
+        for (id, logs) in [("r1", "one"), ("r2", "two")] {
+            let sent = tokio::time::timeout(Duration::from_secs(1), second_rx.recv())
+                .await
+                .expect("held response sent")
+                .expect("channel open");
+            let sent: serde_json::Value =
+                serde_json::from_str(sent.to_text().expect("JSON encoding")).unwrap();
+            assert_eq!(sent["id"], serde_json::json!(id));
+            assert_eq!(sent["result"]["logs"], serde_json::json!(logs));
+        }
     }
 }
@@ -6,13 +6,46 @@ use std::sync::{Arc, Mutex};
 use url::Url;
 
 use super::request::{Request, Response};
+use crate::backoff::Backoff;
+use crate::cluster::Cluster;
+use crate::command_source::Outbox;
+use crate::job_log::JobLog;
 use crate::status::StatusHandle;
+use crate::tls::{TlsAcceptorHandle, TlsClientFiles};
+use crate::worker_auth::{AdminToken, WorkerCredentials, WorkerKeys};
 use crate::worker_registry::WorkerRegistryHandle;
 
+/// A coordinator served by a ws-worker. `coordinators` are URLs for it,
+/// tried in order on every (re)connection. `weight` sets its share of
+/// the worker's containers relative to the other links. `outbox` holds
+/// the answers to renders that finish while disconnected, for the next
+/// connection to deliver.
+#[derive(Clone)]
+pub struct CoordinatorLink {
+    pub coordinators: Vec<Url>,
+    pub weight: u32,
+    pub outbox: Outbox,
+}
+
 #[derive(Clone)]
 pub enum CommandSourceConfig {
+    /// Remote worker mode. With `binary_transport`, the worker offers
+    /// `wire::BINARY_SUBPROTOCOL` and, if the coordinator accepts,
+    /// returns render results as binary frames instead of base64 JSON.
+    /// `ws_compression` likewise offers the `wire` deflate envelope.
+    /// `credentials`, if set, sign the `i_haz_computes` handshake for
+    /// coordinators that require it (see `worker_auth`). `tls` adds a
+    /// private CA and client certificate for `wss://` coordinators.
+    /// `links` are the coordinators served; with more than one, the
+    /// worker's containers are split between them (see `federation`).
+    /// `reconnect` spaces out attempts to reconnect.
     Worker {
-        coordinator: Url,
+        links: Vec<CoordinatorLink>,
+        reconnect: Backoff,
+        binary_transport: bool,
+        ws_compression: bool,
+        credentials: Option<WorkerCredentials>,
+        tls: TlsClientFiles,
     },
 
     Batch {
@@ -33,6 +66,16 @@ pub enum CommandSourceConfig {
     /// that the listener is unencrypted. `workers` is the shared
     /// registry used to dispatch renders to remote workers. `status`
     /// is the shared live-state snapshot backing `get_status`.
+    /// `ws_compression` lets peers that ask for it use the `wire`
+    /// deflate envelope. With `worker_keys`, only workers on that
+    /// allow-list may register; without it any peer may.
+    /// `worker_listener` moves workers to their own address, leaving
+    /// `ws_port` to frontends, and `worker_path` requires workers to
+    /// connect on that URL path; with neither, any peer may be either.
+    /// `tls` makes the coordinator terminate TLS itself. `admin_token`
+    /// unlocks admin-only methods such as `drain_worker`. `cluster`
+    /// shares work with other coordinators. `http_listener` serves
+    /// `GET /status` and `POST /render` over HTTP.
     Coordinator {
         bind_address: std::net::IpAddr,
         ws_port: u16,
@@ -40,9 +83,28 @@ pub enum CommandSourceConfig {
         github_secret: String,
         workers: WorkerRegistryHandle,
         status: StatusHandle,
+        ws_compression: bool,
+        worker_keys: Option<Arc<WorkerKeys>>,
+        worker_listener: Option<std::net::SocketAddr>,
+        worker_path: Option<String>,
+        tls: Option<TlsAcceptorHandle>,
+        admin_token: Option<Arc<AdminToken>>,
+        cluster: Option<Cluster>,
+        http_listener: Option<std::net::SocketAddr>,
     },
 }
 
+impl CommandSourceConfig {
+    /// How long to wait before restarting this source after it dies
+    /// or fails to come up.
+    pub fn reconnect(&self) -> Backoff {
+        match self {
+            CommandSourceConfig::Worker { reconnect, .. } => *reconnect,
+            _ => Backoff::default(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub stable_docker_tag: String,
@@ -52,9 +114,15 @@ pub struct Config {
     pub unstable_worker_count: u64,
 
     pub render_timeout_msec: u64,
+    /// Largest synthesized audio file (`RenderOptions::audio`) returned
+    /// in a response, in bytes. Bigger files are dropped with a log line.
+    pub audio_max_bytes: u64,
     pub command_source: CommandSourceConfig,
     /// Shared live-state snapshot for `get_status`. Present in every
     /// mode but only written/read in coordinator mode; the other
     /// modes simply never touch it.
     pub status: StatusHandle,
+    /// Jobs submitted to the coordinator and their results; with a file,
+    /// unfinished ones are replayed after a restart (see `job_log`).
+    pub job_log: JobLog,
 }
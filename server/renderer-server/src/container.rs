@@ -48,6 +48,7 @@ impl ContainerHandle {
     pub async fn create(
         image: String,
         render_timeout_msec: u64,
+        audio_max_bytes: u64,
     ) -> Result<(ContainerHandle, Child), HacklilyError> {
         let max_tries: u8 = 2;
         for _ in 0..max_tries {
@@ -59,10 +60,13 @@ impl ContainerHandle {
             // kills the container. See render-impl.bash for the matching
             // read of HACKLILY_RENDER_TIMEOUT_MS.
             let render_timeout_env = format!("HACKLILY_RENDER_TIMEOUT_MS={}", render_timeout_msec);
+            // Likewise the cap on synthesized audio (options.audio), which
+            // render-impl.bash enforces before encoding the file.
+            let audio_max_bytes_env = format!("HACKLILY_AUDIO_MAX_BYTES={}", audio_max_bytes);
 
             let mut create = Command::new("docker");
             let create = create
-                .args(&[
+                .args([
                     "create",
                     "--rm",
                     "-i",
@@ -76,6 +80,8 @@ impl ContainerHandle {
                     "--cpus=0.8",
                     "-e",
                     &render_timeout_env,
+                    "-e",
+                    &audio_max_bytes_env,
                     &image,
                 ])
                 .stdin(Stdio::null())
@@ -148,7 +154,7 @@ impl ContainerHandle {
 
         let mut start = Command::new("docker");
         let start = start
-            .args(&["start", &self.id])
+            .args(["start", &self.id])
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped());
@@ -179,7 +185,7 @@ impl ContainerHandle {
 
         let mut child = Command::new("docker");
         let child = child
-            .args(&["attach", "--sig-proxy=false", &self.id])
+            .args(["attach", "--sig-proxy=false", &self.id])
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped());
@@ -221,7 +227,7 @@ impl Drop for ContainerHandle {
 async fn _close_container(container_id: String) -> Result<(), HacklilyError> {
     let mut rm = Command::new("docker");
     let rm = rm
-        .args(&["rm", "-f", &container_id])
+        .args(["rm", "-f", &container_id])
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
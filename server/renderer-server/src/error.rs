@@ -5,6 +5,8 @@ use std::fmt;
 pub enum HacklilyError {
     ContainerInitError(String),
     RenderError(String),
+    /// The container didn't answer within the render timeout.
+    RenderTimeout,
     RenderPanic,
     CommandSourceError(String),
 }
@@ -18,6 +20,10 @@ impl fmt::Display for HacklilyError {
                 reason
             ),
             HacklilyError::RenderError(reason) => write!(f, "Crashed during render: {}", reason),
+            HacklilyError::RenderTimeout => write!(
+                f,
+                "Crashed during render: Timeout: the container is unresponsive"
+            ),
             HacklilyError::RenderPanic => write!(f, "Render panic"),
             HacklilyError::CommandSourceError(reason) => {
                 write!(f, "Command source error: {}", reason)
@@ -33,6 +39,7 @@ impl Error for HacklilyError {
                 "Something went wrong while creating the render container."
             }
             HacklilyError::RenderError(_reason) => "Crashed during render",
+            HacklilyError::RenderTimeout => "Crashed during render",
             HacklilyError::RenderPanic => "Render panic",
             HacklilyError::CommandSourceError(_reason) => "Command source error",
         }
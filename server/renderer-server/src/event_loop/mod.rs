@@ -4,13 +4,13 @@ use futures::future::FutureExt;
 use futures::stream::{self, StreamExt};
 use log::{error, info};
 use std::panic::AssertUnwindSafe;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::Stream;
 
-use crate::command_source;
+use crate::command_source::{self, ResponseCallback};
 use crate::config::Config;
 use crate::renderer_manager::RendererManager;
 
@@ -21,6 +21,7 @@ fn init_and_attach_command_source(config: &Config) -> impl Stream<Item = Event>
     let (event_sender, event_receiver) = mpsc::channel::<Event>(50);
 
     let make_worker_client = command_source::new(config);
+    let status = config.status.clone();
 
     tokio::spawn(async move {
         let emergency_event_sender = event_sender.clone();
@@ -51,6 +52,17 @@ fn init_and_attach_command_source(config: &Config) -> impl Stream<Item = Event>
                         let event_sender = event_sender.clone();
                         match request {
                             Ok((request, response_cb)) => {
+                                // Counted in `history` here rather than in
+                                // `State`, which sees requeued renders again.
+                                let status = status.clone();
+                                let arrived = Instant::now();
+                                let response_cb: ResponseCallback = Box::new(move |response| {
+                                    status.snapshot().history.render_finished(
+                                        arrived.elapsed(),
+                                        !response.files.is_empty(),
+                                    );
+                                    response_cb(response);
+                                });
                                 event_sender
                                     .send(Event::QueueRequest(request, response_cb))
                                     .await
@@ -120,7 +132,7 @@ async fn spin_up_new_source(
 }
 
 pub async fn event_loop(config: Config) {
-    let manager = RendererManager::new();
+    let manager = RendererManager::new(config.status.clone());
     let (mut state, internal_events) = State::new(&config, manager.command_sender).await;
 
     // Treat SIGINT (Ctrl-C) and SIGTERM (the signal supervisors send
@@ -177,27 +189,31 @@ pub async fn event_loop(config: Config) {
                 info!("Queueing request");
                 state.handle_request(request, response_cb).await;
             }
+            Event::WorkersIdle => {
+                state.process_if_possible().await;
+            }
             Event::Manager(clean_event) => {
                 state.handle_manager_event(*clean_event).await;
             }
             Event::GracefullyQuit => {
                 state.gracefully_quit().await;
             }
+            Event::ClusterTick => {
+                state.handle_cluster_tick().await;
+            }
+            Event::ClusterJob(request, response_cb) => {
+                state.handle_cluster_job(request, response_cb).await;
+            }
             Event::CommandSourceReady(quit_sink) => {
                 info!("Command source ready");
                 state.handle_command_source_ready(quit_sink).await;
             }
             Event::CommandSourceDead => {
-                state.handle_command_source_dead();
+                let delay = state.handle_command_source_dead();
 
                 // Spin up a new one!
                 let source = init_and_attach_command_source(&config);
-                spin_up_new_source(
-                    command_source_sink.clone(),
-                    source,
-                    Duration::from_millis(2000),
-                )
-                .await;
+                spin_up_new_source(command_source_sink.clone(), source, delay).await;
             }
             Event::CommandSourceFailedToStart => {
                 if let Some(duration) = state.handle_command_source_failed_to_start().await {
@@ -4,26 +4,37 @@ use futures::future::FutureExt;
 use log::{debug, error, info, warn};
 use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::panic::AssertUnwindSafe;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
+use crate::backoff::Backoff;
+use crate::cluster::Cluster;
 use crate::command_source::{QuitSignal, QuitSink, ResponseCallback};
 use crate::config::{CommandSourceConfig, Config};
+use crate::job_log::JobLog;
+use crate::metrics::Location;
 use crate::renderer::{ReadyRenderContainer, RenderContainer, RendererMeta};
 use crate::renderer_manager::{Command, Event as RenderEvent};
 use crate::request::{Request, Response as RenderResponse, Version};
-use crate::status::StatusHandle;
-use crate::worker_registry::WorkerRegistryHandle;
+use crate::status::{ContainerState, DockerTags, StatusHandle};
+use crate::worker_registry::{RenderStats, WorkerRegistryHandle};
 
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub enum Event {
     QueueRequest(Request, ResponseCallback),
+    /// Remote worker slots went idle.
+    WorkersIdle,
     Manager(Box<RenderEvent>),
     CommandSourceReady(QuitSink),
     CommandSourceFailedToStart,
     CommandSourceDead,
     GracefullyQuit,
+    /// Time to trade work with the rest of the cluster.
+    ClusterTick,
+    /// A render claimed from another coordinator in the cluster.
+    ClusterJob(Request, ResponseCallback),
 }
 
 pub struct State {
@@ -31,18 +42,46 @@ pub struct State {
     ready_containers: HashMap<Version, BinaryHeap<ReadyRenderContainer>>,
     total_containers: i8,
     renderer_manager_command_sender: mpsc::Sender<Command>,
-    pending_requests: HashMap<Version, VecDeque<(Request, ResponseCallback)>>,
+    /// Renders waiting for a renderer, with when they were queued.
+    pending_requests: HashMap<Version, VecDeque<(Request, ResponseCallback, Instant)>>,
     command_source_quit_sink: Option<QuitSink>,
     command_source_was_created: bool,
+    /// Spaces out restarts of the command source.
+    reconnect: Backoff,
     internal_sink: mpsc::Sender<Event>,
     /// Remote worker registry. `None` in worker/batch/test-runner
     /// modes; `Some` in coordinator mode, where it's used to dispatch
     /// renders to remote `ws-worker` peers when the local pool has no
-    /// ready container for the requested version.
+    /// ready container for the requested version, or is slower.
     workers: Option<WorkerRegistryHandle>,
+    /// Latency and failures of local renders, recorded by the tasks
+    /// awaiting them, so dispatch can tell whether a remote worker would
+    /// be faster.
+    local_stats: Arc<Mutex<RenderStats>>,
     /// Shared live-state snapshot. Always present; the event loop
     /// publishes local-pool and backlog counters here for `get_status`.
     status: StatusHandle,
+    /// Other coordinators to share work with, in coordinator mode.
+    cluster: Option<Cluster>,
+    /// How long a render offered to the cluster may take.
+    offload_timeout: Duration,
+    /// Set while a claim from the cluster is in progress.
+    claiming: Arc<AtomicBool>,
+    /// Jobs submitted to the coordinator, which the event loop marks
+    /// as running and whose queue positions it publishes.
+    job_log: JobLog,
+}
+
+/// Where a render came from, which decides what `enqueue` may do with it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    /// A command source.
+    Client,
+    /// Offered by another coordinator, so never offered on again.
+    Cluster,
+    /// Recorded in the job log before a restart. Waits for a renderer
+    /// to attach rather than failing for want of one.
+    Replay,
 }
 
 impl State {
@@ -60,20 +99,78 @@ impl State {
             pending_requests: HashMap::new(),
             command_source_quit_sink: None,
             command_source_was_created: false,
+            reconnect: config.command_source.reconnect(),
             internal_sink,
             workers: match config.command_source {
                 CommandSourceConfig::Coordinator { ref workers, .. } => Some(workers.clone()),
                 _ => None,
             },
+            local_stats: Arc::new(Mutex::new(RenderStats::default())),
             status: config.status.clone(),
+            cluster: match config.command_source {
+                CommandSourceConfig::Coordinator { ref cluster, .. } => cluster.clone(),
+                _ => None,
+            },
+            offload_timeout: Duration::from_millis(config.render_timeout_msec + 5000),
+            claiming: Arc::new(AtomicBool::new(false)),
+            job_log: config.job_log.clone(),
         };
 
+        if let Some(cluster) = &state.cluster {
+            let cluster = cluster.clone();
+            let status = state.status.clone();
+            let internal_sink = state.internal_sink.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(cluster.tick()).await;
+                    cluster.heartbeat(&status).await;
+                    if internal_sink.send(Event::ClusterTick).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Remote renders that miss their deadline come back through the
+        // queue, like local ones whose container crashed.
+        if let Some(workers) = &state.workers {
+            let internal_sink = state.internal_sink.clone();
+            workers
+                .set_requeue(Arc::new(move |request, response_cb| {
+                    let internal_sink = internal_sink.clone();
+                    tokio::spawn(async move {
+                        internal_sink
+                            .send(Event::QueueRequest(request, response_cb))
+                            .await
+                            .map(|_| ())
+                            .unwrap_or(());
+                    });
+                }))
+                .await;
+            // Renders queued while every slot was busy go out as soon as
+            // one frees up.
+            let internal_sink = state.internal_sink.clone();
+            workers
+                .set_wake(Arc::new(move || {
+                    let internal_sink = internal_sink.clone();
+                    tokio::spawn(async move {
+                        internal_sink
+                            .send(Event::WorkersIdle)
+                            .await
+                            .map(|_| ())
+                            .unwrap_or(());
+                    });
+                }))
+                .await;
+        }
+
         for i in 0..config.stable_worker_count {
             state
                 .create_container(
                     Version::Stable,
                     config.stable_docker_tag.to_owned(),
                     config.render_timeout_msec,
+                    config.audio_max_bytes,
                     i as i8,
                 )
                 .await;
@@ -85,11 +182,36 @@ impl State {
                     Version::Unstable,
                     config.unstable_docker_tag.to_owned(),
                     config.render_timeout_msec,
+                    config.audio_max_bytes,
                     (config.stable_worker_count + i) as i8,
                 )
                 .await;
         }
 
+        // Jobs a previous run queued but never finished. Their clients
+        // collect the answers from the log.
+        let unfinished = state.job_log.unfinished();
+        if !unfinished.is_empty() {
+            info!("Replaying {} unfinished jobs", unfinished.len());
+        }
+        for job in unfinished {
+            let job_log = state.job_log.clone();
+            let response_cb: ResponseCallback =
+                Box::new(move |response| job_log.done(&job.id, &response));
+            state
+                .enqueue(job.request, response_cb, Origin::Replay)
+                .await;
+        }
+
+        *state
+            .status
+            .snapshot()
+            .docker_tags
+            .lock()
+            .expect("status lock poisoned") = DockerTags {
+            stable: config.stable_docker_tag.clone(),
+            unstable: config.unstable_docker_tag.clone(),
+        };
         state.republish_local_status();
         (state, internal_events)
     }
@@ -99,6 +221,7 @@ impl State {
         version: Version,
         image: String,
         render_timeout_msec: u64,
+        audio_max_bytes: u64,
         id: i8,
     ) {
         if self.stopping {
@@ -111,6 +234,7 @@ impl State {
             image,
             version,
             timeout: render_timeout_msec,
+            audio_max_bytes,
             num_renders: 0,
         };
 
@@ -164,35 +288,61 @@ impl State {
     }
 
     pub async fn handle_request(&mut self, request: Request, response_cb: ResponseCallback) {
+        self.enqueue(request, response_cb, Origin::Client).await;
+    }
+
+    /// Like `handle_request`, for a render another coordinator offered,
+    /// which is never offered on again.
+    pub async fn handle_cluster_job(&mut self, request: Request, response_cb: ResponseCallback) {
+        self.enqueue(request, response_cb, Origin::Cluster).await;
+    }
+
+    async fn enqueue(&mut self, request: Request, response_cb: ResponseCallback, origin: Origin) {
         if self.stopping {
             warn!("Not registering a new request, because we're shutting down.");
             return;
         }
 
         // Fail fast if no renderers are attached: no local containers
-        // (created or creating) and no remote workers. This matches the
+        // (created or creating) and no remote worker advertising slots
+        // for this version and its backend. This matches the
         // "fail if there's no render servers attached" requirement.
         let has_local = self.total_containers > 0;
         let has_remote = match &self.workers {
-            Some(w) => w.worker_count().await > 0,
+            Some(w) => w.can_render(&request).await,
             None => false,
         };
-        if !has_local && !has_remote {
+        if !has_local && !has_remote && origin != Origin::Replay {
+            if let (Some(cluster), Origin::Client) = (&self.cluster, origin) {
+                cluster.offload(request, response_cb, self.offload_timeout);
+                return;
+            }
             let request_id = request.id.clone();
-            (response_cb)(RenderResponse {
-                files: vec![],
-                logs: "No renderers attached: no local containers and no remote workers."
-                    .to_owned(),
-                midi: String::new(),
-            });
+            (response_cb)(RenderResponse::failure(
+                "No renderers attached: no local containers and no remote workers \
+                 that can render this version and backend.",
+            ));
             warn!("rejected render {}: no renderers attached", request_id);
             return;
         }
 
+        // Every command source funnels through here, so this is the one
+        // place that guarantees render options and auxiliary files are
+        // sane before they reach a container (the coordinator also checks
+        // them up front so it can answer with a JSON-RPC error).
+        if let Err(reason) = request.validate() {
+            warn!("rejected render {}: {}", request.id, reason);
+            (response_cb)(RenderResponse::failure(format!(
+                "Invalid render request: {}",
+                reason
+            )));
+            return;
+        }
+
         self.pending_requests
             .entry(request.version)
-            .or_insert_with(VecDeque::new)
-            .push_back((request, response_cb));
+            .or_default()
+            .push_back((request, response_cb, Instant::now()));
 
         self.process_if_possible().await;
         self.republish_local_status();
@@ -201,6 +351,9 @@ impl State {
     pub async fn handle_manager_event(&mut self, clean_event: RenderEvent) {
         match clean_event {
             RenderEvent::ContainerReady(container) => {
+                self.status
+                    .snapshot()
+                    .set_local_container(container.meta.to_status(ContainerState::Ready, None));
                 if self.stopping {
                     self.renderer_manager_command_sender
                         .clone()
@@ -210,7 +363,7 @@ impl State {
                 } else {
                     self.ready_containers
                         .entry(container.meta.version)
-                        .or_insert_with(BinaryHeap::new)
+                        .or_default()
                         .push(*container);
 
                     self.process_if_possible().await;
@@ -232,10 +385,7 @@ impl State {
 
     pub async fn process_if_possible(&mut self) {
         for (version, pending_requests) in self.pending_requests.iter_mut() {
-            let ready_containers = self
-                .ready_containers
-                .entry(*version)
-                .or_insert_with(BinaryHeap::new);
+            let ready_containers = self.ready_containers.entry(*version).or_default();
 
             debug!(
                 "Processing {:?}: pending: {} ready: {}",
@@ -248,12 +398,44 @@ impl State {
                 continue;
             }
 
-            // Prefer a local ready container if one is available.
+            // Prefer a local ready container if one is available,
+            // unless an idle remote worker has been rendering faster.
+            if let (Some(workers), false) = (&self.workers, ready_containers.is_empty()) {
+                let local_stats = self
+                    .local_stats
+                    .lock()
+                    .expect("stats lock poisoned")
+                    .clone();
+                let (request, _, _) = pending_requests.front().expect("emptiness checked above");
+                if workers.faster_than(&local_stats, request).await {
+                    let (request, response_cb, queued) =
+                        pending_requests.pop_front().expect("len checked above");
+                    let id = request.id.clone();
+                    match workers.try_dispatch(request, response_cb).await {
+                        Ok(()) => {
+                            self.job_log.started(&id);
+                            self.status.snapshot().metrics.dequeued(queued.elapsed());
+                            continue;
+                        }
+                        Err((request, response_cb)) => {
+                            pending_requests.push_front((request, response_cb, queued))
+                        }
+                    }
+                }
+            }
+
             if !ready_containers.is_empty() {
-                let (request, response_cb) =
+                let (request, response_cb, queued) =
                     pending_requests.pop_front().expect("len checked above");
                 let container = ready_containers.pop().expect("len checked above");
+                self.status.snapshot().set_local_container(
+                    container
+                        .meta
+                        .to_status(ContainerState::Busy, Some(&request.id)),
+                );
                 let timeout = Duration::from_millis(container.meta.timeout);
+                self.job_log.started(&request.id);
+                self.status.snapshot().metrics.dequeued(queued.elapsed());
 
                 let (render_container, result) = container.handle_request(request.clone(), timeout);
                 self.renderer_manager_command_sender
@@ -264,14 +446,30 @@ impl State {
 
                 let emergency_command_sender = self.renderer_manager_command_sender.clone();
                 let internal_sink = self.internal_sink.clone();
+                let local_stats = self.local_stats.clone();
+                let status = self.status.clone();
+                let started = Instant::now();
 
                 tokio::spawn(async move {
                     let f = async move {
-                        match result.await {
+                        let result = result.await;
+                        local_stats
+                            .lock()
+                            .expect("stats lock poisoned")
+                            .record(started.elapsed(), result.is_ok());
+                        let metrics = &status.snapshot().metrics;
+                        match result {
                             Ok(render_result) => {
+                                metrics.render_finished(
+                                    request.backend,
+                                    request.version,
+                                    Location::Local,
+                                    started.elapsed(),
+                                );
                                 response_cb(render_result);
                             }
                             Err(_) => {
+                                StatusHandle::bump(&metrics.dirty_crash_requeues);
                                 internal_sink
                                     .send(Event::QueueRequest(request, response_cb))
                                     .await
@@ -297,14 +495,19 @@ impl State {
             // Pop first, then attempt dispatch; if dispatch fails,
             // push back so the request isn't lost.
             if let Some(workers) = &self.workers {
-                if let Some((request, response_cb)) = pending_requests.pop_front() {
+                if let Some((request, response_cb, queued)) = pending_requests.pop_front() {
+                    let id = request.id.clone();
                     match workers.try_dispatch(request, response_cb).await {
-                        Ok(()) => {}
+                        Ok(()) => {
+                            self.job_log.started(&id);
+                            self.status.snapshot().metrics.dequeued(queued.elapsed());
+                        }
                         Err((request, response_cb)) => {
-                            // No idle worker or send failed; re-queue
-                            // and stop trying this version for now.
-                            pending_requests.push_front((request, response_cb));
-                            break;
+                            // No idle slot for this version or send
+                            // failed; re-queue and move on to the next
+                            // version, whose slots may still be free.
+                            pending_requests.push_front((request, response_cb, queued));
+                            continue;
                         }
                     }
                 }
@@ -320,11 +523,16 @@ impl State {
         } else {
             self.command_source_quit_sink = Some(sink);
             self.command_source_was_created = true;
+            self.reconnect.reset();
         }
     }
 
-    pub fn handle_command_source_dead(&mut self) {
+    /// Handle when a running command source died.
+    ///
+    /// Returns the duration we should delay before starting another.
+    pub fn handle_command_source_dead(&mut self) -> Duration {
         self.command_source_quit_sink = None;
+        self.reconnect.next_delay()
     }
 
     /// Handle when a command source failed to initialize.
@@ -333,13 +541,70 @@ impl State {
     /// to terminate.
     pub async fn handle_command_source_failed_to_start(&mut self) -> Option<Duration> {
         if self.command_source_was_created {
-            Some(Duration::from_millis(4000))
+            Some(self.reconnect.next_delay())
         } else {
             self.gracefully_quit().await;
             None
         }
     }
 
+    /// Offer renders this coordinator has no room for to the cluster,
+    /// if another coordinator does, and claim what others offered if
+    /// there's room here.
+    pub async fn handle_cluster_tick(&mut self) {
+        let Some(cluster) = self.cluster.clone() else {
+            return;
+        };
+        if self.stopping {
+            return;
+        }
+
+        let mut spare = cluster.spare() as usize;
+        let mut room: HashMap<Version, usize> = HashMap::new();
+        for version in [Version::Stable, Version::Unstable] {
+            let ready = self.ready_containers.get(&version).map_or(0, |c| c.len());
+            let idle = match &self.workers {
+                Some(workers) => workers.idle_slots_for(version).await,
+                None => 0,
+            };
+            let pending = self.pending_requests.entry(version).or_default();
+            if ready + idle == 0 {
+                // Newest first: the oldest are likeliest to get a
+                // renderer here soon.
+                while spare > 0 {
+                    let Some((request, response_cb, _)) = pending.pop_back() else {
+                        break;
+                    };
+                    cluster.offload(request, response_cb, self.offload_timeout);
+                    spare -= 1;
+                }
+            } else if ready + idle > pending.len() {
+                room.insert(version, ready + idle - pending.len());
+            }
+        }
+        self.republish_local_status();
+
+        if room.is_empty() || self.claiming.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let claiming = self.claiming.clone();
+        let internal_sink = self.internal_sink.clone();
+        tokio::spawn(async move {
+            let n = room.values().sum();
+            for (request, response_cb) in cluster
+                .claim(n, |request| room.contains_key(&request.version))
+                .await
+            {
+                internal_sink
+                    .send(Event::ClusterJob(request, response_cb))
+                    .await
+                    .map(|_| ())
+                    .unwrap_or(());
+            }
+            claiming.store(false, Ordering::Release);
+        });
+    }
+
     pub fn is_done(&self) -> bool {
         self.stopping && self.total_containers == 0
     }
@@ -358,5 +623,22 @@ impl State {
         snap.local_busy
             .store(total.saturating_sub(free as u64), Ordering::Relaxed);
         snap.backlog.store(backlog as u64, Ordering::Relaxed);
+        snap.history.backlog(backlog as u64);
+        self.job_log
+            .set_positions(self.pending_requests.values().flat_map(|queue| {
+                queue
+                    .iter()
+                    .enumerate()
+                    .map(|(ahead, (request, _, _))| (request.id.as_str(), ahead))
+            }));
+        let latency = self
+            .local_stats
+            .lock()
+            .expect("stats lock poisoned")
+            .latency_msec();
+        snap.local_latency_msec.store(
+            latency.map_or(0, |msec| msec.round() as u64),
+            Ordering::Relaxed,
+        );
     }
 }
@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// Rolling windows of recent activity, reported as `history` in
+// `get_status` (and so by `GET /status` too).
+//
+// The rest of `StatusSnapshot` is either a point-in-time number or a
+// total since `startup_time`, neither of which says whether the last
+// hour was worse than usual. `History` keeps the last day in ten-second
+// slots, each holding the renders clients asked for, how many of them
+// failed, a histogram of how long they took over `metrics::BUCKETS_SECS`
+// and the largest backlog seen, and sums the slots of the last minute,
+// hour and day when asked. A day of slots is 8640 fixed-size entries,
+// whatever the traffic. Percentiles come from the summed histogram, so
+// they are the upper bound of the bucket they fall in, capped at the
+// slowest render of the window. Nothing is persisted: a restart starts
+// the windows over, like every other counter.
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics::BUCKETS_SECS;
+
+/// Width of one slot.
+const SLOT: Duration = Duration::from_secs(10);
+
+/// The windows reported, by name, and how far back each reaches.
+const WINDOWS: [(&str, Duration); 3] = [
+    ("minute", Duration::from_secs(60)),
+    ("hour", Duration::from_secs(60 * 60)),
+    ("day", Duration::from_secs(24 * 60 * 60)),
+];
+
+/// Renders per latency bucket: one per bound of `BUCKETS_SECS`, and
+/// one for anything slower.
+type Latencies = [u64; BUCKETS_SECS.len() + 1];
+
+/// One ten-second slot.
+#[derive(Debug)]
+struct Slot {
+    /// Slots since `History::origin`.
+    index: u64,
+    renders: u64,
+    errors: u64,
+    latencies: Latencies,
+    max_latency_msec: u32,
+    peak_backlog: u64,
+}
+
+/// One window as listed in `get_status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct WindowStats {
+    pub renders: u64,
+    /// Renders answered without any output.
+    pub errors: u64,
+    /// Latency percentiles from request to answer, if anything finished.
+    pub p50_latency_msec: Option<u32>,
+    pub p95_latency_msec: Option<u32>,
+    pub peak_backlog: u64,
+}
+
+/// The last day of activity. Writers hold the lock for one slot update.
+pub struct History {
+    origin: Instant,
+    slots: Mutex<VecDeque<Slot>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            origin: Instant::now(),
+            slots: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl History {
+    /// Record a client's render, answered `elapsed` after it arrived.
+    pub fn render_finished(&self, elapsed: Duration, ok: bool) {
+        self.render_finished_at(Instant::now(), elapsed, ok);
+    }
+
+    /// Record the backlog, keeping the largest of each slot.
+    pub fn backlog(&self, backlog: u64) {
+        self.backlog_at(Instant::now(), backlog);
+    }
+
+    /// Every window, as `{"minute": {...}, "hour": {...}, "day": {...}}`.
+    pub fn to_json(&self) -> Value {
+        let windows = self.windows_at(Instant::now());
+        Value::Object(
+            windows
+                .into_iter()
+                .map(|(name, stats)| (name.to_owned(), json!(stats)))
+                .collect(),
+        )
+    }
+
+    fn render_finished_at(&self, now: Instant, elapsed: Duration, ok: bool) {
+        self.with_slot(now, |slot| {
+            slot.renders += 1;
+            if !ok {
+                slot.errors += 1;
+            }
+            let secs = elapsed.as_secs_f64();
+            let bucket = BUCKETS_SECS
+                .iter()
+                .position(|bound| secs <= *bound)
+                .unwrap_or(BUCKETS_SECS.len());
+            slot.latencies[bucket] += 1;
+            let msec = u32::try_from(elapsed.as_millis()).unwrap_or(u32::MAX);
+            slot.max_latency_msec = slot.max_latency_msec.max(msec);
+        });
+    }
+
+    fn backlog_at(&self, now: Instant, backlog: u64) {
+        self.with_slot(now, |slot| {
+            slot.peak_backlog = slot.peak_backlog.max(backlog);
+        });
+    }
+
+    fn windows_at(&self, now: Instant) -> Vec<(&'static str, WindowStats)> {
+        let current = self.index(now);
+        let slots = self.slots.lock().expect("history lock poisoned");
+        WINDOWS
+            .iter()
+            .map(|(name, span)| {
+                let count = slot_count(*span);
+                let recent = slots
+                    .iter()
+                    .filter(|slot| current.saturating_sub(slot.index) < count);
+                (*name, summarize(recent))
+            })
+            .collect()
+    }
+
+    /// Run `f` on the slot `now` falls in, dropping slots older than
+    /// the longest window.
+    fn with_slot(&self, now: Instant, f: impl FnOnce(&mut Slot)) {
+        let index = self.index(now);
+        let keep = slot_count(WINDOWS[WINDOWS.len() - 1].1);
+        let mut slots = self.slots.lock().expect("history lock poisoned");
+        while slots
+            .front()
+            .is_some_and(|slot| index.saturating_sub(slot.index) >= keep)
+        {
+            slots.pop_front();
+        }
+        if slots.back().is_none_or(|slot| slot.index < index) {
+            slots.push_back(Slot {
+                index,
+                renders: 0,
+                errors: 0,
+                latencies: [0; BUCKETS_SECS.len() + 1],
+                max_latency_msec: 0,
+                peak_backlog: 0,
+            });
+        }
+        // Callers race for the lock, so a render finishing on a slot
+        // boundary may land in the next slot, which is close enough.
+        f(slots.back_mut().expect("slot just pushed"));
+    }
+
+    fn index(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.origin).as_secs() / SLOT.as_secs()
+    }
+}
+
+fn slot_count(span: Duration) -> u64 {
+    span.as_secs() / SLOT.as_secs()
+}
+
+fn summarize<'a>(slots: impl Iterator<Item = &'a Slot>) -> WindowStats {
+    let mut stats = WindowStats::default();
+    let mut latencies: Latencies = [0; BUCKETS_SECS.len() + 1];
+    let mut max_latency_msec = 0;
+    for slot in slots {
+        stats.renders += slot.renders;
+        stats.errors += slot.errors;
+        stats.peak_backlog = stats.peak_backlog.max(slot.peak_backlog);
+        for (sum, n) in latencies.iter_mut().zip(slot.latencies) {
+            *sum += n;
+        }
+        max_latency_msec = max_latency_msec.max(slot.max_latency_msec);
+    }
+    stats.p50_latency_msec = percentile(&latencies, max_latency_msec, 50);
+    stats.p95_latency_msec = percentile(&latencies, max_latency_msec, 95);
+    stats
+}
+
+/// The nearest-rank percentile of the renders counted in `latencies`:
+/// the upper bound of its bucket, but no more than `max_msec`.
+fn percentile(latencies: &Latencies, max_msec: u32, pct: u64) -> Option<u32> {
+    let count: u64 = latencies.iter().sum();
+    if count == 0 {
+        return None;
+    }
+    let rank = (count * pct).div_ceil(100).max(1);
+    let mut seen = 0;
+    for (bucket, n) in latencies.iter().enumerate() {
+        seen += n;
+        if seen >= rank {
+            let bound = BUCKETS_SECS.get(bucket).map(|secs| (secs * 1000.0) as u32);
+            return Some(bound.map_or(max_msec, |bound| bound.min(max_msec)));
+        }
+    }
+    unreachable!("rank is at most count")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(history: &History, now: Instant, name: &str) -> WindowStats {
+        history
+            .windows_at(now)
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .expect("window")
+            .1
+    }
+
+    #[test]
+    fn windows_sum_their_slots_and_forget_old_ones() {
+        let history = History::default();
+        let t0 = history.origin;
+        for msec in 1..=100 {
+            history.render_finished_at(t0, Duration::from_millis(msec), msec % 10 != 0);
+        }
+        history.backlog_at(t0, 7);
+        let later = t0 + Duration::from_secs(120);
+        history.render_finished_at(later, Duration::from_millis(500), true);
+        history.backlog_at(later, 2);
+
+        let minute = window(&history, later, "minute");
+        assert_eq!(
+            minute,
+            WindowStats {
+                renders: 1,
+                errors: 0,
+                p50_latency_msec: Some(500),
+                p95_latency_msec: Some(500),
+                peak_backlog: 2,
+            }
+        );
+        let hour = window(&history, later, "hour");
+        assert_eq!(hour.renders, 101);
+        assert_eq!(hour.errors, 10);
+        // 1-50 ms fall in the 50 ms bucket, 51-100 ms in the 100 ms one,
+        // and the 500 ms render tips the median into the second.
+        assert_eq!(hour.p50_latency_msec, Some(100));
+        assert_eq!(hour.p95_latency_msec, Some(100));
+        assert_eq!(hour.peak_backlog, 7);
+
+        // A day on, only the latest render is still in the day.
+        let tomorrow = t0 + Duration::from_secs(24 * 60 * 60 + 60);
+        history.backlog_at(tomorrow, 0);
+        assert_eq!(window(&history, tomorrow, "day").renders, 1);
+        assert_eq!(window(&history, tomorrow, "minute"), WindowStats::default());
+    }
+
+    #[test]
+    fn percentile_uses_nearest_rank_over_buckets() {
+        let mut latencies: Latencies = [0; BUCKETS_SECS.len() + 1];
+        assert_eq!(percentile(&latencies, 0, 50), None);
+        // Two renders of at most 250 ms, two of at most 1 s.
+        latencies[2] = 2;
+        latencies[4] = 2;
+        assert_eq!(percentile(&latencies, 900, 50), Some(250));
+        assert_eq!(percentile(&latencies, 900, 95), Some(900));
+        // Past the last bound, only the slowest render says how slow.
+        latencies[BUCKETS_SECS.len()] = 1;
+        assert_eq!(percentile(&latencies, 75_000, 95), Some(75_000));
+    }
+}
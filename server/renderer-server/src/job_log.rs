@@ -0,0 +1,630 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// The coordinator's record of render jobs: renders submitted with
+// `submitRender`, which a client polls with `getJob` and collects with
+// `fetchResult` instead of waiting on its connection. Each job moves
+// from queued to running (a renderer picked it up) to done or failed,
+// and its response is kept for `retention` after it finishes, or until
+// `max_finished` later jobs have finished, whichever comes first, so a
+// burst of submissions can't hold more results than that in memory.
+//
+// With a file (`--job-log`), every transition is also appended to it as
+// a JSON line, so that a restart doesn't lose jobs: `State` replays the
+// ones that never finished, and the responses of finished ones stay
+// available by job id for clients that reconnect. Opening the file
+// compacts it, dropping jobs past their retention and rewriting the
+// rest, and so does pruning once the file has grown to several times
+// what the jobs still kept need, so it doesn't grow without bound
+// between restarts either. A line cut short by a crash mid-write is
+// skipped. Lines reach the OS as they are written but are not synced to
+// disk, which survives the process dying, not the machine.
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::request::{Request, Response};
+
+/// How long a finished job's response is kept, unless configured.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many finished jobs' responses are kept, unless configured.
+pub const DEFAULT_MAX_FINISHED: usize = 10_000;
+
+/// How many `submitRender` jobs one frontend connection may have queued
+/// or running at once.
+pub const MAX_OUTSTANDING_PER_CONNECTION: usize = 16;
+
+/// How often finished jobs are checked for having outlived `retention`.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The file is rewritten once it has this many times the lines needed
+/// for the jobs kept...
+const COMPACT_RATIO: usize = 4;
+/// ...and at least this many, so a quiet log isn't rewritten every prune.
+const COMPACT_MIN_LINES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    Queued,
+    /// A renderer picked it up. A job whose renderer fails it stays
+    /// running while it is retried.
+    Running,
+    Done,
+    /// Finished without producing anything; the response's logs say why.
+    Failed,
+}
+
+/// A job as recorded in the log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub id: String,
+    pub request: Request,
+    pub state: JobState,
+    pub response: Option<Response>,
+    /// How many renders are ahead of it, while queued.
+    pub position: Option<usize>,
+    pub queued_unix_msec: u64,
+    pub started_unix_msec: Option<u64>,
+    pub done_unix_msec: Option<u64>,
+}
+
+impl Job {
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, JobState::Done | JobState::Failed)
+    }
+}
+
+/// One line of the log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+enum Record {
+    Queued {
+        job: String,
+        at: u64,
+        request: Request,
+    },
+    Running {
+        job: String,
+        at: u64,
+    },
+    Done {
+        job: String,
+        at: u64,
+        response: Response,
+    },
+}
+
+fn unix_msec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A cloneable handle to the job log. Cheap to clone.
+#[derive(Clone)]
+pub struct JobLog {
+    inner: Arc<Mutex<JobLogInner>>,
+}
+
+struct JobLogInner {
+    /// `None` for a log kept only in memory.
+    file: Option<(PathBuf, File)>,
+    /// Lines in the file, to tell when it is worth compacting.
+    lines: usize,
+    jobs: HashMap<String, Job>,
+    /// Jobs not yet finished, to keep `set_positions` cheap when
+    /// there are none.
+    unfinished: HashSet<String>,
+    /// Finished jobs, oldest first, to evict past `max_finished`. May
+    /// name jobs already pruned.
+    finished: VecDeque<String>,
+    max_finished: usize,
+    retention: Duration,
+    last_prune: Instant,
+}
+
+impl JobLog {
+    /// A log that keeps jobs only for as long as the process runs.
+    pub fn in_memory(retention: Duration) -> JobLog {
+        JobLog::with(None, HashMap::new(), retention)
+    }
+
+    /// Opens the log at `path`, creating it if needed, and compacts it.
+    pub fn open(path: &Path, retention: Duration) -> io::Result<JobLog> {
+        let mut jobs = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for (n, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    match serde_json::from_str(&line) {
+                        Ok(record) => apply(&mut jobs, record),
+                        Err(err) => warn!("job log: skipping line {}: {}", n + 1, err),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        prune(&mut jobs, retention);
+        // Nothing is running in a process that just started.
+        for job in jobs.values_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+                job.started_unix_msec = None;
+            }
+        }
+
+        // Rewrite what's left, and append from there.
+        let (file, lines) = compact(path, &jobs)?;
+        info!("job log {}: {} jobs", path.display(), jobs.len());
+
+        let log = JobLog::with(Some((path.to_owned(), file)), jobs, retention);
+        log.lock().lines = lines;
+        Ok(log)
+    }
+
+    fn with(
+        file: Option<(PathBuf, File)>,
+        jobs: HashMap<String, Job>,
+        retention: Duration,
+    ) -> JobLog {
+        let unfinished = jobs
+            .values()
+            .filter(|job| !job.is_finished())
+            .map(|job| job.id.clone())
+            .collect();
+        let mut finished: Vec<&Job> = jobs.values().filter(|job| job.is_finished()).collect();
+        finished.sort_by_key(|job| job.done_unix_msec);
+        let finished = finished.into_iter().map(|job| job.id.clone()).collect();
+        JobLog {
+            inner: Arc::new(Mutex::new(JobLogInner {
+                file,
+                lines: 0,
+                jobs,
+                unfinished,
+                finished,
+                max_finished: DEFAULT_MAX_FINISHED,
+                retention,
+                last_prune: Instant::now(),
+            })),
+        }
+    }
+
+    /// Keeps the responses of at most `max` finished jobs, forgetting
+    /// the ones that finished first.
+    pub fn with_max_finished(self, max: usize) -> JobLog {
+        {
+            let mut inner = self.lock();
+            inner.max_finished = max;
+            inner.evict();
+        }
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JobLogInner> {
+        self.inner.lock().expect("job log lock poisoned")
+    }
+
+    /// Records `request` as a newly queued job, under its id.
+    pub fn queued(&self, request: &Request) {
+        let record = Record::Queued {
+            job: request.id.clone(),
+            at: unix_msec(),
+            request: request.clone(),
+        };
+        let mut inner = self.lock();
+        inner.prune_now_and_then();
+        inner.record(record);
+    }
+
+    /// Records that a renderer picked up job `id`. Does nothing for a
+    /// request that isn't a job, or a job already running.
+    pub fn started(&self, id: &str) {
+        let mut inner = self.lock();
+        if inner.jobs.get(id).map(|job| job.state) == Some(JobState::Queued) {
+            inner.record(Record::Running {
+                job: id.to_owned(),
+                at: unix_msec(),
+            });
+        }
+    }
+
+    /// Records that job `id` finished with `response`.
+    pub fn done(&self, id: &str, response: &Response) {
+        let mut inner = self.lock();
+        inner.record(Record::Done {
+            job: id.to_owned(),
+            at: unix_msec(),
+            response: response.clone(),
+        });
+        inner.prune_now_and_then();
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.lock().jobs.get(id).cloned()
+    }
+
+    /// Jobs that were queued and never finished, oldest first.
+    pub fn unfinished(&self) -> Vec<Job> {
+        let inner = self.lock();
+        let mut jobs: Vec<Job> = inner
+            .unfinished
+            .iter()
+            .filter_map(|id| inner.jobs.get(id).cloned())
+            .collect();
+        jobs.sort_by_key(|job| job.queued_unix_msec);
+        jobs
+    }
+
+    /// Updates the positions of queued jobs from the render queue, given
+    /// as request ids and how many renders are ahead of each.
+    pub fn set_positions<'a>(&self, queue: impl Iterator<Item = (&'a str, usize)>) {
+        let mut inner = self.lock();
+        if inner.unfinished.is_empty() {
+            return;
+        }
+        let positions: HashMap<&str, usize> = queue.collect();
+        let JobLogInner {
+            jobs, unfinished, ..
+        } = &mut *inner;
+        for id in unfinished.iter() {
+            if let Some(job) = jobs.get_mut(id) {
+                job.position = match job.state {
+                    JobState::Queued => positions.get(id.as_str()).copied(),
+                    _ => None,
+                };
+            }
+        }
+    }
+}
+
+impl Default for JobLog {
+    fn default() -> Self {
+        JobLog::in_memory(DEFAULT_RETENTION)
+    }
+}
+
+impl JobLogInner {
+    fn record(&mut self, record: Record) {
+        if let Some((path, file)) = &mut self.file {
+            let line = serde_json::to_string(&record).expect("records are serializable") + "\n";
+            match file.write_all(line.as_bytes()) {
+                Ok(()) => self.lines += 1,
+                Err(err) => warn!("job log {}: could not append: {}", path.display(), err),
+            }
+        }
+        let id = match &record {
+            Record::Queued { job, .. } | Record::Running { job, .. } | Record::Done { job, .. } => {
+                job.clone()
+            }
+        };
+        apply(&mut self.jobs, record);
+        match self.jobs.get(&id) {
+            Some(job) if !job.is_finished() => {
+                self.unfinished.insert(id);
+            }
+            _ => {
+                if self.unfinished.remove(&id) {
+                    self.finished.push_back(id);
+                    self.evict();
+                }
+            }
+        }
+    }
+
+    /// Forgets the oldest finished jobs past `max_finished`.
+    fn evict(&mut self) {
+        while self.finished.len() > self.max_finished {
+            if let Some(id) = self.finished.pop_front() {
+                self.jobs.remove(&id);
+            }
+        }
+    }
+
+    fn prune_now_and_then(&mut self) {
+        if self.last_prune.elapsed() >= PRUNE_INTERVAL {
+            self.last_prune = Instant::now();
+            prune(&mut self.jobs, self.retention);
+            let jobs = &self.jobs;
+            self.finished.retain(|id| jobs.contains_key(id));
+            self.compact_if_outgrown();
+        }
+    }
+
+    /// Rewrites the file with only the jobs still kept, if it has grown
+    /// well past them. Keeps appending to the old file if that fails.
+    fn compact_if_outgrown(&mut self) {
+        let path = match &self.file {
+            Some((path, _)) => path.clone(),
+            None => return,
+        };
+        let needed: usize = self.jobs.values().map(|job| records(job).len()).sum();
+        if self.lines < COMPACT_MIN_LINES.max(needed * COMPACT_RATIO) {
+            return;
+        }
+        match compact(&path, &self.jobs) {
+            Ok((file, lines)) => {
+                info!(
+                    "job log {}: compacted {} lines to {}",
+                    path.display(),
+                    self.lines,
+                    lines
+                );
+                self.file = Some((path, file));
+                self.lines = lines;
+            }
+            Err(err) => warn!("job log {}: could not compact: {}", path.display(), err),
+        }
+    }
+}
+
+/// Replaces the file at `path` with the records of `jobs`, oldest
+/// first, and returns it open for appending with how many lines it has.
+/// The file at `path` is untouched if this fails.
+fn compact(path: &Path, jobs: &HashMap<String, Job>) -> io::Result<(File, usize)> {
+    let mut sorted: Vec<&Job> = jobs.values().collect();
+    sorted.sort_by_key(|job| job.queued_unix_msec);
+    let tmp = path.with_extension("compacting");
+    let mut lines = 0;
+    let written = (|| {
+        let mut out = io::BufWriter::new(File::create(&tmp)?);
+        for job in sorted {
+            for record in records(job) {
+                writeln!(out, "{}", serde_json::to_string(&record)?)?;
+                lines += 1;
+            }
+        }
+        out.flush()?;
+        drop(out);
+        fs::rename(&tmp, path)?;
+        OpenOptions::new().append(true).open(path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok((written?, lines))
+}
+
+/// Whether `response` is a failure rather than a result.
+fn failed(response: &Response) -> bool {
+    response.files.is_empty() && response.midi.is_empty() && response.audio.is_empty()
+}
+
+/// Drops jobs that finished more than `retention` ago.
+fn prune(jobs: &mut HashMap<String, Job>, retention: Duration) {
+    let oldest = unix_msec().saturating_sub(retention.as_millis() as u64);
+    jobs.retain(|_, job| job.done_unix_msec.is_none_or(|at| at >= oldest));
+}
+
+fn apply(jobs: &mut HashMap<String, Job>, record: Record) {
+    match record {
+        Record::Queued { job, at, request } => {
+            jobs.insert(
+                job.clone(),
+                Job {
+                    id: job,
+                    request,
+                    state: JobState::Queued,
+                    response: None,
+                    position: None,
+                    queued_unix_msec: at,
+                    started_unix_msec: None,
+                    done_unix_msec: None,
+                },
+            );
+        }
+        Record::Running { job, at } => {
+            if let Some(job) = jobs.get_mut(&job) {
+                job.state = JobState::Running;
+                job.position = None;
+                job.started_unix_msec = Some(at);
+            }
+        }
+        Record::Done { job, at, response } => {
+            if let Some(job) = jobs.get_mut(&job) {
+                job.state = if failed(&response) {
+                    JobState::Failed
+                } else {
+                    JobState::Done
+                };
+                job.response = Some(response);
+                job.position = None;
+                job.done_unix_msec = Some(at);
+            }
+        }
+    }
+}
+
+/// The records that recreate `job`.
+fn records(job: &Job) -> Vec<Record> {
+    let mut records = vec![Record::Queued {
+        job: job.id.clone(),
+        at: job.queued_unix_msec,
+        request: job.request.clone(),
+    }];
+    if let Some(at) = job.started_unix_msec {
+        records.push(Record::Running {
+            job: job.id.clone(),
+            at,
+        });
+    }
+    if let (Some(response), Some(at)) = (&job.response, job.done_unix_msec) {
+        records.push(Record::Done {
+            job: job.id.clone(),
+            at,
+            response: response.clone(),
+        });
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Backend, RenderOptions, Version};
+    use std::collections::BTreeMap;
+    use uuid::Uuid;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("job-log-{}.jsonl", Uuid::new_v4()))
+    }
+
+    fn request(id: &str) -> Request {
+        Request {
+            id: id.to_owned(),
+            backend: Backend::Svg,
+            src: "c4".to_owned(),
+            version: Version::Stable,
+            options: RenderOptions::default(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    fn response(files: &[&str], logs: &str) -> Response {
+        Response {
+            files: files.iter().map(|f| f.to_string()).collect(),
+            logs: logs.to_owned(),
+            midi: String::new(),
+            audio: String::new(),
+        }
+    }
+
+    #[test]
+    fn unfinished_jobs_survive_a_restart() {
+        let path = temp_path();
+        let log = JobLog::open(&path, DAY).unwrap();
+        log.queued(&request("a"));
+        log.queued(&request("b"));
+        log.started("a");
+        log.started("b");
+        log.done("a", &response(&["<svg/>"], "ok"));
+        drop(log);
+
+        let log = JobLog::open(&path, DAY).unwrap();
+        let replay = log.unfinished();
+        assert_eq!(replay.len(), 1);
+        assert_eq!(replay[0].request, request("b"));
+        assert_eq!(replay[0].state, JobState::Queued, "nothing runs yet");
+        let job = log.get("a").unwrap();
+        assert_eq!(job.state, JobState::Done);
+        assert_eq!(job.response, Some(response(&["<svg/>"], "ok")));
+
+        log.done("b", &response(&[], "late"));
+        drop(log);
+        let log = JobLog::open(&path, DAY).unwrap();
+        assert!(log.unfinished().is_empty());
+        assert_eq!(log.get("b").unwrap().state, JobState::Failed);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn torn_lines_and_expired_jobs_are_dropped() {
+        let path = temp_path();
+        let log = JobLog::open(&path, DAY).unwrap();
+        log.queued(&request("kept"));
+        drop(log);
+
+        let expired = Job {
+            id: "expired".to_owned(),
+            request: request("expired"),
+            state: JobState::Done,
+            response: Some(response(&["<svg/>"], "old")),
+            position: None,
+            queued_unix_msec: 1,
+            started_unix_msec: None,
+            done_unix_msec: Some(2),
+        };
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        for record in records(&expired) {
+            writeln!(file, "{}", serde_json::to_string(&record).unwrap()).unwrap();
+        }
+        write!(file, "{{\"state\":\"done\",\"job\":\"kept\",\"at\"").unwrap();
+        drop(file);
+
+        let log = JobLog::open(&path, DAY).unwrap();
+        assert!(log.get("expired").is_none());
+        assert_eq!(log.get("kept").unwrap().state, JobState::Queued);
+        drop(log);
+        let compacted = fs::read_to_string(&path).unwrap();
+        assert_eq!(compacted.lines().count(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn the_file_is_compacted_once_it_outgrows_the_jobs_kept() {
+        let path = temp_path();
+        let log = JobLog::open(&path, Duration::ZERO).unwrap();
+        log.queued(&request("kept"));
+        for n in 0..COMPACT_MIN_LINES / 2 {
+            let id = format!("gone-{}", n);
+            log.queued(&request(&id));
+            log.done(&id, &response(&["<svg/>"], "ok"));
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        {
+            let mut inner = log.lock();
+            assert_eq!(inner.lines, COMPACT_MIN_LINES + 1);
+            inner.last_prune -= PRUNE_INTERVAL;
+            inner.prune_now_and_then();
+            assert_eq!(inner.lines, 1);
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        // Appends go to the compacted file.
+        log.started("kept");
+        drop(log);
+        let log = JobLog::open(&path, DAY).unwrap();
+        assert_eq!(log.unfinished().len(), 1);
+        assert!(log.get("gone-0").is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_the_latest_finished_jobs_are_kept() {
+        let log = JobLog::in_memory(DAY).with_max_finished(2);
+        for id in ["a", "b", "c"] {
+            log.queued(&request(id));
+        }
+        log.queued(&request("waiting"));
+        log.done("b", &response(&["<svg/>"], "b"));
+        log.done("a", &response(&["<svg/>"], "a"));
+        log.done("c", &response(&[], "c"));
+        assert!(log.get("b").is_none(), "finished first, so evicted first");
+        assert_eq!(log.get("a").unwrap().state, JobState::Done);
+        assert_eq!(log.get("c").unwrap().state, JobState::Failed);
+        assert_eq!(log.get("waiting").unwrap().state, JobState::Queued);
+
+        let log = log.with_max_finished(1);
+        assert!(log.get("a").is_none());
+        assert!(log.get("c").is_some());
+    }
+
+    #[test]
+    fn jobs_move_through_the_queue() {
+        let log = JobLog::in_memory(DAY);
+        log.queued(&request("first"));
+        log.queued(&request("second"));
+        log.set_positions([("ws-render", 0), ("first", 1), ("second", 2)].into_iter());
+        assert_eq!(log.get("second").unwrap().position, Some(2));
+
+        log.started("first");
+        log.set_positions([("second", 0)].into_iter());
+        let first = log.get("first").unwrap();
+        assert_eq!((first.state, first.position), (JobState::Running, None));
+        assert_eq!(log.get("second").unwrap().position, Some(0));
+
+        log.started("not-a-job");
+        assert!(log.get("not-a-job").is_none());
+    }
+}
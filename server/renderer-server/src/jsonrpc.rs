@@ -20,6 +20,16 @@ pub const JSONRPC_VERSION: &str = "2.0";
 pub const ERROR_JSON_PARSE: i64 = 1;
 pub const ERROR_INTERNAL: i64 = 2;
 pub const ERROR_GITHUB: i64 = 3;
+/// A worker's `i_haz_computes` credentials were not accepted.
+pub const ERROR_UNAUTHORIZED: i64 = 4;
+/// `getJob`/`fetchResult` named a job that doesn't exist, or whose
+/// result is past its retention.
+pub const ERROR_NO_SUCH_JOB: i64 = 5;
+/// `fetchResult` named a job that hasn't finished yet.
+pub const ERROR_JOB_NOT_DONE: i64 = 6;
+/// `submitRender` on a connection that already has
+/// `job_log::MAX_OUTSTANDING_PER_CONNECTION` jobs queued or running.
+pub const ERROR_TOO_MANY_JOBS: i64 = 7;
 
 // Standard JSON-RPC 2.0 error codes (used only for protocol-level
 // framing errors, not application errors).
@@ -37,8 +47,22 @@ pub mod method {
     pub const SIGN_OUT: &str = "signOut";
     pub const NOTIFY_SAVED: &str = "notifySaved";
     pub const GET_STATUS: &str = "get_status";
+    /// Queue a render and answer with its job id, instead of its result.
+    pub const SUBMIT_RENDER: &str = "submitRender";
+    /// The state of a submitted render, and its place in the queue.
+    pub const GET_JOB: &str = "getJob";
+    /// The result of a finished submitted render.
+    pub const FETCH_RESULT: &str = "fetchResult";
     /// Worker registration, sent by a freshly connected `ws-worker`.
     pub const I_HAZ_COMPUTES: &str = "i_haz_computes";
+    /// Notification from a worker that is shutting down: send it no new
+    /// renders, and hang up once the ones it has are answered.
+    pub const DRAIN: &str = "drain";
+    /// Admin request to drain a worker, by its id in `get_status`.
+    pub const DRAIN_WORKER: &str = "drain_worker";
+    /// Admin request for every local container and remote worker, one
+    /// by one, instead of the totals in `get_status`.
+    pub const GET_STATUS_DETAIL: &str = "get_status_detail";
 }
 
 /// Default `params` when a request omits the field (the frontend always
@@ -3,18 +3,26 @@
 //
 #![warn(clippy::all)]
 pub mod auth;
+pub mod backoff;
+pub mod cluster;
 mod command_source;
 mod config;
 mod container;
 mod error;
 mod event_loop;
-pub mod http_status;
+pub mod history;
+pub mod job_log;
 pub mod jsonrpc;
+pub mod metrics;
 mod renderer;
 mod renderer_manager;
 pub mod request;
 pub mod status;
+pub mod tls;
+pub mod wire;
+pub mod worker_auth;
 pub mod worker_registry;
 
-pub use crate::config::{CommandSourceConfig, Config};
+pub use crate::command_source::Outbox;
+pub use crate::config::{CommandSourceConfig, Config, CoordinatorLink};
 pub use crate::event_loop::event_loop;
@@ -6,13 +6,22 @@ use ansi_term::Colour::{Green, Red};
 use clap::{value_t, App, Arg, ArgAction, SubCommand};
 use log::info;
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 extern crate renderer_lib;
 
 use renderer_lib::{
-    event_loop, status::StatusHandle, worker_registry::WorkerRegistryHandle, CommandSourceConfig,
-    Config,
+    backoff::Backoff,
+    cluster::{Cluster, FileState},
+    event_loop,
+    job_log::JobLog,
+    status::StatusHandle,
+    tls::{TlsAcceptorHandle, TlsClientFiles, TlsFiles},
+    worker_auth::{AdminToken, WorkerCredentials, WorkerKeys},
+    worker_registry::{Deadline, Heartbeat, WorkerRegistryHandle},
+    CommandSourceConfig, Config, CoordinatorLink, Outbox,
 };
 
 #[tokio::main]
@@ -61,6 +70,41 @@ async fn main() {
                 .value_name("MSEC")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("audio-max-bytes")
+                .long("audio-max-bytes")
+                .help("The largest synthesized audio file (render option `audio`) to return, in bytes.")
+                .required(false)
+                .value_name("BYTES")
+                .takes_value(true)
+                .default_value("8388608"),
+        )
+        .arg(
+            Arg::with_name("job-log")
+                .long("job-log")
+                .help("Record submitted renders in this file, and replay unfinished ones on startup.")
+                .required(false)
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("job-retention-secs")
+                .long("job-retention-secs")
+                .help("How long to keep the result of a submitted render after it finishes.")
+                .required(false)
+                .value_name("SECS")
+                .takes_value(true)
+                .default_value("86400"),
+        )
+        .arg(
+            Arg::with_name("job-max-results")
+                .long("job-max-results")
+                .help("How many results of submitted renders to keep at most; the oldest go first.")
+                .required(false)
+                .value_name("JOBS")
+                .takes_value(true)
+                .default_value("10000"),
+        )
         .arg(
             Arg::with_name("v")
                 .long("verbose")
@@ -73,10 +117,92 @@ async fn main() {
                 .about("Offer computing power to a Hacklily cordinator.")
                 .arg(
                     Arg::with_name("coordinator-address")
-                        .help("The address of the coordinator (starts with ws:// or wss://)")
+                        .help("The address of the coordinator (starts with ws:// or wss://). Given several, each connection tries them in order.")
                         .index(1)
                         .required(true)
+                        .multiple_values(true)
                         .validator(is_url),
+                )
+                .arg(
+                    Arg::with_name("weight")
+                        .long("weight")
+                        .help("With --federate, this coordinator's share of the containers, relative to the others' weights")
+                        .value_name("WEIGHT")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(is_weight),
+                )
+                .arg(
+                    Arg::with_name("federate")
+                        .long("federate")
+                        .help("Also serve another coordinator, splitting the containers between them by weight. May be repeated. Several URLs, separated by commas, are tried in order like the main address.")
+                        .value_name("WEIGHT:URL[,URL...]")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .validator(|spec| federated_link(spec).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("reconnect-max-msec")
+                        .long("reconnect-max-msec")
+                        .help("After losing the coordinator, wait up to this long between attempts to reconnect. The wait starts at a second and doubles with each failure.")
+                        .value_name("MSEC")
+                        .takes_value(true)
+                        .default_value("30000"),
+                )
+                .arg(
+                    Arg::with_name("binary-transport")
+                        .long("binary-transport")
+                        .help("Offer to send render results to the coordinator as binary WebSocket frames instead of base64 JSON. Falls back to JSON if the coordinator doesn't support it.")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("ws-compression")
+                        .long("ws-compression")
+                        .help("Offer to deflate-compress messages to and from the coordinator. Falls back to uncompressed if the coordinator doesn't support or allow it.")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("worker-name")
+                        .long("worker-name")
+                        .help("The name this worker is listed under in the coordinator's --worker-keys file")
+                        .value_name("NAME")
+                        .takes_value(true)
+                        .requires("worker-key-file"),
+                )
+                .arg(
+                    Arg::with_name("worker-key-file")
+                        .long("worker-key-file")
+                        .help("File holding this worker's secret. The worker sends the coordinator a short-lived token signed with it, not the secret itself.")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .requires("worker-name")
+                        .validator(file_exists),
+                )
+                .arg(
+                    Arg::with_name("tls-ca")
+                        .long("tls-ca")
+                        .help("Also trust this CA (PEM) for a wss:// coordinator's certificate, on top of the system roots")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .validator(file_exists),
+                )
+                .arg(
+                    Arg::with_name("tls-client-cert")
+                        .long("tls-client-cert")
+                        .help("Client certificate (PEM) to present to a coordinator that requires mutual TLS")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .requires("tls-client-key")
+                        .validator(file_exists),
+                )
+                .arg(
+                    Arg::with_name("tls-client-key")
+                        .long("tls-client-key")
+                        .help("Private key (PEM) for --tls-client-cert")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .requires("tls-client-cert")
+                        .validator(file_exists),
                 ),
         )
         .subcommand(
@@ -100,14 +226,107 @@ async fn main() {
                         .default_value("127.0.0.1")
                         .validator(is_ip_addr),
                 )
+                .arg(
+                    Arg::with_name("ws-compression")
+                        .long("ws-compression")
+                        .help("Let frontends and workers that ask for it deflate-compress their WebSocket messages.")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("worker-port")
+                        .long("worker-port")
+                        .help("Accept ws-worker connections on this separate port instead of --ws-port, which then only serves frontends. Lets you firewall worker ingress on its own.")
+                        .value_name("PORT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("worker-bind-address")
+                        .long("worker-bind-address")
+                        .help("Interface for --worker-port. Defaults to --bind-address.")
+                        .value_name("IP")
+                        .takes_value(true)
+                        .requires("worker-port")
+                        .validator(is_ip_addr),
+                )
+                .arg(
+                    Arg::with_name("worker-path")
+                        .long("worker-path")
+                        .help("Only accept ws-worker connections on this URL path (e.g. /worker). On a shared port, every other path is for frontends only.")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .validator(is_url_path),
+                )
+                .arg(
+                    Arg::with_name("remote-timeout-msec")
+                        .long("remote-timeout-msec")
+                        .help("Take a render back from a ws-worker that hasn't answered it after this long, and retry it elsewhere. Defaults to --render-timeout-msec plus 5000.")
+                        .value_name("MSEC")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("remote-retries")
+                        .long("remote-retries")
+                        .help("How many times a render that timed out on ws-workers is retried before it fails.")
+                        .value_name("N")
+                        .takes_value(true)
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::with_name("worker-silence-msec")
+                        .long("worker-silence-msec")
+                        .help("Drop a ws-worker that hasn't sent anything, pongs to the coordinator's pings included, for this long.")
+                        .value_name("MSEC")
+                        .takes_value(true)
+                        .default_value("30000"),
+                )
+                .arg(
+                    Arg::with_name("worker-keys")
+                        .long("worker-keys")
+                        .help("Only let workers listed in this file register. One `name secret` pair per line; `#` starts a comment. Without it, any peer can register as a worker.")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .validator(file_exists),
+                )
+                .arg(
+                    Arg::with_name("admin-token-file")
+                        .long("admin-token-file")
+                        .help("Allow admin requests (drain_worker, get_status_detail) from frontends presenting the token in this file. Without it, they are refused.")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .validator(file_exists),
+                )
+                .arg(
+                    Arg::with_name("shared-state")
+                        .long("shared-state")
+                        .help("Share renders with the other coordinators using this directory (on a filesystem they all see). A coordinator with no renderer for a request, or no idle one while another coordinator has, hands it to them.")
+                        .value_name("DIR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("node-id")
+                        .long("node-id")
+                        .help("This coordinator's name in the --shared-state directory. Defaults to a random one.")
+                        .value_name("NAME")
+                        .takes_value(true)
+                        .requires("shared-state"),
+                )
                 .arg(
                     Arg::with_name("http-status-port")
                         .long("http-status-port")
-                        .help("Optional HTTP port serving GET /status -> JSON (e.g. 9990). Set to 0 to disable.")
+                        .help("Optional HTTP port serving GET /status -> JSON and POST /render (e.g. 9990). Set to 0 to disable.")
                         .required(false)
                         .value_name("PORT")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("http-bind-address")
+                        .long("http-bind-address")
+                        .help("Interface for --http-status-port. Defaults to 127.0.0.1, for a local reverse proxy; POST /render is unauthenticated, so think twice before exposing it.")
+                        .value_name("IP")
+                        .takes_value(true)
+                        .default_value("127.0.0.1")
+                        .validator(is_ip_addr),
+                )
                 .arg(
                     Arg::with_name("github-client-id")
                         .long("github-client-id")
@@ -123,6 +342,33 @@ async fn main() {
                         .required(false)
                         .value_name("SECRET")
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tls-cert")
+                        .long("tls-cert")
+                        .help("Serve wss:// (and https:// status) with this certificate chain (PEM) instead of plain ws://. Reloaded when the file changes.")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .requires("tls-key")
+                        .validator(file_exists),
+                )
+                .arg(
+                    Arg::with_name("tls-key")
+                        .long("tls-key")
+                        .help("Private key (PEM) for --tls-cert. Reloaded when the file changes.")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .requires("tls-cert")
+                        .validator(file_exists),
+                )
+                .arg(
+                    Arg::with_name("tls-client-ca")
+                        .long("tls-client-ca")
+                        .help("Require ws-workers to present a client certificate signed by this CA (PEM). Frontends are not asked for one unless they share a port with workers; some browsers then prompt for a certificate, so use --worker-port.")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .requires("tls-cert")
+                        .validator(file_exists),
                 ),
         )
         .subcommand(
@@ -158,6 +404,16 @@ async fn main() {
     // client + analytics counts. Only meaningfully used in `serve` mode.
     let status = StatusHandle::new();
 
+    let render_timeout_msec = value_t!(matches.value_of("render-timeout-msec"), u64)
+        .expect("Required config option render-timeout-msec malformed or not found.");
+
+    let job_retention = Duration::from_secs(
+        value_t!(matches.value_of("job-retention-secs"), u64)
+            .expect("Config option job-retention-secs malformed."),
+    );
+    let job_max_results = value_t!(matches.value_of("job-max-results"), usize)
+        .expect("Config option job-max-results malformed.");
+
     let config = Config {
         stable_docker_tag: matches
             .value_of("stable-docker-tag")
@@ -173,22 +429,77 @@ async fn main() {
         unstable_worker_count: value_t!(matches.value_of("unstable-worker-count"), u64)
             .expect("Required config option unstable-worker-count malformed or not found."),
 
-        render_timeout_msec: value_t!(matches.value_of("render-timeout-msec"), u64)
-            .expect("Required config option render-timeout-msec malformed or not found."),
+        render_timeout_msec,
+
+        audio_max_bytes: value_t!(matches.value_of("audio-max-bytes"), u64)
+            .expect("Config option audio-max-bytes malformed."),
 
         status: status.clone(),
 
+        job_log: match matches.value_of("job-log") {
+            Some(path) => JobLog::open(Path::new(path), job_retention).unwrap_or_else(|e| {
+                eprintln!("{}: {}: {}", Red.paint("error"), path, e);
+                ::std::process::exit(1);
+            }),
+            None => JobLog::in_memory(job_retention),
+        }
+        .with_max_finished(job_max_results),
+
         command_source: match matches.subcommand_name() {
-            Some("ws-worker") => CommandSourceConfig::Worker {
-                coordinator: url::Url::parse(
-                    matches
-                        .subcommand_matches("ws-worker")
-                        .unwrap()
-                        .value_of("coordinator-address")
-                        .expect("Missing address (this field was marked as required above)"),
-                )
-                .expect("Invalid coordinator URL (this field was validated above)"),
-            },
+            Some("ws-worker") => {
+                let sm = matches.subcommand_matches("ws-worker").unwrap();
+                let main_link = CoordinatorLink {
+                    coordinators: sm
+                        .values_of("coordinator-address")
+                        .expect("Missing address (this field was marked as required above)")
+                        .map(|address| {
+                            url::Url::parse(address)
+                                .expect("Invalid coordinator URL (this field was validated above)")
+                        })
+                        .collect(),
+                    weight: value_t!(sm.value_of("weight"), u32)
+                        .expect("Config option weight malformed."),
+                    outbox: Outbox::default(),
+                };
+                let federated = sm.values_of("federate").into_iter().flatten().map(|spec| {
+                    let (weight, coordinators) =
+                        federated_link(spec).expect("federate was validated above");
+                    CoordinatorLink {
+                        coordinators,
+                        weight,
+                        outbox: Outbox::default(),
+                    }
+                });
+                CommandSourceConfig::Worker {
+                    links: std::iter::once(main_link).chain(federated).collect(),
+                    reconnect: Backoff::new(
+                        Duration::from_millis(1000),
+                        Duration::from_millis(
+                            value_t!(sm.value_of("reconnect-max-msec"), u64)
+                                .expect("Config option reconnect-max-msec malformed."),
+                        ),
+                    ),
+                    binary_transport: sm.is_present("binary-transport"),
+                    ws_compression: sm.is_present("ws-compression"),
+                    credentials: sm.value_of("worker-name").map(|name| WorkerCredentials {
+                        name: name.to_owned(),
+                        secret: read_secret(
+                            sm.value_of("worker-key-file")
+                                .expect("worker-name requires worker-key-file"),
+                        ),
+                    }),
+                    tls: TlsClientFiles {
+                        ca: sm.value_of("tls-ca").map(PathBuf::from),
+                        cert: sm.value_of("tls-client-cert").map(PathBuf::from),
+                        key: sm.value_of("tls-client-key").map(PathBuf::from),
+                    }
+                    .checked()
+                    .unwrap_or_else(|e| {
+                        eprintln!("{}: {}", Red.paint("error"), e);
+                        ::std::process::exit(1);
+                    }),
+                }
+            }
             Some("serve") => {
                 let sm = matches.subcommand_matches("serve").unwrap();
                 let ws_port = value_t!(sm.value_of("ws-port"), u16)
@@ -198,13 +509,82 @@ async fn main() {
                     .expect("bind-address has a default_value, so it is always present")
                     .parse::<std::net::IpAddr>()
                     .expect("bind-address was validated by is_ip_addr");
+                let remote_timeout_msec = sm
+                    .value_of("remote-timeout-msec")
+                    .map(|v| v.parse().expect("remote-timeout-msec must be a number"))
+                    .unwrap_or(render_timeout_msec + 5000);
+                let workers = WorkerRegistryHandle::with_status(status.clone())
+                    .with_deadline(Deadline {
+                        timeout: Duration::from_millis(remote_timeout_msec),
+                        retries: value_t!(sm.value_of("remote-retries"), u32)
+                            .expect("Config option remote-retries malformed."),
+                    })
+                    .with_heartbeat(Heartbeat::for_silence(Duration::from_millis(
+                        value_t!(sm.value_of("worker-silence-msec"), u64)
+                            .expect("Config option worker-silence-msec malformed."),
+                    )));
                 CommandSourceConfig::Coordinator {
                     bind_address,
                     ws_port,
                     github_client_id: sm.value_of("github-client-id").unwrap_or("").to_owned(),
                     github_secret: sm.value_of("github-secret").unwrap_or("").to_owned(),
-                    workers: WorkerRegistryHandle::with_status(status.clone()),
+                    workers,
                     status: status.clone(),
+                    ws_compression: sm.is_present("ws-compression"),
+                    worker_listener: sm.value_of("worker-port").map(|port| {
+                        let ip = sm
+                            .value_of("worker-bind-address")
+                            .map(|ip| ip.parse().expect("validated by is_ip_addr"))
+                            .unwrap_or(bind_address);
+                        let port = port.parse::<u16>().expect("worker-port must be 0-65535");
+                        std::net::SocketAddr::new(ip, port)
+                    }),
+                    worker_path: sm.value_of("worker-path").map(str::to_owned),
+                    worker_keys: sm.value_of("worker-keys").map(|path| {
+                        Arc::new(WorkerKeys::load(Path::new(path)).unwrap_or_else(|e| {
+                            eprintln!("{}: {}", Red.paint("error"), e);
+                            ::std::process::exit(1);
+                        }))
+                    }),
+                    tls: sm.value_of("tls-cert").map(|cert| {
+                        TlsAcceptorHandle::load(TlsFiles {
+                            cert: PathBuf::from(cert),
+                            key: PathBuf::from(
+                                sm.value_of("tls-key").expect("tls-cert requires tls-key"),
+                            ),
+                            client_ca: sm.value_of("tls-client-ca").map(PathBuf::from),
+                        })
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}: {}", Red.paint("error"), e);
+                            ::std::process::exit(1);
+                        })
+                    }),
+                    admin_token: sm
+                        .value_of("admin-token-file")
+                        .map(|path| Arc::new(AdminToken::new(read_secret(path)))),
+                    cluster: sm.value_of("shared-state").map(|dir| {
+                        let backend = FileState::new(PathBuf::from(dir)).unwrap_or_else(|e| {
+                            eprintln!("{}: {}: {}", Red.paint("error"), dir, e);
+                            ::std::process::exit(1);
+                        });
+                        let node = sm
+                            .value_of("node-id")
+                            .map(str::to_owned)
+                            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+                        Cluster::new(node, Arc::new(backend))
+                    }),
+                    http_listener: sm
+                        .value_of("http-status-port")
+                        .map(|v| v.parse::<u16>().expect("http-status-port must be 0-65535"))
+                        .filter(|&p| p != 0)
+                        .map(|port| {
+                            let ip = sm
+                                .value_of("http-bind-address")
+                                .expect("http-bind-address has a default_value")
+                                .parse::<std::net::IpAddr>()
+                                .expect("validated by is_ip_addr");
+                            std::net::SocketAddr::new(ip, port)
+                        }),
                 }
             }
             Some("batch") => CommandSourceConfig::Batch {
@@ -228,30 +608,26 @@ async fn main() {
         },
     };
 
-    // Start the optional HTTP status endpoint before running the
-    // event loop. It's a tokio background task that reads atomics;
-    // tokio cancels it when main exits.
-    let http_status_port: Option<u16> = match matches.subcommand_name() {
-        Some("serve") => {
-            let sm = matches.subcommand_matches("serve").unwrap();
-            sm.value_of("http-status-port")
-                .map(|v| v.parse::<u16>().expect("http-status-port must be 0-65535"))
-                .filter(|&p| p != 0)
-        }
-        _ => None,
-    };
-    if let Some(port) = http_status_port {
-        let status_for_http = status.clone();
-        tokio::spawn(async move {
-            renderer_lib::http_status::serve(port, status_for_http).await;
-        });
-    }
-
     event_loop(config).await;
 
     info!("Bye.")
 }
 
+/// Read a secret (a worker key or the admin token) from a file.
+fn read_secret(path: &str) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(key) if !key.trim().is_empty() => key.trim().to_owned(),
+        Ok(_) => {
+            eprintln!("{}: {} is empty", Red.paint("error"), path);
+            ::std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}: could not read {}: {}", Red.paint("error"), path, e);
+            ::std::process::exit(1);
+        }
+    }
+}
+
 fn is_url(val: &str) -> Result<(), String> {
     if let Err(_error) = url::Url::parse(val) {
         Err(format!("{} is not a valid URL", val))
@@ -260,12 +636,40 @@ fn is_url(val: &str) -> Result<(), String> {
     }
 }
 
+fn is_weight(val: &str) -> Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(weight) if weight > 0 => Ok(()),
+        _ => Err(format!("{} is not a positive whole number", val)),
+    }
+}
+
+/// Parses a `--federate` value, `WEIGHT:URL[,URL...]`.
+fn federated_link(val: &str) -> Result<(u32, Vec<url::Url>), String> {
+    let (weight, urls) = val
+        .split_once(':')
+        .ok_or_else(|| format!("{} is not WEIGHT:URL", val))?;
+    is_weight(weight)?;
+    let urls = urls
+        .split(',')
+        .map(|url| url::Url::parse(url).map_err(|_| format!("{} is not a valid URL", url)))
+        .collect::<Result<_, _>>()?;
+    Ok((weight.parse().expect("checked by is_weight"), urls))
+}
+
 fn is_ip_addr(val: &str) -> Result<(), String> {
     val.parse::<std::net::IpAddr>()
         .map(|_| ())
         .map_err(|_| format!("{} is not a valid IP address", val))
 }
 
+fn is_url_path(val: &str) -> Result<(), String> {
+    if val.starts_with('/') {
+        Ok(())
+    } else {
+        Err(format!("{} is not a URL path (no leading /)", val))
+    }
+}
+
 fn file_exists(val: &str) -> Result<(), String> {
     if !Path::new(&val).exists() {
         Err(format!("{} does not exist", val))
@@ -0,0 +1,376 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// Prometheus metrics, served as `GET /metrics` by the coordinator's
+// HTTP endpoint (`command_source::http`).
+//
+// `StatusSnapshot` already carries the point-in-time numbers behind
+// `get_status`; what it lacks are distributions and the counters only
+// a scraper cares about. `Metrics` holds those, and lives in the
+// snapshot so every subsystem that publishes status can record them:
+// the event loop (local render latency, queue wait, dirty-crash
+// requeues), the renderer manager (container creations, crashes and
+// timeouts), the worker registry (remote render latency, connects and
+// disconnects) and the coordinator (WebSocket messages).
+// `exposition` renders both into the Prometheus text format. No
+// client library: the format is a few lines per metric.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::request::{Backend, Version};
+use crate::status::StatusHandle;
+
+/// Upper bounds, in seconds, of the latency and queue-wait buckets.
+/// Renders take from a few hundred milliseconds to the render timeout.
+pub const BUCKETS_SECS: [f64; 11] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 30.0, 60.0];
+
+/// Where a render ran.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    /// In one of this coordinator's own containers.
+    Local,
+    /// On a `ws-worker`.
+    Remote,
+}
+
+impl Location {
+    fn as_str(self) -> &'static str {
+        match self {
+            Location::Local => "local",
+            Location::Remote => "remote",
+        }
+    }
+}
+
+/// A cumulative histogram over `BUCKETS_SECS`.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Observations at most each bound; the `+Inf` bucket is `count`.
+    buckets: [u64; BUCKETS_SECS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS_SECS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    /// Write the series of `name`, with `labels` (`key="value",…` or
+    /// empty) on each.
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS_SECS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, bucket
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+/// Counters and histograms only `GET /metrics` reports. Like the rest
+/// of `StatusSnapshot`, counters are atomics; the histograms share a
+/// mutex nobody holds for longer than an observation.
+#[derive(Default)]
+pub struct Metrics {
+    render_latency: Mutex<BTreeMap<(String, String, Location), Histogram>>,
+    queue_wait: Mutex<Histogram>,
+    /// Local containers started, replacements included.
+    pub containers_created: AtomicU64,
+    /// Local containers that died mid-render or timed out.
+    pub container_crashes: AtomicU64,
+    /// Local renders whose container crashed after earlier renders, and
+    /// which were queued again in case the crash wasn't theirs.
+    pub dirty_crash_requeues: AtomicU64,
+    /// Local renders that hit the render timeout.
+    pub local_timeouts: AtomicU64,
+    pub worker_connects: AtomicU64,
+    pub worker_disconnects: AtomicU64,
+}
+
+impl Metrics {
+    /// Record how long a render took, from dispatch to answer.
+    pub fn render_finished(
+        &self,
+        backend: Backend,
+        version: Version,
+        location: Location,
+        elapsed: Duration,
+    ) {
+        self.render_latency
+            .lock()
+            .expect("metrics lock poisoned")
+            .entry((label(backend), label(version), location))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    /// Record how long a render waited in the queue for a renderer.
+    pub fn dequeued(&self, waited: Duration) {
+        self.queue_wait
+            .lock()
+            .expect("metrics lock poisoned")
+            .observe(waited);
+    }
+}
+
+/// How a backend or version is spelled on the wire, e.g. `musicxml2ly`.
+fn label<T: Serialize>(value: T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// Write the `# HELP` and `# TYPE` lines of a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write a metric with a single, unlabelled series.
+fn scalar(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Write a metric with one series per `(labels, value)`.
+fn labelled(out: &mut String, name: &str, kind: &str, help: &str, series: &[(&str, u64)]) {
+    header(out, name, kind, help);
+    for (labels, value) in series {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Everything `status` knows, in the Prometheus text format (0.0.4).
+pub fn exposition(status: &StatusHandle) -> String {
+    let snap = status.snapshot();
+    let metrics = &snap.metrics;
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = String::new();
+
+    let name = "hacklily_render_duration_seconds";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Time from dispatching a render to its answer.",
+    );
+    for ((backend, version, location), histogram) in metrics
+        .render_latency
+        .lock()
+        .expect("metrics lock poisoned")
+        .iter()
+    {
+        let labels = format!(
+            "backend=\"{}\",version=\"{}\",location=\"{}\"",
+            backend,
+            version,
+            location.as_str()
+        );
+        histogram.write(&mut out, name, &labels);
+    }
+    let name = "hacklily_queue_wait_seconds";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Time renders spent queued before a renderer took them.",
+    );
+    metrics
+        .queue_wait
+        .lock()
+        .expect("metrics lock poisoned")
+        .write(&mut out, name, "");
+
+    scalar(
+        &mut out,
+        "hacklily_renders_total",
+        "counter",
+        "Renders requested by clients.",
+        load(&snap.analytics_renders),
+    );
+    scalar(
+        &mut out,
+        "hacklily_backlog",
+        "gauge",
+        "Renders waiting for a renderer.",
+        load(&snap.backlog),
+    );
+    labelled(
+        &mut out,
+        "hacklily_renderers",
+        "gauge",
+        "Render slots, by location and whether they are busy.",
+        &[
+            ("location=\"local\",state=\"busy\"", load(&snap.local_busy)),
+            ("location=\"local\",state=\"free\"", load(&snap.local_free)),
+            (
+                "location=\"remote\",state=\"busy\"",
+                load(&snap.remote_busy),
+            ),
+            (
+                "location=\"remote\",state=\"free\"",
+                load(&snap.remote_free),
+            ),
+        ],
+    );
+    scalar(
+        &mut out,
+        "hacklily_containers_created_total",
+        "counter",
+        "Local render containers started, replacements included.",
+        load(&metrics.containers_created),
+    );
+    scalar(
+        &mut out,
+        "hacklily_container_crashes_total",
+        "counter",
+        "Local render containers that died or timed out mid-render.",
+        load(&metrics.container_crashes),
+    );
+    scalar(
+        &mut out,
+        "hacklily_dirty_crash_requeues_total",
+        "counter",
+        "Renders queued again after a used container crashed on them.",
+        load(&metrics.dirty_crash_requeues),
+    );
+    labelled(
+        &mut out,
+        "hacklily_render_timeouts_total",
+        "counter",
+        "Renders that missed their deadline.",
+        &[
+            ("location=\"local\"", load(&metrics.local_timeouts)),
+            ("location=\"remote\"", load(&snap.remote_timeouts)),
+        ],
+    );
+    scalar(
+        &mut out,
+        "hacklily_worker_connects_total",
+        "counter",
+        "Remote workers that registered.",
+        load(&metrics.worker_connects),
+    );
+    scalar(
+        &mut out,
+        "hacklily_worker_disconnects_total",
+        "counter",
+        "Remote workers that went away, evictions included.",
+        load(&metrics.worker_disconnects),
+    );
+    scalar(
+        &mut out,
+        "hacklily_workers_rejected_total",
+        "counter",
+        "Remote workers refused at registration.",
+        load(&snap.workers_rejected),
+    );
+    scalar(
+        &mut out,
+        "hacklily_workers_evicted_total",
+        "counter",
+        "Remote workers dropped for going silent.",
+        load(&snap.workers_evicted),
+    );
+    labelled(
+        &mut out,
+        "hacklily_ws_messages_total",
+        "counter",
+        "WebSocket messages, frontends and workers together.",
+        &[
+            ("direction=\"in\"", load(&snap.ws_messages_in)),
+            ("direction=\"out\"", load(&snap.ws_messages_out)),
+        ],
+    );
+    labelled(
+        &mut out,
+        "hacklily_ws_bytes_total",
+        "counter",
+        "WebSocket bytes as sent, after compression.",
+        &[
+            ("direction=\"in\"", load(&snap.ws_bytes_in)),
+            ("direction=\"out\"", load(&snap.ws_bytes_out)),
+        ],
+    );
+    scalar(
+        &mut out,
+        "hacklily_active_users",
+        "gauge",
+        "Connected frontends.",
+        load(&snap.active_users),
+    );
+    scalar(
+        &mut out,
+        "hacklily_uptime_seconds",
+        "gauge",
+        "Seconds since the coordinator started.",
+        status.uptime_secs(),
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_are_cumulative_and_labelled() {
+        let status = StatusHandle::new();
+        let metrics = &status.snapshot().metrics;
+        metrics.render_finished(
+            Backend::MusicXml2Ly,
+            Version::Unstable,
+            Location::Remote,
+            Duration::from_millis(300),
+        );
+        metrics.render_finished(
+            Backend::MusicXml2Ly,
+            Version::Unstable,
+            Location::Remote,
+            Duration::from_secs(90),
+        );
+        let text = exposition(&status);
+        let labels = "backend=\"musicxml2ly\",version=\"unstable\",location=\"remote\"";
+        for (le, count) in [("0.25", 0), ("0.5", 1), ("60", 1), ("+Inf", 2)] {
+            let line = format!(
+                "hacklily_render_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                labels, le, count
+            );
+            assert!(text.contains(&line), "missing {:?} in\n{}", line, text);
+        }
+        assert!(text.contains(&format!(
+            "hacklily_render_duration_seconds_count{{{}}} 2\n",
+            labels
+        )));
+        assert!(text.contains("hacklily_queue_wait_seconds_bucket{le=\"+Inf\"} 0\n"));
+        assert!(text.contains("# TYPE hacklily_render_timeouts_total counter\n"));
+    }
+}
@@ -6,7 +6,7 @@ use log::{debug, error, info, warn};
 use std::cmp::Ordering;
 use std::panic::AssertUnwindSafe;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::{Child, ChildStderr};
 use tokio::time::sleep;
@@ -14,6 +14,7 @@ use tokio::time::sleep;
 use crate::container::ContainerHandle;
 use crate::error::HacklilyError;
 use crate::request::{Backend, Request, Response, Version};
+use crate::status::{ContainerState, LocalContainerStatus};
 
 #[derive(Debug)]
 pub struct RendererMeta {
@@ -21,9 +22,29 @@ pub struct RendererMeta {
     pub version: Version,
     pub image: String,
     pub timeout: u64,
+    pub audio_max_bytes: u64,
     pub num_renders: u64,
 }
 
+impl RendererMeta {
+    /// This container in `state`, as published to `StatusSnapshot`.
+    pub fn to_status(
+        &self,
+        state: ContainerState,
+        request_id: Option<&str>,
+    ) -> LocalContainerStatus {
+        LocalContainerStatus {
+            id: self.id,
+            version: self.version,
+            image: self.image.clone(),
+            num_renders: self.num_renders,
+            state,
+            request_id: request_id.map(str::to_owned),
+            since: Instant::now(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ReadyRenderContainer {
     pub meta: RendererMeta,
@@ -73,7 +94,7 @@ async fn handle_request_impl(
     // Write the request to stdin.
     match &mut child.stdin {
         Some(stdin) => {
-            if request.backend == Backend::Svg {
+            let backend_option = match request.backend {
                 // SVG is rendered by the Cairo backend, which is ~15x faster
                 // than LilyPond's legacy 'svg backend (the latter re-emits
                 // every glyph as an inline outline path). Point-and-click
@@ -81,10 +102,22 @@ async fn handle_request_impl(
                 // the stock SVG surface silently drops CAIRO_TAG_LINK -- see
                 // the renderer Dockerfile. Kept in sync with hacklily:opt:svg
                 // in lily-server.scm.
-                request.src = "#(ly:set-option 'backend 'cairo)\n".to_owned() + &request.src;
-            } else if request.backend == Backend::Pdf {
+                Backend::Svg => Some("#(ly:set-option 'backend 'cairo)"),
                 // PDF is produced by the PS backend in LilyPond 2.27.
-                request.src = "#(ly:set-option 'backend 'ps)\n".to_owned() + &request.src;
+                Backend::Pdf => Some("#(ly:set-option 'backend 'ps)"),
+                Backend::MusicXml2Ly => None,
+            };
+            if let Some(backend_option) = backend_option {
+                // Everything we inject goes on ONE line: the frontend maps
+                // point-and-click locations back to the editor by
+                // subtracting exactly one line.
+                let options = request.options.preamble();
+                let preamble = if options.is_empty() {
+                    backend_option.to_owned()
+                } else {
+                    format!("{} {}", backend_option, options)
+                };
+                request.src = preamble + "\n" + &request.src;
             }
 
             let request_json = serde_json::to_string(&request)
@@ -176,12 +209,20 @@ async fn try_handle_request(
 
     match select(response, timeout).await {
         Either::Left((response, _)) => response,
-        Either::Right(_) => Err(HacklilyError::RenderError(
-            "Timeout: the container is unresponsive".to_owned(),
-        )),
+        Either::Right(_) => Err(HacklilyError::RenderTimeout),
     }
 }
 
+/// Approximate size of a base64 payload once decoded, ignoring the line
+/// wrapping `base64` adds.
+fn base64_decoded_len(encoded: &str) -> u64 {
+    let chars = encoded
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace() && *b != b'=')
+        .count() as u64;
+    chars * 3 / 4
+}
+
 // An error when we crashed, but we want to try again, because the container has been used
 // many times before, and so the crash could have been from previous renders.
 pub struct DirtyCrashError {}
@@ -215,32 +256,43 @@ impl ReadyRenderContainer {
         // container-oriented and requester-oriented values.
 
         let is_fresh_container = self.meta.num_renders == 0;
+        let audio_max_bytes = self.meta.audio_max_bytes;
 
         let result_copy = result.clone();
         let extract_result = async move {
             match result_copy.await {
                 Ok(result_copy) => match serde_json::from_str::<Response>(&result_copy.1) {
-                    Ok(result_copy) => Ok(Response {
-                        files: result_copy.files,
-                        logs: result_copy.logs,
-                        midi: result_copy.midi,
-                    }),
-                    Err(err) => Ok(Response {
-                        files: vec![],
-                        // TODO: in this case, we should kill the renderer!
-                        logs: "Could not parse response: ".to_owned() + &err.to_string(),
-                        midi: "".to_owned(),
-                    }),
+                    Ok(result_copy) => {
+                        let mut logs = result_copy.logs;
+                        let mut audio = result_copy.audio;
+                        // render-impl.bash already enforces the cap, but the
+                        // file it reads lives in a directory the user's
+                        // LilyPond code can write to, so check again here.
+                        if base64_decoded_len(&audio) > audio_max_bytes {
+                            audio = String::new();
+                            logs +=
+                                &format!("\naudio: dropped, larger than {} bytes", audio_max_bytes);
+                        }
+                        Ok(Response {
+                            files: result_copy.files,
+                            logs,
+                            midi: result_copy.midi,
+                            audio,
+                        })
+                    }
+                    // TODO: in this case, we should kill the renderer!
+                    Err(err) => Ok(Response::failure(format!(
+                        "Could not parse response: {}",
+                        err
+                    ))),
                 },
-                Err(HacklilyError::RenderError(_)) if !is_fresh_container => {
+                Err(HacklilyError::RenderError(_) | HacklilyError::RenderTimeout)
+                    if !is_fresh_container =>
+                {
                     warn!("Dirty crash. Will requeue.");
                     Err(DirtyCrashError {})
                 }
-                Err(err) => Ok(Response {
-                    files: vec![],
-                    logs: "Could not render file: ".to_owned() + &err.to_string(),
-                    midi: "".to_owned(),
-                }),
+                Err(err) => Ok(Response::failure(format!("Could not render file: {}", err))),
             }
         };
 
@@ -307,10 +359,15 @@ impl RenderContainer {
     pub fn new(meta: RendererMeta) -> RenderContainer {
         let image = meta.image.to_owned();
         let timeout = meta.timeout;
+        let audio_max_bytes = meta.audio_max_bytes;
 
         RenderContainer::Creating(
             meta,
-            FutureObj::new(Box::new(ContainerHandle::create(image, timeout))),
+            FutureObj::new(Box::new(ContainerHandle::create(
+                image,
+                timeout,
+                audio_max_bytes,
+            ))),
         )
     }
 
@@ -342,6 +399,7 @@ impl RenderContainer {
                         version: meta.version,
                         image: meta.image,
                         timeout: meta.timeout,
+                        audio_max_bytes: meta.audio_max_bytes,
                         num_renders: meta.num_renders + 1,
                     },
                     container,
@@ -360,6 +418,7 @@ impl RenderContainer {
                             version: meta.version,
                             image: meta.image,
                             timeout: meta.timeout,
+                            audio_max_bytes: meta.audio_max_bytes,
                             num_renders: 0,
                         },
                         err,
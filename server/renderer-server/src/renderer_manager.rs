@@ -16,6 +16,7 @@ use crate::error::HacklilyError;
 use crate::renderer::{
     ReadyRenderContainer, RenderContainer, RendererMeta, TerminalRenderContainer,
 };
+use crate::status::{ContainerState, StatusHandle};
 
 #[derive(Debug)]
 pub enum Command {
@@ -38,15 +39,31 @@ pub struct RendererManager {
     pub event_receiver: Receiver<Event>,
 }
 
-fn steal_lines(ready_container: &mut ReadyRenderContainer) {
+// lily-server.scm logs this, then the version, once it's listening.
+const LILYPOND_VERSION_MARKER: &str = "LilyPond version ";
+
+/// The version in a container's startup line, if `line` is that line.
+fn reported_lilypond_version(line: &str) -> Option<&str> {
+    let (_, version) = line.split_once(LILYPOND_VERSION_MARKER)?;
+    Some(version.trim()).filter(|version| !version.is_empty())
+}
+
+fn steal_lines(ready_container: &mut ReadyRenderContainer, status: &StatusHandle) {
     if let Some(stderr) = ready_container.take_stderr() {
         let id = ready_container.meta.id;
+        let version = ready_container.meta.version;
+        let status = status.clone();
         let mut stderr_lines = LinesStream::new(BufReader::new(stderr).lines());
 
         // NOTE: panics are ignored here.
         tokio::spawn(async move {
             while let Some(Ok(line)) = stderr_lines.next().await {
                 info!("stderr from {}: {}", id, &line);
+                if let Some(lilypond) = reported_lilypond_version(&line) {
+                    status
+                        .snapshot()
+                        .set_lilypond_version(id, version, lilypond);
+                }
             }
         });
     }
@@ -55,7 +72,9 @@ fn steal_lines(ready_container: &mut ReadyRenderContainer) {
 async fn emit_recycled_or_new_ready_container(
     source_container: RenderContainer,
     event_stream: Sender<Event>,
+    status: StatusHandle,
 ) {
+    let metrics = &status.snapshot().metrics;
     match source_container.next_terminal().await {
         TerminalRenderContainer::Ready(clean) => {
             event_stream
@@ -72,27 +91,51 @@ async fn emit_recycled_or_new_ready_container(
                 .expect("Renderer dropped.");
         }
 
-        TerminalRenderContainer::Dead(meta, _) | TerminalRenderContainer::Stopped(meta) => {
-            if let TerminalRenderContainer::Ready(mut clean) =
-                RenderContainer::new(meta).next_terminal().await
-            {
-                steal_lines(&mut clean);
-                event_stream
-                    .send(Event::ContainerReady(clean))
-                    .await
-                    .expect("Receiver dropped.");
-            } else {
-                error!("Could not recreate render container.");
-                event_stream
-                    .send(Event::Fatal)
-                    .await
-                    .expect("Receiver dropped.");
+        TerminalRenderContainer::Dead(meta, err) => {
+            status
+                .snapshot()
+                .set_local_container(meta.to_status(ContainerState::Dead, None));
+            StatusHandle::bump(&metrics.container_crashes);
+            if let HacklilyError::RenderTimeout = err {
+                StatusHandle::bump(&metrics.local_timeouts);
             }
+            replace_container(meta, event_stream, &status).await;
+        }
+
+        TerminalRenderContainer::Stopped(meta) => {
+            replace_container(meta, event_stream, &status).await;
         }
     }
 }
 
-async fn manager_event_loop(command_receiver: Receiver<Command>, event_sender: Sender<Event>) {
+/// Start a fresh container in place of one that died or stopped.
+async fn replace_container(meta: RendererMeta, event_stream: Sender<Event>, status: &StatusHandle) {
+    StatusHandle::bump(&status.snapshot().metrics.containers_created);
+    status
+        .snapshot()
+        .set_local_container(meta.to_status(ContainerState::Creating, None));
+    if let TerminalRenderContainer::Ready(mut clean) =
+        RenderContainer::new(meta).next_terminal().await
+    {
+        steal_lines(&mut clean, status);
+        event_stream
+            .send(Event::ContainerReady(clean))
+            .await
+            .expect("Receiver dropped.");
+    } else {
+        error!("Could not recreate render container.");
+        event_stream
+            .send(Event::Fatal)
+            .await
+            .expect("Receiver dropped.");
+    }
+}
+
+async fn manager_event_loop(
+    command_receiver: Receiver<Command>,
+    event_sender: Sender<Event>,
+    status: StatusHandle,
+) {
     let mut closed = false;
 
     let mut command_receiver = ReceiverStream::new(command_receiver);
@@ -100,7 +143,12 @@ async fn manager_event_loop(command_receiver: Receiver<Command>, event_sender: S
         match command {
             Command::CreateContainer(meta) => {
                 let event_sender = event_sender.clone();
+                status
+                    .snapshot()
+                    .set_local_container(meta.to_status(ContainerState::Creating, None));
                 let new_container = RenderContainer::new(meta);
+                StatusHandle::bump(&status.snapshot().metrics.containers_created);
+                let status = status.clone();
 
                 tokio::spawn(async move {
                     let emergency_event_sender = event_sender.clone();
@@ -109,7 +157,7 @@ async fn manager_event_loop(command_receiver: Receiver<Command>, event_sender: S
                         if let TerminalRenderContainer::Ready(mut clean) =
                             new_container.next_terminal().await
                         {
-                            steal_lines(&mut clean);
+                            steal_lines(&mut clean, &status);
                             event_sender
                                 .send(Event::ContainerReady(clean))
                                 .await
@@ -134,6 +182,7 @@ async fn manager_event_loop(command_receiver: Receiver<Command>, event_sender: S
             }
             Command::ReceiveContainer(command) => {
                 let event_sender = event_sender.clone();
+                let status = status.clone();
                 let was_closed_when_queued = closed;
 
                 tokio::spawn(async move {
@@ -141,7 +190,11 @@ async fn manager_event_loop(command_receiver: Receiver<Command>, event_sender: S
 
                     let f = async move {
                         if was_closed_when_queued {
-                            command.terminate().await;
+                            if let RenderContainer::Stopped(meta) = command.terminate().await {
+                                status.snapshot().set_local_container(
+                                    meta.to_status(ContainerState::Stopped, None),
+                                );
+                            }
                             event_sender
                                 .send(Event::ContainerTerminated)
                                 .await
@@ -150,7 +203,7 @@ async fn manager_event_loop(command_receiver: Receiver<Command>, event_sender: S
                             return;
                         }
 
-                        emit_recycled_or_new_ready_container(command, event_sender).await;
+                        emit_recycled_or_new_ready_container(command, event_sender, status).await;
                     };
                     if AssertUnwindSafe(f).catch_unwind().await.is_err() {
                         error!("FATAL: render job panicked.");
@@ -174,14 +227,16 @@ async fn manager_event_loop(command_receiver: Receiver<Command>, event_sender: S
 }
 
 impl RendererManager {
-    pub fn new() -> RendererManager {
+    /// Start the manager. `status` counts the containers it creates
+    /// and sees crash.
+    pub fn new(status: StatusHandle) -> RendererManager {
         let (command_sender, command_receiver) = channel(50);
         let (event_sender, event_receiver) = channel(50);
 
         tokio::spawn(async move {
             let emergency_event_sender = event_sender.clone();
             let f = async move {
-                manager_event_loop(command_receiver, event_sender).await;
+                manager_event_loop(command_receiver, event_sender, status).await;
             };
 
             if AssertUnwindSafe(f).catch_unwind().await.is_err() {
@@ -202,8 +257,19 @@ impl RendererManager {
     }
 }
 
-impl Default for RendererManager {
-    fn default() -> Self {
-        RendererManager::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_lilypond_version_in_the_startup_line() {
+        assert_eq!(
+            reported_lilypond_version("2026-07-02 09:30:45 (12): LilyPond version 2.26.0"),
+            Some("2.26.0")
+        );
+        assert_eq!(
+            reported_lilypond_version("2026-07-02 09:30:45 (12): Listening on port 1225"),
+            None
+        );
     }
 }
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Copy, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -9,6 +10,10 @@ pub enum Backend {
     MusicXml2Ly,
 }
 
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Svg, Backend::Pdf, Backend::MusicXml2Ly];
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Copy, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum Version {
@@ -22,6 +27,20 @@ pub struct Request {
     pub backend: Backend,
     pub version: Version,
     pub src: String,
+    #[serde(default, skip_serializing_if = "RenderOptions::is_default")]
+    pub options: RenderOptions,
+    /// Auxiliary files (path -> content) written beside hacklily.ly so
+    /// `src` can `\include` them. See `validate_files` for the rules.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<String, String>,
+}
+
+impl Request {
+    /// Everything a command source needs to check before queueing.
+    pub fn validate(&self) -> Result<(), String> {
+        self.options.validate(self.backend)?;
+        validate_files(&self.files, self.backend)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
@@ -30,4 +49,459 @@ pub struct Response {
     pub logs: String,
     // in base64
     pub midi: String,
+    // in base64, synthesized from `midi` when `RenderOptions::audio` is set
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub audio: String,
+}
+
+impl Response {
+    /// A render that produced nothing; `logs` says why.
+    pub fn failure(logs: impl Into<String>) -> Response {
+        Response {
+            files: vec![],
+            logs: logs.into(),
+            midi: String::new(),
+            audio: String::new(),
+        }
+    }
+
+    /// A render that produced one SVG page, for tests.
+    #[cfg(test)]
+    pub fn ok(logs: impl Into<String>) -> Response {
+        Response {
+            files: vec!["<svg/>".to_owned()],
+            ..Response::failure(logs)
+        }
+    }
+}
+
+/// Paper sizes accepted by `RenderOptions::paper_size`. This is the set
+/// LilyPond's `paper-alist` (scm/paper.scm) knows about; anything else
+/// is rejected so the name can be spliced into Scheme verbatim.
+#[rustfmt::skip]
+pub const PAPER_SIZES: &[&str] = &[
+    "a10", "a9", "a8", "a7", "a6", "a5", "a4", "a3", "a2", "a1", "a0",
+    "b10", "b9", "b8", "b7", "b6", "b5", "b4", "b3", "b2", "b1", "b0",
+    "c10", "c9", "c8", "c7", "c6", "c5", "c4", "c3", "c2", "c1", "c0",
+    "junior-legal", "executive", "letter", "legal", "ledger", "tabloid",
+    "11x17", "17x11", "government-letter", "government-legal", "philippine-legal",
+    "a4landscape", "a4portrait", "letterlandscape", "letterportrait",
+];
+
+pub const MIN_STAFF_SIZE: u8 = 10;
+pub const MAX_STAFF_SIZE: u8 = 40;
+pub const MAX_PAGE: u32 = 10_000;
+
+/// Limits on `Request::files`.
+pub const MAX_FILES: usize = 32;
+pub const MAX_FILE_PATH_LEN: usize = 128;
+pub const MAX_FILE_PATH_DEPTH: usize = 4;
+pub const MAX_FILES_TOTAL_BYTES: usize = 1024 * 1024;
+
+/// Check the auxiliary files of a request. Paths are relative to the
+/// directory holding hacklily.ly and may only use `[A-Za-z0-9._-]`
+/// components separated by `/`; no component may start with a dot (so
+/// no `..` or hidden files), and nothing may start with `hacklily`,
+/// since render-impl.bash owns those names for its own input and output.
+pub fn validate_files(files: &BTreeMap<String, String>, backend: Backend) -> Result<(), String> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    if backend == Backend::MusicXml2Ly {
+        return Err("files are not supported by the musicxml2ly backend".to_owned());
+    }
+    if files.len() > MAX_FILES {
+        return Err(format!("at most {} files are allowed", MAX_FILES));
+    }
+    let total: usize = files.values().map(String::len).sum();
+    if total > MAX_FILES_TOTAL_BYTES {
+        return Err(format!(
+            "files must not exceed {} bytes in total",
+            MAX_FILES_TOTAL_BYTES
+        ));
+    }
+    for path in files.keys() {
+        validate_file_path(path)
+            .map_err(|reason| format!("invalid file path {:?}: {}", path, reason))?;
+    }
+    Ok(())
+}
+
+fn validate_file_path(path: &str) -> Result<(), &'static str> {
+    if path.is_empty() || path.len() > MAX_FILE_PATH_LEN {
+        return Err("length out of range");
+    }
+    if path.starts_with("hacklily") {
+        return Err("reserved name");
+    }
+    let components: Vec<&str> = path.split('/').collect();
+    if components.len() > MAX_FILE_PATH_DEPTH {
+        return Err("nested too deeply");
+    }
+    for component in components {
+        if component.is_empty() || component.starts_with('.') {
+            return Err("empty or hidden component");
+        }
+        if !component
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+        {
+            return Err("unsupported character");
+        }
+    }
+    Ok(())
+}
+
+/// Which part of the engraved output to return.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Hash, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputMode {
+    /// Every page, one entry in `Response::files` per page (SVG) or a
+    /// single multi-page document (PDF).
+    #[default]
+    Pages,
+    /// Only the first system, as LilyPond's `-dpreview` produces it.
+    Preview,
+    /// The whole score on a single page cropped tightly to the music,
+    /// as LilyPond's `-dcrop` produces it. Meant for embedding snippets.
+    Crop,
+}
+
+/// Audio container to synthesize the MIDI output into.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum AudioFormat {
+    Ogg,
+    Mp3,
+}
+
+/// An inclusive, 1-based range of pages to return.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct PageRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+/// Render settings that would otherwise require editing the LilyPond
+/// source. Everything here is turned into a fixed, validated preamble in
+/// `renderer::handle_request_impl` (or, for `page_range`, applied by
+/// render-impl.bash when packaging the output), so no user-controlled
+/// text is ever evaluated as Scheme.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct RenderOptions {
+    /// One of `PAPER_SIZES`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paper_size: Option<String>,
+    /// Rotate `paper_size` to landscape. Requires `paper_size`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub landscape: bool,
+    /// Global staff size in points.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staff_size: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_range: Option<PageRange>,
+    /// Force point-and-click links on or off. `None` keeps LilyPond's
+    /// default (on).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_and_click: Option<bool>,
+    #[serde(skip_serializing_if = "OutputMode::is_pages")]
+    pub mode: OutputMode,
+    /// Also synthesize the score's MIDI into `Response::audio`. Done in
+    /// the container by render-impl.bash, inside the same render timeout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioFormat>,
+}
+
+impl OutputMode {
+    fn is_pages(&self) -> bool {
+        *self == OutputMode::Pages
+    }
+}
+
+impl RenderOptions {
+    pub fn is_default(&self) -> bool {
+        *self == RenderOptions::default()
+    }
+
+    /// Check that every option is in range. Called before a request is
+    /// queued; `preamble` additionally refuses to emit anything it
+    /// doesn't recognise, so an unvalidated request can't inject code.
+    pub fn validate(&self, backend: Backend) -> Result<(), String> {
+        if self.is_default() {
+            return Ok(());
+        }
+        if backend == Backend::MusicXml2Ly {
+            return Err("render options are not supported by the musicxml2ly backend".to_owned());
+        }
+        if let Some(paper_size) = &self.paper_size {
+            if !PAPER_SIZES.contains(&paper_size.as_str()) {
+                return Err(format!("unknown paper size: {:?}", paper_size));
+            }
+        } else if self.landscape {
+            return Err("landscape requires paperSize".to_owned());
+        }
+        if let Some(staff_size) = self.staff_size {
+            if !(MIN_STAFF_SIZE..=MAX_STAFF_SIZE).contains(&staff_size) {
+                return Err(format!(
+                    "staffSize must be between {} and {}",
+                    MIN_STAFF_SIZE, MAX_STAFF_SIZE
+                ));
+            }
+        }
+        if let Some(range) = self.page_range {
+            if range.first == 0 || range.first > range.last || range.last > MAX_PAGE {
+                return Err(format!(
+                    "pageRange must satisfy 1 <= first <= last <= {}",
+                    MAX_PAGE
+                ));
+            }
+            if self.mode != OutputMode::Pages {
+                return Err("pageRange only applies to the pages output mode".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Scheme to prepend to the source, without a trailing newline.
+    ///
+    /// This is emitted on the same line as the backend selection, because
+    /// the frontend assumes exactly one injected line when mapping
+    /// point-and-click locations back to the editor.
+    pub fn preamble(&self) -> String {
+        let mut preamble = Vec::new();
+        if let Some(paper_size) = &self.paper_size {
+            if PAPER_SIZES.contains(&paper_size.as_str()) {
+                if self.landscape {
+                    preamble.push(format!(
+                        "#(set-default-paper-size \"{}\" 'landscape)",
+                        paper_size
+                    ));
+                } else {
+                    preamble.push(format!("#(set-default-paper-size \"{}\")", paper_size));
+                }
+            }
+        }
+        if let Some(staff_size) = self.staff_size {
+            preamble.push(format!("#(set-global-staff-size {})", staff_size));
+        }
+        match self.point_and_click {
+            Some(true) => preamble.push("#(ly:set-option 'point-and-click #t)".to_owned()),
+            Some(false) => preamble.push("#(ly:set-option 'point-and-click #f)".to_owned()),
+            None => {}
+        }
+        // With print-pages off, the preview/cropped file is the only
+        // output LilyPond writes, so render-impl.bash's hacklily*.<ext>
+        // glob picks it up without knowing about the mode.
+        match self.mode {
+            OutputMode::Pages => {}
+            OutputMode::Preview => {
+                preamble.push("#(ly:set-option 'preview #t)".to_owned());
+                preamble.push("#(ly:set-option 'print-pages #f)".to_owned());
+            }
+            OutputMode::Crop => {
+                preamble.push("#(ly:set-option 'crop #t)".to_owned());
+                preamble.push("#(ly:set-option 'print-pages #f)".to_owned());
+            }
+        }
+        preamble.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_default_when_omitted() {
+        let r: Request =
+            serde_json::from_str(r#"{"id":"1","backend":"svg","version":"stable","src":"c4"}"#)
+                .expect("parse");
+        assert!(r.options.is_default());
+        // And are omitted again on the way out, so older workers and
+        // containers see the same wire format as before.
+        assert!(!serde_json::to_string(&r).unwrap().contains("options"));
+    }
+
+    #[test]
+    fn options_parse_camel_case() {
+        let o: RenderOptions = serde_json::from_str(
+            r#"{"paperSize":"a5","landscape":true,"staffSize":16,"pageRange":{"first":2,"last":3},"pointAndClick":false}"#,
+        )
+        .expect("parse");
+        assert_eq!(o.paper_size.as_deref(), Some("a5"));
+        assert!(o.landscape);
+        assert_eq!(o.staff_size, Some(16));
+        assert_eq!(o.page_range, Some(PageRange { first: 2, last: 3 }));
+        assert_eq!(o.point_and_click, Some(false));
+        assert!(o.validate(Backend::Svg).is_ok());
+    }
+
+    #[test]
+    fn options_reject_unknown_fields() {
+        assert!(serde_json::from_str::<RenderOptions>(r#"{"scheme":"(system \"rm\")"}"#).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_unknown_paper_size() {
+        let o = RenderOptions {
+            paper_size: Some("a4\") (system \"rm -rf /".to_owned()),
+            ..RenderOptions::default()
+        };
+        assert!(o.validate(Backend::Svg).is_err());
+        // Even if validation were skipped, nothing is emitted for it.
+        assert_eq!(o.preamble(), "");
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_values() {
+        let staff = RenderOptions {
+            staff_size: Some(200),
+            ..RenderOptions::default()
+        };
+        assert!(staff.validate(Backend::Svg).is_err());
+
+        let range = RenderOptions {
+            page_range: Some(PageRange { first: 3, last: 2 }),
+            ..RenderOptions::default()
+        };
+        assert!(range.validate(Backend::Pdf).is_err());
+
+        let landscape = RenderOptions {
+            landscape: true,
+            ..RenderOptions::default()
+        };
+        assert!(landscape.validate(Backend::Svg).is_err());
+
+        let musicxml = RenderOptions {
+            staff_size: Some(20),
+            ..RenderOptions::default()
+        };
+        assert!(musicxml.validate(Backend::MusicXml2Ly).is_err());
+    }
+
+    #[test]
+    fn preamble_is_a_single_line() {
+        let o = RenderOptions {
+            paper_size: Some("letter".to_owned()),
+            landscape: true,
+            staff_size: Some(18),
+            point_and_click: Some(false),
+            mode: OutputMode::Preview,
+            ..RenderOptions::default()
+        };
+        let preamble = o.preamble();
+        assert!(!preamble.contains('\n'));
+        assert_eq!(
+            preamble,
+            "#(set-default-paper-size \"letter\" 'landscape) \
+             #(set-global-staff-size 18) \
+             #(ly:set-option 'point-and-click #f) \
+             #(ly:set-option 'preview #t) \
+             #(ly:set-option 'print-pages #f)"
+        );
+    }
+
+    #[test]
+    fn audio_is_not_injected_into_source() {
+        let o: RenderOptions = serde_json::from_str(r#"{"audio":"ogg"}"#).expect("parse");
+        assert_eq!(o.audio, Some(AudioFormat::Ogg));
+        assert!(o.validate(Backend::Svg).is_ok());
+        assert_eq!(o.preamble(), "");
+    }
+
+    #[test]
+    fn response_audio_is_optional_on_the_wire() {
+        let r: Response =
+            serde_json::from_str(r#"{"files":[],"logs":"","midi":""}"#).expect("parse");
+        assert!(r.audio.is_empty());
+        assert!(!serde_json::to_string(&r).unwrap().contains("audio"));
+    }
+
+    #[test]
+    fn crop_mode_parses_and_disables_pages() {
+        let o: RenderOptions = serde_json::from_str(r#"{"mode":"crop"}"#).expect("parse");
+        assert_eq!(o.mode, OutputMode::Crop);
+        assert!(o.validate(Backend::Pdf).is_ok());
+        assert_eq!(
+            o.preamble(),
+            "#(ly:set-option 'crop #t) #(ly:set-option 'print-pages #f)"
+        );
+
+        let with_range = RenderOptions {
+            mode: OutputMode::Crop,
+            page_range: Some(PageRange { first: 1, last: 1 }),
+            ..RenderOptions::default()
+        };
+        assert!(with_range.validate(Backend::Svg).is_err());
+    }
+
+    fn files(paths: &[&str]) -> BTreeMap<String, String> {
+        paths
+            .iter()
+            .map(|p| (p.to_string(), "\\version \"2.26.0\"".to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn files_parse_and_are_optional_on_the_wire() {
+        let r: Request = serde_json::from_str(
+            r#"{"id":"1","backend":"svg","version":"stable","src":"\\include \"parts/a.ily\"","files":{"parts/a.ily":"c4"}}"#,
+        )
+        .expect("parse");
+        assert_eq!(r.files.get("parts/a.ily").map(String::as_str), Some("c4"));
+        assert!(r.validate().is_ok());
+
+        let plain = Request {
+            files: BTreeMap::new(),
+            ..r
+        };
+        assert!(!serde_json::to_string(&plain).unwrap().contains("files"));
+    }
+
+    #[test]
+    fn validate_files_accepts_nested_paths() {
+        let ok = files(&["style.ily", "parts/violin-1.ily", "a/b/c/d.ly"]);
+        assert!(validate_files(&ok, Backend::Svg).is_ok());
+    }
+
+    #[test]
+    fn validate_files_rejects_escaping_paths() {
+        for path in [
+            "../etc/passwd",
+            "/etc/passwd",
+            "parts/../../x.ily",
+            ".hidden",
+            "parts//a.ily",
+            "parts/",
+            "",
+            "hacklily.ly",
+            "hacklily-1.svg",
+            "a b.ily",
+            "a\nb.ily",
+            "C:\\x.ily",
+            "a/b/c/d/e.ily",
+        ] {
+            assert!(
+                validate_files(&files(&[path]), Backend::Svg).is_err(),
+                "{:?} should be rejected",
+                path
+            );
+        }
+        let long = "a".repeat(MAX_FILE_PATH_LEN + 1);
+        assert!(validate_files(&files(&[&long]), Backend::Svg).is_err());
+    }
+
+    #[test]
+    fn validate_files_enforces_limits() {
+        let names: Vec<String> = (0..=MAX_FILES).map(|i| format!("f{}.ily", i)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        assert!(validate_files(&files(&names), Backend::Svg).is_err());
+
+        let mut big = BTreeMap::new();
+        big.insert("big.ily".to_owned(), "%".repeat(MAX_FILES_TOTAL_BYTES + 1));
+        assert!(validate_files(&big, Backend::Pdf).is_err());
+
+        assert!(validate_files(&files(&["a.ily"]), Backend::MusicXml2Ly).is_err());
+    }
 }
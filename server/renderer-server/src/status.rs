@@ -10,16 +10,27 @@
 // live in `worker_registry::WorkerRegistryHandle`, and the analytics /
 // active-user counts live in the coordinator. `StatusSnapshot` is the
 // single place all three subsystems publish their numbers, and the
-// coordinator reads it when answering `get_status`.
+// coordinator reads it when answering `get_status`. In a `cluster`,
+// the heartbeats of the other coordinators land here too.
 //
-// All fields are `AtomicU64` so writers in different tasks never block
+// All counters are `AtomicU64` so writers in different tasks never block
 // each other and the reader (a frontend connection task) never blocks
-// writers. `StatusHandle` is a cheap `Arc` clone handed to every
-// subsystem at startup (in `main.rs`).
+// writers. The per-worker list is the exception: the registry swaps in
+// a fresh `Vec` under a mutex nobody holds for longer than that.
+// `StatusHandle` is a cheap `Arc` clone handed to every subsystem at
+// startup (in `main.rs`).
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use crate::cluster::NodeStatus;
+use crate::history::History;
+use crate::metrics::Metrics;
+use crate::request::Version;
+
 /// Live operational state, shared between the coordinator, the event
 /// loop, and the worker registry. Each field is owned by exactly one
 /// writer subsystem; the coordinator reads them all in `get_status`.
@@ -29,15 +40,50 @@ pub struct StatusSnapshot {
     pub local_busy: AtomicU64,
     pub local_free: AtomicU64,
     pub backlog: AtomicU64,
+    /// Smoothed latency of local renders in ms, or 0 before the first.
+    pub local_latency_msec: AtomicU64,
+    /// The images local containers (and a `ws-worker`'s) are started from.
+    pub docker_tags: Mutex<DockerTags>,
+    // --- renderer manager ---
+    /// What each local container's LilyPond said it was, by container id.
+    pub lilypond_versions: Mutex<Vec<ContainerLilyPond>>,
+    // --- renderer manager + event loop ---
+    /// Each local container's place in the `RenderContainer` state
+    /// machine, by container id. Only `get_status_detail` lists these.
+    pub local_containers: Mutex<BTreeMap<i8, LocalContainerStatus>>,
     // --- worker registry (remote workers) ---
     pub remote_total: AtomicU64,
     pub remote_busy: AtomicU64,
     pub remote_free: AtomicU64,
+    /// `i_haz_computes` attempts refused by `worker_auth`.
+    pub workers_rejected: AtomicU64,
+    /// Remote renders taken back after `worker_registry`'s deadline.
+    pub remote_timeouts: AtomicU64,
+    /// Workers dropped for going silent past the heartbeat limit.
+    pub workers_evicted: AtomicU64,
+    /// Dispatch stats of each connected worker, ordered by id.
+    pub remote_workers: Mutex<Vec<RemoteWorkerStatus>>,
+    // --- cluster ---
+    /// The latest heartbeat of each live coordinator, this one included.
+    pub cluster_nodes: Mutex<Vec<NodeStatus>>,
     // --- coordinator (clients + analytics) ---
     pub active_users: AtomicU64,
     pub analytics_renders: AtomicU64,
     pub analytics_saves: AtomicU64,
     pub analytics_sign_in: AtomicU64,
+    // --- coordinator (WebSocket traffic, frontends and workers) ---
+    /// Bytes as they crossed the socket, after `wire` compression.
+    pub ws_bytes_in: AtomicU64,
+    pub ws_bytes_out: AtomicU64,
+    /// The same messages uncompressed, so the two show the savings.
+    pub ws_payload_bytes_in: AtomicU64,
+    pub ws_payload_bytes_out: AtomicU64,
+    pub ws_messages_in: AtomicU64,
+    pub ws_messages_out: AtomicU64,
+    // --- everyone: what only `GET /metrics` reports ---
+    pub metrics: Metrics,
+    // --- event loop: the last minute, hour and day ---
+    pub history: History,
     // --- immutable ---
     startup_instant: Instant,
     startup_unix: u64,
@@ -49,14 +95,141 @@ impl StatusSnapshot {
         self.startup_instant.elapsed().as_secs()
     }
 
-    /// Startup time as a coarse string. The Qt server emitted
-    /// `QDateTime::toString(Qt::ISODate)` (e.g. `2026-07-02T...`);
-    /// without a chrono dependency we fall back to a unix epoch
-    /// marker, which the frontend status page displays verbatim in a
-    /// `<pre>`. The `uptime_secs` field carries the precise value.
+    /// Startup time in ISO 8601, as the Qt server's
+    /// `QDateTime::toString(Qt::ISODate)` emitted it, but in UTC.
     pub fn startup_time(&self) -> String {
-        format!("unix:{}", self.startup_unix)
+        iso8601(self.startup_unix)
+    }
+
+    /// Record the LilyPond a local container reported, replacing what
+    /// an earlier container with the same id said.
+    pub fn set_lilypond_version(&self, id: i8, version: Version, lilypond: &str) {
+        let mut versions = self.lilypond_versions.lock().expect("status lock poisoned");
+        versions.retain(|container| container.id != id);
+        versions.push(ContainerLilyPond {
+            id,
+            version,
+            lilypond: lilypond.to_owned(),
+        });
+        versions.sort_by_key(|container| container.id);
+    }
+
+    /// Record what a local container is doing, in place of whatever
+    /// the container with its id did before.
+    pub fn set_local_container(&self, container: LocalContainerStatus) {
+        self.local_containers
+            .lock()
+            .expect("status lock poisoned")
+            .insert(container.id, container);
+    }
+
+    /// Every local container, as `get_status_detail` lists them.
+    pub fn local_containers_detail(&self) -> Value {
+        let lilypond: BTreeMap<i8, String> = self
+            .lilypond_versions
+            .lock()
+            .expect("status lock poisoned")
+            .iter()
+            .map(|container| (container.id, container.lilypond.clone()))
+            .collect();
+        let containers = self.local_containers.lock().expect("status lock poisoned");
+        Value::Array(
+            containers
+                .values()
+                .map(|container| {
+                    let age_msec = container.since.elapsed().as_millis() as u64;
+                    json!({
+                        "id": container.id,
+                        "version": container.version,
+                        "image": container.image,
+                        "num_renders": container.num_renders,
+                        "state": container.state,
+                        "state_msec": age_msec,
+                        "request_id": container.request_id,
+                        "request_age_msec": container.request_id.as_ref().map(|_| age_msec),
+                        "lilypond": lilypond.get(&container.id),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// A copy of `remote_workers`.
+    pub fn remote_workers(&self) -> Vec<RemoteWorkerStatus> {
+        self.remote_workers
+            .lock()
+            .expect("status lock poisoned")
+            .clone()
     }
+
+    /// A copy of `cluster_nodes`.
+    pub fn cluster_nodes(&self) -> Vec<NodeStatus> {
+        self.cluster_nodes
+            .lock()
+            .expect("status lock poisoned")
+            .clone()
+    }
+}
+
+/// The configured image of each LilyPond version.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DockerTags {
+    pub stable: String,
+    pub unstable: String,
+}
+
+/// One local container's LilyPond, as listed in `get_status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ContainerLilyPond {
+    pub id: i8,
+    pub version: Version,
+    /// As the container printed it on startup, e.g. `2.26.0`.
+    pub lilypond: String,
+}
+
+/// The states of `renderer::RenderContainer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContainerState {
+    Creating,
+    Ready,
+    Busy,
+    Dead,
+    Stopped,
+}
+
+/// One local container, as last published.
+#[derive(Clone, Debug)]
+pub struct LocalContainerStatus {
+    pub id: i8,
+    pub version: Version,
+    pub image: String,
+    /// Renders finished by this container (not by ones before it).
+    pub num_renders: u64,
+    pub state: ContainerState,
+    /// The render a `Busy` container is on.
+    pub request_id: Option<String>,
+    /// When the container entered `state`.
+    pub since: Instant,
+}
+
+/// One remote worker as listed in `get_status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RemoteWorkerStatus {
+    pub id: String,
+    pub slots: u64,
+    pub busy: u64,
+    /// Slots held by renders that timed out and haven't been answered.
+    pub quarantined: u64,
+    pub completed: u64,
+    pub failed: u64,
+    /// Smoothed latency of successful renders, if any have finished.
+    pub latency_msec: Option<u64>,
+    pub failure_rate: f64,
+    /// Time since the worker last sent anything, as of the last ping.
+    pub silent_msec: u64,
+    /// Going out of service: finishing its renders, taking no new ones.
+    pub draining: bool,
 }
 
 /// Cloneable handle. Cheap to clone (one `Arc`).
@@ -83,13 +256,30 @@ impl StatusHandle {
                 local_busy: AtomicU64::new(0),
                 local_free: AtomicU64::new(0),
                 backlog: AtomicU64::new(0),
+                local_latency_msec: AtomicU64::new(0),
+                docker_tags: Mutex::new(DockerTags::default()),
+                lilypond_versions: Mutex::new(vec![]),
+                local_containers: Mutex::new(BTreeMap::new()),
                 remote_total: AtomicU64::new(0),
                 remote_busy: AtomicU64::new(0),
                 remote_free: AtomicU64::new(0),
+                workers_rejected: AtomicU64::new(0),
+                remote_timeouts: AtomicU64::new(0),
+                workers_evicted: AtomicU64::new(0),
+                remote_workers: Mutex::new(vec![]),
+                cluster_nodes: Mutex::new(vec![]),
                 active_users: AtomicU64::new(0),
                 analytics_renders: AtomicU64::new(0),
                 analytics_saves: AtomicU64::new(0),
                 analytics_sign_in: AtomicU64::new(0),
+                ws_bytes_in: AtomicU64::new(0),
+                ws_bytes_out: AtomicU64::new(0),
+                ws_payload_bytes_in: AtomicU64::new(0),
+                ws_payload_bytes_out: AtomicU64::new(0),
+                ws_messages_in: AtomicU64::new(0),
+                ws_messages_out: AtomicU64::new(0),
+                metrics: Metrics::default(),
+                history: History::default(),
                 startup_instant: Instant::now(),
                 startup_unix,
             }),
@@ -111,6 +301,53 @@ impl StatusHandle {
         self.inner.startup_time()
     }
 
+    /// The `get_status` result, as served over both the WebSocket RPC
+    /// and HTTP.
+    pub fn get_status(&self) -> Value {
+        let snap = self.snapshot();
+        let local_total = snap.local_total.load(Ordering::Relaxed);
+        let remote_total = snap.remote_total.load(Ordering::Relaxed);
+        let total = local_total + remote_total;
+        let busy =
+            snap.local_busy.load(Ordering::Relaxed) + snap.remote_busy.load(Ordering::Relaxed);
+        let free =
+            snap.local_free.load(Ordering::Relaxed) + snap.remote_free.load(Ordering::Relaxed);
+        json!({
+            "alive": total > 0,
+            "total_worker_count": total,
+            "local_worker_count": local_total,
+            "remote_worker_count": remote_total,
+            "busy_worker_count": busy,
+            "free_worker_count": free,
+            "backlog": snap.backlog.load(Ordering::Relaxed),
+            "workers_rejected": snap.workers_rejected.load(Ordering::Relaxed),
+            "remote_timeouts": snap.remote_timeouts.load(Ordering::Relaxed),
+            "workers_evicted": snap.workers_evicted.load(Ordering::Relaxed),
+            "local_latency_msec": snap.local_latency_msec.load(Ordering::Relaxed),
+            "remote_workers": snap.remote_workers(),
+            "cluster": snap.cluster_nodes(),
+            "startup_time": self.startup_time(),
+            "build": {
+                "version": env!("CARGO_PKG_VERSION"),
+                "git_hash": env!("HACKLILY_GIT_HASH"),
+            },
+            "docker_tags": snap.docker_tags.lock().expect("status lock poisoned").clone(),
+            "lilypond_versions": snap.lilypond_versions.lock().expect("status lock poisoned").clone(),
+            "uptime_secs": self.uptime_secs(),
+            "current_active_users": snap.active_users.load(Ordering::Relaxed),
+            "analytics_renders": snap.analytics_renders.load(Ordering::Relaxed),
+            "analytics_saves": snap.analytics_saves.load(Ordering::Relaxed),
+            "analytics_sign_in": snap.analytics_sign_in.load(Ordering::Relaxed),
+            "ws_bytes_in": snap.ws_bytes_in.load(Ordering::Relaxed),
+            "ws_bytes_out": snap.ws_bytes_out.load(Ordering::Relaxed),
+            "ws_payload_bytes_in": snap.ws_payload_bytes_in.load(Ordering::Relaxed),
+            "ws_payload_bytes_out": snap.ws_payload_bytes_out.load(Ordering::Relaxed),
+            "ws_messages_in": snap.ws_messages_in.load(Ordering::Relaxed),
+            "ws_messages_out": snap.ws_messages_out.load(Ordering::Relaxed),
+            "history": snap.history.to_json(),
+        })
+    }
+
     /// Convenience: atomically increment a counter and return the new
     /// value, mirroring the old `SharedState::bump` helper.
     pub fn bump(counter: &AtomicU64) -> u64 {
@@ -118,6 +355,32 @@ impl StatusHandle {
     }
 }
 
+/// `unix_secs` as an ISO 8601 UTC timestamp, e.g.
+/// `2026-07-02T09:30:00Z`.
+pub fn iso8601(unix_secs: u64) -> String {
+    let days = (unix_secs / 86400) as i64;
+    let secs = unix_secs % 86400;
+    // Howard Hinnant's `civil_from_days`, for days since 1970-01-01.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,9 +403,59 @@ mod tests {
     }
 
     #[test]
-    fn startup_time_is_unix_marked() {
+    fn timestamps_are_iso8601() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601(1_782_984_645), "2026-07-02T09:30:45Z");
         let h = StatusHandle::new();
-        assert!(h.snapshot().startup_time().starts_with("unix:"));
+        let startup = h.snapshot().startup_time();
+        assert_eq!(startup.len(), "2026-07-02T09:30:45Z".len(), "{}", startup);
+        assert!(startup.ends_with('Z'));
     }
-}
 
+    #[test]
+    fn local_containers_are_listed_by_id_with_their_request() {
+        let h = StatusHandle::new();
+        let snap = h.snapshot();
+        let container = |id, state, request_id: Option<&str>| LocalContainerStatus {
+            id,
+            version: Version::Stable,
+            image: "hacklily-renderer".to_owned(),
+            num_renders: 3,
+            state,
+            request_id: request_id.map(str::to_owned),
+            since: Instant::now(),
+        };
+        snap.set_local_container(container(1, ContainerState::Creating, None));
+        snap.set_local_container(container(0, ContainerState::Ready, None));
+        snap.set_local_container(container(1, ContainerState::Busy, Some("r1")));
+        snap.set_lilypond_version(1, Version::Stable, "2.26.0");
+        let detail = snap.local_containers_detail();
+        assert_eq!(detail[0]["state"], json!("ready"));
+        assert_eq!(detail[0]["request_age_msec"], Value::Null);
+        assert_eq!(detail[0]["lilypond"], Value::Null);
+        assert_eq!(detail[1]["state"], json!("busy"));
+        assert_eq!(detail[1]["request_id"], json!("r1"));
+        assert!(detail[1]["request_age_msec"].is_u64());
+        assert_eq!(detail[1]["lilypond"], json!("2.26.0"));
+        assert_eq!(detail.as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn lilypond_versions_are_replaced_by_id() {
+        let h = StatusHandle::new();
+        let snap = h.snapshot();
+        snap.set_lilypond_version(1, Version::Unstable, "2.27.1");
+        snap.set_lilypond_version(0, Version::Stable, "2.24.4");
+        snap.set_lilypond_version(0, Version::Stable, "2.26.0");
+        let status = h.get_status();
+        assert_eq!(
+            status["lilypond_versions"],
+            json!([
+                {"id": 0, "version": "stable", "lilypond": "2.26.0"},
+                {"id": 1, "version": "unstable", "lilypond": "2.27.1"},
+            ])
+        );
+        assert_eq!(status["build"]["version"], json!(env!("CARGO_PKG_VERSION")));
+    }
+}
@@ -0,0 +1,477 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// Native TLS for the coordinator's listeners, and client certificates
+// for `ws-worker`.
+//
+// By default the coordinator serves plain `ws://` behind a
+// TLS-terminating reverse proxy. With `serve --tls-cert/--tls-key` it
+// terminates TLS itself, on the WebSocket listeners and the HTTP status
+// listener alike. While the coordinator runs, a task checks the
+// certificate files every `RELOAD_INTERVAL` and reloads them when they
+// change, so a renewed certificate (e.g. from certbot) is picked up
+// without a restart; if the new files don't load, the old certificate
+// stays in use. Accepting a connection never touches the filesystem.
+//
+// With `--tls-client-ca`, workers must also present a client
+// certificate signed by that CA (mutual TLS). Frontends never need one:
+// a separate worker listener requires a certificate, the frontend
+// listener doesn't ask for one, and a shared listener accepts either
+// and leaves it to the coordinator to turn away `i_haz_computes` from a
+// peer without one. Accepting either still means sending every peer a
+// CertificateRequest, and some browsers answer it by prompting the user
+// to pick a certificate; give workers their own `--worker-port` to keep
+// frontends clear of it.
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+/// How often `TlsAcceptorHandle::watch` checks the files for changes.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a client gets to finish the TLS handshake before the
+/// connection is dropped, so idle sockets can't pile up.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The coordinator's certificate, key and optional client CA, as PEM
+/// files.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+/// What a listener asks of the client's certificate. Without a client
+/// CA, all three are the same: no client certificate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuth {
+    None,
+    Optional,
+    Required,
+}
+
+/// A `TlsFiles`, loaded, and reloaded when the files change. Cheap to
+/// clone; clones share the loaded certificate.
+#[derive(Clone)]
+pub struct TlsAcceptorHandle {
+    files: Arc<TlsFiles>,
+    loaded: Arc<Mutex<Loaded>>,
+}
+
+struct Loaded {
+    mtimes: Vec<Option<SystemTime>>,
+    none: TlsAcceptor,
+    optional: TlsAcceptor,
+    required: TlsAcceptor,
+    /// Bumped on every successful reload.
+    generation: u64,
+}
+
+impl TlsAcceptorHandle {
+    /// Load the certificate and key, failing if they don't parse.
+    pub fn load(files: TlsFiles) -> Result<TlsAcceptorHandle, String> {
+        let loaded = Loaded::load(&files, 0)?;
+        Ok(TlsAcceptorHandle {
+            files: Arc::new(files),
+            loaded: Arc::new(Mutex::new(loaded)),
+        })
+    }
+
+    /// Whether workers must present a client certificate.
+    pub fn verifies_clients(&self) -> bool {
+        self.files.client_ca.is_some()
+    }
+
+    /// Reload the files every `interval` while the returned task runs,
+    /// if they changed.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()> {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                handle.reload_if_changed().await;
+            }
+        })
+    }
+
+    /// Reload the files if they changed since they were last loaded.
+    pub async fn reload_if_changed(&self) {
+        let mtimes = current_mtimes(&self.files).await;
+        let generation = {
+            let loaded = self.lock();
+            if mtimes == loaded.mtimes {
+                return;
+            }
+            loaded.generation + 1
+        };
+        let files = self.files.clone();
+        let reloaded = tokio::task::spawn_blocking(move || Loaded::load(&files, generation))
+            .await
+            .unwrap_or_else(|e| Err(format!("reload panicked: {}", e)));
+        let mut loaded = self.lock();
+        match reloaded {
+            Ok(reloaded) => {
+                info!("TLS: reloaded {}", self.files.cert.display());
+                *loaded = reloaded;
+            }
+            Err(e) => {
+                // Don't retry until the files change again.
+                warn!("TLS: keeping the previous certificate: {}", e);
+                loaded.mtimes = mtimes;
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Loaded> {
+        self.loaded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The acceptor for a listener asking `auth` of clients.
+    pub fn acceptor(&self, auth: ClientAuth) -> TlsAcceptor {
+        let loaded = self.lock();
+        match auth {
+            ClientAuth::None => loaded.none.clone(),
+            ClientAuth::Optional => loaded.optional.clone(),
+            ClientAuth::Required => loaded.required.clone(),
+        }
+    }
+
+    /// Run the TLS handshake on a freshly accepted connection.
+    pub async fn accept(&self, stream: TcpStream, auth: ClientAuth) -> io::Result<ServerStream> {
+        let tls = self.acceptor(auth).accept(stream).await?;
+        Ok(ServerStream::Tls(Box::new(tls)))
+    }
+
+    #[cfg(test)]
+    fn generation(&self) -> u64 {
+        self.loaded.lock().unwrap().generation
+    }
+}
+
+impl Loaded {
+    fn load(files: &TlsFiles, generation: u64) -> Result<Loaded, String> {
+        // Read the modification times first: if a file changes while
+        // loading, the next check sees a newer time and loads again.
+        let mtimes = mtimes(files);
+        let certs = load_certs(&files.cert)?;
+        let key = load_key(&files.key)?;
+        let server_config = |auth: ClientAuth| -> Result<TlsAcceptor, String> {
+            let builder = ServerConfig::builder().with_safe_defaults();
+            let builder = match (&files.client_ca, auth) {
+                (Some(ca), ClientAuth::Optional) => builder.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(ca)?),
+                ),
+                (Some(ca), ClientAuth::Required) => builder
+                    .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?)),
+                _ => builder.with_no_client_auth(),
+            };
+            let config = builder
+                .with_single_cert(certs.clone(), key.clone())
+                .map_err(|e| format!("{}: {}", files.key.display(), e))?;
+            Ok(TlsAcceptor::from(Arc::new(config)))
+        };
+        Ok(Loaded {
+            mtimes,
+            none: server_config(ClientAuth::None)?,
+            optional: server_config(ClientAuth::Optional)?,
+            required: server_config(ClientAuth::Required)?,
+            generation,
+        })
+    }
+}
+
+fn paths(files: &TlsFiles) -> impl Iterator<Item = &PathBuf> {
+    [
+        Some(&files.cert),
+        Some(&files.key),
+        files.client_ca.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+fn mtimes(files: &TlsFiles) -> Vec<Option<SystemTime>> {
+    paths(files)
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// `mtimes`, without blocking the runtime.
+async fn current_mtimes(files: &TlsFiles) -> Vec<Option<SystemTime>> {
+    let mut mtimes = vec![];
+    for path in paths(files) {
+        let metadata = tokio::fs::metadata(path).await;
+        mtimes.push(metadata.and_then(|m| m.modified()).ok());
+    }
+    mtimes
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+        {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("{}: no private key found", path.display())),
+        }
+    }
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+/// What `ws-worker` needs to reach a coordinator over TLS beyond the
+/// system roots: a private CA, and a client certificate for mutual TLS.
+#[derive(Clone, Debug, Default)]
+pub struct TlsClientFiles {
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl TlsClientFiles {
+    /// Whether the default client config (system roots, no client
+    /// certificate) will do.
+    pub fn is_empty(&self) -> bool {
+        self.ca.is_none() && self.cert.is_none() && self.key.is_none()
+    }
+
+    /// Load the files once, so that a bad CA, or a certificate without
+    /// a key or the reverse, is reported at startup rather than on the
+    /// first connection.
+    pub fn checked(self) -> Result<TlsClientFiles, String> {
+        if !self.is_empty() {
+            self.client_config()?;
+        }
+        Ok(self)
+    }
+
+    /// Build a client config trusting the system roots plus `ca`, and
+    /// presenting `cert` if set.
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, String> {
+        let mut roots = match &self.ca {
+            Some(ca) => load_roots(ca)?,
+            None => RootCertStore::empty(),
+        };
+        for cert in rustls_native_certs::load_native_certs().unwrap_or_default() {
+            // Skip system roots webpki can't parse rather than fail.
+            let _ = roots.add(&Certificate(cert.0));
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => builder
+                .with_single_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|e| format!("{}: {}", key.display(), e))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("a client certificate needs both a cert and a key".to_owned()),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// An accepted connection, with or without TLS.
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl ServerStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ServerStream::Plain(s) => s.peer_addr(),
+            ServerStream::Tls(s) => s.get_ref().0.peer_addr(),
+        }
+    }
+
+    /// Whether the client presented a certificate that verified against
+    /// the client CA.
+    pub fn client_verified(&self) -> bool {
+        match self {
+            ServerStream::Plain(_) => false,
+            ServerStream::Tls(s) => s.get_ref().1.peer_certificates().is_some(),
+        }
+    }
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            ServerStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            ServerStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `tests/tls` holds a throwaway CA, a `localhost` server certificate
+    // and a client certificate it signed, all valid for a century.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    #[test]
+    fn load_rejects_missing_and_malformed_files() {
+        let files = |cert: &str, key: &str| TlsFiles {
+            cert: fixture(cert),
+            key: fixture(key),
+            client_ca: None,
+        };
+        assert!(TlsAcceptorHandle::load(files("server.pem", "server.key")).is_ok());
+        assert!(TlsAcceptorHandle::load(files("server.pem", "nope.key")).is_err());
+        assert!(TlsAcceptorHandle::load(files("server.key", "server.key")).is_err());
+        assert!(TlsAcceptorHandle::load(files("server.pem", "server.pem")).is_err());
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_files_and_keeps_the_old_ones_on_error() {
+        let dir = std::env::temp_dir().join(format!("hacklily-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        std::fs::copy(fixture("server.pem"), &cert).unwrap();
+        std::fs::copy(fixture("server.key"), &key).unwrap();
+        let touch = |path: &Path, secs: u64| {
+            let t = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(t)
+                .unwrap();
+        };
+
+        let handle = TlsAcceptorHandle::load(TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: None,
+        })
+        .expect("load");
+        handle.reload_if_changed().await;
+        assert_eq!(handle.generation(), 0);
+
+        // A renewed certificate is picked up.
+        touch(&cert, 1_000_000);
+        handle.reload_if_changed().await;
+        assert_eq!(handle.generation(), 1);
+
+        // A half-written one is not, and isn't retried until it changes.
+        std::fs::write(&cert, "-----BEGIN CERTIFICATE-----\n").unwrap();
+        touch(&cert, 2_000_000);
+        handle.reload_if_changed().await;
+        handle.reload_if_changed().await;
+        assert_eq!(handle.generation(), 1);
+
+        std::fs::copy(fixture("server.pem"), &cert).unwrap();
+        touch(&cert, 3_000_000);
+        handle.reload_if_changed().await;
+        assert_eq!(handle.generation(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn client_config_needs_both_cert_and_key() {
+        let files = TlsClientFiles {
+            ca: Some(fixture("ca.pem")),
+            cert: Some(fixture("client.pem")),
+            key: None,
+        };
+        assert!(files.client_config().is_err());
+        let files = TlsClientFiles {
+            key: Some(fixture("client.key")),
+            ..files
+        };
+        assert!(files.client_config().is_ok());
+        assert!(TlsClientFiles::default().is_empty());
+
+        let key_only = TlsClientFiles {
+            key: Some(fixture("client.key")),
+            ..TlsClientFiles::default()
+        };
+        assert!(!key_only.is_empty());
+        assert!(key_only.checked().is_err());
+        let cert_only = TlsClientFiles {
+            cert: Some(fixture("client.pem")),
+            ..TlsClientFiles::default()
+        };
+        assert!(cert_only.checked().is_err());
+    }
+}
@@ -0,0 +1,477 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// Optional WebSocket framing: binary render results and compression.
+//
+// In JSON, PDFs, MIDI and audio travel base64-encoded inside the
+// `RenderResponse`, which costs a third more bytes on every hop. A peer
+// that offers the `BINARY_SUBPROTOCOL` WebSocket subprotocol during the
+// handshake instead receives successful render results as binary
+// frames carrying the raw bytes. Everything else on the connection
+// (requests, errors, non-render RPCs) stays JSON text, and a peer that
+// doesn't offer the subprotocol sees exactly the old protocol.
+//
+// A binary frame is:
+//
+//     [u32 big-endian header length][header][payload]
+//
+// where the header is a UTF-8 JSON-RPC 2.0 response whose `result` is a
+// `BinaryResult` (logs inline, every other field a byte length), and the
+// payload is the files, then the MIDI, then the audio, back to back.
+//
+// The in-process `RenderResponse` keeps its base64 strings: frames are
+// converted at the edges by `encode_render_result`/`decode_render_result`.
+//
+// Separately, a peer can ask for `Compression::Deflate` (SVG compresses
+// very well). tungstenite has no permessage-deflate, so this is an
+// envelope of our own: once negotiated, every data message in both
+// directions is a binary frame whose first byte says whether the
+// original message was text or binary and whether the rest is raw
+// DEFLATE (RFC 1951, `DecompressionStream("deflate-raw")` in browsers)
+// or stored as-is, which `seal` does for short messages.
+//
+// WebSocket peers agree on a single subprotocol, so each combination
+// has its own name; see `Transport`.
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::jsonrpc;
+use crate::request::{Backend, Response as RenderResponse};
+
+/// WebSocket subprotocol a peer offers to receive binary render results.
+pub const BINARY_SUBPROTOCOL: &str = "hacklily.binary.1";
+/// ... to compress every message.
+pub const DEFLATE_SUBPROTOCOL: &str = "hacklily.deflate.1";
+/// ... for both.
+pub const BINARY_DEFLATE_SUBPROTOCOL: &str = "hacklily.binary.deflate.1";
+
+/// Messages shorter than this are stored in the envelope uncompressed;
+/// DEFLATE can't win much on a ping.
+pub const COMPRESS_MIN_BYTES: usize = 256;
+/// Refuse to inflate a message past tungstenite's own message limit.
+pub const MAX_INFLATED_BYTES: u64 = 64 << 20;
+
+/// First byte of an envelope: set if the message was binary, not text.
+const ENVELOPE_BINARY: u8 = 0x01;
+/// First byte of an envelope: set if the rest is DEFLATE-compressed.
+const ENVELOPE_DEFLATE: u8 = 0x02;
+
+/// How render results are framed on a connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Text frames with base64 payloads. What every peer understands.
+    #[default]
+    Json,
+    /// `encode_render_result` frames, negotiated via `BINARY_SUBPROTOCOL`.
+    Binary,
+}
+
+/// Whether messages on a connection go through the `seal`/`open` envelope.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+}
+
+/// Everything negotiated for one connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Transport {
+    pub encoding: Encoding,
+    pub compression: Compression,
+}
+
+/// Our subprotocols, most preferred first.
+const SUBPROTOCOLS: [(&str, Transport); 3] = [
+    (
+        BINARY_DEFLATE_SUBPROTOCOL,
+        Transport {
+            encoding: Encoding::Binary,
+            compression: Compression::Deflate,
+        },
+    ),
+    (
+        BINARY_SUBPROTOCOL,
+        Transport {
+            encoding: Encoding::Binary,
+            compression: Compression::None,
+        },
+    ),
+    (
+        DEFLATE_SUBPROTOCOL,
+        Transport {
+            encoding: Encoding::Json,
+            compression: Compression::Deflate,
+        },
+    ),
+];
+
+impl Transport {
+    /// Server side: pick the most preferred subprotocol the client
+    /// offered in its `Sec-WebSocket-Protocol` header (a comma-separated
+    /// list), leaving out compressed ones unless `allow_compression`.
+    /// Returns the subprotocol to echo back, if any.
+    pub fn negotiate(
+        offered: Option<&str>,
+        allow_compression: bool,
+    ) -> (Transport, Option<&'static str>) {
+        let offered: Vec<&str> = offered
+            .map(|o| o.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        SUBPROTOCOLS
+            .iter()
+            .filter(|(_, t)| allow_compression || t.compression == Compression::None)
+            .find(|(name, _)| offered.contains(name))
+            .map(|&(name, t)| (t, Some(name)))
+            .unwrap_or_default()
+    }
+
+    /// Client side: the `Sec-WebSocket-Protocol` header value offering
+    /// every subprotocol that provides no more than what's wanted, or
+    /// `None` for a plain connection.
+    pub fn offer(binary: bool, compression: bool) -> Option<String> {
+        let offer: Vec<&str> = SUBPROTOCOLS
+            .iter()
+            .filter(|(_, t)| binary || t.encoding == Encoding::Json)
+            .filter(|(_, t)| compression || t.compression == Compression::None)
+            .map(|(name, _)| *name)
+            .collect();
+        if offer.is_empty() {
+            None
+        } else {
+            Some(offer.join(", "))
+        }
+    }
+
+    /// Client side: what the server's echoed subprotocol means. Servers
+    /// that don't know ours echo nothing, which means plain JSON.
+    pub fn accepted(selected: Option<&str>) -> Transport {
+        SUBPROTOCOLS
+            .iter()
+            .find(|(name, _)| Some(*name) == selected.map(str::trim))
+            .map(|&(_, t)| t)
+            .unwrap_or_default()
+    }
+}
+
+/// Wrap an outgoing message in the compression envelope. Control
+/// frames, and everything on an uncompressed connection, pass through.
+pub fn seal(message: Message, compression: Compression) -> Message {
+    if compression == Compression::None {
+        return message;
+    }
+    let (mut flags, payload) = match message {
+        Message::Text(text) => (0, text.into_bytes()),
+        Message::Binary(data) => (ENVELOPE_BINARY, data),
+        other => return other,
+    };
+    let mut envelope = Vec::with_capacity(1 + payload.len());
+    envelope.push(0);
+    if payload.len() >= COMPRESS_MIN_BYTES {
+        let mut encoder = DeflateEncoder::new(envelope, flate2::Compression::default());
+        // Writing to a Vec can't fail.
+        encoder.write_all(&payload).expect("deflate into memory");
+        envelope = encoder.finish().expect("deflate into memory");
+        flags |= ENVELOPE_DEFLATE;
+    } else {
+        envelope.extend_from_slice(&payload);
+    }
+    envelope[0] = flags;
+    Message::Binary(envelope)
+}
+
+/// Undo `seal` on an incoming message.
+pub fn open(message: Message, compression: Compression) -> Result<Message, String> {
+    if compression == Compression::None {
+        return Ok(message);
+    }
+    let envelope = match message {
+        Message::Binary(data) => data,
+        Message::Text(_) => return Err("text message on a compressed connection".to_owned()),
+        other => return Ok(other),
+    };
+    let (&flags, rest) = envelope
+        .split_first()
+        .ok_or_else(|| "empty envelope".to_owned())?;
+    let payload = if flags & ENVELOPE_DEFLATE != 0 {
+        let mut inflated = Vec::new();
+        DeflateDecoder::new(rest)
+            .take(MAX_INFLATED_BYTES + 1)
+            .read_to_end(&mut inflated)
+            .map_err(|e| format!("bad deflate stream: {}", e))?;
+        if inflated.len() as u64 > MAX_INFLATED_BYTES {
+            return Err("inflated message is too large".to_owned());
+        }
+        inflated
+    } else {
+        rest.to_vec()
+    };
+    if flags & ENVELOPE_BINARY != 0 {
+        Ok(Message::Binary(payload))
+    } else {
+        String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| "text message is not UTF-8".to_owned())
+    }
+}
+
+/// The `result` of a binary frame's header.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct BinaryResult {
+    /// Byte length of each entry of `RenderResponse::files`.
+    pub files: Vec<u64>,
+    /// Whether the files are UTF-8 text (SVG, musicxml2ly output) rather
+    /// than binary documents that JSON would carry as base64 (PDF).
+    pub text_files: bool,
+    pub logs: String,
+    pub midi: u64,
+    pub audio: u64,
+}
+
+/// Encode a successful render result as a binary frame. Fails (and the
+/// caller should fall back to JSON) if a payload that should be base64
+/// isn't.
+pub fn encode_render_result(
+    id: &Value,
+    response: &RenderResponse,
+    backend: Backend,
+) -> Result<Vec<u8>, String> {
+    let text_files = backend != Backend::Pdf;
+    let files = response
+        .files
+        .iter()
+        .map(|file| {
+            if text_files {
+                Ok(file.as_bytes().to_vec())
+            } else {
+                decode_base64(file)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let midi = decode_base64(&response.midi)?;
+    let audio = decode_base64(&response.audio)?;
+
+    let result = BinaryResult {
+        files: files.iter().map(|f| f.len() as u64).collect(),
+        text_files,
+        logs: response.logs.clone(),
+        midi: midi.len() as u64,
+        audio: audio.len() as u64,
+    };
+    let header = jsonrpc::Response::success(
+        id.clone(),
+        serde_json::to_value(&result).expect("BinaryResult is always serializable"),
+    )
+    .serialize();
+
+    let payload_len: usize = files.iter().map(Vec::len).sum::<usize>() + midi.len() + audio.len();
+    let mut frame = Vec::with_capacity(4 + header.len() + payload_len);
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(header.as_bytes());
+    for file in &files {
+        frame.extend_from_slice(file);
+    }
+    frame.extend_from_slice(&midi);
+    frame.extend_from_slice(&audio);
+    Ok(frame)
+}
+
+/// Decode a binary frame back into the JSON-RPC id and the (base64)
+/// `RenderResponse` it carries.
+pub fn decode_render_result(frame: &[u8]) -> Result<(Value, RenderResponse), String> {
+    if frame.len() < 4 {
+        return Err("binary frame too short".to_owned());
+    }
+    let header_len = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+    let header = frame
+        .get(4..4 + header_len)
+        .ok_or_else(|| "binary frame header is truncated".to_owned())?;
+    let header: jsonrpc::Response =
+        serde_json::from_slice(header).map_err(|e| format!("bad binary frame header: {}", e))?;
+    let result: BinaryResult = header
+        .result
+        .ok_or_else(|| "binary frame header has no result".to_owned())
+        .and_then(|r| {
+            serde_json::from_value(r).map_err(|e| format!("bad binary frame result: {}", e))
+        })?;
+
+    let mut payload = &frame[4 + header_len..];
+    let mut take = |len: u64| -> Result<&[u8], String> {
+        let len = usize::try_from(len).map_err(|_| "payload length overflows".to_owned())?;
+        if len > payload.len() {
+            return Err("binary frame payload is truncated".to_owned());
+        }
+        let (head, rest) = payload.split_at(len);
+        payload = rest;
+        Ok(head)
+    };
+
+    let mut files = Vec::with_capacity(result.files.len());
+    for &len in &result.files {
+        let bytes = take(len)?;
+        files.push(if result.text_files {
+            String::from_utf8(bytes.to_vec()).map_err(|_| "text file is not UTF-8".to_owned())?
+        } else {
+            base64::encode(bytes)
+        });
+    }
+    let midi = base64::encode(take(result.midi)?);
+    let audio = base64::encode(take(result.audio)?);
+    if !payload.is_empty() {
+        return Err("binary frame has trailing bytes".to_owned());
+    }
+
+    Ok((
+        header.id,
+        RenderResponse {
+            files,
+            logs: result.logs,
+            midi,
+            audio,
+        },
+    ))
+}
+
+/// render-impl.bash pipes through `base64`, which wraps its output, so
+/// whitespace is dropped before decoding.
+pub(crate) fn decode_base64(encoded: &str) -> Result<Vec<u8>, String> {
+    let stripped: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+    base64::decode(stripped).map_err(|e| format!("invalid base64 payload: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(files: Vec<&str>, midi: &str) -> RenderResponse {
+        RenderResponse {
+            files: files.into_iter().map(str::to_owned).collect(),
+            logs: "Processing `hacklily.ly'".to_owned(),
+            midi: midi.to_owned(),
+            audio: String::new(),
+        }
+    }
+
+    #[test]
+    fn negotiate_picks_binary_only_when_offered() {
+        assert_eq!(
+            Transport::negotiate(None, true),
+            (Transport::default(), None)
+        );
+        assert_eq!(
+            Transport::negotiate(Some("graphql-ws"), true),
+            (Transport::default(), None)
+        );
+        let (t, echoed) = Transport::negotiate(Some("graphql-ws, hacklily.binary.1"), true);
+        assert_eq!(t.encoding, Encoding::Binary);
+        assert_eq!(t.compression, Compression::None);
+        assert_eq!(echoed, Some(BINARY_SUBPROTOCOL));
+    }
+
+    #[test]
+    fn negotiate_prefers_compression_unless_disabled() {
+        let offer = Transport::offer(true, true).expect("offer");
+        let (t, echoed) = Transport::negotiate(Some(&offer), true);
+        assert_eq!(echoed, Some(BINARY_DEFLATE_SUBPROTOCOL));
+        assert_eq!(Transport::accepted(echoed), t);
+
+        let (t, echoed) = Transport::negotiate(Some(&offer), false);
+        assert_eq!(echoed, Some(BINARY_SUBPROTOCOL));
+        assert_eq!(t.compression, Compression::None);
+
+        let offer = Transport::offer(false, true).expect("offer");
+        assert_eq!(offer, DEFLATE_SUBPROTOCOL);
+        assert_eq!(Transport::negotiate(Some(&offer), false).1, None);
+        assert_eq!(Transport::offer(false, false), None);
+        assert_eq!(Transport::accepted(None), Transport::default());
+    }
+
+    #[test]
+    fn envelope_round_trips_text_and_binary() {
+        let svg = format!("<svg>{}</svg>", "<path d=\"M0 0\"/>".repeat(200));
+        for message in [
+            Message::Text("{\"jsonrpc\":\"2.0\"}".to_owned()),
+            Message::Text(svg.clone()),
+            Message::Binary(vec![1, 2, 3]),
+            Message::Binary(svg.clone().into_bytes()),
+        ] {
+            let sealed = seal(message.clone(), Compression::Deflate);
+            assert!(sealed.is_binary());
+            assert_eq!(open(sealed, Compression::Deflate).expect("open"), message);
+        }
+        // Long SVG actually shrinks.
+        let sealed = seal(Message::Text(svg.clone()), Compression::Deflate);
+        assert!(sealed.len() < svg.len() / 4);
+    }
+
+    #[test]
+    fn envelope_is_a_no_op_when_uncompressed() {
+        let m = Message::Text("hi".to_owned());
+        assert_eq!(seal(m.clone(), Compression::None), m);
+        assert_eq!(open(m.clone(), Compression::None).expect("open"), m);
+        let ping = Message::Ping(vec![0]);
+        assert_eq!(seal(ping.clone(), Compression::Deflate), ping);
+    }
+
+    #[test]
+    fn open_rejects_bad_envelopes() {
+        assert!(open(Message::Binary(vec![]), Compression::Deflate).is_err());
+        assert!(open(Message::Text("{}".to_owned()), Compression::Deflate).is_err());
+        assert!(open(
+            Message::Binary(vec![ENVELOPE_DEFLATE, 0xff]),
+            Compression::Deflate
+        )
+        .is_err());
+        assert!(open(Message::Binary(vec![0, 0xff]), Compression::Deflate).is_err());
+    }
+
+    #[test]
+    fn svg_round_trips_as_text() {
+        let r = response(vec!["<svg/>", "<svg>2</svg>"], "TVRoZA==");
+        let frame = encode_render_result(&json!("42"), &r, Backend::Svg).expect("encode");
+        let (id, back) = decode_render_result(&frame).expect("decode");
+        assert_eq!(id, json!("42"));
+        assert_eq!(back, r);
+        // The MIDI travels raw: "MThd" rather than its base64.
+        assert!(frame.ends_with(b"MThd"));
+    }
+
+    #[test]
+    fn pdf_travels_raw_and_comes_back_as_base64() {
+        // Wrapped the way coreutils `base64` wraps long output.
+        let pdf = base64::encode(vec![b'%'; 100]);
+        let wrapped = format!("{}\n{}\n", &pdf[..76], &pdf[76..]);
+        let r = response(vec![&wrapped], "");
+        let frame = encode_render_result(&json!(7), &r, Backend::Pdf).expect("encode");
+        assert!(frame.len() < 4 + 200 + wrapped.len());
+        let (id, back) = decode_render_result(&frame).expect("decode");
+        assert_eq!(id, json!(7));
+        assert_eq!(back.files, vec![pdf]);
+        assert_eq!(back.logs, r.logs);
+    }
+
+    #[test]
+    fn encode_rejects_invalid_base64() {
+        let r = response(vec!["not base64!"], "");
+        assert!(encode_render_result(&json!("1"), &r, Backend::Pdf).is_err());
+    }
+
+    #[test]
+    fn decode_rejects_malformed_frames() {
+        let r = response(vec!["<svg/>"], "");
+        let frame = encode_render_result(&json!("1"), &r, Backend::Svg).expect("encode");
+        assert!(decode_render_result(&frame[..frame.len() - 1]).is_err());
+        let mut extra = frame.clone();
+        extra.push(0);
+        assert!(decode_render_result(&extra).is_err());
+        assert!(decode_render_result(&[0, 0]).is_err());
+        assert!(decode_render_result(&[0, 0, 0, 9, b'{']).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+
+// Worker authentication for the coordinator.
+//
+// Remote workers see every score dispatched to them and their results go
+// straight back to frontends, so a coordinator started with an allow-list
+// (`serve --worker-keys FILE`) only registers workers that prove they
+// know a shared secret. The file has one `name secret` pair per line;
+// blank lines and lines starting with `#` are ignored.
+//
+// A worker names itself in `i_haz_computes` and presents a `token`,
+// which is either:
+//
+//   * the secret itself (a pre-shared key), or
+//   * a signed token `v1.<expires>.<mac>`, where `expires` is a Unix
+//     time in seconds and `mac` is the unpadded URL-safe base64 of
+//     HMAC-SHA256(secret, "<name>.<expires>"). This is what `ws-worker`
+//     sends, so the secret itself never crosses the wire.
+//
+// Without an allow-list every worker is accepted, as before.
+//
+// Admin requests (`drain_worker`) are checked against a single shared
+// token instead (`serve --admin-token-file FILE`), presented as is.
+// Without one, they are refused.
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::{constant_time, hmac};
+
+/// Version prefix of signed tokens.
+const SIGNED_TOKEN_PREFIX: &str = "v1.";
+
+/// How long the tokens `ws-worker` signs for itself stay valid. They are
+/// minted fresh on every connection, so this only needs to cover clock
+/// skew between worker and coordinator.
+pub const TOKEN_LIFETIME_SEC: u64 = 300;
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Sign a token for worker `name`, valid until `expires`.
+pub fn sign(name: &str, secret: &str, expires: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, signed_message(name, expires).as_bytes());
+    format!(
+        "{}{}.{}",
+        SIGNED_TOKEN_PREFIX,
+        expires,
+        base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+fn signed_message(name: &str, expires: u64) -> String {
+    format!("{}.{}", name, expires)
+}
+
+/// What `ws-worker --worker-name/--worker-key-file` identifies itself with.
+// No `Debug`: keep secrets out of logs.
+#[derive(Clone)]
+pub struct WorkerCredentials {
+    pub name: String,
+    pub secret: String,
+}
+
+impl WorkerCredentials {
+    /// A signed token valid for the next `TOKEN_LIFETIME_SEC`.
+    pub fn token(&self) -> String {
+        sign(&self.name, &self.secret, unix_now() + TOKEN_LIFETIME_SEC)
+    }
+}
+
+/// The allow-list of worker names and their secrets.
+#[derive(Default)]
+pub struct WorkerKeys {
+    keys: BTreeMap<String, String>,
+}
+
+impl WorkerKeys {
+    /// Parse an allow-list; see the module comment for the format.
+    pub fn parse(text: &str) -> Result<WorkerKeys, String> {
+        let mut keys = BTreeMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (name, secret) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(secret), None) => (name, secret),
+                _ => return Err(format!("line {}: expected `name secret`", i + 1)),
+            };
+            if keys.insert(name.to_owned(), secret.to_owned()).is_some() {
+                return Err(format!("line {}: duplicate worker name {}", i + 1, name));
+            }
+        }
+        if keys.is_empty() {
+            return Err("no workers listed".to_owned());
+        }
+        Ok(WorkerKeys { keys })
+    }
+
+    /// Read and parse an allow-list file.
+    pub fn load(path: &Path) -> Result<WorkerKeys, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        WorkerKeys::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Check the credentials a worker presented at `now`. The error is
+    /// for the coordinator's log only; the worker just learns it was
+    /// rejected.
+    pub fn verify(&self, name: Option<&str>, token: Option<&str>, now: u64) -> Result<(), String> {
+        let name = name.ok_or("no worker name")?;
+        let token = token.ok_or("no token")?;
+        let secret = self
+            .keys
+            .get(name)
+            .ok_or_else(|| format!("unknown worker name {:?}", name))?;
+
+        match token.strip_prefix(SIGNED_TOKEN_PREFIX) {
+            Some(signed) => {
+                let (expires, mac) = signed.split_once('.').ok_or("malformed signed token")?;
+                let expires: u64 = expires.parse().map_err(|_| "malformed token expiry")?;
+                let mac = base64::decode_config(mac, base64::URL_SAFE_NO_PAD)
+                    .map_err(|_| "malformed token signature")?;
+                let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+                hmac::verify(&key, signed_message(name, expires).as_bytes(), &mac)
+                    .map_err(|_| "bad token signature")?;
+                if expires <= now {
+                    return Err(format!("token expired {}s ago", now - expires));
+                }
+                Ok(())
+            }
+            None => constant_time::verify_slices_are_equal(token.as_bytes(), secret.as_bytes())
+                .map_err(|_| "wrong key".to_owned()),
+        }
+    }
+}
+
+/// The token admin requests must carry.
+// No `Debug`: keep secrets out of logs.
+pub struct AdminToken {
+    token: String,
+}
+
+impl AdminToken {
+    pub fn new(token: String) -> Self {
+        AdminToken { token }
+    }
+
+    pub fn verify(&self, presented: Option<&str>) -> bool {
+        presented.is_some_and(|presented| {
+            constant_time::verify_slices_are_equal(presented.as_bytes(), self.token.as_bytes())
+                .is_ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> WorkerKeys {
+        WorkerKeys::parse("# workers\n\nbasement  s3cret\nattic hunter2\n").expect("parse")
+    }
+
+    #[test]
+    fn parse_rejects_bad_lines() {
+        assert!(WorkerKeys::parse("").is_err());
+        assert!(WorkerKeys::parse("# only comments\n").is_err());
+        assert!(WorkerKeys::parse("lonely\n").is_err());
+        assert!(WorkerKeys::parse("a b c\n").is_err());
+        assert!(WorkerKeys::parse("a b\na c\n").is_err());
+    }
+
+    #[test]
+    fn accepts_pre_shared_keys() {
+        let keys = keys();
+        assert!(keys.verify(Some("basement"), Some("s3cret"), 0).is_ok());
+        assert!(keys.verify(Some("attic"), Some("hunter2"), 0).is_ok());
+        assert!(keys.verify(Some("attic"), Some("s3cret"), 0).is_err());
+        assert!(keys.verify(Some("garage"), Some("s3cret"), 0).is_err());
+        assert!(keys.verify(None, Some("s3cret"), 0).is_err());
+        assert!(keys.verify(Some("basement"), None, 0).is_err());
+    }
+
+    #[test]
+    fn accepts_signed_tokens_until_they_expire() {
+        let keys = keys();
+        let token = sign("basement", "s3cret", 1000);
+        assert!(token.starts_with("v1.1000."));
+        assert!(keys.verify(Some("basement"), Some(&token), 999).is_ok());
+        assert!(keys.verify(Some("basement"), Some(&token), 1000).is_err());
+        // Signed for someone else, or with the wrong secret.
+        assert!(keys.verify(Some("attic"), Some(&token), 999).is_err());
+        let forged = sign("basement", "guess", 1000);
+        assert!(keys.verify(Some("basement"), Some(&forged), 999).is_err());
+        // Tampering with the expiry breaks the signature.
+        let extended = token.replace("v1.1000.", "v1.9999.");
+        assert!(keys.verify(Some("basement"), Some(&extended), 999).is_err());
+        assert!(keys.verify(Some("basement"), Some("v1.nope"), 999).is_err());
+    }
+
+    #[test]
+    fn admin_token_must_match() {
+        let admin = AdminToken::new("letmein".to_owned());
+        assert!(admin.verify(Some("letmein")));
+        assert!(!admin.verify(Some("letmeout")));
+        assert!(!admin.verify(Some("")));
+        assert!(!admin.verify(None));
+    }
+}
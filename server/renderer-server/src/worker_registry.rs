@@ -6,28 +6,68 @@
 // `_freeWorkers` / `_busyWorkers` / `_remoteProcessingRequests` maps
 // (the former Qt `HacklilyServer`, now retired).
 //
-// Remote workers are version-agnostic: the coordinator sends a render
-// request with the `version` field in params, and the worker's own
-// `RendererManager` (see `command_source/ws_worker_client.rs`) routes
-// it to its stable or unstable pool. So the idle queue is a single
-// deque, not per-version. Each worker connection contributes `max_jobs`
-// slots to the idle queue, matching the Qt behaviour of pushing the
-// socket N times into `_freeWorkers`.
+// The coordinator sends a render request with the `version` field in
+// params, and the worker's own `RendererManager` (see
+// `command_source/ws_worker_client.rs`) routes it to its stable or
+// unstable pool. A worker advertises how many containers it has per
+// version, and which backends it renders, in `i_haz_computes`
+// (`WorkerCapabilities`); each container becomes one idle slot for its
+// version, matching the Qt behaviour of pushing the socket N times into
+// `_freeWorkers`. Older workers only send `max_jobs`, and their slots
+// take any version, as before.
+//
+// Dispatch picks, among the idle slots that fit the request, the one on
+// the worker expected to answer soonest: each worker keeps a smoothed
+// render latency and failure rate (`RenderStats`), and a worker we have
+// no numbers for yet is tried first so it gets some. Ties go to the
+// worker with the smallest share of its slots busy. `State` keeps the
+// same stats for the local pool and sends a request remote even when a
+// local container is ready if a remote worker is faster
+// (`faster_than`). The stats are published per worker in `get_status`.
+//
+// Each dispatch goes out under an id of its own, not the client's
+// request id: two clients can pick the same id, and a retry of a request
+// must not take the answer meant for an earlier attempt. The registry
+// maps it back to the client's request when the worker answers.
+//
+// A worker can accept a render and never answer without disconnecting.
+// With a deadline (`with_deadline`), a remote render that outlives it is
+// taken back: its slot is quarantined until the worker finally answers
+// (the late answer is dropped, and the slot freed), and the request goes
+// back to `State`'s queue through the `Requeue` hook, to be retried on
+// another worker or the local pool. Workers a request already timed out
+// on are only picked again as a last resort, and after `retries`
+// timeouts the request fails.
+//
+// A half-open connection never disconnects at all. The coordinator
+// notes when it last heard from each worker (`saw_worker`), pings it
+// every `Heartbeat::interval`, and evicts it once it has been silent for
+// `Heartbeat::silence`.
+//
+// A worker going out of service drains first (`drain_worker`, when it
+// sends `drain` or an admin asks): its idle slots are withdrawn and
+// answered renders don't return theirs, and once nothing is pending on
+// it the coordinator is told (`drained`) and hangs up.
 //
 // The registry is shared between the coordinator (which manages worker
 // connections and responses) and `State` (which dispatches renders).
 // `WorkerRegistryHandle` is a cheap `Arc` clone suitable for passing
 // into `State::new`.
 use log::{debug, info, warn};
-use std::collections::{HashMap, VecDeque};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
 
 use crate::command_source::ResponseCallback;
 use crate::jsonrpc;
-use crate::request::{Request, Response as RenderResponse};
-use crate::status::StatusHandle;
+use crate::metrics::Location;
+use crate::request::{Backend, Request, Response as RenderResponse, Version};
+use crate::status::{RemoteWorkerStatus, StatusHandle};
 use std::sync::atomic::Ordering;
 
 /// A cloneable handle to the worker registry. Cheap to clone (the
@@ -38,8 +78,57 @@ use std::sync::atomic::Ordering;
 pub struct WorkerRegistryHandle {
     inner: Arc<Mutex<WorkerRegistryState>>,
     status: Option<StatusHandle>,
+    deadline: Option<Deadline>,
+    heartbeat: Heartbeat,
+    /// The workers each timed-out request has timed out on so far, by
+    /// request id. Behind a std mutex because it is cleared from the
+    /// (synchronous) response callback once the request is answered.
+    timed_out: Arc<std::sync::Mutex<HashMap<String, Vec<String>>>>,
+}
+
+/// How long a remote render may take, and how often it's retried after
+/// taking longer.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+/// How often the coordinator pings workers, and how long one may stay
+/// silent before it's evicted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub silence: Duration,
+}
+
+impl Heartbeat {
+    /// The shortest ping interval; `tokio::time::interval` panics on zero.
+    const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+    /// Evict after `silence`, pinging often enough that a live worker
+    /// gets three chances to answer first.
+    pub fn for_silence(silence: Duration) -> Self {
+        Heartbeat {
+            interval: (silence / 3).max(Self::MIN_INTERVAL),
+            silence,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::for_silence(Duration::from_secs(30))
+    }
 }
 
+/// Puts a timed-out request back into the event loop's queue.
+pub type Requeue = Arc<dyn Fn(Request, ResponseCallback) + Send + Sync>;
+
+/// Tells the event loop that slots went idle, so it can hand them renders
+/// it has queued.
+pub type Wake = Arc<dyn Fn() + Send + Sync>;
+
 struct WorkerRegistryState {
     /// Idle worker slots. Each slot is a sink that can receive one
     /// render request. When a worker connects with `max_jobs=N`, we
@@ -47,28 +136,171 @@ struct WorkerRegistryState {
     /// deque; when the response arrives, a new slot for the same
     /// worker is pushed back.
     idle: VecDeque<WorkerSlot>,
-    /// Pending render requests keyed by request id, awaiting a
+    /// Pending render requests keyed by dispatch id, awaiting a
     /// response from a remote worker. Stored so we can deliver the
     /// response to the originating frontend callback and return the
     /// slot to the idle queue.
     pending: HashMap<String, PendingRemote>,
+    /// Renders whose worker disconnected before answering, by dispatch
+    /// id. Whichever connection answers one before its deadline
+    /// delivers it: a ws-worker that reconnects sends the renders that
+    /// finished while it was away.
+    orphaned: HashMap<String, PendingRemote>,
     /// Metadata for each connected worker, keyed by worker id. Used
     /// for cleanup on disconnect: all idle slots for the worker are
     /// drained, and all pending requests for the worker are failed.
     workers: HashMap<String, WorkerMeta>,
+    /// Slots held by renders that timed out, keyed by (worker id,
+    /// dispatch id), until the worker answers or disconnects.
+    quarantined: HashMap<(String, String), Option<Version>>,
+    /// Set by `State`; without it, timed-out requests just fail.
+    requeue: Option<Requeue>,
+    /// Set by `State`; called whenever slots go idle.
+    wake: Option<Wake>,
 }
 
 struct WorkerSlot {
     worker_id: String,
+    /// `None` for a slot that takes any version.
+    version: Option<Version>,
+}
+
+impl WorkerSlot {
+    fn accepts(&self, version: Version) -> bool {
+        self.version.is_none_or(|v| v == version)
+    }
 }
 
 struct PendingRemote {
+    /// Kept to retry it if the worker doesn't answer in time; its id
+    /// is the client's, which the worker never sees.
+    request: Request,
     callback: ResponseCallback,
     worker_id: String,
+    /// The version of the slot this took, so the same slot comes back.
+    slot_version: Option<Version>,
+    dispatched: Instant,
+    timer: Option<JoinHandle<()>>,
 }
 
 struct WorkerMeta {
     sink: crate::command_source::SharedSink,
+    capabilities: WorkerCapabilities,
+    stats: RenderStats,
+    /// When the worker last sent anything, pongs included.
+    last_seen: Instant,
+    /// The peer the worker connected from, once the coordinator says.
+    address: Option<String>,
+    draining: bool,
+    /// Notified once a draining worker has nothing pending.
+    drained: Arc<Notify>,
+}
+
+/// One remote worker, as `get_status_detail` lists it.
+#[derive(Clone, Debug, Serialize)]
+pub struct RemoteWorkerDetail {
+    pub id: String,
+    pub address: Option<String>,
+    /// Slots per version, `any` for those that take either.
+    pub slots: BTreeMap<&'static str, u64>,
+    /// Renders dispatched to the worker and not yet answered, oldest
+    /// first.
+    pub in_flight: Vec<InFlightRender>,
+    /// Time since the worker last sent anything.
+    pub last_seen_msec: u64,
+    pub draining: bool,
+}
+
+/// A render a remote worker holds.
+#[derive(Clone, Debug, Serialize)]
+pub struct InFlightRender {
+    pub id: String,
+    pub age_msec: u64,
+}
+
+/// Weight of the newest sample in the moving averages of `RenderStats`.
+const STATS_SMOOTHING: f64 = 0.2;
+
+/// Failure rates are capped here so a worker that failed a few times in
+/// a row still gets the occasional request, and can recover.
+const MAX_FAILURE_RATE: f64 = 0.9;
+
+/// Render latency and failure rate of one renderer: a remote worker, or
+/// the local pool as a whole.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    pub completed: u64,
+    pub failed: u64,
+    /// Exponentially smoothed latency of successful renders, in ms.
+    latency_msec: Option<f64>,
+    /// Exponentially smoothed fraction of renders that failed.
+    failure_rate: f64,
+}
+
+impl RenderStats {
+    /// Record a render that took `elapsed`. A failure is a render that
+    /// produced no answer (a crash, a disconnect, a JSON-RPC error), not
+    /// one where LilyPond rejected the score.
+    pub fn record(&mut self, elapsed: Duration, ok: bool) {
+        let smooth = |old: f64, new: f64| old + STATS_SMOOTHING * (new - old);
+        if ok {
+            self.completed += 1;
+            let msec = elapsed.as_secs_f64() * 1000.0;
+            self.latency_msec = Some(self.latency_msec.map_or(msec, |old| smooth(old, msec)));
+        } else {
+            self.failed += 1;
+        }
+        self.failure_rate = smooth(self.failure_rate, if ok { 0.0 } else { 1.0 });
+    }
+
+    /// Smoothed latency of successful renders, if there have been any.
+    pub fn latency_msec(&self) -> Option<f64> {
+        self.latency_msec
+    }
+
+    pub fn failure_rate(&self) -> f64 {
+        self.failure_rate
+    }
+
+    /// Expected time to a successful render, counting retries after
+    /// failures, or `None` before the first success.
+    pub fn expected_msec(&self) -> Option<f64> {
+        self.latency_msec
+            .map(|msec| msec / (1.0 - self.failure_rate.min(MAX_FAILURE_RATE)))
+    }
+}
+
+/// What a worker can render, as advertised in `i_haz_computes`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerCapabilities {
+    /// Slots per version. The `None` key holds slots that take any
+    /// version, which is all an older worker, sending just `max_jobs`,
+    /// gets.
+    pub slots: HashMap<Option<Version>, u64>,
+    /// The backends the worker renders, or `None` for all of them.
+    pub backends: Option<Vec<Backend>>,
+}
+
+impl WorkerCapabilities {
+    /// `max_jobs` slots for any version and backend.
+    pub fn any_version(max_jobs: u64) -> Self {
+        WorkerCapabilities {
+            slots: HashMap::from([(None, max_jobs)]),
+            backends: None,
+        }
+    }
+
+    fn renders(&self, version: Version, backend: Backend) -> bool {
+        let has_slots = self
+            .slots
+            .iter()
+            .any(|(v, n)| *n > 0 && v.is_none_or(|v| v == version));
+        let has_backend = self
+            .backends
+            .as_ref()
+            .is_none_or(|backends| backends.contains(&backend));
+        has_slots && has_backend
+    }
 }
 
 impl WorkerRegistryState {
@@ -76,9 +308,73 @@ impl WorkerRegistryState {
         WorkerRegistryState {
             idle: VecDeque::new(),
             pending: HashMap::new(),
+            orphaned: HashMap::new(),
             workers: HashMap::new(),
+            quarantined: HashMap::new(),
+            requeue: None,
+            wake: None,
+        }
+    }
+
+    /// Put a slot back in the idle queue, unless its worker is draining.
+    fn release(&mut self, slot: WorkerSlot) {
+        let worker_id = slot.worker_id.clone();
+        match self.workers.get(&worker_id) {
+            Some(meta) if meta.draining => self.notify_if_drained(&worker_id),
+            _ => {
+                self.idle.push_back(slot);
+                self.wake();
+            }
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            wake();
+        }
+    }
+
+    fn notify_if_drained(&self, worker_id: &str) {
+        let Some(meta) = self.workers.get(worker_id) else {
+            return;
+        };
+        if meta.draining && !self.pending.values().any(|p| p.worker_id == worker_id) {
+            meta.drained.notify_one();
         }
     }
+
+    /// Index in `idle` of the slot `request` should go to, if any fits.
+    /// Slots on the workers in `avoid` only win if nothing else fits.
+    fn best_slot(&self, request: &Request, avoid: &[String]) -> Option<usize> {
+        let busy = |worker_id: &str| {
+            self.pending
+                .values()
+                .filter(|p| p.worker_id == worker_id)
+                .count() as f64
+        };
+        self.idle
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| {
+                if !slot.accepts(request.version) {
+                    return None;
+                }
+                let meta = self.workers.get(&slot.worker_id)?;
+                if !meta.capabilities.renders(request.version, request.backend) {
+                    return None;
+                }
+                let slots: u64 = meta.capabilities.slots.values().sum();
+                let load = busy(&slot.worker_id) / slots.max(1) as f64;
+                let avoided = avoid.contains(&slot.worker_id);
+                Some((i, avoided, meta.stats.expected_msec().unwrap_or(0.0), load))
+            })
+            .min_by(|a, b| {
+                a.1.cmp(&b.1)
+                    .then(a.2.total_cmp(&b.2))
+                    .then(a.3.total_cmp(&b.3))
+            })
+            .map(|(i, _, _, _)| i)
+    }
 }
 
 impl Default for WorkerRegistryHandle {
@@ -92,6 +388,9 @@ impl WorkerRegistryHandle {
         WorkerRegistryHandle {
             inner: Arc::new(Mutex::new(WorkerRegistryState::new())),
             status: None,
+            deadline: None,
+            heartbeat: Heartbeat::default(),
+            timed_out: Arc::default(),
         }
     }
 
@@ -100,11 +399,115 @@ impl WorkerRegistryHandle {
     /// coordinator in `serve` mode.
     pub fn with_status(status: StatusHandle) -> Self {
         WorkerRegistryHandle {
-            inner: Arc::new(Mutex::new(WorkerRegistryState::new())),
             status: Some(status),
+            ..WorkerRegistryHandle::new()
         }
     }
 
+    /// Take back remote renders that take longer than `deadline.timeout`
+    /// (see the module comment). Without it, only a disconnect does.
+    pub fn with_deadline(self, deadline: Deadline) -> Self {
+        WorkerRegistryHandle {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// Evict workers on `heartbeat` instead of `Heartbeat::default()`.
+    pub fn with_heartbeat(self, heartbeat: Heartbeat) -> Self {
+        WorkerRegistryHandle { heartbeat, ..self }
+    }
+
+    pub fn heartbeat(&self) -> Heartbeat {
+        self.heartbeat
+    }
+
+    /// Note that `worker_id` is still there. Called by the coordinator
+    /// for every message the worker sends.
+    pub async fn saw_worker(&self, worker_id: &str) {
+        if let Some(meta) = self.inner.lock().await.workers.get_mut(worker_id) {
+            meta.last_seen = Instant::now();
+        }
+    }
+
+    /// Note where `worker_id` connected from, for `detail`.
+    pub async fn set_address(&self, worker_id: &str, address: String) {
+        if let Some(meta) = self.inner.lock().await.workers.get_mut(worker_id) {
+            meta.address = Some(address);
+        }
+    }
+
+    /// Every connected worker, with the renders it holds, ordered by id.
+    pub async fn detail(&self) -> Vec<RemoteWorkerDetail> {
+        let state = self.inner.lock().await;
+        let mut workers: Vec<RemoteWorkerDetail> = state
+            .workers
+            .iter()
+            .map(|(id, meta)| {
+                let mut in_flight: Vec<&PendingRemote> = state
+                    .pending
+                    .values()
+                    .filter(|p| &p.worker_id == id)
+                    .collect();
+                in_flight.sort_by_key(|p| p.dispatched);
+                RemoteWorkerDetail {
+                    id: id.clone(),
+                    address: meta.address.clone(),
+                    slots: meta
+                        .capabilities
+                        .slots
+                        .iter()
+                        .map(|(version, n)| {
+                            let version = match version {
+                                Some(Version::Stable) => "stable",
+                                Some(Version::Unstable) => "unstable",
+                                None => "any",
+                            };
+                            (version, *n)
+                        })
+                        .collect(),
+                    in_flight: in_flight
+                        .into_iter()
+                        .map(|p| InFlightRender {
+                            id: p.request.id.clone(),
+                            age_msec: p.dispatched.elapsed().as_millis() as u64,
+                        })
+                        .collect(),
+                    last_seen_msec: meta.last_seen.elapsed().as_millis() as u64,
+                    draining: meta.draining,
+                }
+            })
+            .collect();
+        workers.sort_by(|a, b| a.id.cmp(&b.id));
+        workers
+    }
+
+    /// Whether `worker_id` has been silent for longer than the heartbeat
+    /// allows. Called by the coordinator every `Heartbeat::interval`,
+    /// which also keeps the published `silent_msec` fresh.
+    pub async fn is_silent(&self, worker_id: &str) -> bool {
+        let silent = self
+            .inner
+            .lock()
+            .await
+            .workers
+            .get(worker_id)
+            .is_some_and(|meta| meta.last_seen.elapsed() >= self.heartbeat.silence);
+        self.republish_status().await;
+        silent
+    }
+
+    /// Install the hook timed-out requests are retried through. Called
+    /// by `State::new`.
+    pub async fn set_requeue(&self, requeue: Requeue) {
+        self.inner.lock().await.requeue = Some(requeue);
+    }
+
+    /// Install the hook called when slots go idle. Called by `State::new`.
+    pub async fn set_wake(&self, wake: Wake) {
+        self.inner.lock().await.wake = Some(wake);
+    }
+
     /// Recompute and publish `remote_*` counters. Callers must NOT
     /// hold `inner` lock when calling this (it acquires it).
     async fn republish_status(&self) {
@@ -119,6 +522,28 @@ impl WorkerRegistryHandle {
             .store(state.pending.len() as u64, Ordering::Relaxed);
         snap.remote_free
             .store(state.idle.len() as u64, Ordering::Relaxed);
+        let mut workers: Vec<RemoteWorkerStatus> = state
+            .workers
+            .iter()
+            .map(|(id, meta)| RemoteWorkerStatus {
+                id: id.clone(),
+                slots: meta.capabilities.slots.values().sum(),
+                busy: state
+                    .pending
+                    .values()
+                    .filter(|p| &p.worker_id == id)
+                    .count() as u64,
+                quarantined: state.quarantined.keys().filter(|(w, _)| w == id).count() as u64,
+                completed: meta.stats.completed,
+                failed: meta.stats.failed,
+                latency_msec: meta.stats.latency_msec().map(|msec| msec.round() as u64),
+                failure_rate: meta.stats.failure_rate(),
+                silent_msec: meta.last_seen.elapsed().as_millis() as u64,
+                draining: meta.draining,
+            })
+            .collect();
+        workers.sort_by(|a, b| a.id.cmp(&b.id));
+        *snap.remote_workers.lock().expect("status lock poisoned") = workers;
     }
 
     /// Number of currently-registered workers (not slots). Used by
@@ -128,38 +553,123 @@ impl WorkerRegistryHandle {
         self.inner.lock().await.workers.len()
     }
 
+    /// Whether any registered worker could render `request`, now or once
+    /// it has an idle slot. `State` fails requests that none could.
+    pub async fn can_render(&self, request: &Request) -> bool {
+        self.inner.lock().await.workers.values().any(|meta| {
+            !meta.draining && meta.capabilities.renders(request.version, request.backend)
+        })
+    }
+
+    /// Stop dispatching to `worker_id` and let its pending renders
+    /// finish; see the module comment. Returns `false` for an unknown
+    /// worker.
+    pub async fn drain_worker(&self, worker_id: &str) -> bool {
+        let mut state = self.inner.lock().await;
+        match state.workers.get_mut(worker_id) {
+            Some(meta) if !meta.draining => meta.draining = true,
+            Some(_) => return true,
+            None => return false,
+        }
+        state.idle.retain(|slot| slot.worker_id != worker_id);
+        info!("draining worker {}", worker_id);
+        state.notify_if_drained(worker_id);
+        drop(state);
+        self.republish_status().await;
+        true
+    }
+
+    /// Notified once `worker_id` is draining and has nothing pending.
+    pub async fn drained(&self, worker_id: &str) -> Option<Arc<Notify>> {
+        self.inner
+            .lock()
+            .await
+            .workers
+            .get(worker_id)
+            .map(|meta| meta.drained.clone())
+    }
+
+    /// Whether an idle slot that could take `request` is on a worker
+    /// expected to render it sooner than `local` would. `State` asks
+    /// this before using a ready local container. Without numbers for
+    /// both sides, the local pool wins.
+    pub async fn faster_than(&self, local: &RenderStats, request: &Request) -> bool {
+        let Some(local_msec) = local.expected_msec() else {
+            return false;
+        };
+        let state = self.inner.lock().await;
+        state.idle.iter().any(|slot| {
+            slot.accepts(request.version)
+                && state.workers.get(&slot.worker_id).is_some_and(|meta| {
+                    meta.capabilities.renders(request.version, request.backend)
+                        && meta
+                            .stats
+                            .expected_msec()
+                            .is_some_and(|msec| msec < local_msec)
+                })
+        })
+    }
+
     /// Number of idle worker slots available for dispatch.
     pub async fn idle_slot_count(&self) -> usize {
         self.inner.lock().await.idle.len()
     }
 
+    /// Number of idle slots that can take a `version` render.
+    pub async fn idle_slots_for(&self, version: Version) -> usize {
+        let state = self.inner.lock().await;
+        state
+            .idle
+            .iter()
+            .filter(|slot| slot.accepts(version))
+            .count()
+    }
+
     /// Number of in-flight render requests on remote workers.
     pub async fn busy_slot_count(&self) -> usize {
         self.inner.lock().await.pending.len()
     }
 
-    /// Register a new worker. Adds `max_jobs` slots to the idle queue.
+    /// Register a new worker. Adds its slots to the idle queue.
     /// Called by the coordinator when a worker sends `i_haz_computes`.
     pub async fn register_worker(
         &self,
         worker_id: String,
-        max_jobs: u64,
+        capabilities: WorkerCapabilities,
         sink: crate::command_source::SharedSink,
     ) {
         let mut state = self.inner.lock().await;
-        state.workers.insert(worker_id.clone(), WorkerMeta { sink });
-        for _ in 0..max_jobs {
-            state.idle.push_back(WorkerSlot {
-                worker_id: worker_id.clone(),
-            });
+        for (version, n) in &capabilities.slots {
+            for _ in 0..*n {
+                state.idle.push_back(WorkerSlot {
+                    worker_id: worker_id.clone(),
+                    version: *version,
+                });
+            }
         }
         info!(
-            "registered worker {} (max_jobs={}, total idle={})",
+            "registered worker {} ({:?}, total idle={})",
             worker_id,
-            max_jobs,
+            capabilities,
             state.idle.len(),
         );
+        state.workers.insert(
+            worker_id.clone(),
+            WorkerMeta {
+                sink,
+                capabilities,
+                stats: RenderStats::default(),
+                last_seen: Instant::now(),
+                address: None,
+                draining: false,
+                drained: Arc::new(Notify::new()),
+            },
+        );
+        state.wake();
         drop(state);
+        if let Some(status) = &self.status {
+            StatusHandle::bump(&status.snapshot().metrics.worker_connects);
+        }
         self.republish_status().await;
     }
 
@@ -169,55 +679,74 @@ impl WorkerRegistryHandle {
     /// `_removeWorker`.
     pub async fn unregister_worker(&self, worker_id: &str) {
         let mut state = self.inner.lock().await;
-        state.workers.remove(worker_id);
+        if state.workers.remove(worker_id).is_some() {
+            if let Some(status) = &self.status {
+                StatusHandle::bump(&status.snapshot().metrics.worker_disconnects);
+            }
+        }
 
         // Remove idle slots for this worker.
         let before = state.idle.len();
         state.idle.retain(|slot| slot.worker_id != worker_id);
         let removed_idle = before - state.idle.len();
+        state.quarantined.retain(|(w, _), _| w != worker_id);
 
-        // Fail all pending requests for this worker.
+        // Fail all pending requests for this worker, except that with a
+        // deadline they wait for it in case the worker reconnects.
         let failed_ids: Vec<String> = state
             .pending
             .iter()
             .filter(|(_, p)| p.worker_id == worker_id)
             .map(|(id, _)| id.clone())
             .collect();
+        let mut orphaned = 0;
         for id in &failed_ids {
             let pending = state.pending.remove(id).expect("checked above");
+            if pending.timer.is_some() {
+                state.orphaned.insert(id.clone(), pending);
+                orphaned += 1;
+                continue;
+            }
             let callback = pending.callback;
             // The callback spawns a tokio task that sends the error
             // back to the frontend, so invoking it here (while holding
             // the lock) is safe — the actual network send is async.
-            callback(RenderResponse {
-                files: vec![],
-                logs: "Internal error: worker died".to_owned(),
-                midi: String::new(),
-            });
+            callback(RenderResponse::failure("Internal error: worker died"));
         }
 
         info!(
-            "unregistered worker {} (removed {} idle slots, failed {} pending)",
+            "unregistered worker {} (removed {} idle slots, failed {} pending, {} left to its deadline)",
             worker_id,
             removed_idle,
-            failed_ids.len(),
+            failed_ids.len() - orphaned,
+            orphaned,
         );
         drop(state);
         self.republish_status().await;
     }
 
-    /// Try to dispatch a render request to an idle remote worker.
-    /// Returns `Ok(())` if dispatched, or `Err((request, callback))`
-    /// with the inputs back if no worker is idle or the send failed
-    /// (so the caller can re-queue). Called by `State::process_if_possible`.
+    /// Try to dispatch a render request to an idle remote worker slot
+    /// for its version, on a worker that renders its backend, choosing
+    /// the worker as described in the module comment. Returns
+    /// `Ok(())` if dispatched, or `Err((request, callback))` with the
+    /// inputs back if no such slot is idle or the send failed (so the
+    /// caller can re-queue). Called by `State::process_if_possible`.
     pub async fn try_dispatch(
         &self,
         request: Request,
         callback: ResponseCallback,
     ) -> Result<(), (Request, ResponseCallback)> {
+        let avoid = self
+            .timed_out
+            .lock()
+            .expect("timed_out lock poisoned")
+            .get(&request.id)
+            .cloned()
+            .unwrap_or_default();
         let mut state = self.inner.lock().await;
 
-        let slot = match state.idle.pop_front() {
+        let index = state.best_slot(&request, &avoid);
+        let slot = match index.and_then(|i| state.idle.remove(i)) {
             Some(s) => s,
             None => return Err((request, callback)),
         };
@@ -236,14 +765,19 @@ impl WorkerRegistryHandle {
         // Build the JSON-RPC render request to send to the worker.
         // The worker's `ws_worker_client` expects this format (see
         // `WsWorkerMethod::Render` in `command_source/ws_worker_client.rs`).
-        let render_params = serde_json::json!({
-            "backend": request.backend,
-            "src": request.src,
-            "version": request.version,
-        });
+        // Serializing the `Request` leaves out default options and empty
+        // files, so workers that predate them get the shape they know;
+        // the client's id stays here.
+        let mut render_params =
+            serde_json::to_value(&request).expect("render request is always serializable");
+        render_params
+            .as_object_mut()
+            .expect("a request serializes to an object")
+            .remove("id");
+        let dispatch_id = Uuid::new_v4().to_string();
         let rpc_request = jsonrpc::Request {
             jsonrpc: jsonrpc::JSONRPC_VERSION.to_owned(),
-            id: serde_json::json!(&request.id),
+            id: serde_json::json!(&dispatch_id),
             method: jsonrpc::method::RENDER.to_owned(),
             params: render_params,
         };
@@ -251,58 +785,145 @@ impl WorkerRegistryHandle {
             serde_json::to_string(&rpc_request).expect("render request is always serializable");
 
         let worker_id = slot.worker_id.clone();
-        let request_id = request.id.clone();
-
-        // Release the registry lock before sending.
-        drop(state);
+        let slot_version = slot.version;
 
-        {
-            let mut sink_guard = sink.lock().await;
-            if let Err(e) = sink_guard.send_text(text).await {
-                warn!("could not send render to worker {}: {}", worker_id, e);
-                // Could not dispatch — the worker is probably dead.
-                // `unregister_worker` will handle cleanup when the
-                // connection drops. Return the inputs so the caller
-                // can re-queue.
-                return Err((request, callback));
-            }
-        }
-
-        // Re-acquire the lock to record the pending request.
-        let mut state = self.inner.lock().await;
+        // Record the pending request before sending, so a worker that
+        // answers at once finds it.
+        let timer = self.deadline.map(|deadline| {
+            let registry = self.clone();
+            let worker_id = worker_id.clone();
+            let dispatch_id = dispatch_id.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(deadline.timeout).await;
+                registry.expire(&worker_id, &dispatch_id).await;
+            })
+        });
         state.pending.insert(
-            request_id,
+            dispatch_id.clone(),
             PendingRemote {
+                request,
                 callback,
                 worker_id: worker_id.clone(),
+                slot_version,
+                dispatched: Instant::now(),
+                timer,
             },
         );
-        debug!("dispatched render to worker {}", worker_id);
+
+        // Release the registry lock before sending.
         drop(state);
+
+        let sent = sink.lock().await.send_text(text).await;
+        if let Err(e) = sent {
+            warn!("could not send render to worker {}: {}", worker_id, e);
+            // Could not dispatch — the worker is probably dead.
+            // `unregister_worker` will handle cleanup when the
+            // connection drops. Take the inputs back so the caller can
+            // re-queue, unless that cleanup already answered them.
+            let mut state = self.inner.lock().await;
+            let pending = match state.pending.remove(&dispatch_id) {
+                Some(pending) => Some(pending),
+                None => state.orphaned.remove(&dispatch_id),
+            };
+            return match pending {
+                Some(pending) => {
+                    if let Some(timer) = pending.timer {
+                        timer.abort();
+                    }
+                    Err((pending.request, pending.callback))
+                }
+                None => Ok(()),
+            };
+        }
+
+        debug!("dispatched render to worker {}", worker_id);
         self.republish_status().await;
         Ok(())
     }
 
-    /// Deliver a render response from a remote worker. Looks up the
-    /// pending callback by request id, invokes it, and returns a new
-    /// idle slot for the worker. Called by the coordinator when it
-    /// receives a JSON-RPC response on a worker connection.
-    pub async fn handle_response(&self, request_id: &str, response: RenderResponse) {
+    /// Deliver a render response from remote worker `worker_id`. Looks
+    /// up the pending callback by the dispatch id the render was sent
+    /// under, invokes it, and returns a new idle slot for the worker.
+    /// Called by the coordinator when it receives a JSON-RPC response on
+    /// a worker connection.
+    pub async fn handle_response(
+        &self,
+        worker_id: &str,
+        dispatch_id: &str,
+        response: RenderResponse,
+    ) {
+        self.complete(worker_id, dispatch_id, response, true).await;
+    }
+
+    /// Like `handle_response`, for a worker that answered with an error
+    /// instead of a render result; counts against its failure rate.
+    pub async fn handle_error(&self, worker_id: &str, dispatch_id: &str, response: RenderResponse) {
+        self.complete(worker_id, dispatch_id, response, false).await;
+    }
+
+    async fn complete(
+        &self,
+        worker_id: &str,
+        dispatch_id: &str,
+        response: RenderResponse,
+        ok: bool,
+    ) {
         let mut state = self.inner.lock().await;
-        let pending = match state.pending.remove(request_id) {
-            Some(p) => p,
-            None => {
-                warn!(
-                    "received response for unknown request id {} (already completed or worker died?)",
-                    request_id,
+        let is_theirs = state
+            .pending
+            .get(dispatch_id)
+            .is_some_and(|p| p.worker_id == worker_id);
+        if !is_theirs {
+            if let Some(pending) = state.orphaned.remove(dispatch_id) {
+                info!(
+                    "worker {} answered {} (dispatch {}) after {} disconnected",
+                    worker_id, pending.request.id, dispatch_id, pending.worker_id,
                 );
+                if let Some(timer) = &pending.timer {
+                    timer.abort();
+                }
+                // Its old connection is gone, so the render counts for
+                // the worker that answered it (if it's registered); there
+                // is no slot to give back.
+                self.record_finished(&mut state, worker_id, &pending, ok);
+                (pending.callback)(response);
+                drop(state);
+                self.republish_status().await;
                 return;
             }
-        };
+            let key = (worker_id.to_owned(), dispatch_id.to_owned());
+            match state.quarantined.remove(&key) {
+                Some(version) => {
+                    // It was retried elsewhere; the worker is just slow.
+                    info!(
+                        "ignoring late response from worker {} for dispatch {}, releasing its slot",
+                        worker_id, dispatch_id,
+                    );
+                    state.release(WorkerSlot {
+                        worker_id: worker_id.to_owned(),
+                        version,
+                    });
+                    drop(state);
+                    self.republish_status().await;
+                }
+                None => warn!(
+                    "received response from worker {} for unknown dispatch id {} (already completed or worker died?)",
+                    worker_id, dispatch_id,
+                ),
+            }
+            return;
+        }
+        let pending = state.pending.remove(dispatch_id).expect("checked above");
+        if let Some(timer) = &pending.timer {
+            timer.abort();
+        }
+
+        self.record_finished(&mut state, worker_id, &pending, ok);
 
         // Return a slot for this worker to the idle queue.
-        state.idle.push_back(WorkerSlot {
+        state.release(WorkerSlot {
             worker_id: pending.worker_id.clone(),
+            version: pending.slot_version,
         });
 
         // Invoke the callback (it spawns its own async task to send
@@ -311,13 +932,111 @@ impl WorkerRegistryHandle {
         drop(state);
         self.republish_status().await;
     }
+
+    /// Count `pending`'s render, answered by `worker_id`, in that
+    /// worker's `RenderStats` and in the render metrics.
+    fn record_finished(
+        &self,
+        state: &mut WorkerRegistryState,
+        worker_id: &str,
+        pending: &PendingRemote,
+        ok: bool,
+    ) {
+        let elapsed = pending.dispatched.elapsed();
+        if let Some(meta) = state.workers.get_mut(worker_id) {
+            meta.stats.record(elapsed, ok);
+        }
+        if let Some(status) = &self.status {
+            status.snapshot().metrics.render_finished(
+                pending.request.backend,
+                pending.request.version,
+                Location::Remote,
+                elapsed,
+            );
+        }
+    }
+
+    /// Called by the deadline timer of dispatch `dispatch_id`: if that
+    /// render is still pending, quarantine its slot and retry or fail it.
+    async fn expire(&self, worker_id: &str, dispatch_id: &str) {
+        let deadline = self.deadline.expect("timers only run with a deadline");
+        let mut state = self.inner.lock().await;
+        let pending = match state.pending.remove(dispatch_id) {
+            Some(pending) => pending,
+            None => match state.orphaned.remove(dispatch_id) {
+                Some(pending) => pending,
+                None => return,
+            },
+        };
+        let request_id = pending.request.id.clone();
+        if state.workers.contains_key(worker_id) {
+            warn!(
+                "worker {} did not answer {} within {:?}; quarantining its slot",
+                worker_id, request_id, deadline.timeout,
+            );
+            if let Some(meta) = state.workers.get_mut(worker_id) {
+                meta.stats.record(deadline.timeout, false);
+            }
+            state.quarantined.insert(
+                (worker_id.to_owned(), dispatch_id.to_owned()),
+                pending.slot_version,
+            );
+            state.notify_if_drained(worker_id);
+        } else {
+            warn!(
+                "worker {} disconnected and nobody answered {} within {:?}",
+                worker_id, request_id, deadline.timeout,
+            );
+        }
+        let requeue = state.requeue.clone();
+        drop(state);
+        if let Some(status) = &self.status {
+            StatusHandle::bump(&status.snapshot().remote_timeouts);
+        }
+
+        let attempts = {
+            let mut timed_out = self.timed_out.lock().expect("timed_out lock poisoned");
+            let workers = timed_out.entry(request_id.clone()).or_default();
+            workers.push(worker_id.to_owned());
+            workers.len() as u32
+        };
+        match requeue {
+            Some(requeue) if attempts <= deadline.retries => {
+                // Forget where it timed out once it's finally answered.
+                let timed_out = self.timed_out.clone();
+                let callback = pending.callback;
+                requeue(
+                    pending.request,
+                    Box::new(move |response| {
+                        timed_out
+                            .lock()
+                            .expect("timed_out lock poisoned")
+                            .remove(&request_id);
+                        callback(response)
+                    }),
+                );
+            }
+            _ => {
+                self.timed_out
+                    .lock()
+                    .expect("timed_out lock poisoned")
+                    .remove(&request_id);
+                (pending.callback)(RenderResponse::failure(format!(
+                    "Internal error: no worker answered within {} ms",
+                    deadline.timeout.as_millis()
+                )));
+            }
+        }
+        self.republish_status().await;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::command_source::{SendFut, SharedSink, WsSink};
-    use crate::request::{Backend, Request, Version};
+    use crate::request::{Backend, RenderOptions, Request, Version};
+    use std::collections::BTreeMap;
 
     /// A `WsSink` that records sent text messages into a `Mutex<Vec<String>>`
     /// so tests can assert on what was dispatched.
@@ -333,9 +1052,18 @@ mod tests {
                 Ok(())
             })
         }
+        fn send_binary(&mut self, _data: Vec<u8>) -> SendFut<'_> {
+            Box::pin(async { Ok(()) })
+        }
         fn send_pong(&mut self, _payload: Vec<u8>) -> SendFut<'_> {
             Box::pin(async { Ok(()) })
         }
+        fn send_ping(&mut self, _payload: Vec<u8>) -> SendFut<'_> {
+            Box::pin(async { Ok(()) })
+        }
+        fn send_close(&mut self) -> SendFut<'_> {
+            Box::pin(async { Ok(()) })
+        }
     }
 
     fn discard_sink() -> SharedSink {
@@ -350,9 +1078,34 @@ mod tests {
             backend: Backend::Svg,
             src: "c4".to_owned(),
             version: Version::Stable,
+            options: RenderOptions::default(),
+            files: BTreeMap::new(),
         }
     }
 
+    /// The id the dispatch of client request `request_id` went out under.
+    async fn dispatch_id(reg: &WorkerRegistryHandle, request_id: &str) -> String {
+        let state = reg.inner.lock().await;
+        state
+            .pending
+            .iter()
+            .chain(&state.orphaned)
+            .find(|(_, p)| p.request.id == request_id)
+            .map(|(id, _)| id.clone())
+            .expect("dispatched")
+    }
+
+    /// The worker rendering client request `request_id`.
+    async fn worker_of(reg: &WorkerRegistryHandle, request_id: &str) -> String {
+        let state = reg.inner.lock().await;
+        state
+            .pending
+            .values()
+            .find(|p| p.request.id == request_id)
+            .map(|p| p.worker_id.clone())
+            .expect("dispatched")
+    }
+
     fn cb_noop() -> ResponseCallback {
         Box::new(|_r| {})
     }
@@ -372,7 +1125,12 @@ mod tests {
     #[tokio::test]
     async fn register_then_dispatch_succeeds() {
         let reg = WorkerRegistryHandle::new();
-        reg.register_worker("w1".into(), 2, discard_sink()).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
         assert_eq!(reg.worker_count().await, 1);
         assert_eq!(reg.idle_slot_count().await, 2);
 
@@ -388,7 +1146,12 @@ mod tests {
     #[tokio::test]
     async fn handle_response_returns_slot_and_invokes_callback() {
         let reg = WorkerRegistryHandle::new();
-        reg.register_worker("w1".into(), 1, discard_sink()).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(1),
+            discard_sink(),
+        )
+        .await;
 
         let req = sample_request("r3");
         match reg.try_dispatch(req, cb_noop()).await {
@@ -400,16 +1163,153 @@ mod tests {
             files: vec!["svg".into()],
             logs: "ok".into(),
             midi: String::new(),
+            audio: String::new(),
         };
-        reg.handle_response("r3", rendered).await;
+        let id = dispatch_id(&reg, "r3").await;
+        reg.handle_response("w1", &id, rendered).await;
+        assert_eq!(reg.busy_slot_count().await, 0);
+        assert_eq!(reg.idle_slot_count().await, 1);
+    }
+
+    /// A worker so quick it answers each render before the send of it
+    /// has returned.
+    struct AnsweringSink {
+        reg: WorkerRegistryHandle,
+    }
+
+    impl WsSink for AnsweringSink {
+        fn send_text(&mut self, text: String) -> SendFut<'_> {
+            let reg = self.reg.clone();
+            Box::pin(async move {
+                let sent: serde_json::Value = serde_json::from_str(&text).unwrap();
+                let rendered = RenderResponse {
+                    files: vec!["svg".into()],
+                    logs: "quick".into(),
+                    midi: String::new(),
+                    audio: String::new(),
+                };
+                reg.handle_response("w1", sent["id"].as_str().unwrap(), rendered)
+                    .await;
+                Ok(())
+            })
+        }
+        fn send_binary(&mut self, _data: Vec<u8>) -> SendFut<'_> {
+            Box::pin(async { Ok(()) })
+        }
+        fn send_pong(&mut self, _payload: Vec<u8>) -> SendFut<'_> {
+            Box::pin(async { Ok(()) })
+        }
+        fn send_ping(&mut self, _payload: Vec<u8>) -> SendFut<'_> {
+            Box::pin(async { Ok(()) })
+        }
+        fn send_close(&mut self) -> SendFut<'_> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_that_beat_the_dispatch_are_delivered() {
+        let reg = WorkerRegistryHandle::new();
+        let sink: SharedSink = Arc::new(tokio::sync::Mutex::new(Box::new(AnsweringSink {
+            reg: reg.clone(),
+        })));
+        reg.register_worker("w1".into(), WorkerCapabilities::any_version(1), sink)
+            .await;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cb: ResponseCallback = Box::new(move |r| tx.send(r.logs).unwrap());
+        if reg
+            .try_dispatch(sample_request("r-quick"), cb)
+            .await
+            .is_err()
+        {
+            panic!("dispatch ok");
+        }
+        assert_eq!(rx.try_recv().as_deref(), Ok("quick"));
         assert_eq!(reg.busy_slot_count().await, 0);
         assert_eq!(reg.idle_slot_count().await, 1);
     }
 
+    #[tokio::test]
+    async fn workers_only_get_options_and_files_that_are_set() {
+        let reg = WorkerRegistryHandle::new();
+        let sent = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink: SharedSink = Arc::new(tokio::sync::Mutex::new(Box::new(FakeSink {
+            sent: sent.clone(),
+        })));
+        reg.register_worker("w1".into(), WorkerCapabilities::any_version(2), sink)
+            .await;
+
+        assert!(reg
+            .try_dispatch(sample_request("plain"), cb_noop())
+            .await
+            .is_ok());
+        let mut with_files = sample_request("with-files");
+        with_files
+            .files
+            .insert("notes.ly".to_owned(), "c4".to_owned());
+        assert!(reg.try_dispatch(with_files, cb_noop()).await.is_ok());
+
+        let params: Vec<serde_json::Value> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|text| serde_json::from_str::<serde_json::Value>(text).unwrap()["params"].clone())
+            .collect();
+        assert_eq!(
+            params[0],
+            serde_json::json!({ "backend": "svg", "src": "c4", "version": "stable" })
+        );
+        assert_eq!(params[1]["files"], serde_json::json!({ "notes.ly": "c4" }));
+        assert!(params[1].get("options").is_none());
+    }
+
+    #[tokio::test]
+    async fn clients_may_reuse_request_ids() {
+        let reg = WorkerRegistryHandle::new();
+        let sent = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink: SharedSink = Arc::new(tokio::sync::Mutex::new(Box::new(FakeSink {
+            sent: sent.clone(),
+        })));
+        reg.register_worker("w1".into(), WorkerCapabilities::any_version(2), sink)
+            .await;
+
+        // Two frontends both call their render "1".
+        let (alice, alice_got) = cb_recording();
+        let (bob, bob_got) = cb_recording();
+        assert!(reg.try_dispatch(sample_request("1"), alice).await.is_ok());
+        assert!(reg.try_dispatch(sample_request("1"), bob).await.is_ok());
+        assert_eq!(reg.busy_slot_count().await, 2);
+
+        let ids: Vec<String> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|text| {
+                let sent: serde_json::Value = serde_json::from_str(text).unwrap();
+                sent["id"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&"1".to_owned()));
+        reg.handle_response("w1", &ids[1], RenderResponse::ok("for bob"))
+            .await;
+        reg.handle_response("w1", &ids[0], RenderResponse::ok("for alice"))
+            .await;
+        assert_eq!(*alice_got.lock().unwrap(), ["for alice"]);
+        assert_eq!(*bob_got.lock().unwrap(), ["for bob"]);
+        assert_eq!(reg.idle_slot_count().await, 2);
+    }
+
     #[tokio::test]
     async fn unregister_worker_fails_pending() {
         let reg = WorkerRegistryHandle::new();
-        reg.register_worker("w1".into(), 1, discard_sink()).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(1),
+            discard_sink(),
+        )
+        .await;
         let req = sample_request("r4");
         match reg.try_dispatch(req, cb_noop()).await {
             Ok(()) => {}
@@ -426,13 +1326,445 @@ mod tests {
     #[tokio::test]
     async fn unregister_worker_drains_idle_slots() {
         let reg = WorkerRegistryHandle::new();
-        reg.register_worker("w1".into(), 3, discard_sink()).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(3),
+            discard_sink(),
+        )
+        .await;
         assert_eq!(reg.idle_slot_count().await, 3);
 
         reg.unregister_worker("w1").await;
         assert_eq!(reg.idle_slot_count().await, 0);
     }
 
+    #[tokio::test]
+    async fn dispatch_respects_versions_and_backends() {
+        let reg = WorkerRegistryHandle::new();
+        reg.register_worker(
+            "stable-svg".into(),
+            WorkerCapabilities {
+                slots: HashMap::from([(Some(Version::Stable), 1)]),
+                backends: Some(vec![Backend::Svg]),
+            },
+            discard_sink(),
+        )
+        .await;
+
+        let mut unstable = sample_request("unstable");
+        unstable.version = Version::Unstable;
+        let mut pdf = sample_request("pdf");
+        pdf.backend = Backend::Pdf;
+        assert!(!reg.can_render(&unstable).await);
+        assert!(!reg.can_render(&pdf).await);
+        assert!(reg.try_dispatch(unstable, cb_noop()).await.is_err());
+        assert!(reg.try_dispatch(pdf, cb_noop()).await.is_err());
+        assert_eq!(reg.idle_slot_count().await, 1);
+
+        let stable = sample_request("stable");
+        assert!(reg.can_render(&stable).await);
+        assert!(reg.try_dispatch(stable, cb_noop()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn slots_return_to_their_version() {
+        let reg = WorkerRegistryHandle::new();
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities {
+                slots: HashMap::from([(Some(Version::Unstable), 1)]),
+                backends: None,
+            },
+            discard_sink(),
+        )
+        .await;
+
+        let mut first = sample_request("first");
+        first.version = Version::Unstable;
+        assert!(reg.try_dispatch(first, cb_noop()).await.is_ok());
+        let mut second = sample_request("second");
+        second.version = Version::Unstable;
+        // Busy, but it could render this once the slot frees up.
+        assert!(reg.can_render(&second).await);
+        assert!(reg.try_dispatch(second.clone(), cb_noop()).await.is_err());
+
+        let id = dispatch_id(&reg, "first").await;
+        reg.handle_response("w1", &id, RenderResponse::ok("ok"))
+            .await;
+        assert!(reg
+            .try_dispatch(sample_request("stable"), cb_noop())
+            .await
+            .is_err());
+        assert!(reg.try_dispatch(second, cb_noop()).await.is_ok());
+    }
+
+    fn ok_response() -> RenderResponse {
+        RenderResponse::ok("ok")
+    }
+
+    #[test]
+    fn tiny_silences_still_get_a_nonzero_ping_interval() {
+        let heartbeat = Heartbeat::for_silence(Duration::from_secs(30));
+        assert_eq!(heartbeat.interval, Duration::from_secs(10));
+        for msec in [0, 1, 2] {
+            let heartbeat = Heartbeat::for_silence(Duration::from_millis(msec));
+            assert_eq!(heartbeat.interval, Duration::from_millis(1));
+            assert_eq!(heartbeat.silence, Duration::from_millis(msec));
+        }
+    }
+
+    #[test]
+    fn render_stats_smooth_latency_and_failures() {
+        let mut stats = RenderStats::default();
+        assert_eq!(stats.expected_msec(), None);
+        stats.record(Duration::from_millis(100), true);
+        assert_eq!(stats.latency_msec(), Some(100.0));
+        stats.record(Duration::from_millis(200), true);
+        assert_eq!(stats.latency_msec(), Some(120.0));
+        // Failures don't move the latency, but make the worker look
+        // slower by the expected retries.
+        stats.record(Duration::from_millis(5000), false);
+        assert_eq!(stats.latency_msec(), Some(120.0));
+        assert!((stats.failure_rate() - 0.2).abs() < 1e-9);
+        assert!((stats.expected_msec().unwrap() - 150.0).abs() < 1e-9);
+        assert_eq!((stats.completed, stats.failed), (2, 1));
+    }
+
+    /// Registers `w` with one slot and a render history of `msec`.
+    async fn register_with_latency(reg: &WorkerRegistryHandle, w: &str, msec: u64, ok: bool) {
+        reg.register_worker(w.into(), WorkerCapabilities::any_version(1), discard_sink())
+            .await;
+        let mut state = reg.inner.lock().await;
+        let meta = state.workers.get_mut(w).expect("registered");
+        meta.stats.record(Duration::from_millis(msec), ok);
+    }
+
+    #[tokio::test]
+    async fn dispatch_prefers_faster_healthier_workers() {
+        let reg = WorkerRegistryHandle::new();
+        register_with_latency(&reg, "slow", 900, true).await;
+        register_with_latency(&reg, "fast", 100, true).await;
+        register_with_latency(&reg, "broken", 50, true).await;
+        for _ in 0..10 {
+            let mut state = reg.inner.lock().await;
+            let meta = state.workers.get_mut("broken").expect("registered");
+            meta.stats.record(Duration::from_millis(50), false);
+        }
+
+        assert!(reg
+            .try_dispatch(sample_request("a"), cb_noop())
+            .await
+            .is_ok());
+        assert!(reg
+            .try_dispatch(sample_request("b"), cb_noop())
+            .await
+            .is_ok());
+        assert!(reg
+            .try_dispatch(sample_request("c"), cb_noop())
+            .await
+            .is_ok());
+        assert_eq!(worker_of(&reg, "a").await, "fast");
+        assert_eq!(worker_of(&reg, "b").await, "broken");
+        assert_eq!(worker_of(&reg, "c").await, "slow");
+    }
+
+    #[tokio::test]
+    async fn new_workers_are_tried_and_less_loaded_ones_win_ties() {
+        let reg = WorkerRegistryHandle::new();
+        register_with_latency(&reg, "known", 10, true).await;
+        reg.register_worker(
+            "new".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
+        reg.register_worker(
+            "newer".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
+
+        for id in ["a", "b", "c"] {
+            assert!(reg
+                .try_dispatch(sample_request(id), cb_noop())
+                .await
+                .is_ok());
+        }
+        let mut on_new = vec![];
+        for id in ["a", "b", "c"] {
+            on_new.push(worker_of(&reg, id).await);
+        }
+        on_new.sort();
+        assert_eq!(on_new, ["new", "new", "newer"]);
+    }
+
+    #[tokio::test]
+    async fn faster_than_compares_with_local_stats() {
+        let reg = WorkerRegistryHandle::new();
+        let mut local = RenderStats::default();
+        let request = sample_request("r");
+        register_with_latency(&reg, "w1", 300, true).await;
+        // No local numbers yet: stay local.
+        assert!(!reg.faster_than(&local, &request).await);
+        local.record(Duration::from_millis(200), true);
+        assert!(!reg.faster_than(&local, &request).await);
+        local.record(Duration::from_millis(2000), true);
+        assert!(reg.faster_than(&local, &request).await);
+    }
+
+    #[tokio::test]
+    async fn responses_and_errors_update_published_stats() {
+        let status = StatusHandle::new();
+        let reg = WorkerRegistryHandle::with_status(status.clone());
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
+        assert!(reg
+            .try_dispatch(sample_request("a"), cb_noop())
+            .await
+            .is_ok());
+        assert!(reg
+            .try_dispatch(sample_request("b"), cb_noop())
+            .await
+            .is_ok());
+
+        let listed = status.snapshot().remote_workers();
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].slots, listed[0].busy), (2, 2));
+        assert_eq!(listed[0].latency_msec, None);
+
+        let (a, b) = (dispatch_id(&reg, "a").await, dispatch_id(&reg, "b").await);
+        reg.handle_response("w1", &a, ok_response()).await;
+        reg.handle_error("w1", &b, ok_response()).await;
+        let listed = status.snapshot().remote_workers();
+        assert_eq!(listed[0].id, "w1");
+        assert_eq!((listed[0].completed, listed[0].failed), (1, 1));
+        assert_eq!(listed[0].busy, 0);
+        assert!(listed[0].latency_msec.is_some());
+        assert!(listed[0].failure_rate > 0.0);
+
+        reg.unregister_worker("w1").await;
+        assert!(status.snapshot().remote_workers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn detail_lists_slots_and_renders_in_flight() {
+        let reg = WorkerRegistryHandle::new();
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities {
+                slots: HashMap::from([(Some(Version::Stable), 2), (None, 1)]),
+                backends: None,
+            },
+            discard_sink(),
+        )
+        .await;
+        reg.set_address("w1", "192.0.2.7:51234".to_owned()).await;
+        assert!(reg
+            .try_dispatch(sample_request("a"), cb_noop())
+            .await
+            .is_ok());
+
+        let detail = reg.detail().await;
+        assert_eq!(detail.len(), 1);
+        assert_eq!(detail[0].address.as_deref(), Some("192.0.2.7:51234"));
+        assert_eq!(detail[0].slots, BTreeMap::from([("any", 1), ("stable", 2)]));
+        let in_flight: Vec<&str> = detail[0].in_flight.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(in_flight, ["a"]);
+
+        let id = dispatch_id(&reg, "a").await;
+        reg.handle_response("w1", &id, ok_response()).await;
+        assert!(reg.detail().await[0].in_flight.is_empty());
+    }
+
+    /// A callback recording the logs of each response it gets.
+    fn cb_recording() -> (ResponseCallback, Arc<std::sync::Mutex<Vec<String>>>) {
+        let got = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = got.clone();
+        let cb: ResponseCallback = Box::new(move |r| sink.lock().unwrap().push(r.logs));
+        (cb, got)
+    }
+
+    type Requeued = Arc<std::sync::Mutex<Vec<(Request, ResponseCallback)>>>;
+
+    async fn registry_with_deadline(
+        retries: u32,
+    ) -> (WorkerRegistryHandle, StatusHandle, Requeued) {
+        let status = StatusHandle::new();
+        let reg = WorkerRegistryHandle::with_status(status.clone()).with_deadline(Deadline {
+            timeout: Duration::from_millis(50),
+            retries,
+        });
+        let requeued: Requeued = Arc::default();
+        let sink = requeued.clone();
+        reg.set_requeue(Arc::new(move |request, cb| {
+            sink.lock().unwrap().push((request, cb))
+        }))
+        .await;
+        (reg, status, requeued)
+    }
+
+    #[tokio::test]
+    async fn timed_out_renders_are_retried_elsewhere_and_late_answers_dropped() {
+        let (reg, status, requeued) = registry_with_deadline(1).await;
+        for w in ["w1", "w2"] {
+            reg.register_worker(w.into(), WorkerCapabilities::any_version(1), discard_sink())
+                .await;
+        }
+        let (cb, got) = cb_recording();
+        assert!(reg.try_dispatch(sample_request("r"), cb).await.is_ok());
+        assert_eq!(worker_of(&reg, "r").await, "w1");
+        let first = dispatch_id(&reg, "r").await;
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let (request, cb) = requeued.lock().unwrap().pop().expect("requeued");
+        assert_eq!(status.snapshot().remote_timeouts.load(Ordering::Relaxed), 1);
+        assert_eq!(status.snapshot().remote_workers()[0].quarantined, 1);
+        assert_eq!(reg.idle_slot_count().await, 1);
+
+        // The retry goes to the other worker...
+        assert!(reg.try_dispatch(request, cb).await.is_ok());
+        assert_eq!(worker_of(&reg, "r").await, "w2");
+        let retry = dispatch_id(&reg, "r").await;
+        assert_ne!(first, retry);
+        // ...and the first one's late answer only frees its slot, even
+        // if it arrives on the connection of the retry.
+        reg.handle_response("w2", &first, ok_response()).await;
+        assert!(got.lock().unwrap().is_empty());
+        reg.handle_response("w1", &first, ok_response()).await;
+        assert!(got.lock().unwrap().is_empty());
+        assert_eq!(reg.idle_slot_count().await, 1);
+        assert_eq!(status.snapshot().remote_workers()[0].quarantined, 0);
+
+        reg.handle_response("w2", &retry, ok_response()).await;
+        assert_eq!(*got.lock().unwrap(), ["ok"]);
+        assert!(reg.timed_out.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let (reg, _status, requeued) = registry_with_deadline(0).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(1),
+            discard_sink(),
+        )
+        .await;
+        let (cb, got) = cb_recording();
+        assert!(reg.try_dispatch(sample_request("r"), cb).await.is_ok());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(requeued.lock().unwrap().is_empty());
+        let got = got.lock().unwrap();
+        assert_eq!(got.len(), 1);
+        assert!(got[0].starts_with("Internal error: no worker answered"));
+        assert!(reg.timed_out.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn renders_outlive_their_worker_until_the_deadline() {
+        let (reg, status, requeued) = registry_with_deadline(1).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
+        let (cb, got) = cb_recording();
+        assert!(reg.try_dispatch(sample_request("r"), cb).await.is_ok());
+        assert!(reg
+            .try_dispatch(sample_request("lost"), cb_noop())
+            .await
+            .is_ok());
+        let id = dispatch_id(&reg, "r").await;
+        reg.unregister_worker("w1").await;
+        assert_eq!(reg.busy_slot_count().await, 0);
+
+        // The same worker, reconnected under a new id, answers one; an
+        // answer under the client's id instead is not accepted...
+        reg.register_worker(
+            "w1-again".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
+        reg.handle_response("w1-again", "r", ok_response()).await;
+        assert!(got.lock().unwrap().is_empty());
+        reg.handle_response("w1-again", &id, ok_response()).await;
+        assert_eq!(*got.lock().unwrap(), ["ok"]);
+        assert_eq!(reg.idle_slot_count().await, 2);
+        // It counts for the worker that answered, and is published.
+        let listed = status.snapshot().remote_workers();
+        assert_eq!(listed[0].id, "w1-again");
+        assert_eq!((listed[0].completed, listed[0].failed), (1, 0));
+        assert!(crate::metrics::exposition(&status).contains(
+            "hacklily_render_duration_seconds_count{backend=\"svg\",version=\"stable\",location=\"remote\"} 1\n"
+        ));
+
+        // ...and the other goes back to the queue at the deadline.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let requeued = requeued.lock().unwrap();
+        assert_eq!(requeued.len(), 1);
+        assert_eq!(requeued[0].0.id, "lost");
+    }
+
+    #[tokio::test]
+    async fn answers_in_time_cancel_the_deadline() {
+        let (reg, status, requeued) = registry_with_deadline(1).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(1),
+            discard_sink(),
+        )
+        .await;
+        let (cb, got) = cb_recording();
+        assert!(reg.try_dispatch(sample_request("r"), cb).await.is_ok());
+        let id = dispatch_id(&reg, "r").await;
+        reg.handle_response("w1", &id, ok_response()).await;
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(*got.lock().unwrap(), ["ok"]);
+        assert!(requeued.lock().unwrap().is_empty());
+        assert_eq!(status.snapshot().remote_timeouts.load(Ordering::Relaxed), 0);
+        assert_eq!(reg.idle_slot_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn draining_lets_pending_renders_finish_then_notifies() {
+        let reg = WorkerRegistryHandle::new();
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
+        let (cb, got) = cb_recording();
+        assert!(reg.try_dispatch(sample_request("r"), cb).await.is_ok());
+        let drained = reg.drained("w1").await.expect("registered");
+
+        assert!(reg.drain_worker("w1").await);
+        assert!(!reg.drain_worker("nobody").await);
+        assert_eq!(reg.idle_slot_count().await, 0);
+        assert!(!reg.can_render(&sample_request("next")).await);
+        assert!(reg
+            .try_dispatch(sample_request("next"), cb_noop())
+            .await
+            .is_err());
+        let not_yet = tokio::time::timeout(Duration::from_millis(50), drained.notified()).await;
+        assert!(not_yet.is_err());
+
+        let id = dispatch_id(&reg, "r").await;
+        reg.handle_response("w1", &id, ok_response()).await;
+        assert_eq!(*got.lock().unwrap(), ["ok"]);
+        assert_eq!(reg.idle_slot_count().await, 0);
+        let done = tokio::time::timeout(Duration::from_millis(50), drained.notified()).await;
+        assert!(done.is_ok());
+    }
+
     #[tokio::test]
     async fn with_status_publishes_remote_counts() {
         let status = StatusHandle::new();
@@ -443,7 +1775,12 @@ mod tests {
         assert_eq!(snap.remote_total.load(Ordering::Relaxed), 0);
         assert_eq!(snap.remote_free.load(Ordering::Relaxed), 0);
 
-        reg.register_worker("w1".into(), 2, discard_sink()).await;
+        reg.register_worker(
+            "w1".into(),
+            WorkerCapabilities::any_version(2),
+            discard_sink(),
+        )
+        .await;
         assert_eq!(snap.remote_total.load(Ordering::Relaxed), 1);
         assert_eq!(snap.remote_free.load(Ordering::Relaxed), 2);
         assert_eq!(snap.remote_busy.load(Ordering::Relaxed), 0);
@@ -457,15 +1794,9 @@ mod tests {
         assert_eq!(snap.remote_busy.load(Ordering::Relaxed), 1);
 
         // A response returns the slot to free.
-        reg.handle_response(
-            "rx",
-            RenderResponse {
-                files: vec![],
-                logs: "ok".into(),
-                midi: String::new(),
-            },
-        )
-        .await;
+        let id = dispatch_id(&reg, "rx").await;
+        reg.handle_response("w1", &id, RenderResponse::ok("ok"))
+            .await;
         assert_eq!(snap.remote_free.load(Ordering::Relaxed), 2);
         assert_eq!(snap.remote_busy.load(Ordering::Relaxed), 0);
 
@@ -475,4 +1806,3 @@ mod tests {
         assert_eq!(snap.remote_free.load(Ordering::Relaxed), 0);
     }
 }
-
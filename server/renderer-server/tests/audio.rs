@@ -0,0 +1,58 @@
+#![warn(clippy::all)]
+
+extern crate renderer_lib;
+
+mod common;
+mod util;
+
+use self::common::request;
+use self::util::run_test;
+use renderer_lib::request::{AudioFormat, Backend, RenderOptions, Request, Version};
+
+fn get_request(id: &str, src: &str, audio: AudioFormat) -> Request {
+    Request {
+        options: RenderOptions {
+            audio: Some(audio),
+            ..RenderOptions::default()
+        },
+        ..request(id, Backend::Svg, Version::Stable, src)
+    }
+}
+
+#[test]
+fn audio() {
+    let res = run_test(vec![
+        get_request("ogg", include_str!("ly/simple_midi.ly"), AudioFormat::Ogg),
+        get_request("mp3", include_str!("ly/simple_midi.ly"), AudioFormat::Mp3),
+        get_request("no-midi", include_str!("ly/simple.ly"), AudioFormat::Ogg),
+    ]);
+
+    // base64 of "OggS", the Ogg page capture pattern.
+    let ogg = &res["ogg"];
+    assert!(!ogg.files.is_empty(), "ogg render failed: {}", ogg.logs);
+    assert!(
+        ogg.audio.starts_with("T2dnUw"),
+        "ogg payload is not an Ogg stream; logs: {}",
+        ogg.logs
+    );
+
+    // lame writes an ID3 tag ("SUQz") or starts straight on a frame sync
+    // (0xFF 0xFB/0xF3, "//").
+    let mp3 = &res["mp3"];
+    assert!(
+        mp3.audio.starts_with("SUQz") || mp3.audio.starts_with("//"),
+        "mp3 payload is not an MP3 stream; logs: {}",
+        mp3.logs
+    );
+
+    // Without a \midi block there's nothing to synthesize, but the render
+    // itself still succeeds.
+    let no_midi = &res["no-midi"];
+    assert_eq!(no_midi.files.len(), 1);
+    assert!(no_midi.audio.is_empty());
+    assert!(
+        no_midi.logs.contains("audio: no MIDI output"),
+        "logs were: {}",
+        no_midi.logs
+    );
+}
@@ -0,0 +1,15 @@
+use renderer_lib::request::{Backend, RenderOptions, Request, Version};
+use std::collections::BTreeMap;
+
+/// A request to render `src` with default options and no other files;
+/// tests change the rest with `..request(...)`.
+pub fn request(id: &str, backend: Backend, version: Version, src: &str) -> Request {
+    Request {
+        id: id.to_owned(),
+        backend,
+        version,
+        src: src.to_owned(),
+        options: RenderOptions::default(),
+        files: BTreeMap::new(),
+    }
+}
@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright (C) 2017-present Jocelyn Stericker <jocelyn@nettek.ca>
+#![warn(clippy::all)]
+
+// End-to-end test of the coordinator terminating TLS itself, with
+// mutual TLS for workers, using the throwaway CA in `tests/tls`. Like
+// `coordinator_ws`, no Docker: the local render pool is empty.
+
+extern crate renderer_lib;
+
+use futures::{SinkExt, StreamExt};
+use renderer_lib::tls::{TlsAcceptorHandle, TlsClientFiles, TlsFiles};
+use renderer_lib::{
+    event_loop, job_log::JobLog, status::StatusHandle, worker_registry::WorkerRegistryHandle,
+    CommandSourceConfig, Config,
+};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+fn ephemeral_port() -> u16 {
+    let l = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    l.local_addr().expect("addr").port()
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/tls")
+        .join(name)
+}
+
+/// Trust the test CA, optionally presenting the test client certificate.
+fn client(with_cert: bool) -> TlsClientFiles {
+    TlsClientFiles {
+        ca: Some(fixture("ca.pem")),
+        cert: Some(fixture("client.pem")).filter(|_| with_cert),
+        key: Some(fixture("client.key")).filter(|_| with_cert),
+    }
+}
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Sends `method` as the first message and returns the socket and the
+/// first reply, if any arrives before the coordinator closes it.
+async fn first_reply(port: u16, tls: TlsClientFiles, method: &str) -> (Client, Option<Value>) {
+    let connector = Connector::Rustls(tls.client_config().expect("client config"));
+    let (mut ws, _resp) = tokio_tungstenite::connect_async_tls_with_config(
+        format!("wss://localhost:{}", port),
+        None,
+        Some(connector),
+    )
+    .await
+    .expect("connect");
+    let hello = json!({
+        "jsonrpc": "2.0",
+        "id": "1",
+        "method": method,
+        "params": { "max_jobs": 1 },
+    });
+    ws.send(Message::Text(hello.to_string()))
+        .await
+        .expect("send");
+    let reply = tokio::time::timeout(Duration::from_millis(500), ws.next())
+        .await
+        .ok()
+        .flatten()
+        .and_then(|m| m.ok())
+        .and_then(|m| m.into_text().ok())
+        .and_then(|t| serde_json::from_str::<Value>(&t).ok());
+    (ws, reply)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_serves_tls_and_requires_worker_certificates() {
+    let port = ephemeral_port();
+    let status_port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let tls = TlsAcceptorHandle::load(TlsFiles {
+        cert: fixture("server.pem"),
+        key: fixture("server.key"),
+        client_ca: Some(fixture("ca.pem")),
+    })
+    .expect("load TLS files");
+
+    let config = Config {
+        stable_docker_tag: "unused-no-local-pool".to_owned(),
+        stable_worker_count: 0,
+        unstable_docker_tag: "unused-no-local-pool".to_owned(),
+        unstable_worker_count: 0,
+        render_timeout_msec: 8000,
+        audio_max_bytes: 8 * 1024 * 1024,
+        job_log: JobLog::default(),
+        status: status.clone(),
+        command_source: CommandSourceConfig::Coordinator {
+            bind_address: "127.0.0.1".parse().unwrap(),
+            ws_port: port,
+            github_client_id: String::new(),
+            github_secret: String::new(),
+            workers: workers.clone(),
+            status: status.clone(),
+            ws_compression: false,
+            worker_keys: None,
+            worker_listener: None,
+            worker_path: None,
+            tls: Some(tls),
+            admin_token: None,
+            cluster: None,
+            http_listener: Some(([127, 0, 0, 1], status_port).into()),
+        },
+    };
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // Plain ws:// gets nowhere.
+    let plain = tokio_tungstenite::connect_async(format!("ws://localhost:{}", port)).await;
+    assert!(plain.is_err());
+
+    // A worker without a client certificate is turned away...
+    let (_ws, reply) = first_reply(port, client(false), "i_haz_computes").await;
+    assert_eq!(reply.expect("refusal")["error"]["code"], json!(4));
+    assert_eq!(
+        status.snapshot().workers_rejected.load(Ordering::Relaxed),
+        1
+    );
+
+    // ...one with a certificate registers...
+    let (_worker, reply) = first_reply(port, client(true), "i_haz_computes").await;
+    assert_eq!(reply, None);
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 1);
+
+    // ...and frontends don't need one.
+    let (_frontend, reply) = first_reply(port, client(false), "get_status").await;
+    assert_eq!(
+        reply.expect("status")["result"]["remote_worker_count"],
+        json!(1)
+    );
+
+    // The status endpoint speaks HTTPS too.
+    let connector = tokio_rustls::TlsConnector::from(client(false).client_config().unwrap());
+    let tcp = TcpStream::connect(("127.0.0.1", status_port))
+        .await
+        .expect("connect status");
+    let mut https = connector
+        .connect("localhost".try_into().unwrap(), tcp)
+        .await
+        .expect("TLS handshake");
+    https
+        .write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    let _ = https.read_to_string(&mut response).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(
+        response.contains("\"remote_worker_count\":1"),
+        "{}",
+        response
+    );
+}
@@ -16,12 +16,22 @@
 extern crate renderer_lib;
 
 use futures::{SinkExt, StreamExt};
+use renderer_lib::request::{Backend, Request, Response as RenderResponse, Version};
 use renderer_lib::{
-    event_loop, status::StatusHandle, worker_registry::WorkerRegistryHandle, CommandSourceConfig,
-    Config,
+    backoff::Backoff,
+    cluster::{Cluster, InProcessState},
+    event_loop,
+    job_log::{JobLog, JobState, MAX_OUTSTANDING_PER_CONNECTION},
+    status::StatusHandle,
+    tls::TlsClientFiles,
+    wire,
+    worker_auth::{self, AdminToken, WorkerKeys},
+    worker_registry::{Heartbeat, WorkerRegistryHandle},
+    CommandSourceConfig, Config, CoordinatorLink, Outbox,
 };
 use serde_json::{json, Value};
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio_tungstenite::tungstenite::Message;
 
@@ -31,18 +41,17 @@ fn ephemeral_port() -> u16 {
     l.local_addr().expect("addr").port()
 }
 
-#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
-async fn coordinator_dispatches_render_to_remote_worker_end_to_end() {
-    let port = ephemeral_port();
-    let status = StatusHandle::new();
-    let workers = WorkerRegistryHandle::with_status(status.clone());
-
-    let config = Config {
+/// A coordinator on `port` with no local render pool, so renders MUST
+/// go to remote workers. Tests change only the fields they exercise.
+fn coordinator_config(port: u16, status: &StatusHandle, workers: &WorkerRegistryHandle) -> Config {
+    Config {
         stable_docker_tag: "unused-no-local-pool".to_owned(),
         stable_worker_count: 0,
         unstable_docker_tag: "unused-no-local-pool".to_owned(),
         unstable_worker_count: 0,
         render_timeout_msec: 8000,
+        audio_max_bytes: 8 * 1024 * 1024,
+        job_log: JobLog::default(),
         status: status.clone(),
         command_source: CommandSourceConfig::Coordinator {
             bind_address: "127.0.0.1".parse().unwrap(),
@@ -51,8 +60,25 @@ async fn coordinator_dispatches_render_to_remote_worker_end_to_end() {
             github_secret: String::new(),
             workers: workers.clone(),
             status: status.clone(),
+            ws_compression: false,
+            worker_keys: None,
+            worker_listener: None,
+            worker_path: None,
+            tls: None,
+            admin_token: None,
+            cluster: None,
+            http_listener: None,
         },
-    };
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_dispatches_render_to_remote_worker_end_to_end() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+
+    let config = coordinator_config(port, &status, &workers);
 
     // Run the full event loop in the background. It binds the
     // coordinator listener and processes render requests. With zero
@@ -181,3 +207,1252 @@ async fn coordinator_dispatches_render_to_remote_worker_end_to_end() {
     );
 }
 
+/// Connect to the coordinator offering the binary-results subprotocol,
+/// asserting that the coordinator accepted it.
+async fn connect_binary(
+    port: u16,
+) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>> {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    let mut req = format!("ws://127.0.0.1:{}", port)
+        .into_client_request()
+        .expect("request");
+    req.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        wire::BINARY_SUBPROTOCOL.parse().unwrap(),
+    );
+    let (ws, resp) = tokio_tungstenite::connect_async(req)
+        .await
+        .expect("connect");
+    assert_eq!(
+        resp.headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|v| v.to_str().ok()),
+        Some(wire::BINARY_SUBPROTOCOL),
+        "coordinator accepted the binary subprotocol",
+    );
+    ws
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_relays_binary_results_end_to_end() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+
+    let config = coordinator_config(port, &status, &workers);
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // A "PDF" and MIDI, base64-encoded the way render-impl.bash does.
+    let pdf = "JVBERi0xLjQKJcOkw7zDtsOfCg==";
+    let midi = "TVRoZAAAAAYAAQACAYA=";
+
+    // --- Fake worker, replying with binary frames ---------------------
+    let (mut w_sink, mut w_stream) = connect_binary(port).await.split();
+    let handshake = json!({
+        "jsonrpc": "2.0",
+        "id": "11111111-2222-3333-4444-555555555555",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    w_sink
+        .send(Message::Text(handshake.to_string()))
+        .await
+        .expect("send handshake");
+    let registered = tokio::time::timeout(Duration::from_secs(5), async {
+        while status.snapshot().remote_total.load(Ordering::Relaxed) < 1 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(registered.is_ok(), "worker did not register in time");
+
+    let worker_task = tokio::spawn(async move {
+        while let Some(Ok(Message::Text(text))) = w_stream.next().await {
+            let v: Value = serde_json::from_str(&text).expect("render request");
+            if v["method"] == "render" {
+                let result = RenderResponse {
+                    files: vec![pdf.to_owned()],
+                    logs: "ok from binary worker".to_owned(),
+                    midi: midi.to_owned(),
+                    audio: String::new(),
+                };
+                let frame =
+                    wire::encode_render_result(&v["id"], &result, Backend::Pdf).expect("encode");
+                w_sink
+                    .send(Message::Binary(frame))
+                    .await
+                    .expect("worker reply");
+                return;
+            }
+        }
+        panic!("worker never received a render request");
+    });
+
+    // --- Fake frontend, also asking for binary results ----------------
+    let (mut f_sink, mut f_stream) = connect_binary(port).await.split();
+    let render = json!({
+        "jsonrpc": "2.0",
+        "id": "43",
+        "method": "render",
+        "params": { "backend": "pdf", "src": "c4", "version": "stable" },
+    });
+    f_sink
+        .send(Message::Text(render.to_string()))
+        .await
+        .expect("frontend send render");
+
+    let frame = match tokio::time::timeout(Duration::from_secs(10), f_stream.next())
+        .await
+        .expect("frontend timed out waiting for render response")
+        .expect("stream ended")
+        .expect("ws error")
+    {
+        Message::Binary(b) => b,
+        other => panic!("expected binary response, got {:?}", other),
+    };
+    // The PDF and MIDI bytes are in the frame raw, not base64.
+    assert!(frame.windows(5).any(|w| w == b"%PDF-"));
+    assert!(frame.ends_with(&[0x00, 0x01, 0x00, 0x02, 0x01, 0x80]));
+
+    let (id, result) = wire::decode_render_result(&frame).expect("decode");
+    assert_eq!(id, json!("43"));
+    assert_eq!(result.files, vec![pdf.to_owned()]);
+    assert_eq!(result.midi, midi);
+    assert_eq!(result.logs, "ok from binary worker");
+
+    worker_task.await.expect("worker task did not panic");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_compresses_when_asked_and_counts_bytes() {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+
+    let mut config = coordinator_config(port, &status, &workers);
+    if let CommandSourceConfig::Coordinator { ws_compression, .. } = &mut config.command_source {
+        *ws_compression = true;
+    }
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // A plain, uncompressed worker returning a large, repetitive SVG.
+    let svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\">{}</svg>",
+        "<path d=\"M0 0L10 10\"/>".repeat(500)
+    );
+    let (ws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+        .await
+        .expect("worker connect");
+    let (mut w_sink, mut w_stream) = ws.split();
+    let handshake = json!({
+        "jsonrpc": "2.0",
+        "id": "11111111-2222-3333-4444-555555555555",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    w_sink
+        .send(Message::Text(handshake.to_string()))
+        .await
+        .expect("send handshake");
+    let registered = tokio::time::timeout(Duration::from_secs(5), async {
+        while status.snapshot().remote_total.load(Ordering::Relaxed) < 1 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(registered.is_ok(), "worker did not register in time");
+
+    let worker_svg = svg.clone();
+    let worker_task = tokio::spawn(async move {
+        while let Some(Ok(Message::Text(text))) = w_stream.next().await {
+            let v: Value = serde_json::from_str(&text).expect("render request");
+            if v["method"] == "render" {
+                let resp = json!({
+                    "jsonrpc": "2.0",
+                    "id": v["id"],
+                    "result": { "files": [worker_svg], "logs": "", "midi": "" },
+                });
+                w_sink
+                    .send(Message::Text(resp.to_string()))
+                    .await
+                    .expect("worker reply");
+                return;
+            }
+        }
+        panic!("worker never received a render request");
+    });
+
+    // A frontend asking for JSON with compression.
+    let mut req = format!("ws://127.0.0.1:{}", port)
+        .into_client_request()
+        .expect("request");
+    req.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        wire::DEFLATE_SUBPROTOCOL.parse().unwrap(),
+    );
+    let (fws, resp) = tokio_tungstenite::connect_async(req)
+        .await
+        .expect("frontend connect");
+    assert_eq!(
+        resp.headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|v| v.to_str().ok()),
+        Some(wire::DEFLATE_SUBPROTOCOL),
+    );
+    let (mut f_sink, mut f_stream) = fws.split();
+    let render = json!({
+        "jsonrpc": "2.0",
+        "id": "44",
+        "method": "render",
+        "params": { "backend": "svg", "src": "c4", "version": "stable" },
+    });
+    f_sink
+        .send(wire::seal(
+            Message::Text(render.to_string()),
+            wire::Compression::Deflate,
+        ))
+        .await
+        .expect("frontend send render");
+
+    let sealed = tokio::time::timeout(Duration::from_secs(10), f_stream.next())
+        .await
+        .expect("frontend timed out waiting for render response")
+        .expect("stream ended")
+        .expect("ws error");
+    assert!(sealed.is_binary(), "compressed messages are binary frames");
+    assert!(sealed.len() < svg.len() / 4, "response was compressed");
+    let text = match wire::open(sealed, wire::Compression::Deflate).expect("open") {
+        Message::Text(t) => t,
+        other => panic!("expected a text message inside, got {:?}", other),
+    };
+    let v: Value = serde_json::from_str(&text).expect("parse response");
+    assert_eq!(v["id"], json!("44"));
+    assert_eq!(v["result"]["files"][0], json!(svg));
+
+    worker_task.await.expect("worker task did not panic");
+
+    // The SVG crossed the coordinator twice: once in from the worker,
+    // uncompressed, and once out to the frontend, compressed.
+    let snap = status.snapshot();
+    let bytes_in = snap.ws_bytes_in.load(Ordering::Relaxed);
+    let bytes_out = snap.ws_bytes_out.load(Ordering::Relaxed);
+    let payload_out = snap.ws_payload_bytes_out.load(Ordering::Relaxed);
+    assert!(bytes_in as usize > svg.len());
+    assert!(payload_out as usize > svg.len());
+    assert!(
+        bytes_out < payload_out / 4,
+        "{} vs {}",
+        bytes_out,
+        payload_out
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_only_registers_workers_on_the_allow_list() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let keys = WorkerKeys::parse("basement s3cret\n").expect("keys");
+
+    let mut config = coordinator_config(port, &status, &workers);
+    if let CommandSourceConfig::Coordinator { worker_keys, .. } = &mut config.command_source {
+        *worker_keys = Some(Arc::new(keys));
+    }
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // Sends `i_haz_computes` with `params` and returns the first reply,
+    // if any arrives before the coordinator closes the socket.
+    let handshake = |params: Value| async move {
+        let (mut ws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+            .await
+            .expect("worker connect");
+        let hello = json!({
+            "jsonrpc": "2.0",
+            "id": "11111111-2222-3333-4444-555555555555",
+            "method": "i_haz_computes",
+            "params": params,
+        });
+        ws.send(Message::Text(hello.to_string()))
+            .await
+            .expect("send handshake");
+        let reply = tokio::time::timeout(Duration::from_millis(500), ws.next())
+            .await
+            .ok()
+            .flatten()
+            .and_then(|m| m.ok())
+            .and_then(|m| m.into_text().ok())
+            .and_then(|t| serde_json::from_str::<Value>(&t).ok());
+        (ws, reply)
+    };
+
+    let expired = worker_auth::sign("basement", "s3cret", worker_auth::unix_now() - 1);
+    for params in [
+        json!({ "max_jobs": 1 }),
+        json!({ "max_jobs": 1, "name": "basement", "token": "guess" }),
+        json!({ "max_jobs": 1, "name": "attic", "token": "s3cret" }),
+        json!({ "max_jobs": 1, "name": "basement", "token": expired }),
+    ] {
+        let (_ws, reply) = handshake(params.clone()).await;
+        let reply = reply.unwrap_or_else(|| panic!("no rejection for {}", params));
+        assert_eq!(reply["error"]["code"], json!(4), "{}", params);
+    }
+    assert_eq!(
+        status.snapshot().workers_rejected.load(Ordering::Relaxed),
+        4
+    );
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 0);
+
+    // Both credential forms get in, and get no reply.
+    let token = worker_auth::sign("basement", "s3cret", worker_auth::unix_now() + 60);
+    let (_signed, reply) =
+        handshake(json!({ "max_jobs": 1, "name": "basement", "token": token })).await;
+    assert_eq!(reply, None);
+    let (_psk, reply) =
+        handshake(json!({ "max_jobs": 1, "name": "basement", "token": "s3cret" })).await;
+    assert_eq!(reply, None);
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 2);
+    assert_eq!(
+        status.snapshot().workers_rejected.load(Ordering::Relaxed),
+        4
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_keeps_workers_and_frontends_apart() {
+    let port = ephemeral_port();
+    let worker_port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+
+    let mut config = coordinator_config(port, &status, &workers);
+    if let CommandSourceConfig::Coordinator {
+        worker_listener,
+        worker_path,
+        ..
+    } = &mut config.command_source
+    {
+        *worker_listener = Some(format!("127.0.0.1:{}", worker_port).parse().unwrap());
+        *worker_path = Some("/worker".to_owned());
+    }
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // Sends `method` as the first message to `url` and returns the
+    // first reply, if any arrives before the coordinator closes the
+    // socket.
+    let first_reply = |url: String, method: &'static str| async move {
+        let (mut ws, _resp) = tokio_tungstenite::connect_async(url)
+            .await
+            .expect("connect");
+        let hello = json!({
+            "jsonrpc": "2.0",
+            "id": "1",
+            "method": method,
+            "params": { "max_jobs": 1 },
+        });
+        ws.send(Message::Text(hello.to_string()))
+            .await
+            .expect("send");
+        let reply = tokio::time::timeout(Duration::from_millis(500), ws.next())
+            .await
+            .ok()
+            .flatten()
+            .and_then(|m| m.ok())
+            .and_then(|m| m.into_text().ok())
+            .and_then(|t| serde_json::from_str::<Value>(&t).ok());
+        (ws, reply)
+    };
+
+    // No posing as a worker on the frontend port, whatever the path.
+    for path in ["/", "/worker"] {
+        let url = format!("ws://127.0.0.1:{}{}", port, path);
+        let (_ws, reply) = first_reply(url, "i_haz_computes").await;
+        assert_eq!(reply.expect("refusal")["error"]["code"], json!(-32601));
+    }
+    assert_eq!(
+        status.snapshot().workers_rejected.load(Ordering::Relaxed),
+        2
+    );
+
+    // The worker port only upgrades the worker path...
+    let wrong_path = format!("ws://127.0.0.1:{}/rpc", worker_port);
+    assert!(tokio_tungstenite::connect_async(wrong_path).await.is_err());
+
+    // ...where frontend methods are refused...
+    let worker_url = format!("ws://127.0.0.1:{}/worker", worker_port);
+    let (_ws, reply) = first_reply(worker_url.clone(), "get_status").await;
+    assert_eq!(reply.expect("refusal")["error"]["code"], json!(-32600));
+
+    // ...and workers register.
+    let (_worker, reply) = first_reply(worker_url, "i_haz_computes").await;
+    assert_eq!(reply, None);
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 1);
+
+    // Frontends still work on the main port.
+    let (_frontend, reply) =
+        first_reply(format!("ws://127.0.0.1:{}/rpc", port), "get_status").await;
+    assert_eq!(
+        reply.expect("status")["result"]["remote_worker_count"],
+        json!(1)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_evicts_workers_that_stop_answering_pings() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone())
+        .with_heartbeat(Heartbeat::for_silence(Duration::from_millis(600)));
+
+    let config = coordinator_config(port, &status, &workers);
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    let hello = json!({
+        "jsonrpc": "2.0",
+        "id": "1",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    let mut workers_ws = vec![];
+    for _ in 0..2 {
+        let (mut ws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+            .await
+            .expect("worker connect");
+        ws.send(Message::Text(hello.to_string()))
+            .await
+            .expect("send handshake");
+        workers_ws.push(ws);
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 2);
+
+    // The first worker keeps reading, so tungstenite answers the
+    // coordinator's pings. The second never reads again, like a peer
+    // behind a dead NAT mapping.
+    let mut live = workers_ws.remove(0);
+    let _hung = workers_ws.remove(0);
+    let reader = tokio::spawn(async move { while let Some(Ok(_)) = live.next().await {} });
+
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 1);
+    assert_eq!(status.snapshot().workers_evicted.load(Ordering::Relaxed), 1);
+    reader.abort();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_drains_workers_on_request() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+
+    let mut config = coordinator_config(port, &status, &workers);
+    if let CommandSourceConfig::Coordinator { admin_token, .. } = &mut config.command_source {
+        *admin_token = Some(Arc::new(AdminToken::new("letmein".to_owned())));
+    }
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    let url = format!("ws://127.0.0.1:{}", port);
+    let hello = json!({
+        "jsonrpc": "2.0",
+        "id": "1",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 2 },
+    });
+    let (mut worker, _resp) = tokio_tungstenite::connect_async(url.clone())
+        .await
+        .expect("worker connect");
+    worker
+        .send(Message::Text(hello.to_string()))
+        .await
+        .expect("send handshake");
+    let (mut frontend, _resp) = tokio_tungstenite::connect_async(url.clone())
+        .await
+        .expect("frontend connect");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Reads text frames until one parses as JSON.
+    async fn next_json<S>(ws: &mut S) -> Option<Value>
+    where
+        S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let next = async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Ok(v) = serde_json::from_str(msg.to_text().unwrap_or("")) {
+                    return Some(v);
+                }
+            }
+            None
+        };
+        tokio::time::timeout(Duration::from_secs(2), next)
+            .await
+            .ok()
+            .flatten()
+    }
+    let render = |id: &str| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "render",
+            "params": { "backend": "svg", "src": "c4", "version": "stable" },
+        })
+        .to_string()
+    };
+
+    // A render is in flight when the worker says it's going away...
+    frontend
+        .send(Message::Text(render("r1")))
+        .await
+        .expect("send render");
+    let job = next_json(&mut worker).await.expect("render on worker");
+    assert_eq!(job["method"], json!("render"));
+
+    // Admins can see it there, one worker at a time.
+    for (token, code) in [(json!(null), json!(4)), (json!("guess"), json!(4))] {
+        let ask = json!({
+            "jsonrpc": "2.0",
+            "id": "s",
+            "method": "get_status_detail",
+            "params": { "token": token },
+        });
+        frontend
+            .send(Message::Text(ask.to_string()))
+            .await
+            .expect("send get_status_detail");
+        let reply = next_json(&mut frontend).await.expect("detail reply");
+        assert_eq!(reply["error"]["code"], code);
+    }
+    let ask = json!({
+        "jsonrpc": "2.0",
+        "id": "s",
+        "method": "get_status_detail",
+        "params": { "token": "letmein" },
+    });
+    frontend
+        .send(Message::Text(ask.to_string()))
+        .await
+        .expect("send get_status_detail");
+    let detail = next_json(&mut frontend).await.expect("detail reply")["result"].take();
+    assert_eq!(detail["local_containers"], json!([]));
+    let listed = &detail["remote_workers"][0];
+    assert_eq!(listed["slots"], json!({ "any": 2 }));
+    assert_eq!(listed["in_flight"].as_array().map(Vec::len), Some(1));
+    assert!(
+        listed["address"]
+            .as_str()
+            .is_some_and(|a| a.starts_with("127.0.0.1:")),
+        "{}",
+        listed
+    );
+
+    let drain = json!({ "jsonrpc": "2.0", "method": "drain", "params": {} });
+    worker
+        .send(Message::Text(drain.to_string()))
+        .await
+        .expect("send drain");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // ...so new renders find no renderer...
+    frontend
+        .send(Message::Text(render("r2")))
+        .await
+        .expect("send render");
+    let refused = next_json(&mut frontend).await.expect("r2 reply");
+    assert_eq!(refused["id"], json!("r2"));
+    let logs = refused["result"]["logs"].as_str().unwrap_or_default();
+    assert!(logs.starts_with("No renderers attached"), "{}", logs);
+
+    // ...but the one in flight is still delivered, and then the
+    // coordinator hangs up.
+    let answer = json!({
+        "jsonrpc": "2.0",
+        "id": job["id"],
+        "result": { "files": ["<svg/>"], "logs": "done", "midi": "" },
+    });
+    worker
+        .send(Message::Text(answer.to_string()))
+        .await
+        .expect("send answer");
+    let delivered = next_json(&mut frontend).await.expect("r1 reply");
+    assert_eq!(delivered["result"]["logs"], json!("done"));
+    let hung_up = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match worker.next().await {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                Some(Ok(_)) => {}
+            }
+        }
+    })
+    .await;
+    assert!(
+        hung_up.is_ok(),
+        "coordinator did not close the drained worker"
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 0);
+
+    // Admins can drain a worker by its id, with the token.
+    let (mut idle, _resp) = tokio_tungstenite::connect_async(url.clone())
+        .await
+        .expect("worker connect");
+    idle.send(Message::Text(hello.to_string()))
+        .await
+        .expect("send handshake");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let id = status.snapshot().remote_workers()[0].id.clone();
+    for (token, code) in [(json!(null), json!(4)), (json!("guess"), json!(4))] {
+        let ask = json!({
+            "jsonrpc": "2.0",
+            "id": "d",
+            "method": "drain_worker",
+            "params": { "worker": id, "token": token },
+        });
+        frontend
+            .send(Message::Text(ask.to_string()))
+            .await
+            .expect("send drain_worker");
+        let reply = next_json(&mut frontend).await.expect("drain_worker reply");
+        assert_eq!(reply["error"]["code"], code);
+    }
+    let ask = json!({
+        "jsonrpc": "2.0",
+        "id": "d",
+        "method": "drain_worker",
+        "params": { "worker": id, "token": "letmein" },
+    });
+    frontend
+        .send(Message::Text(ask.to_string()))
+        .await
+        .expect("send drain_worker");
+    let reply = next_json(&mut frontend).await.expect("drain_worker reply");
+    assert_eq!(reply["result"], json!("OK"));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(status.snapshot().remote_total.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn worker_fails_over_to_the_next_coordinator() {
+    let port = ephemeral_port();
+    let unreachable = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let config = |command_source| Config {
+        status: StatusHandle::new(),
+        command_source,
+        ..coordinator_config(port, &status, &workers)
+    };
+
+    let _coordinator = tokio::spawn(event_loop(coordinator_config(port, &status, &workers)));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    let _worker = tokio::spawn(event_loop(config(CommandSourceConfig::Worker {
+        links: vec![CoordinatorLink {
+            coordinators: vec![
+                format!("ws://127.0.0.1:{}", unreachable).parse().unwrap(),
+                format!("ws://127.0.0.1:{}", port).parse().unwrap(),
+            ],
+            weight: 1,
+            outbox: Outbox::default(),
+        }],
+        reconnect: Backoff::default(),
+        binary_transport: false,
+        ws_compression: false,
+        credentials: None,
+        tls: TlsClientFiles::default(),
+    })));
+
+    let registered = tokio::time::timeout(Duration::from_secs(3), async {
+        while status.snapshot().remote_total.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(
+        registered.is_ok(),
+        "worker did not get past the unreachable coordinator"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn clustered_coordinators_hand_renders_to_each_other() {
+    let shared = Arc::new(InProcessState::default());
+    let coordinator = |port: u16, node: &str| {
+        let status = StatusHandle::new();
+        let cluster =
+            Cluster::new(node.to_owned(), shared.clone()).with_tick(Duration::from_millis(20));
+        let mut config = coordinator_config(
+            port,
+            &status,
+            &WorkerRegistryHandle::with_status(status.clone()),
+        );
+        if let CommandSourceConfig::Coordinator { cluster: slot, .. } = &mut config.command_source {
+            *slot = Some(cluster);
+        }
+        (tokio::spawn(event_loop(config)), status)
+    };
+    let (port_a, port_b) = (ephemeral_port(), ephemeral_port());
+    let (_a, status_a) = coordinator(port_a, "a");
+    let (_b, _status_b) = coordinator(port_b, "b");
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // The only worker is on b...
+    let (mut worker, _resp) =
+        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port_b))
+            .await
+            .expect("worker connect");
+    let hello = json!({
+        "jsonrpc": "2.0",
+        "id": "1",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    worker
+        .send(Message::Text(hello.to_string()))
+        .await
+        .expect("send handshake");
+
+    // ...and the frontend is on a.
+    let (mut frontend, _resp) =
+        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port_a))
+            .await
+            .expect("frontend connect");
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let nodes = status_a.snapshot().cluster_nodes();
+    assert_eq!(nodes.len(), 2, "{:?}", nodes);
+
+    let render = json!({
+        "jsonrpc": "2.0",
+        "id": "r1",
+        "method": "render",
+        "params": { "backend": "svg", "src": "c4", "version": "stable" },
+    });
+    frontend
+        .send(Message::Text(render.to_string()))
+        .await
+        .expect("send render");
+
+    let job = tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = worker.next().await {
+            if let Ok(v) = serde_json::from_str::<Value>(msg.to_text().unwrap_or("")) {
+                if v["method"] == json!("render") {
+                    return v;
+                }
+            }
+        }
+        panic!("worker closed");
+    })
+    .await
+    .expect("render reached the worker on the other coordinator");
+    let answer = json!({
+        "jsonrpc": "2.0",
+        "id": job["id"],
+        "result": { "files": ["<svg/>"], "logs": "from b", "midi": "" },
+    });
+    worker
+        .send(Message::Text(answer.to_string()))
+        .await
+        .expect("send answer");
+
+    let reply = tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = frontend.next().await {
+            if let Ok(v) = serde_json::from_str::<Value>(msg.to_text().unwrap_or("")) {
+                if v["id"] == json!("r1") {
+                    return v;
+                }
+            }
+        }
+        panic!("frontend closed");
+    })
+    .await
+    .expect("answer came back through the first coordinator");
+    assert_eq!(reply["result"]["logs"], json!("from b"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_replays_unfinished_jobs_after_a_restart() {
+    // A previous run queued this render and died before it finished.
+    let path = std::env::temp_dir().join(format!("job-log-{}.jsonl", uuid::Uuid::new_v4()));
+    let job = "before-the-crash";
+    let retention = Duration::from_secs(60);
+    JobLog::open(&path, retention)
+        .expect("open job log")
+        .queued(&Request {
+            id: job.to_owned(),
+            backend: Backend::Svg,
+            version: Version::Stable,
+            src: "c4".to_owned(),
+            options: Default::default(),
+            files: Default::default(),
+        });
+    let job_log = JobLog::open(&path, retention).expect("reopen job log");
+    assert_eq!(job_log.unfinished().len(), 1);
+
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let config = Config {
+        job_log: job_log.clone(),
+        ..coordinator_config(port, &status, &workers)
+    };
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // No renderer was attached when the job was replayed; it waits for
+    // the first worker instead of failing.
+    let (ws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+        .await
+        .expect("worker connect");
+    let (mut w_sink, mut w_stream) = ws.split();
+    let handshake = json!({
+        "jsonrpc": "2.0",
+        "id": "11111111-2222-3333-4444-555555555555",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    w_sink
+        .send(Message::Text(handshake.to_string()))
+        .await
+        .expect("send handshake");
+
+    let render = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(msg)) = w_stream.next().await {
+            if let Message::Text(text) = msg {
+                let v: Value = serde_json::from_str(&text).expect("parse");
+                if v["method"] == "render" {
+                    return v;
+                }
+            }
+        }
+        panic!("worker disconnected");
+    })
+    .await
+    .expect("replayed job was never dispatched");
+    assert_eq!(render["params"]["src"], json!("c4"));
+    let resp = json!({
+        "jsonrpc": "2.0",
+        "id": render["id"],
+        "result": { "files": ["<svg/>"], "logs": "rendered after restart", "midi": "" },
+    });
+    w_sink
+        .send(Message::Text(resp.to_string()))
+        .await
+        .expect("worker reply");
+
+    // The answer lands in the log for the client to pick up.
+    let done = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let job = job_log.get(job).expect("job is kept");
+            if job.state == JobState::Done {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("replayed job never finished");
+    assert_eq!(
+        done.response.expect("response recorded").logs,
+        "rendered after restart",
+    );
+    assert!(job_log.unfinished().is_empty());
+    std::fs::remove_file(path).unwrap_or(());
+}
+
+type Frontend =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Make one JSON-RPC call as a frontend and return its answer.
+async fn call(frontend: &mut Frontend, method: &str, params: Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "id": "1", "method": method, "params": params });
+    frontend
+        .send(Message::Text(request.to_string()))
+        .await
+        .expect("frontend send");
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), frontend.next())
+            .await
+            .expect("frontend timed out waiting for an answer")
+            .expect("stream ended")
+            .expect("ws error");
+        if let Message::Text(text) = msg {
+            return serde_json::from_str(&text).expect("parse answer");
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn submitted_renders_are_collected_after_reconnecting() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let config = coordinator_config(port, &status, &workers);
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    let (ws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+        .await
+        .expect("worker connect");
+    let (mut w_sink, mut w_stream) = ws.split();
+    let handshake = json!({
+        "jsonrpc": "2.0",
+        "id": "11111111-2222-3333-4444-555555555555",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    w_sink
+        .send(Message::Text(handshake.to_string()))
+        .await
+        .expect("send handshake");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status.snapshot().remote_total.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("worker did not register in time");
+
+    let url = format!("ws://127.0.0.1:{}", port);
+    let (mut frontend, _resp) = tokio_tungstenite::connect_async(&url)
+        .await
+        .expect("frontend connect");
+    let submitted = call(
+        &mut frontend,
+        "submitRender",
+        json!({ "backend": "svg", "src": "c4" }),
+    )
+    .await;
+    let job = submitted["result"]["job"].clone();
+    assert!(job.is_string(), "{}", submitted);
+
+    // The worker has it, but hasn't answered.
+    let render = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(msg)) = w_stream.next().await {
+            if let Message::Text(text) = msg {
+                let v: Value = serde_json::from_str(&text).expect("parse");
+                if v["method"] == "render" {
+                    return v;
+                }
+            }
+        }
+        panic!("worker disconnected");
+    })
+    .await
+    .expect("submitted render was never dispatched");
+    let running = call(&mut frontend, "getJob", json!({ "job": job })).await;
+    assert_eq!(running["result"]["state"], json!("running"), "{}", running);
+    let early = call(&mut frontend, "fetchResult", json!({ "job": job })).await;
+    assert_eq!(early["error"]["code"], json!(6), "{}", early);
+
+    // The client goes away; the render finishes without it.
+    drop(frontend);
+    let resp = json!({
+        "jsonrpc": "2.0",
+        "id": render["id"],
+        "result": { "files": ["<svg/>"], "logs": "rendered while away", "midi": "" },
+    });
+    w_sink
+        .send(Message::Text(resp.to_string()))
+        .await
+        .expect("worker reply");
+
+    let (mut frontend, _resp) = tokio_tungstenite::connect_async(&url)
+        .await
+        .expect("frontend reconnect");
+    let done = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let answer = call(&mut frontend, "getJob", json!({ "job": job })).await;
+            if answer["result"]["state"] == json!("done") {
+                return answer;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("job never finished");
+    assert!(done["result"]["done_unix_msec"].is_u64());
+    let result = call(&mut frontend, "fetchResult", json!({ "job": job })).await;
+    assert_eq!(result["result"]["logs"], json!("rendered while away"));
+    assert_eq!(result["result"]["files"], json!(["<svg/>"]));
+
+    let unknown = call(&mut frontend, "getJob", json!({ "job": "no-such-job" })).await;
+    assert_eq!(unknown["error"]["code"], json!(5), "{}", unknown);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn each_connection_may_only_have_so_many_jobs_outstanding() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let config = coordinator_config(port, &status, &workers);
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // A worker that takes one render and never answers, so nothing
+    // submitted ever finishes.
+    let url = format!("ws://127.0.0.1:{}", port);
+    let (mut worker, _resp) = tokio_tungstenite::connect_async(&url)
+        .await
+        .expect("worker connect");
+    let handshake = json!({
+        "jsonrpc": "2.0",
+        "id": "11111111-2222-3333-4444-555555555555",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    worker
+        .send(Message::Text(handshake.to_string()))
+        .await
+        .expect("send handshake");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status.snapshot().remote_total.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("worker did not register in time");
+
+    let submit = json!({ "backend": "svg", "src": "c4" });
+    let (mut greedy, _resp) = tokio_tungstenite::connect_async(&url)
+        .await
+        .expect("frontend connect");
+    for _ in 0..MAX_OUTSTANDING_PER_CONNECTION {
+        let submitted = call(&mut greedy, "submitRender", submit.clone()).await;
+        assert!(submitted["result"]["job"].is_string(), "{}", submitted);
+    }
+    let refused = call(&mut greedy, "submitRender", submit.clone()).await;
+    assert_eq!(refused["error"]["code"], json!(7), "{}", refused);
+
+    // Other connections have their own allowance.
+    let (mut other, _resp) = tokio_tungstenite::connect_async(&url)
+        .await
+        .expect("frontend connect");
+    let submitted = call(&mut other, "submitRender", submit).await;
+    assert!(submitted["result"]["job"].is_string(), "{}", submitted);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn http_renders_go_through_the_same_queue() {
+    let port = ephemeral_port();
+    let http_port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let mut config = coordinator_config(port, &status, &workers);
+    if let CommandSourceConfig::Coordinator { http_listener, .. } = &mut config.command_source {
+        *http_listener = Some(([127, 0, 0, 1], http_port).into());
+    }
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // A worker that answers every render with a one-page SVG and MIDI.
+    let (ws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+        .await
+        .expect("worker connect");
+    let (mut w_sink, mut w_stream) = ws.split();
+    let handshake = json!({
+        "jsonrpc": "2.0",
+        "id": "11111111-2222-3333-4444-555555555555",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    w_sink
+        .send(Message::Text(handshake.to_string()))
+        .await
+        .expect("send handshake");
+    tokio::spawn(async move {
+        while let Some(Ok(msg)) = w_stream.next().await {
+            if let Message::Text(text) = msg {
+                let v: Value = serde_json::from_str(&text).expect("parse");
+                if v["method"] == "render" {
+                    let resp = json!({
+                        "jsonrpc": "2.0",
+                        "id": v["id"],
+                        "result": {
+                            "files": ["<svg/>"],
+                            "logs": "rendered over HTTP",
+                            "midi": base64::encode(b"MThd"),
+                        },
+                    });
+                    let _ = w_sink.send(Message::Text(resp.to_string())).await;
+                }
+            }
+        }
+    });
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status.snapshot().remote_total.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("worker did not register in time");
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}", http_port);
+    let render = |accept: &'static str| {
+        client
+            .post(format!("{}/render", url))
+            .header("Accept", accept)
+            .body(json!({ "backend": "svg", "src": "c4" }).to_string())
+            .send()
+    };
+
+    let resp = render("application/json").await.expect("JSON render");
+    assert_eq!(resp.status(), 200);
+    let result: Value = resp.json().await.expect("JSON body");
+    assert_eq!(result["logs"], json!("rendered over HTTP"));
+
+    let resp = render("image/svg+xml").await.expect("SVG render");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/svg+xml");
+    assert_eq!(resp.headers()["x-page-count"], "1");
+    assert_eq!(resp.text().await.unwrap(), "<svg/>");
+
+    let resp = render("audio/midi").await.expect("MIDI render");
+    assert_eq!(resp.bytes().await.unwrap().as_ref(), b"MThd");
+
+    let resp = render("application/pdf").await.expect("PDF request");
+    assert_eq!(resp.status(), 406);
+
+    let resp = client
+        .post(format!("{}/render", url))
+        .body(json!({ "backend": "svg", "src": "" }).to_string())
+        .send()
+        .await
+        .expect("bad request");
+    assert_eq!(resp.status(), 400);
+
+    let status_json: Value = client
+        .get(format!("{}/status", url))
+        .send()
+        .await
+        .expect("status")
+        .json()
+        .await
+        .expect("status JSON");
+    assert_eq!(status_json["analytics_renders"], json!(3));
+    assert_eq!(status_json["remote_worker_count"], json!(1));
+    let minute = &status_json["history"]["minute"];
+    assert_eq!(minute["renders"], json!(3));
+    assert_eq!(minute["errors"], json!(0));
+    assert!(minute["p95_latency_msec"].is_u64(), "{}", minute);
+    assert_eq!(status_json["history"]["day"]["renders"], json!(3));
+
+    let metrics = client
+        .get(format!("{}/metrics", url))
+        .send()
+        .await
+        .expect("metrics")
+        .text()
+        .await
+        .expect("metrics text");
+    for line in [
+        "hacklily_render_duration_seconds_count{backend=\"svg\",version=\"stable\",location=\"remote\"} 3\n",
+        "hacklily_queue_wait_seconds_count 3\n",
+        "hacklily_renders_total 3\n",
+        "hacklily_worker_connects_total 1\n",
+        "hacklily_ws_messages_total{direction=\"in\"} 4\n",
+    ] {
+        assert!(metrics.contains(line), "missing {:?} in\n{}", line, metrics);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coordinator_hands_queued_renders_to_slots_that_free_up() {
+    let port = ephemeral_port();
+    let status = StatusHandle::new();
+    let workers = WorkerRegistryHandle::with_status(status.clone());
+    let config = coordinator_config(port, &status, &workers);
+    let _loop = tokio::spawn(event_loop(config));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // One slot, so the second render has to wait for the first.
+    let (ws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+        .await
+        .expect("worker connect");
+    let (mut w_sink, mut w_stream) = ws.split();
+    let handshake = json!({
+        "jsonrpc": "2.0",
+        "id": "11111111-2222-3333-4444-555555555555",
+        "method": "i_haz_computes",
+        "params": { "max_jobs": 1 },
+    });
+    w_sink
+        .send(Message::Text(handshake.to_string()))
+        .await
+        .expect("send handshake");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while status.snapshot().remote_total.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("worker did not register in time");
+
+    let (fws, _resp) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port))
+        .await
+        .expect("frontend connect");
+    let (mut f_sink, mut f_stream) = fws.split();
+    for id in ["first", "second"] {
+        let render = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "render",
+            "params": { "backend": "svg", "src": "c4", "version": "stable" },
+        });
+        f_sink
+            .send(Message::Text(render.to_string()))
+            .await
+            .expect("frontend send render");
+    }
+
+    // Nothing else happens after the worker answers the first render,
+    // so only the freed slot can get the second one going.
+    let worker_task = tokio::spawn(async move {
+        let mut answered = 0;
+        while let Some(Ok(msg)) = w_stream.next().await {
+            let v: Value = match msg {
+                Message::Text(text) => serde_json::from_str(&text).expect("parse"),
+                _ => continue,
+            };
+            if v["method"] != "render" {
+                continue;
+            }
+            // Take a moment over it, like a real render.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let resp = json!({
+                "jsonrpc": "2.0",
+                "id": v["id"],
+                "result": { "files": ["<svg/>"], "logs": "ok", "midi": "" },
+            });
+            w_sink
+                .send(Message::Text(resp.to_string()))
+                .await
+                .expect("worker reply");
+            answered += 1;
+            if answered == 2 {
+                break;
+            }
+        }
+    });
+
+    let mut ids = vec![];
+    while ids.len() < 2 {
+        let msg = tokio::time::timeout(Duration::from_secs(5), f_stream.next())
+            .await
+            .expect("queued render was never dispatched")
+            .expect("stream ended")
+            .expect("ws error");
+        if let Message::Text(text) = msg {
+            let v: Value = serde_json::from_str(&text).expect("parse response");
+            assert_eq!(v["result"]["logs"], json!("ok"), "{}", v);
+            ids.push(v["id"].clone());
+        }
+    }
+    assert_eq!(ids, vec![json!("first"), json!("second")]);
+    worker_task.await.expect("worker task did not panic");
+}
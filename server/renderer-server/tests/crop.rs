@@ -0,0 +1,72 @@
+#![warn(clippy::all)]
+
+extern crate renderer_lib;
+
+mod common;
+mod util;
+
+use self::common::request;
+use self::util::run_test;
+use renderer_lib::request::{Backend, OutputMode, RenderOptions, Request, Response, Version};
+
+fn get_request(id: &str, backend: Backend, version: Version) -> Request {
+    Request {
+        options: RenderOptions {
+            mode: OutputMode::Crop,
+            ..RenderOptions::default()
+        },
+        ..request(id, backend, version, include_str!("ly/simple.ly"))
+    }
+}
+
+/// Reads a numeric attribute (e.g. `width="596"`) from the root <svg> tag.
+fn svg_dimension(svg: &str, attr: &str) -> f64 {
+    let root = &svg[svg.find("<svg").expect("no <svg> tag")..];
+    let root = &root[..root.find('>').expect("unterminated <svg> tag")];
+    let needle = format!(" {}=\"", attr);
+    let start = root.find(&needle).expect("missing attribute") + needle.len();
+    let len = root[start..].find('"').expect("unterminated attribute");
+    root[start..start + len]
+        .trim_end_matches("pt")
+        .parse()
+        .expect("non-numeric attribute")
+}
+
+fn assert_cropped_svg(r: &Response, what: &str) {
+    assert!(
+        r.logs
+            .contains("Processing `/tmp/lyp/wrappers/hacklily.ly'"),
+        "{} logs missing canary; logs were: {}",
+        what,
+        r.logs
+    );
+    // Cropping replaces the per-page output with one tight image.
+    assert_eq!(r.files.len(), 1, "{} should have one file", what);
+
+    // simple.ly on a full A4 page is 596x842; cropped to a single note
+    // it must be much smaller in both directions.
+    let width = svg_dimension(&r.files[0], "width");
+    let height = svg_dimension(&r.files[0], "height");
+    assert!(width < 596.0, "{} width {} is not cropped", what, width);
+    assert!(height < 200.0, "{} height {} is not cropped", what, height);
+}
+
+#[test]
+fn crop() {
+    let res = run_test(vec![
+        get_request("crop-svg-s", Backend::Svg, Version::Stable),
+        get_request("crop-svg-u", Backend::Svg, Version::Unstable),
+        get_request("crop-pdf-s", Backend::Pdf, Version::Stable),
+    ]);
+
+    assert_cropped_svg(&res["crop-svg-s"], "stable SVG");
+    assert_cropped_svg(&res["crop-svg-u"], "unstable SVG");
+
+    let pdf = &res["crop-pdf-s"];
+    assert_eq!(pdf.files.len(), 1, "cropped PDF should have one file");
+    assert!(
+        pdf.files[0].starts_with("JVBERi"),
+        "cropped PDF payload is not base64 %PDF; logs: {}",
+        pdf.logs
+    );
+}
@@ -5,7 +5,8 @@ extern crate renderer_lib;
 mod util;
 
 use self::util::run_test;
-use renderer_lib::request::{Backend, Request, Response, Version};
+use renderer_lib::request::{Backend, RenderOptions, Request, Response, Version};
+use std::collections::BTreeMap;
 
 const NUM_ITERATIONS: u32 = 5;
 
@@ -14,7 +15,7 @@ fn evil() {
     // Test that some obvious evil inputs do not impact good inputs.
 
     env_logger::init();
-    let evils = vec![
+    let evils = [
         include_str!("ly/danger_forever.ly"),
         include_str!("ly/danger_forkbomb.ly"),
         include_str!("ly/danger_kill_group.ly"),
@@ -31,6 +32,8 @@ fn evil() {
             backend: Backend::Svg,
             src: src.to_owned(),
             version,
+            options: RenderOptions::default(),
+            files: BTreeMap::new(),
         }
     }
 
@@ -51,12 +54,14 @@ fn evil() {
         files: vec![include_str!("ly/simple.ly.2_26_0.svg").to_owned()],
         logs: include_str!("ly/simple.ly.2_26_0.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     let simple_unstable_response = Response {
         files: vec![include_str!("ly/simple.ly.2_27_1.svg").to_owned()],
         logs: include_str!("ly/simple.ly.2_27_1.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     let res = run_test(tests);
@@ -66,7 +71,7 @@ fn evil() {
             // Make sure the evil outputs gave something (anything!)
             for v in &[Version::Stable, Version::Unstable] {
                 let evil_id = format!("evil_{}_{:?}_{}", evil_i, v, iteration);
-                assert!(res.get(&evil_id).is_some());
+                assert!(res.contains_key(&evil_id));
             }
 
             // Make sure the good output is as it is supposed to be.
@@ -0,0 +1,51 @@
+#![warn(clippy::all)]
+
+extern crate renderer_lib;
+
+mod util;
+
+use self::util::run_test;
+use renderer_lib::request::{Backend, RenderOptions, Request, Version};
+
+fn get_request(id: &str, version: Version, files: &[(&str, &str)]) -> Request {
+    Request {
+        id: id.to_owned(),
+        backend: Backend::Svg,
+        version,
+        src: "\\include \"style.ily\"\n\\include \"parts/melody.ily\"\n{ \\melody }".to_owned(),
+        options: RenderOptions::default(),
+        files: files
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_string()))
+            .collect(),
+    }
+}
+
+#[test]
+fn files() {
+    let files = [
+        ("style.ily", "\\header { tagline = ##f }"),
+        ("parts/melody.ily", "melody = { c'4 d' e' f' }"),
+    ];
+    let res = run_test(vec![
+        get_request("files-s", Version::Stable, &files),
+        get_request("files-u", Version::Unstable, &files),
+        // Same source without the files: the includes can't be found,
+        // even on a container that just rendered them.
+        get_request("no-files", Version::Stable, &[]),
+    ]);
+
+    for id in ["files-s", "files-u"] {
+        let r = &res[id];
+        assert_eq!(r.files.len(), 1, "{} logs: {}", id, r.logs);
+        assert!(r.files[0].starts_with("<?xml") || r.files[0].starts_with("<svg"));
+        assert!(!r.logs.contains("cannot find file"), "{}", r.logs);
+    }
+
+    let missing = &res["no-files"];
+    assert!(
+        missing.logs.contains("cannot find file"),
+        "logs were: {}",
+        missing.logs
+    );
+}
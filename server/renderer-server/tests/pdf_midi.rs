@@ -5,7 +5,8 @@ extern crate renderer_lib;
 mod util;
 
 use self::util::run_test;
-use renderer_lib::request::{Backend, Request, Version};
+use renderer_lib::request::{Backend, RenderOptions, Request, Version};
+use std::collections::BTreeMap;
 
 /// Decodes a base64-encoded string into bytes (matches render-impl.bash's
 /// `cat | base64` encoding for non-SVG backends). Tolerates whitespace/newlines
@@ -51,6 +52,8 @@ fn get_request(id: &str, backend: Backend, version: Version) -> Request {
         backend,
         version,
         src: include_str!("ly/simple_midi.ly").to_owned(),
+        options: RenderOptions::default(),
+        files: BTreeMap::new(),
     }
 }
 
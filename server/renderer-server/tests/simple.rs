@@ -5,7 +5,8 @@ extern crate renderer_lib;
 mod util;
 
 use self::util::run_test;
-use renderer_lib::request::{Backend, Request, Response, Version};
+use renderer_lib::request::{Backend, RenderOptions, Request, Response, Version};
+use std::collections::BTreeMap;
 
 #[test]
 fn simple() {
@@ -15,6 +16,8 @@ fn simple() {
             backend: Backend::Svg,
             src: include_str!("ly/simple.ly").to_owned(),
             version,
+            options: RenderOptions::default(),
+            files: BTreeMap::new(),
         }
     }
 
@@ -22,12 +25,14 @@ fn simple() {
         files: vec![include_str!("ly/simple.ly.2_26_0.svg").to_owned()],
         logs: include_str!("ly/simple.ly.2_26_0.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     let unstable_response = Response {
         files: vec![include_str!("ly/simple.ly.2_27_1.svg").to_owned()],
         logs: include_str!("ly/simple.ly.2_27_1.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     assert_eq!(
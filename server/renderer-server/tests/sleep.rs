@@ -5,7 +5,8 @@ extern crate renderer_lib;
 mod util;
 
 use self::util::run_test;
-use renderer_lib::request::{Backend, Request, Response, Version};
+use renderer_lib::request::{Backend, RenderOptions, Request, Response, Version};
+use std::collections::BTreeMap;
 
 #[test]
 fn sleep() {
@@ -15,6 +16,8 @@ fn sleep() {
             backend: Backend::Svg,
             src: include_str!("ly/sleep.ly").to_owned(),
             version,
+            options: RenderOptions::default(),
+            files: BTreeMap::new(),
         }
     }
     fn get_simple_request(id: &str, version: Version) -> Request {
@@ -23,6 +26,8 @@ fn sleep() {
             backend: Backend::Svg,
             src: include_str!("ly/simple.ly").to_owned(),
             version,
+            options: RenderOptions::default(),
+            files: BTreeMap::new(),
         }
     }
 
@@ -30,24 +35,28 @@ fn sleep() {
         files: vec![include_str!("ly/simple.ly.2_26_0.svg").to_owned()],
         logs: include_str!("ly/simple.ly.2_26_0.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     let simple_unstable_response = Response {
         files: vec![include_str!("ly/simple.ly.2_27_1.svg").to_owned()],
         logs: include_str!("ly/simple.ly.2_27_1.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     let sleep_stable_response = Response {
         files: vec![include_str!("ly/sleep.ly.2_26_0.svg").to_owned()],
         logs: include_str!("ly/sleep.ly.2_26_0.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     let sleep_unstable_response = Response {
         files: vec![include_str!("ly/sleep.ly.2_27_1.svg").to_owned()],
         logs: include_str!("ly/sleep.ly.2_27_1.txt").to_owned(),
         midi: "".to_owned(),
+        audio: "".to_owned(),
     };
 
     assert_eq!(
@@ -1,5 +1,5 @@
 use renderer_lib::request::{Request, Response};
-use renderer_lib::{event_loop, CommandSourceConfig, Config};
+use renderer_lib::{event_loop, job_log::JobLog, CommandSourceConfig, Config};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -39,6 +39,8 @@ pub fn run_test(requests: Vec<Request>) -> HashMap<String, Response> {
         unstable_docker_tag: "hacklily-renderer-unstable".to_owned(),
         unstable_worker_count: worker_count,
         render_timeout_msec: 8000,
+        audio_max_bytes: 8 * 1024 * 1024,
+        job_log: JobLog::default(),
         status: renderer_lib::status::StatusHandle::new(),
         command_source: CommandSourceConfig::TestRunner {
             input: requests,
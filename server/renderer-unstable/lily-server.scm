@@ -342,6 +342,9 @@
     ;; load is still shared via COW, so only ~0.2s of init is repaid.
     (let ((server-socket (hacklily:open-listening-socket port)))
       (hacklily:debug (format #f "Listening on port ~a" port))
+      ;; renderer-server reads this line to list each container's
+      ;; LilyPond in its status (see renderer_manager.rs).
+      (hacklily:debug (format #f "LilyPond version ~a" (lilypond-version)))
       (flush-all-ports)
       (while #t
         (let ((connection (accept server-socket)))
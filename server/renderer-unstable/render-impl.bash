@@ -12,8 +12,8 @@
 # You should have received a copy of the GNU Affero General Public License
 # along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-# Stdin: one JSON request per line, {id, backend, version, src}.
-# Stdout: one JSON response per line, {files, logs, midi}.
+# Stdin: one JSON request per line, {id, backend, version, src, options?, files?}.
+# Stdout: one JSON response per line, {files, logs, midi, audio}.
 # stderr: lilypond / server log noise.
 #
 # A warm LilyPond process runs in the background running
@@ -41,6 +41,12 @@ if awk "BEGIN{exit !($RUST_TIMEOUT_MS <= 500)}"; then
 fi
 INNER_TIMEOUT_SEC=$(awk "BEGIN{printf \"%.1f\", ($RUST_TIMEOUT_MS - 500)/1000}")
 
+# Cap on synthesized audio (options.audio), passed in by renderer-server
+# as HACKLILY_AUDIO_MAX_BYTES. Larger files are dropped with a log line.
+AUDIO_MAX_BYTES=${HACKLILY_AUDIO_MAX_BYTES:-8388608}
+# General MIDI soundfont FluidSynth renders with; set by the Dockerfile.
+SOUNDFONT=${HACKLILY_SOUNDFONT:-/usr/share/sounds/sf2/TimGM6mb.sf2}
+
 # Reassert the font directory mtimes to the value the Dockerfile baked
 # into the fontconfig cache header. Image-layer unpacking re-stamps these
 # dirs with the container's start time (writing the font files into a dir
@@ -81,6 +87,9 @@ IFS="
 "
 
 while read -r line; do
+    # Start of this request's time budget (see the audio step below).
+    request_start=$(date +%s.%N)
+
     # Wait until the server is accepting connections.
     until printf "" 2>>/dev/null >>/dev/tcp/localhost/1225; do sleep 0.05; done
 
@@ -105,8 +114,25 @@ while read -r line; do
         continue
     fi
 
+    # Start from an empty directory so auxiliary files (and anything a
+    # previous score wrote) can't leak into this request.
+    find /tmp/lyp/wrappers -mindepth 1 -delete 2> /dev/null
+
     echo "$line" | jq -r .src > /tmp/lyp/wrappers/hacklily.ly 2> /dev/null
 
+    # Write the request's auxiliary files (path -> content) beside
+    # hacklily.ly so it can \include them. renderer-server has already
+    # validated the paths (relative, no dot-prefixed components, no
+    # hacklily* names, no newlines); the case below just refuses to
+    # write anywhere else if that ever stops being true.
+    for path in $(echo "$line" | jq -r '.files // {} | keys[]' 2> /dev/null); do
+        case "$path" in
+            /*|.*|*/.*|hacklily*) continue ;;
+        esac
+        mkdir -p "/tmp/lyp/wrappers/$(dirname "$path")"
+        echo "$line" | jq -j --arg path "$path" '.files[$path]' > "/tmp/lyp/wrappers/$path" 2> /dev/null
+    done
+
     # Translate the requested backend into LilyPond long options.
     case "$backend" in
         svg) opts="--svg" ;;
@@ -175,8 +201,37 @@ while read -r line; do
         continue
     fi
 
+    # Apply options.pageRange (validated by renderer-server: integers,
+    # 1 <= first <= last). SVG output is one file per page, so drop the
+    # pages outside the range; PDF is a single document, so have
+    # ghostscript extract the range into a new one. Everything else
+    # that renderer-server injects (paper size, staff size, ...) is
+    # LilyPond code prepended to the source.
+    page_first=$(echo "$line" | jq -r '.options.pageRange.first // empty')
+    page_last=$(echo "$line" | jq -r '.options.pageRange.last // empty')
+    if [ -n "$page_first" ] && [ -n "$page_last" ]; then
+        if [ "$backend" == "pdf" ] && [ -f /tmp/lyp/wrappers/hacklily.pdf ]; then
+            gs -q -dNOPAUSE -dBATCH -dSAFER -sDEVICE=pdfwrite \
+                -dFirstPage="$page_first" -dLastPage="$page_last" \
+                -sOutputFile=/tmp/lyp/wrappers/hacklily-range.pdf.tmp \
+                /tmp/lyp/wrappers/hacklily.pdf > /dev/null 2>&1 \
+                && mv /tmp/lyp/wrappers/hacklily-range.pdf.tmp /tmp/lyp/wrappers/hacklily.pdf
+            rm -f /tmp/lyp/wrappers/hacklily-range.pdf.tmp
+        elif [ "$backend" == "svg" ]; then
+            page=0
+            for f in $(ls -v /tmp/lyp/wrappers/hacklily*.svg 2>/dev/null); do
+                page=$((page + 1))
+                if [ "$page" -lt "$page_first" ] || [ "$page" -gt "$page_last" ]; then
+                    rm -f "$f"
+                fi
+            done
+        fi
+    fi
+
     # Encode any produced output files. SVG is plain text; everything
-    # else (PDF/PNG/MIDI) is base64'd.
+    # else (PDF/PNG/MIDI) is base64'd. In the preview/crop output modes
+    # renderer-server turns off print-pages, so the only match is the
+    # single hacklily.preview.* / hacklily.cropped.* file.
     for f in /tmp/lyp/wrappers/hacklily*."$backend"; do
         if [ "/tmp/lyp/wrappers/hacklily*.$backend" == "$f" ]; then
             echo '""' > "/tmp/lyp/wrappers/hacklily-null.$backend.json"
@@ -189,6 +244,46 @@ while read -r line; do
     touch /tmp/lyp/wrappers/hacklily.midi
     cat /tmp/lyp/wrappers/hacklily.midi | base64 | jq -Rs . > /tmp/lyp/wrappers/hacklily.midi.json 2>&1
 
+    # Synthesize the MIDI into options.audio ("ogg" or "mp3") with
+    # FluidSynth. This shares the render's time budget: it gets whatever
+    # is left of INNER_TIMEOUT_SEC after LilyPond finished, so a long
+    # score can't push the response past the Rust harness's timeout.
+    # Problems are reported in the logs rather than failing the render.
+    audio_format=$(echo "$line" | jq -r '.options.audio // empty')
+    rm -f /tmp/lyp/wrappers/hacklily.audio /tmp/lyp/wrappers/hacklily.audio.wav
+    if [ -n "$audio_format" ]; then
+        audio_budget=$(awk "BEGIN{printf \"%.1f\", $INNER_TIMEOUT_SEC - ($(date +%s.%N) - $request_start)}")
+        if [ ! -s /tmp/lyp/wrappers/hacklily.midi ]; then
+            printf '\naudio: no MIDI output to synthesize (add a \\midi block)' >> /tmp/lyp/wrappers/hacklily.logs
+        elif awk "BEGIN{exit !($audio_budget < 0.5)}"; then
+            printf '\naudio: skipped, the render used up the time limit' >> /tmp/lyp/wrappers/hacklily.logs
+        else
+            timeout "$audio_budget" bash -c '
+                cd /tmp/lyp/wrappers
+                fluidsynth -n -i -q -r 44100 -F hacklily.audio.wav -T wav "$1" hacklily.midi || exit 1
+                case "$2" in
+                    ogg) oggenc -Q -q 4 -o hacklily.audio hacklily.audio.wav ;;
+                    mp3) lame --silent -V 5 hacklily.audio.wav hacklily.audio ;;
+                    *)   exit 1 ;;
+                esac
+            ' -- "$SOUNDFONT" "$audio_format" > /dev/null 2>&1
+            audio_status=$?
+            audio_size=$(stat -c %s /tmp/lyp/wrappers/hacklily.audio 2>/dev/null || echo 0)
+            if [ $audio_status -eq 124 ]; then
+                printf '\naudio: synthesis timed out' >> /tmp/lyp/wrappers/hacklily.logs
+                rm -f /tmp/lyp/wrappers/hacklily.audio
+            elif [ $audio_status -ne 0 ]; then
+                printf '\naudio: synthesis failed' >> /tmp/lyp/wrappers/hacklily.logs
+                rm -f /tmp/lyp/wrappers/hacklily.audio
+            elif [ "$audio_size" -gt "$AUDIO_MAX_BYTES" ]; then
+                printf '\naudio: dropped, larger than %s bytes' "$AUDIO_MAX_BYTES" >> /tmp/lyp/wrappers/hacklily.logs
+                rm -f /tmp/lyp/wrappers/hacklily.audio
+            fi
+        fi
+    fi
+    touch /tmp/lyp/wrappers/hacklily.audio
+    cat /tmp/lyp/wrappers/hacklily.audio | base64 | jq -Rs . > /tmp/lyp/wrappers/hacklily.audio.json 2>&1
+
     jq -Rs . /tmp/lyp/wrappers/hacklily.logs > /tmp/lyp/wrappers/hacklily.logs.json 2>&1
 
     # Assemble the final JSON response. jq 1.7+ removed --argfile, so
@@ -198,10 +293,11 @@ while read -r line; do
     # lexically (hacklily-1, hacklily-10, hacklily-2, ...), which would
     # mis-order the pages of a 10+ page score in the files array; ls -v
     # uses natural/version ordering (hacklily-1 .. hacklily-2 .. -10).
-    jq -src '{files: ., logs: ($logs | fromjson), midi: ($midi | fromjson)}' \
+    jq -src '{files: ., logs: ($logs | fromjson), midi: ($midi | fromjson), audio: ($audio | fromjson)}' \
         $(ls -v /tmp/lyp/wrappers/hacklily*."$backend".json 2>/dev/null) \
         --rawfile logs /tmp/lyp/wrappers/hacklily.logs.json \
         --rawfile midi /tmp/lyp/wrappers/hacklily.midi.json \
+        --rawfile audio /tmp/lyp/wrappers/hacklily.audio.json \
         2> /dev/null
 
     rm -f /tmp/lyp/wrappers/hacklily* > /dev/null 2>&1
@@ -113,8 +113,28 @@ export interface BaseRPCResponse {
 // "render"
 // -------------------------------------------------------------------------
 
+/**
+ * Mirrors `RenderOptions` in server/renderer-server/src/request.rs. Every
+ * field is optional; the server rejects values it doesn't know.
+ */
+export interface RenderOptions {
+  audio?: "ogg" | "mp3";
+  landscape?: boolean;
+  mode?: "pages" | "preview" | "crop";
+  pageRange?: {
+    first: number;
+    last: number;
+  };
+  paperSize?: string;
+  pointAndClick?: boolean;
+  staffSize?: number;
+}
+
 export interface RenderParams {
   backend: "svg" | "pdf" | "musicxml2ly";
+  /** Auxiliary files `src` can `\include`, by relative path. */
+  files?: { [path: string]: string };
+  options?: RenderOptions;
   src: string;
   version?: "stable" | "unstable";
 }
@@ -128,6 +148,8 @@ export interface RenderResponse extends BaseRPCResponse {
     message: string;
   };
   result: {
+    /** Base64, when `options.audio` was set. */
+    audio?: string;
     err: string;
     files: string[];
     logs: string;
@@ -135,6 +157,34 @@ export interface RenderResponse extends BaseRPCResponse {
   };
 }
 
+// -------------------------------------------------------------------------
+// "submitRender", "getJob", "fetchResult"
+// -------------------------------------------------------------------------
+
+export interface SubmitRenderResponse extends BaseRPCResponse {
+  result: {
+    job: string;
+  };
+}
+
+export interface JobParams {
+  job: string;
+}
+
+export interface Job {
+  done_unix_msec: number | null;
+  job: string;
+  /** Renders ahead of it, while queued. */
+  position: number | null;
+  queued_unix_msec: number;
+  started_unix_msec: number | null;
+  state: "queued" | "running" | "done" | "failed";
+}
+
+export interface GetJobResponse extends BaseRPCResponse {
+  result: Job;
+}
+
 // -------------------------------------------------------------------------
 // "signIn"
 // -------------------------------------------------------------------------
@@ -160,11 +210,39 @@ export interface SignOutParams {
 // "status"
 // -------------------------------------------------------------------------
 
+export interface StatusWindow {
+  errors: number;
+  p50_latency_msec: number | null;
+  p95_latency_msec: number | null;
+  peak_backlog: number;
+  renders: number;
+}
+
+export interface ContainerLilyPond {
+  id: number;
+  lilypond: string;
+  version: "stable" | "unstable";
+}
+
 export interface Status {
   alive: boolean;
   backlog: number;
+  build: {
+    git_hash: string;
+    version: string;
+  };
   busy_worker_count: number;
+  docker_tags: {
+    stable: string;
+    unstable: string;
+  };
   free_worker_count: number;
+  history: {
+    day: StatusWindow;
+    hour: StatusWindow;
+    minute: StatusWindow;
+  };
+  lilypond_versions: ContainerLilyPond[];
   local_worker_count: number;
   remote_worker_count: number;
   startup_time: string;
@@ -184,22 +262,28 @@ interface RPCRequestParamsMap {
   // To type check values
   [key: string]: {};
 
+  fetchResult: JobParams;
+  getJob: JobParams;
   get_status: {};
   ping: {};
   render: RenderParams;
   signIn: SignInParams;
   signOut: SignOutParams;
+  submitRender: RenderParams;
 }
 
 interface RPCResponseMap {
   // To type check values
   [key: string]: BaseRPCResponse;
 
+  fetchResult: RenderResponse;
+  getJob: GetJobResponse;
   get_status: StatusResponse;
   ping: BaseRPCResponse;
   render: RenderResponse;
   signIn: SignInResponse;
   signOut: BaseRPCResponse;
+  submitRender: SubmitRenderResponse;
 }
 
 // -------------------------------------------------------------------------